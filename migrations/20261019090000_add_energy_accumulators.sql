@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS energy_accumulators (
+    uniqueid VARCHAR(255) NOT NULL PRIMARY KEY,
+    total_kwh REAL NOT NULL DEFAULT 0,
+    last_value REAL,
+    last_timestamp INTEGER
+    );
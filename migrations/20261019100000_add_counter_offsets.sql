@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS counter_offsets (
+    uniqueid VARCHAR(255) NOT NULL PRIMARY KEY,
+    offset REAL NOT NULL DEFAULT 0,
+    last_raw REAL,
+    resets INTEGER NOT NULL DEFAULT 0,
+    last_reset INTEGER
+    );
@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS discovery_topics (
+    topic VARCHAR(255) NOT NULL PRIMARY KEY,
+    serial_number VARCHAR(255) NOT NULL,
+    owner VARCHAR(255) NOT NULL,
+    last_published INTEGER NOT NULL
+    );
+CREATE INDEX IF NOT EXISTS discovery_topics_serial_number ON discovery_topics (serial_number);
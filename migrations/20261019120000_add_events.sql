@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    serial_number VARCHAR(255) NOT NULL,
+    model VARCHAR(255) NOT NULL,
+    point VARCHAR(255) NOT NULL,
+    symbol VARCHAR(255) NOT NULL,
+    severity VARCHAR(32) NOT NULL,
+    raised_at INTEGER NOT NULL,
+    cleared_at INTEGER,
+    duration_secs INTEGER
+    );
+CREATE INDEX IF NOT EXISTS events_serial_number ON events (serial_number);
+CREATE INDEX IF NOT EXISTS events_raised_at ON events (raised_at);
@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    login VARCHAR(255) NOT NULL UNIQUE,
+    given_name VARCHAR(255),
+    family_name VARCHAR(255),
+    additional_name VARCHAR(255),
+    preferred_name VARCHAR(255),
+    team_id INTEGER NOT NULL DEFAULT 0
+    );
+CREATE TABLE IF NOT EXISTS api_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    name VARCHAR(255),
+    created_at INTEGER NOT NULL,
+    last_used INTEGER
+    );
+CREATE INDEX IF NOT EXISTS api_keys_user_id ON api_keys (user_id);
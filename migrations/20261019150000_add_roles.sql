@@ -0,0 +1,12 @@
+-- Add migration script here
+ALTER TABLE users ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'viewer';
+-- the user created on first start needs to stay able to manage everyone else
+UPDATE users SET role = 'admin' WHERE login = 'admin';
+CREATE TABLE IF NOT EXISTS operator_scopes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    serial_number VARCHAR(255) NOT NULL,
+    -- {model}/{point path}; null allows every point of the unit
+    point VARCHAR(255)
+    );
+CREATE INDEX IF NOT EXISTS operator_scopes_user_id ON operator_scopes (user_id);
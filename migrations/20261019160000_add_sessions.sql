@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS sessions (
+    id VARCHAR(32) PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL,
+    expiry_date INTEGER NOT NULL
+    );
+CREATE INDEX IF NOT EXISTS sessions_expiry_date ON sessions (expiry_date);
+ALTER TABLE users ADD COLUMN password_hash VARCHAR(255);
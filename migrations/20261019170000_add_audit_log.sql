@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    source VARCHAR(255) NOT NULL,
+    serial_number VARCHAR(255) NOT NULL,
+    model VARCHAR(255) NOT NULL,
+    point VARCHAR(255) NOT NULL,
+    requested_value TEXT NOT NULL,
+    raw_value INTEGER,
+    previous_value TEXT,
+    result VARCHAR(32) NOT NULL,
+    error TEXT
+    );
+CREATE INDEX IF NOT EXISTS audit_log_serial_number ON audit_log (serial_number);
+CREATE INDEX IF NOT EXISTS audit_log_timestamp ON audit_log (timestamp);
@@ -0,0 +1,5 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS gateway_settings (
+    name VARCHAR(255) PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL
+    );
@@ -0,0 +1,17 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS schedules (
+    name VARCHAR(255) PRIMARY KEY NOT NULL,
+    cron VARCHAR(255) NOT NULL,
+    serial_number VARCHAR(255) NOT NULL,
+    model VARCHAR(255) NOT NULL,
+    point VARCHAR(255) NOT NULL,
+    value TEXT NOT NULL,
+    missed VARCHAR(32) NOT NULL DEFAULT 'skip',
+    dry_run BOOLEAN NOT NULL DEFAULT 0,
+    enabled BOOLEAN NOT NULL DEFAULT 1,
+    from_config BOOLEAN NOT NULL DEFAULT 0,
+    -- unix timestamp up to which runs have been made or skipped
+    last_checked INTEGER,
+    -- unix timestamp of the run last made
+    last_run INTEGER
+    );
@@ -0,0 +1,3 @@
+-- the OIDC issuer a user logs in through, whose login is then `{issuer}:{subject}`; null for the
+-- gateway's own users
+ALTER TABLE users ADD COLUMN issuer VARCHAR(255);
@@ -0,0 +1,146 @@
+use crate::ipc::PublishMessage;
+use crate::payload::Payload;
+use crate::state_mgmt::{record_write, AuditRecord};
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use sunspec_rs::sunspec_models::ValueType;
+use utoipa::ToSchema;
+
+/// How a write to a unit went.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteResult {
+    #[default]
+    Written,
+    /// refused before anything was sent to the unit
+    Rejected,
+    /// the unit didn't take it
+    Failed,
+    /// would have been written, but was only a dry run
+    DryRun,
+}
+impl std::fmt::Display for WriteResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteResult::Written => write!(f, "written"),
+            WriteResult::Rejected => write!(f, "rejected"),
+            WriteResult::Failed => write!(f, "failed"),
+            WriteResult::DryRun => write!(f, "dry_run"),
+        }
+    }
+}
+
+/// A point's value as it's kept in the audit log.
+pub fn audit_value(value: &ValueType) -> Option<String> {
+    match value {
+        ValueType::String(s) => Some(s.clone()),
+        ValueType::Integer(i) => Some(i.to_string()),
+        ValueType::Float(f) => Some(f.to_string()),
+        ValueType::Boolean(b) => Some(b.to_string()),
+        ValueType::Array(symbols) => Some(symbols.join(",")),
+        ValueType::Pad => None,
+    }
+}
+
+/// Keep a write in the audit log, returning the messages that announce it: on the unit's audit
+/// topic, and on the point's result topic for whoever asked for it.  It's announced even if it
+/// couldn't be kept, as that's then the only record of it.
+pub async fn audit_write(record: AuditRecord) -> Vec<PublishMessage> {
+    let record = match record_write(&record).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!(
+                "{}: Couldn't add write of {}/{} to the audit log: {e}",
+                record.serial_number, record.model, record.point
+            );
+            record
+        }
+    };
+    vec![
+        PublishMessage {
+            topic: result_topic(&record.serial_number, &record.model, &record.point),
+            payload: Payload::Audit(record.clone()),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        },
+        PublishMessage {
+            topic: format!("sunspec_gateway/{}/audit", record.serial_number),
+            payload: Payload::Audit(record),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        },
+    ]
+}
+
+/// Where the outcome of each write to a point is published.
+pub fn result_topic(sn: &str, model: &str, point: &str) -> String {
+    format!("sunspec_gateway/{sn}/{model}/{point}/result")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::WriteSource;
+    use crate::modules::audit::AuditQuery;
+    use crate::state_mgmt::{list_writes, test_db};
+
+    #[tokio::test]
+    async fn writes_are_kept_and_filtered() {
+        test_db().await;
+        let written = AuditRecord {
+            timestamp: 1_700_000_000,
+            source: WriteSource::Rest("audit-operator".to_string()).to_string(),
+            serial_number: "AUDIT1".to_string(),
+            model: "704".to_string(),
+            point: "WMaxLimPct".to_string(),
+            requested_value: "50".to_string(),
+            raw_value: Some(500),
+            previous_value: Some("1000".to_string()),
+            result: WriteResult::Written.to_string(),
+            ..AuditRecord::default()
+        };
+        let rejected = AuditRecord {
+            timestamp: 1_700_000_060,
+            source: WriteSource::Mqtt("sunspec_gateway/AUDIT1/704/WMaxLimPct/set".to_string())
+                .to_string(),
+            requested_value: "lots".to_string(),
+            raw_value: None,
+            previous_value: None,
+            result: WriteResult::Rejected.to_string(),
+            error: Some("not a number".to_string()),
+            ..written.clone()
+        };
+
+        let msgs = audit_write(written.clone()).await;
+        assert_eq!(
+            msgs[0].topic,
+            "sunspec_gateway/AUDIT1/704/WMaxLimPct/result"
+        );
+        assert_eq!(msgs[1].topic, "sunspec_gateway/AUDIT1/audit");
+        let Payload::Audit(kept) = msgs[1].payload.clone() else {
+            panic!("not an audit payload");
+        };
+        assert!(kept.id > 0);
+        audit_write(rejected).await;
+
+        let query = AuditQuery {
+            serial_number: Some("AUDIT1".to_string()),
+            ..AuditQuery::default()
+        };
+        let all = list_writes(&query, 10).await.unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].result, "rejected");
+        assert_eq!(all[1], kept);
+
+        let from_rest = AuditQuery {
+            source: Some("rest:".to_string()),
+            ..query
+        };
+        assert_eq!(list_writes(&from_rest, 10).await.unwrap(), vec![kept]);
+        let failed = AuditQuery {
+            result: Some(WriteResult::Failed),
+            ..from_rest
+        };
+        assert!(list_writes(&failed, 10).await.unwrap().is_empty());
+    }
+}
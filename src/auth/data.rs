@@ -1,8 +1,88 @@
+use crate::consts::PASSWORD_HASH_ROUNDS;
+use pbkdf2::pbkdf2_hmac_array;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::FromRow;
+use utoipa::ToSchema;
 
-#[derive(Debug, FromRow)]
+/// An API key of a user.  Only a hash of the key is kept; the key itself is shown once, when it's
+/// created.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, ToSchema, PartialEq)]
 pub(crate) struct ApiKey {
-    pub(crate) id: i32,
+    pub(crate) id: i64,
     pub(crate) user_id: i32,
+    /// what the key is for
+    pub(crate) name: Option<String>,
+    /// unix timestamp the key was created
+    pub(crate) created_at: i64,
+    /// unix timestamp the key was last used
+    pub(crate) last_used: Option<i64>,
+}
+
+/// A new API key, and the only time the key is shown.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub(crate) struct NewApiKey {
+    pub(crate) id: i64,
+    /// send as `Authorization: ApiKey {key}`
     pub(crate) key: String,
 }
+
+/// Keys are random, so a plain hash is enough to keep them from being read out of the database.
+pub(crate) fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub(crate) fn generate_api_key() -> String {
+    let mut bytes = [0_u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// A PBKDF2-HMAC-SHA256 hash of a password, as `pbkdf2_sha256${rounds}${salt}${hash}`.
+pub(crate) fn hash_password(password: &str) -> String {
+    hash_password_rounds(password, PASSWORD_HASH_ROUNDS)
+}
+
+pub(crate) fn hash_password_rounds(password: &str, rounds: u32) -> String {
+    let mut salt = [0_u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let hash = pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), &salt, rounds);
+    format!(
+        "pbkdf2_sha256${rounds}${}${}",
+        hex::encode(salt),
+        hex::encode(hash)
+    )
+}
+
+pub(crate) fn verify_password(password: &str, password_hash: &str) -> bool {
+    let parts: Vec<&str> = password_hash.split('$').collect();
+    let [scheme, rounds, salt, hash] = parts[..] else {
+        return false;
+    };
+    let (Ok(rounds), Ok(salt), Ok(hash)) = (rounds.parse(), hex::decode(salt), hex::decode(hash))
+    else {
+        return false;
+    };
+    if scheme != "pbkdf2_sha256" {
+        return false;
+    }
+    let candidate = pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), &salt, rounds);
+    // compare all of it, so how long this takes says nothing about how close the guess was
+    hash.len() == candidate.len()
+        && hash.iter().zip(candidate).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_verify() {
+        let hash = hash_password_rounds("correct horse", 1000);
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert_ne!(hash, hash_password_rounds("correct horse", 1000));
+        assert!(!verify_password("correct horse", "garbage"));
+    }
+}
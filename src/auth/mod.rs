@@ -1,5 +1,6 @@
 pub(crate) mod data;
 pub(crate) mod error_handler;
+pub(crate) mod session_store;
 pub(crate) mod token_extractor;
 pub(crate) mod token_management;
 pub(crate) mod token_middleware;
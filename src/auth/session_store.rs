@@ -0,0 +1,94 @@
+use crate::state_mgmt::{create_session, delete_session, load_session, save_session};
+use async_trait::async_trait;
+use tower_sessions::session::{Id, Record};
+use tower_sessions::session_store::{self, SessionStore};
+
+/// Keeps sessions in the gateway's database, so that logins outlive restarts and can be shared by
+/// gateways using the same database.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SqliteSessionStore;
+
+fn backend(e: anyhow::Error) -> session_store::Error {
+    session_store::Error::Backend(e.to_string())
+}
+
+fn encode(record: &Record) -> session_store::Result<String> {
+    serde_json::to_string(record).map_err(|e| session_store::Error::Encode(e.to_string()))
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        // ids are random, but a new session must never take over an existing one
+        while !create_session(
+            &record.id.to_string(),
+            &encode(record)?,
+            record.expiry_date.unix_timestamp(),
+        )
+        .await
+        .map_err(backend)?
+        {
+            record.id = Id::default();
+        }
+        Ok(())
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        save_session(
+            &record.id.to_string(),
+            &encode(record)?,
+            record.expiry_date.unix_timestamp(),
+        )
+        .await
+        .map_err(backend)
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        match load_session(&session_id.to_string())
+            .await
+            .map_err(backend)?
+        {
+            Some(data) => serde_json::from_str(&data)
+                .map(Some)
+                .map_err(|e| session_store::Error::Decode(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        delete_session(&session_id.to_string())
+            .await
+            .map_err(backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_mgmt::{delete_expired_sessions, test_db};
+    use std::collections::HashMap;
+    use tower_sessions::cookie::time::{Duration, OffsetDateTime};
+
+    #[tokio::test]
+    async fn sessions_round_trip_until_they_expire() {
+        test_db().await;
+        let store = SqliteSessionStore;
+        let mut record = Record {
+            id: Id::default(),
+            data: HashMap::from([("login".to_string(), serde_json::json!({"user_id": 7}))]),
+            expiry_date: OffsetDateTime::now_utc() + Duration::hours(1),
+        };
+        store.create(&mut record).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+
+        record.expiry_date = OffsetDateTime::now_utc() - Duration::seconds(1);
+        store.save(&record).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), None);
+        assert!(delete_expired_sessions().await.unwrap() >= 1);
+
+        record.expiry_date = OffsetDateTime::now_utc() + Duration::hours(1);
+        store.save(&record).await.unwrap();
+        store.delete(&record.id).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), None);
+    }
+}
@@ -1,60 +1,224 @@
-use crate::auth::data::ApiKey;
+use crate::auth::data::hash_api_key;
 use crate::auth::error_handler::AuthError;
 use crate::auth::token_management::JwtClaims;
 use crate::auth::token_management::JwtToken;
+use crate::config_structs::{AuthConfig, IssuerConfig};
 use crate::consts::*;
+use crate::modules::users::Role;
 use crate::state::AppState;
+use crate::state_mgmt::api_key_user;
 use async_trait::async_trait;
 use axum::{
     extract::FromRequestParts,
     http::{header::AUTHORIZATION, request::Parts},
 };
 use jsonwebtoken::{
-    decode,
-    jwk::{AlgorithmParameters, JwkSet},
-    DecodingKey, Validation,
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
 };
+use serde_json::Value;
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 
+#[derive(Debug, Clone)]
+struct CachedJwks {
+    jwks: JwkSet,
+    fetched: Instant,
+}
+
 // Structure to cache JWKs
 #[derive(Debug, Clone)]
 pub(crate) struct JwksCache {
-    keys: Arc<RwLock<HashMap<String, JwkSet>>>,
+    keys: Arc<RwLock<HashMap<String, CachedJwks>>>,
+    ttl: Duration,
+    min_refresh: Duration,
 }
 
 impl JwksCache {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(ttl: Duration) -> Self {
         Self {
             keys: Arc::new(RwLock::new(HashMap::new())),
+            ttl,
+            min_refresh: Duration::from_secs(JWKS_MIN_REFRESH_SECS),
         }
     }
 
-    async fn get_or_fetch_jwks(&self, issuer: &str, jwks_uri: &str) -> Result<JwkSet, AuthError> {
-        // Check cache first
-        if let Some(jwks) = self.keys.read().await.get(issuer) {
-            return Ok(jwks.clone());
-        }
-
-        // If not in cache, fetch from jwks_uri
-        let jwks = reqwest::get(jwks_uri)
+    async fn fetch_jwks(issuer: &IssuerConfig) -> Result<JwkSet, AuthError> {
+        let jwks_uri = match &issuer.jwks_uri {
+            Some(uri) => uri.clone(),
+            None => {
+                let url = issuer.discovery_url.clone().unwrap_or(format!(
+                    "{}/.well-known/openid-configuration",
+                    issuer.issuer.trim_end_matches('/')
+                ));
+                let discovery = reqwest::get(&url)
+                    .await
+                    .map_err(|_| AuthError::JwksError)?
+                    .json::<Value>()
+                    .await
+                    .map_err(|_| AuthError::JwksError)?;
+                match discovery["jwks_uri"].as_str() {
+                    Some(uri) => uri.to_string(),
+                    None => {
+                        error!("{url} has no jwks_uri");
+                        return Err(AuthError::JwksError);
+                    }
+                }
+            }
+        };
+        reqwest::get(&jwks_uri)
             .await
             .map_err(|_| AuthError::JwksError)?
             .json::<JwkSet>()
             .await
-            .map_err(|_| AuthError::JwksError)?;
+            .map_err(|_| AuthError::JwksError)
+    }
 
-        // Store in cache
-        self.keys
-            .write()
-            .await
-            .insert(issuer.to_string(), jwks.clone());
+    /// The issuer's key with `kid`.  The issuer's keys are fetched again once they're older than
+    /// the ttl, or when they don't have `kid` (as the issuer may have rotated its keys), though
+    /// not more often than `min_refresh` so that made-up kids can't hammer the issuer.
+    async fn get_key(&self, issuer: &IssuerConfig, kid: &str) -> Result<Jwk, AuthError> {
+        let cached = self.keys.read().await.get(&issuer.issuer).cloned();
+        if let Some(c) = &cached {
+            let age = c.fetched.elapsed();
+            if age < self.ttl {
+                if let Some(key) = c.jwks.find(kid) {
+                    return Ok(key.clone());
+                }
+            }
+            if age < self.min_refresh {
+                return Err(AuthError::InvalidToken);
+            }
+        }
+
+        let jwks = match Self::fetch_jwks(issuer).await {
+            Ok(jwks) => jwks,
+            Err(e) => {
+                // better to keep using expired keys than to lock everyone out while the issuer is down
+                if let Some(key) = cached.as_ref().and_then(|c| c.jwks.find(kid)) {
+                    warn!("Couldn't refresh keys of {}, using old ones", issuer.issuer);
+                    return Ok(key.clone());
+                }
+                error!("Couldn't fetch keys of {}", issuer.issuer);
+                return Err(e);
+            }
+        };
+        let key = jwks.find(kid).cloned();
+        self.keys.write().await.insert(
+            issuer.issuer.clone(),
+            CachedJwks {
+                jwks,
+                fetched: Instant::now(),
+            },
+        );
+        key.ok_or(AuthError::InvalidToken)
+    }
+}
 
-        Ok(jwks)
+/// The highest gateway role that the token's role claim maps to.
+fn mapped_role(claims: &Value, issuer: &IssuerConfig) -> Option<Role> {
+    let role_map = issuer.role_map.as_ref()?;
+    let mut claim = claims;
+    for part in issuer.role_claim.as_deref().unwrap_or("roles").split('.') {
+        claim = claim.get(part)?;
     }
+    let values: Vec<&str> = match claim {
+        Value::String(s) => vec![s.as_str()],
+        Value::Array(a) => a.iter().filter_map(|v| v.as_str()).collect(),
+        _ => vec![],
+    };
+    values
+        .iter()
+        .filter_map(|v| role_map.get(*v))
+        .max()
+        .copied()
+}
+
+/// Check a JWT's signature and claims, either against the configured secret (HS256) or against the
+/// keys of the issuer it claims to be from.
+pub(crate) async fn validate_jwt(
+    token: &str,
+    config: &AuthConfig,
+    jwks_cache: &JwksCache,
+) -> Result<JwtToken, AuthError> {
+    let header = decode_header(token).map_err(|_| AuthError::InvalidToken)?;
+
+    if header.alg == Algorithm::HS256 {
+        let secret = config.jwt_secret.as_ref().ok_or(AuthError::InvalidToken)?;
+        return match decode::<JwtClaims>(
+            token,
+            &DecodingKey::from_secret(secret.as_ref()),
+            &Validation::new(Algorithm::HS256),
+        ) {
+            Ok(token_data) => Ok(JwtToken {
+                claims: token_data.claims,
+                token: token.to_string(),
+                issuer: None,
+                role: None,
+            }),
+            Err(e) => {
+                info!("in-house jwt failed: {e}");
+                Err(AuthError::InvalidToken)
+            }
+        };
+    }
+
+    // nothing in the token can be trusted yet, but it says which issuer's keys to check it with
+    let mut unverified = Validation::new(header.alg);
+    unverified.insecure_disable_signature_validation();
+    unverified.validate_exp = false;
+    unverified.validate_aud = false;
+    unverified.required_spec_claims = HashSet::new();
+    let claimed_issuer = decode::<Value>(token, &DecodingKey::from_secret(&[]), &unverified)
+        .map_err(|_| AuthError::InvalidToken)?
+        .claims["iss"]
+        .as_str()
+        .map(String::from)
+        .ok_or(AuthError::InvalidToken)?;
+    let issuer = config
+        .issuers
+        .iter()
+        .flatten()
+        .find(|i| i.issuer == claimed_issuer)
+        .ok_or(AuthError::InvalidToken)?;
+
+    let kid = header.kid.ok_or(AuthError::InvalidToken)?;
+    let jwk = jwks_cache.get_key(issuer, &kid).await?;
+    let decoding_key = DecodingKey::from_jwk(&jwk).map_err(|_| AuthError::InvalidToken)?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&issuer.issuer]);
+    match &issuer.audience {
+        Some(aud) => validation.set_audience(&[aud]),
+        None => validation.validate_aud = false,
+    }
+    let claims = match decode::<Value>(token, &decoding_key, &validation) {
+        Ok(token_data) => token_data.claims,
+        Err(e) => {
+            info!("jwt from {} failed: {e}", issuer.issuer);
+            return Err(AuthError::InvalidToken);
+        }
+    };
+
+    let login = claims[issuer.login_claim.as_deref().unwrap_or("sub")]
+        .as_str()
+        .ok_or(AuthError::InvalidToken)?
+        .to_string();
+    Ok(JwtToken {
+        claims: JwtClaims {
+            sub: login,
+            exp: claims["exp"].as_u64().unwrap_or_default() as usize,
+            iat: claims["iat"].as_u64().unwrap_or_default() as usize,
+        },
+        token: token.to_string(),
+        issuer: Some(issuer.issuer.clone()),
+        role: mapped_role(&claims, issuer),
+    })
 }
 
 pub enum AuthToken {
@@ -83,73 +247,12 @@ where
 
             //region JWT
             if auth_str.starts_with("Bearer ") {
-                let token = auth_str.trim_start_matches("Bearer ").to_string();
-
-                match decode::<JwtClaims>(
-                    &token,
-                    &DecodingKey::from_secret(JWT_SECRET.as_ref()),
-                    &Validation::default(),
-                ) {
-                    Ok(token_data) => {
-                        return Ok(AuthToken::Jwt(JwtToken {
-                            claims: token_data.claims,
-                            token,
-                        }));
-                    }
-                    Err(e) => {
-                        info!("in-house jwt failed: {e}");
-                        // If validation with JWT_SECRET fails, try JWKS validation
-                        // First decode header without verification to get kid
-                        let header = jsonwebtoken::decode_header(&token)
-                            .map_err(|_| AuthError::InvalidToken)?;
-
-                        // Get kid from header
-                        let kid = header.kid.ok_or(AuthError::InvalidToken)?;
-
-                        let app_state = <dyn Any>::downcast_ref::<AppState>(state)
-                            .ok_or(AuthError::InvalidState)?;
-
-                        // For Google's JWKS (you can add more issuers as needed)
-                        let google_jwks = app_state
-                            .jwks_cache
-                            .get_or_fetch_jwks(
-                                "https://accounts.google.com",
-                                "https://www.googleapis.com/oauth2/v3/certs",
-                            )
-                            .await?;
-
-                        // Find the key with matching kid
-                        let jwk = google_jwks
-                            .keys
-                            .iter()
-                            .find(|k| k.common.key_id.as_deref() == Some(&kid))
-                            .ok_or(AuthError::InvalidToken)?;
-
-                        // Convert JWK to DecodingKey
-                        let decoding_key = match &jwk.algorithm {
-                            AlgorithmParameters::RSA(rsa) => {
-                                DecodingKey::from_rsa_components(&rsa.n, &rsa.e)
-                                    .map_err(|_| AuthError::InvalidToken)?
-                            }
-                            // Add other algorithms as needed
-                            _ => return Err(AuthError::InvalidToken),
-                        };
-
-                        // Validate the token with the JWKS key
-                        let mut validation = Validation::new(header.alg);
-                        validation.set_issuer(&["https://accounts.google.com"]);
-
-                        match decode::<JwtClaims>(&token, &decoding_key, &validation) {
-                            Ok(token_data) => {
-                                return Ok(AuthToken::Jwt(JwtToken {
-                                    claims: token_data.claims,
-                                    token,
-                                }));
-                            }
-                            Err(_) => return Err(AuthError::InvalidToken),
-                        }
-                    }
-                }
+                let token = auth_str.trim_start_matches("Bearer ");
+                let app_state =
+                    <dyn Any>::downcast_ref::<AppState>(state).ok_or(AuthError::InvalidState)?;
+                return validate_jwt(token, &app_state.auth_config, &app_state.jwks_cache)
+                    .await
+                    .map(AuthToken::Jwt);
             }
             //endregion
 
@@ -160,26 +263,199 @@ where
 
             let token = auth_str.trim_start_matches("ApiKey ").to_string();
 
-            // Here you would typically validate the token
-            // For example, check if it exists in your database or validate a JWT
             if token.is_empty() {
                 return Err(AuthError::InvalidToken);
             }
-            let state = <dyn Any>::downcast_ref::<AppState>(state).expect("Invalid state type");
-            //TODO: implement auth properly for your template project
-            Ok(AuthToken::ApiKey(1))
-            // let rs = sqlx::query_as!(ApiKey, "SELECT * FROM api_keys where key = $1", token)
-            //     .fetch_optional(&state.pool)
-            //     .await;
-            // match rs {
-            //     Ok(Some(record)) => Ok(AuthToken::ApiKey(record.user_id)),
-            //     Ok(None) => Err(AuthError::InvalidToken),
-            //     Err(e) => {
-            //         error!("invalid auth token on request: {e}");
-            //         Err(AuthError::InvalidTokenFormat)
-            //     },
-            // }
+            match api_key_user(&hash_api_key(&token)).await {
+                Ok(Some(user_id)) => Ok(AuthToken::ApiKey(user_id)),
+                Ok(None) => Err(AuthError::InvalidToken),
+                Err(e) => {
+                    error!("Error looking up api key: {e}");
+                    Err(AuthError::InvalidState)
+                }
+            }
             //endregion
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use chrono::Utc;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use openssl::rsa::Rsa;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    /// An RSA key the stand-in issuer signs with, as its private PEM and public JWK.
+    fn rsa_key(kid: &str) -> (Vec<u8>, Value) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwk = json!({
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": kid,
+            "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+            "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+        });
+        (rsa.private_key_to_pem().unwrap(), jwk)
+    }
+
+    #[derive(Clone)]
+    struct Issuer {
+        url: String,
+        keys: Arc<RwLock<Vec<Value>>>,
+        fetches: Arc<AtomicUsize>,
+    }
+
+    /// A local issuer serving a discovery document and whatever keys are in `keys`.
+    async fn stand_in_issuer() -> Issuer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let issuer = Issuer {
+            url: url.clone(),
+            keys: Arc::new(RwLock::new(vec![])),
+            fetches: Arc::new(AtomicUsize::new(0)),
+        };
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || async move { Json(json!({ "jwks_uri": format!("{url}/certs") })) }),
+            )
+            .route(
+                "/certs",
+                get(|State(issuer): State<Issuer>| async move {
+                    issuer.fetches.fetch_add(1, Ordering::SeqCst);
+                    Json(json!({ "keys": *issuer.keys.read().await }))
+                }),
+            )
+            .with_state(issuer.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        issuer
+    }
+
+    fn sign(pem: &[u8], kid: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.to_string());
+        encode(&header, &claims, &EncodingKey::from_rsa_pem(pem).unwrap()).unwrap()
+    }
+
+    fn config(issuer: &Issuer) -> AuthConfig {
+        AuthConfig {
+            issuers: Some(vec![IssuerConfig {
+                issuer: issuer.url.clone(),
+                audience: Some("gateway".into()),
+                login_claim: Some("preferred_username".into()),
+                role_claim: Some("realm_access.roles".into()),
+                role_map: Some(HashMap::from([
+                    ("gw-view".to_string(), Role::Viewer),
+                    ("gw-operate".to_string(), Role::Operator),
+                ])),
+                ..IssuerConfig::default()
+            }]),
+            ..AuthConfig::default()
+        }
+    }
+
+    fn claims(issuer: &Issuer, aud: &str) -> Value {
+        json!({
+            "iss": issuer.url,
+            "aud": aud,
+            "sub": "0b6c4d",
+            "preferred_username": "alice",
+            "realm_access": { "roles": ["gw-view", "gw-operate", "unrelated"] },
+            "exp": Utc::now().timestamp() + 300,
+            "iat": Utc::now().timestamp(),
+        })
+    }
+
+    #[tokio::test]
+    async fn validates_against_issuer_keys() {
+        let issuer = stand_in_issuer().await;
+        let (pem, jwk) = rsa_key("k1");
+        issuer.keys.write().await.push(jwk);
+        let config = config(&issuer);
+        let cache = JwksCache::new(Duration::from_secs(DEFAULT_JWKS_TTL_SECS));
+
+        let token = sign(&pem, "k1", claims(&issuer, "gateway"));
+        let jwt = validate_jwt(&token, &config, &cache).await.unwrap();
+        assert_eq!(jwt.claims.sub, "alice");
+        assert_eq!(jwt.role, Some(Role::Operator));
+
+        // wrong audience, a key nobody published, and an issuer nobody configured
+        let token = sign(&pem, "k1", claims(&issuer, "someone-else"));
+        assert!(validate_jwt(&token, &config, &cache).await.is_err());
+        let (other_pem, _) = rsa_key("k1");
+        let token = sign(&other_pem, "k1", claims(&issuer, "gateway"));
+        assert!(validate_jwt(&token, &config, &cache).await.is_err());
+        let mut stranger = claims(&issuer, "gateway");
+        stranger["iss"] = json!("https://accounts.google.com");
+        let token = sign(&pem, "k1", stranger);
+        assert!(validate_jwt(&token, &config, &cache).await.is_err());
+
+        // all of that from one fetch of the keys
+        assert_eq!(issuer.fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refreshes_on_unknown_kid_and_ttl() {
+        let issuer = stand_in_issuer().await;
+        let (pem, jwk) = rsa_key("k1");
+        issuer.keys.write().await.push(jwk);
+        let config = config(&issuer);
+        let mut cache = JwksCache::new(Duration::from_secs(DEFAULT_JWKS_TTL_SECS));
+
+        let token = sign(&pem, "k1", claims(&issuer, "gateway"));
+        validate_jwt(&token, &config, &cache).await.unwrap();
+
+        // the issuer rotates its keys, but we've fetched too recently to look again
+        let (new_pem, new_jwk) = rsa_key("k2");
+        *issuer.keys.write().await = vec![new_jwk];
+        let new_token = sign(&new_pem, "k2", claims(&issuer, "gateway"));
+        assert!(validate_jwt(&new_token, &config, &cache).await.is_err());
+        assert_eq!(issuer.fetches.load(Ordering::SeqCst), 1);
+
+        cache.min_refresh = Duration::ZERO;
+        validate_jwt(&new_token, &config, &cache).await.unwrap();
+        assert_eq!(issuer.fetches.load(Ordering::SeqCst), 2);
+
+        // once the keys have expired, the old key is gone with them
+        cache.ttl = Duration::ZERO;
+        assert!(validate_jwt(&token, &config, &cache).await.is_err());
+        assert_eq!(issuer.fetches.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn hs256_needs_a_configured_secret() {
+        let claims = JwtClaims {
+            sub: "bob".into(),
+            exp: (Utc::now().timestamp() + 300) as usize,
+            iat: Utc::now().timestamp() as usize,
+        };
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"not-so-secret"),
+        )
+        .unwrap();
+        let cache = JwksCache::new(Duration::from_secs(DEFAULT_JWKS_TTL_SECS));
+
+        assert!(validate_jwt(&token, &AuthConfig::default(), &cache)
+            .await
+            .is_err());
+        let config = AuthConfig {
+            jwt_secret: Some("not-so-secret".into()),
+            ..AuthConfig::default()
+        };
+        let jwt = validate_jwt(&token, &config, &cache).await.unwrap();
+        assert_eq!(jwt.claims.sub, "bob");
+        assert_eq!(jwt.role, None);
+    }
+}
@@ -1,4 +1,5 @@
 // auth_token.rs
+use crate::modules::users::Role;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
@@ -18,4 +19,9 @@ pub struct JwtClaims {
 pub struct JwtToken {
     pub claims: JwtClaims,
     pub token: String,
+    /// the configured OIDC issuer the token is from; none for HS256 tokens, which name the
+    /// gateway's own users
+    pub issuer: Option<String>,
+    /// the role the token's issuer gives the user, if it maps roles
+    pub role: Option<Role>,
 }
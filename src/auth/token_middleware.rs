@@ -1,96 +1,243 @@
 use crate::auth::error_handler::AuthError;
 use crate::auth::token_extractor::AuthToken;
-use crate::auth::token_management::JwtClaims;
-use crate::modules::users::User;
+use crate::auth::token_management::JwtToken;
+use crate::modules::users::{issuer_login, CreateUser, Role, User};
 use crate::state::AppState;
+use crate::state_mgmt::{create_user, get_token_user, get_user};
 use axum::extract::{Request, State};
 use axum::{middleware::Next, response::Response};
 use cached::Cached;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
 use tower_sessions::Session;
 
-pub(crate) async fn auth_middleware(
-    State(state): State<AppState>,
-    auth_token: Result<AuthToken, AuthError>,
-    request: Request,
-    next: Next,
-) -> Result<Response, AuthError> {
-    // If token extraction failed, return the error
-    let session: &Session = request.extensions().get::<Session>().unwrap();
+/// Where a login through the web UI is kept in the session.
+pub(crate) const LOGIN_SESSION_KEY: &str = "login";
 
-    let token = auth_token?;
-    match token {
-        AuthToken::Jwt(jwt) => {
-            let sub = jwt.claims.sub;
-
-            // scope down to release cache rwlock as soon as possible
-            let user: User = {
-                let cached_user: Option<User> = match state.user_cache {
-                    Some(r) => {
-                        let mut cache = r.write().await;
-                        cache.cache_get(&sub).cloned()
-                    }
-                    None => None,
-                };
+/// A login through the web UI.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct SessionLogin {
+    pub(crate) user_id: i32,
+    /// the role given by the OIDC token the user logged in with, if its issuer maps roles
+    pub(crate) role: Option<Role>,
+}
 
-                let user = match cached_user {
-                    Some(s) => {
-                        info!("user {sub} is in cache.");
-                        s
-                    }
-                    None => {
-                        info!("user {sub} is not in cache, fetching from database.");
-                        //TODO: implement auth properly for your template project
-                        User::default()
-                        //return Err(AuthError::NotImplemented);
-                        // match sqlx::query_as!(User, "SELECT * FROM users where login = $1", sub)
-                        //     .fetch_one(&state.pool)
-                        //     .await
-                        // {
-                        //     Ok(u) => {
-                        //         info!("user {sub} is in database, caching.");
-                        //         if let Err(e) = foo.cache_set(sub.clone(), u.clone()).await {
-                        //             error!("Error caching user {sub}: {e}");
-                        //             return Err(AuthError::InvalidState);
-                        //         }
-                        //         u
-                        //     },
-                        //     Err(e) => {
-                        //         error!("Error looking up user {sub} in database: {e}");
-                        //         return Err(AuthError::InvalidState);
-                        //     }
-                        // }
+/// The user from the cache, or from `lookup` (caching it) if they aren't in it.
+async fn cached_user(
+    state: &AppState,
+    cache_key: String,
+    lookup: impl Future<Output = anyhow::Result<Option<User>>>,
+) -> Result<User, AuthError> {
+    // scope down to release cache rwlock as soon as possible
+    let cached_user: Option<User> = match &state.user_cache {
+        Some(r) => {
+            let mut cache = r.write().await;
+            cache.cache_get(&cache_key).cloned()
+        }
+        None => None,
+    };
+
+    match cached_user {
+        Some(s) => {
+            debug!("user {cache_key} is in cache.");
+            Ok(s)
+        }
+        None => {
+            debug!("user {cache_key} is not in cache, fetching from database.");
+            match lookup.await {
+                Ok(Some(u)) => {
+                    if let Some(r) = &state.user_cache {
+                        r.write().await.cache_set(cache_key, u.clone());
                     }
-                };
-                user
-            };
-            match session.insert("user", user.clone()).await {
-                Ok(_) => {
-                    debug!("Set user in session: {user:#?}");
+                    Ok(u)
+                }
+                Ok(None) => {
+                    warn!("no user for {cache_key}");
+                    Err(AuthError::InvalidToken)
                 }
                 Err(e) => {
-                    error!("Couldn't set user in session: {e}");
-                    return Err(AuthError::InvalidToken);
+                    error!("Error looking up user {cache_key} in database: {e}");
+                    Err(AuthError::InvalidState)
                 }
             }
         }
-        AuthToken::ApiKey(user_id) => {
-            //TODO: implement auth properly for your template project
-            let user = User::default();
-            match session.insert("user", user.clone()).await {
-                Ok(_) => {
-                    debug!("Set user in session: {user:#?}");
-                }
-                Err(e) => {
-                    error!("Couldn't set user in session: {e}");
-                    return Err(AuthError::InvalidToken);
-                }
+    }
+}
+
+/// The login a token's user is stored under.
+fn jwt_login(jwt: &JwtToken) -> String {
+    match &jwt.issuer {
+        Some(issuer) => issuer_login(issuer, &jwt.claims.sub),
+        None => jwt.claims.sub.clone(),
+    }
+}
+
+async fn jwt_user(jwt: &JwtToken) -> anyhow::Result<Option<User>> {
+    let login = jwt_login(jwt);
+    match get_token_user(jwt.issuer.as_deref(), &login).await {
+        // users the issuer gives a role to don't have to be added first
+        Ok(None) if jwt.role.is_some() && jwt.issuer.is_some() => {
+            info!("adding user {login} from their token");
+            let new_user = CreateUser {
+                role: jwt.role,
+                issuer: jwt.issuer.clone(),
+                ..CreateUser::default()
+            };
+            create_user(&login, 0, &new_user).await.map(Some)
+        }
+        rs => rs,
+    }
+}
+
+/// The user a token belongs to.
+pub(crate) async fn token_user(state: &AppState, token: &AuthToken) -> Result<User, AuthError> {
+    // jwts name the user by login, api keys by id; both share the one cache
+    match token {
+        AuthToken::Jwt(jwt) => {
+            let cache_key = match &jwt.issuer {
+                Some(issuer) => format!("jwt:{issuer}:{}", jwt.claims.sub),
+                None => format!("login:{}", jwt.claims.sub),
+            };
+            let mut user = cached_user(state, cache_key, jwt_user(jwt)).await?;
+            // the issuer's say on a user's role is the last word
+            if let Some(role) = jwt.role {
+                user.role = role;
             }
+            Ok(user)
+        }
+        AuthToken::ApiKey(user_id) => {
+            cached_user(state, format!("id:{user_id}"), get_user(*user_id)).await
         }
     }
+}
+
+pub(crate) async fn auth_middleware(
+    State(state): State<AppState>,
+    auth_token: Result<AuthToken, AuthError>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let session: Session = request.extensions().get::<Session>().cloned().unwrap();
+    let login: Option<SessionLogin> = match session.get(LOGIN_SESSION_KEY).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Couldn't get login from session: {e}");
+            return Err(AuthError::InvalidState);
+        }
+    };
 
-    // You can perform additional validation here
-    // For example, check token expiration, permissions, etc.
+    let user = match (auth_token, &login) {
+        (Ok(token), _) => token_user(&state, &token).await?,
+        // the web UI sends its session cookie instead of a token
+        (Err(AuthError::MissingToken), Some(login)) => {
+            let mut user = cached_user(
+                &state,
+                format!("id:{}", login.user_id),
+                get_user(login.user_id),
+            )
+            .await?;
+            if let Some(role) = login.role {
+                user.role = role;
+            }
+            user
+        }
+        // If token extraction failed, return the error
+        (Err(e), _) => return Err(e),
+    };
+
+    match session.insert("user", user.clone()).await {
+        Ok(_) => {
+            debug!("Set user in session: {user:#?}");
+        }
+        Err(e) => {
+            error!("Couldn't set user in session: {e}");
+            return Err(AuthError::InvalidToken);
+        }
+    }
 
     // If everything is ok, continue with the request
-    Ok(next.run(request).await)
+    let response = next.run(request).await;
+
+    // tokens come with every request, so there's no need to keep a session (or send a cookie) for them
+    if login.is_none() {
+        let _ = session.remove::<User>("user").await;
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::token_extractor::JwksCache;
+    use crate::auth::token_management::JwtClaims;
+    use crate::config_structs::AuthConfig;
+    use crate::consts::{DEFAULT_JWKS_TTL_SECS, MPSC_BUFFER_SIZE};
+    use crate::state_mgmt::test_db;
+    use cached::UnboundCache;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, RwLock};
+
+    fn jwt(sub: &str, issuer: Option<&str>, role: Option<Role>) -> AuthToken {
+        AuthToken::Jwt(JwtToken {
+            claims: JwtClaims {
+                sub: sub.to_string(),
+                exp: 0,
+                iat: 0,
+            },
+            token: String::new(),
+            issuer: issuer.map(|i| i.to_string()),
+            role,
+        })
+    }
+
+    #[tokio::test]
+    async fn issuers_have_their_own_users() {
+        test_db().await;
+        let (ipc_tx, _ipc_rx) = mpsc::channel(MPSC_BUFFER_SIZE);
+        let (control_tx, _control_rx) = mpsc::channel(MPSC_BUFFER_SIZE);
+        let state = AppState {
+            jwks_cache: JwksCache::new(Duration::from_secs(DEFAULT_JWKS_TTL_SECS)),
+            auth_config: Arc::new(AuthConfig::default()),
+            user_cache: Some(Arc::new(RwLock::new(UnboundCache::new()))),
+            ipc_tx,
+            control_tx,
+        };
+        let admin = create_user(
+            &"jwt_admin".to_string(),
+            0,
+            &CreateUser {
+                role: Some(Role::Admin),
+                ..CreateUser::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        // the gateway's own tokens name its own users
+        let local = token_user(&state, &jwt("jwt_admin", None, None))
+            .await
+            .unwrap();
+        assert_eq!(local.id, admin.id);
+
+        // another issuer's admin is nobody here until it's given a role
+        let other = Some("https://other.example");
+        assert!(token_user(&state, &jwt("jwt_admin", other, None))
+            .await
+            .is_err());
+        let viewer = token_user(&state, &jwt("jwt_admin", other, Some(Role::Viewer)))
+            .await
+            .unwrap();
+        assert_ne!(viewer.id, admin.id);
+        assert_eq!(viewer.role, Role::Viewer);
+        assert_eq!(viewer.issuer.as_deref(), other);
+        assert_eq!(viewer.login, "https://other.example:jwt_admin");
+
+        // and once added, stays apart from the gateway's own user
+        let again = token_user(&state, &jwt("jwt_admin", other, None))
+            .await
+            .unwrap();
+        assert_eq!(again.id, viewer.id);
+        assert_eq!(again.role, Role::Viewer);
+    }
 }
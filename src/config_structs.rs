@@ -1,6 +1,8 @@
-use serde::Deserialize;
+use crate::modules::users::Role;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use sunspec_rs::sunspec_connection::TlsConfig;
+use utoipa::ToSchema;
 
 #[derive(Deserialize, Clone, Debug, Default)]
 pub struct TracingConfig {
@@ -12,6 +14,30 @@ pub struct UnitConfig {
     pub addr: String,
     pub slaves: Vec<u8>,
     pub tls: Option<TlsConfig>,
+    /// the Home Assistant device these slaves sit behind, by identifier or serial number.
+    /// Defaults to a device representing this `addr`.
+    pub parent: Option<String>,
+    /// the area Home Assistant should suggest for these slaves' devices
+    pub suggested_area: Option<String>,
+    /// the profiles whose points these slaves are polled for, on top of `models`
+    pub profiles: Option<Vec<String>>,
+    /// points of these slaves, by model, merged over the points of the same name in `profiles`.
+    /// Points that aren't in any of the profiles have to be given in full.
+    pub models: Option<HashMap<String, Vec<serde_yaml::Value>>>,
+    /// the points these slaves are polled for, once `profiles` and `models` are worked out
+    #[serde(skip)]
+    pub points: Option<HashMap<String, Vec<PointConfig>>>,
+}
+
+/// A named, reusable set of points, e.g. `meter_wye_full`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ProfileConfig {
+    /// what the profile is for, for whoever reads the profile
+    #[allow(dead_code)]
+    pub description: Option<String>,
+    /// points by model, as in `models`.  A point with `phases` stands for one point per phase,
+    /// with `{phase}` in its `point` and `display_name` replaced by the phase.
+    pub models: HashMap<String, Vec<serde_yaml::Value>>,
 }
 #[derive(Deserialize, Clone, Debug)]
 pub struct Switchable {
@@ -19,23 +45,399 @@ pub struct Switchable {
     pub off: String,
 }
 
+/// Limits for a number input, in engineering units (i.e. after `scale_factor` is applied).
 #[derive(Deserialize, Clone, Debug)]
 pub struct Numerable {
-    pub min: i32,
-    pub max: i32,
-    pub step: Option<i32>,
+    pub min: f64,
+    pub max: f64,
+    pub step: Option<f64>,
     pub mode: Option<String>,
 }
 
+/// One option of a select input.  Either a symbol id (or raw value) that is also shown in Home
+/// Assistant, or a label to show along with the symbol id it stands for.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum SelectOption {
+    Symbol(String),
+    Labeled { label: String, value: String },
+}
+
+impl SelectOption {
+    pub fn label(&self) -> &str {
+        match self {
+            SelectOption::Symbol(s) => s,
+            SelectOption::Labeled { label, .. } => label,
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match self {
+            SelectOption::Symbol(s) => s,
+            SelectOption::Labeled { value, .. } => value,
+        }
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum InputType {
-    Select(Vec<String>),
+    Select(Vec<SelectOption>),
     Switch(Switchable),
     Button(String),
     Number(Numerable),
 }
 
+/// How successive power samples are combined when integrating a point into energy.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IntegrationMethod {
+    /// average of the previous and current sample over the elapsed time
+    #[default]
+    Trapezoidal,
+    /// previous sample held constant over the elapsed time
+    Left,
+}
+
+/// What to do when a `total_increasing` counter goes backwards.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CounterRollover {
+    /// add the lost amount to a persisted offset so the published value keeps increasing
+    #[default]
+    Offset,
+    /// publish the raw value, but log and annotate the state as a reset
+    Flag,
+    /// publish the raw value without any checks
+    Ignore,
+}
+
+/// A band around the last published value, inside which new readings aren't published.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Deadband {
+    /// in the point's engineering units
+    Absolute(f64),
+    /// as a percentage of the last published value
+    Percent(f64),
+}
+
+/// How serious it is when a bitfield or enum symbol becomes active.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// Where events are sent, besides MQTT and the events table.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct EventsConfig {
+    /// a url that each event is POSTed to as json
+    pub webhook_url: Option<String>,
+    /// the least severe events that are sent to the webhook (default `warning`)
+    pub webhook_min_severity: Option<Severity>,
+}
+
+/// What the dispatcher does when a sink's queue is full.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backpressure {
+    /// drop the message, so that nothing else waits on the sink
+    Drop,
+    /// wait for room in the queue (for a while) before dropping the message.  The waiting is done
+    /// on the sink's own task, so it only holds up that sink.
+    Block,
+}
+
+/// The queue in front of a sink.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SinkQueueConfig {
+    pub capacity: Option<usize>,
+    pub backpressure: Option<Backpressure>,
+}
+
+/// An HTTP endpoint that batches of readings are POSTed to, as json.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct HttpSinkConfig {
+    pub url: String,
+    /// extra headers sent with every request, e.g. `Authorization`
+    pub headers: Option<HashMap<String, String>>,
+    /// the most readings sent in one request
+    pub batch_size: Option<usize>,
+    /// how often, in seconds, a partial batch is sent
+    pub flush_interval: Option<u64>,
+    /// how many times a failed request is retried before the batch is spooled
+    pub max_retries: Option<u32>,
+    /// seconds to wait before the first retry, doubling on each retry after it
+    pub retry_backoff: Option<u64>,
+    /// the most batches kept in the spool while the endpoint is unreachable; the oldest are
+    /// dropped beyond this
+    pub spool_limit: Option<i64>,
+}
+
+/// Publishing to the same broker as a Sparkplug B edge node, with each unit as a device.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SparkplugConfig {
+    pub group_id: String,
+    /// the edge node id; the mqtt client id if not given
+    pub edge_node_id: Option<String>,
+}
+
+/// Serving the units' SunSpec maps over Modbus TCP, for clients that can't share the units' own
+/// connections with the gateway.
+///
+/// What's served is what the poll loops last read, so only polled points have values; everything
+/// else reads as SunSpec's "not implemented".  Writes are acknowledged once they're handed to the
+/// unit's poll loop, before the unit has accepted them.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ModbusServerConfig {
+    /// the address to listen on, e.g. `0.0.0.0:502`
+    pub listen: Option<String>,
+    /// the unit id each unit is served under, by serial number.  Units that aren't listed get the
+    /// lowest free id when they first come online.
+    pub unit_ids: Option<HashMap<String, u8>>,
+    /// how often, in seconds, the served registers are updated with what was polled
+    pub refresh_interval: Option<u64>,
+}
+
+/// How users of the API prove who they are, besides API keys.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AuthConfig {
+    /// the secret of HS256 JWTs signed by something we trust; such JWTs are refused without it
+    pub jwt_secret: Option<String>,
+    /// the OpenID Connect providers whose JWTs are trusted
+    pub issuers: Option<Vec<IssuerConfig>>,
+    /// how long, in seconds, an issuer's keys are used before they're fetched again
+    pub jwks_ttl: Option<u64>,
+}
+
+/// Which cross-site requests the session cookie is sent with.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSiteConfig {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+/// The cookie that web UI logins are kept in.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SessionConfig {
+    /// only send the cookie over https; on by default when the gateway serves https itself
+    pub secure: Option<bool>,
+    /// which cross-site requests the cookie is sent with (default `lax`)
+    pub same_site: Option<SameSiteConfig>,
+    /// how many hours a login lasts without being used
+    pub expiry_hours: Option<i64>,
+}
+
+/// What a schedule does about runs it missed while the gateway was down.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, ToSchema, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum MissedRuns {
+    /// carry on from the next run
+    #[default]
+    Skip,
+    /// make the latest of the missed runs, so the point ends up as it would have been
+    CatchUp,
+}
+
+/// A write to make whenever a cron expression matches, e.g. `0 16 * * MON-FRI` for 16:00 on
+/// weekdays.  Schedules run in the gateway's local time.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, ToSchema)]
+pub struct ScheduleConfig {
+    /// what the schedule is known as in the API and the audit log
+    pub name: String,
+    /// `minute hour day-of-month month day-of-week`, with an optional leading seconds field
+    pub cron: String,
+    pub serial_number: String,
+    pub model: String,
+    /// the point, by canonical path
+    pub point: String,
+    /// the value to write, as it would be sent to the point's command topic
+    pub value: String,
+    pub missed: Option<MissedRuns>,
+    /// go through every check, but don't write to the unit
+    pub dry_run: Option<bool>,
+    pub enabled: Option<bool>,
+}
+
+/// A point of one of the units, e.g. a meter's `W`.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct UnitPoint {
+    pub serial_number: String,
+    pub model: String,
+    /// the point, by canonical path
+    pub point: String,
+}
+
+/// A write to make before the first limit is sent to a unit, and again when it comes back online.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EnableWrite {
+    /// the point, by canonical path, in the model of the limit point, e.g. `WMaxLim_Ena`
+    pub point: String,
+    /// the value to write, as it would be sent to the point's command topic, e.g. `ENABLED`
+    pub value: String,
+}
+
+/// Keeps export to the grid under a cap, by driving an inverter's power limit (e.g. `WMaxLimPct`
+/// of model 123 or 704) from what a meter (models 201-204) reads, with a PI controller.  The meter
+/// and the inverter have to be polled for their points, and the limit point needs a number input.
+/// That's checked against every unit at start, and against the meter's and inverter's own units
+/// once they're polled and it's known which they are; export isn't limited if it doesn't hold.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ExportLimitConfig {
+    /// the meter's total real power, polled as usual
+    pub meter: UnitPoint,
+    /// whether the meter reads export as negative power, as SunSpec meters do (default true)
+    pub export_negative: Option<bool>,
+    /// the inverter's limit, in percent of `rated_power`
+    pub limit: UnitPoint,
+    /// written before the first limit, for inverters that only apply a limit when it's enabled
+    pub enable: Option<EnableWrite>,
+    /// the most that may be exported, in W
+    pub max_export: f64,
+    /// the W that a limit of 100% stands for
+    pub rated_power: f64,
+    /// proportional gain, in percent of limit per percent of `rated_power` over the cap
+    /// (default 0.5)
+    pub kp: Option<f64>,
+    /// integral gain, per second (default 0.05).  Meters read less often need less of it: the
+    /// limit swings back and forth once twice `kp`, plus `ki` times the meter's interval, gets
+    /// near 2
+    pub ki: Option<f64>,
+    /// the fastest the limit may change, in percent per second (default 10).  The limit is ramped
+    /// toward where the latest reading puts it every second, however far apart readings are.
+    pub ramp: Option<f64>,
+    /// the lowest limit that's written (default 0)
+    pub min_limit: Option<f64>,
+    /// the highest limit that's written (default 100)
+    pub max_limit: Option<f64>,
+    /// the limit to fall back to when the meter can't be read (default 0)
+    pub fallback_limit: Option<f64>,
+    /// how old, in seconds, the latest meter reading can get before falling back.  Has to be more
+    /// than the meter point's interval (default three intervals, and at least 30)
+    pub stale_after: Option<u64>,
+    /// how far, in percent, export can be from the cap before the limit is changed, and the least
+    /// change of limit that's written (default 1)
+    pub deadband: Option<f64>,
+    /// how often, in seconds, an unchanged limit is written again, for inverters that revert
+    /// limits after a while (default 60)
+    pub refresh_interval: Option<u64>,
+    /// go through every check, but don't write to the inverter
+    pub dry_run: Option<bool>,
+}
+
+/// Where the web UI and API are served.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct HttpServerConfig {
+    /// the address to listen on (default `[::]:8080`, or `[::]:8443` with tls)
+    pub listen: Option<String>,
+    /// serve https instead of plain http
+    pub tls: Option<HttpsConfig>,
+    /// an address to listen on for plain http, answering everything with a redirect to https,
+    /// e.g. `[::]:80`.  Only used with tls.
+    pub redirect_listen: Option<String>,
+}
+
+/// The certificate https is served with.  The files are read again when they change, so a renewed
+/// certificate is picked up without a restart.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct HttpsConfig {
+    /// PEM certificate chain, the server's certificate first
+    pub cert_path: String,
+    /// PEM private key
+    pub key_path: String,
+    /// how often, in seconds, the files are checked for changes
+    pub reload_interval: Option<u64>,
+}
+
+/// An OpenID Connect provider, e.g. a Keycloak realm.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct IssuerConfig {
+    /// the `iss` of the provider's tokens, e.g. `https://keycloak.example.com/realms/site`
+    pub issuer: String,
+    /// where the provider's keys are published; looked up in the discovery document if not given
+    pub jwks_uri: Option<String>,
+    /// the provider's discovery document, if it isn't at
+    /// `{issuer}/.well-known/openid-configuration`
+    pub discovery_url: Option<String>,
+    /// the `aud` that tokens have to be for; any audience is accepted if not given
+    pub audience: Option<String>,
+    /// the claim holding the user's login (default `sub`)
+    pub login_claim: Option<String>,
+    /// the claim holding the user's roles, as a dotted path, e.g. `realm_access.roles`
+    pub role_claim: Option<String>,
+    /// the gateway role given for each value of the role claim.  Users with a mapped role don't
+    /// have to be added to the gateway first, and always have the role their tokens give them.
+    pub role_map: Option<HashMap<String, Role>>,
+}
+
+/// Which Home Assistant entities a bitfield point is published as.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SymbolEntities {
+    /// one sensor, with the active symbols as its state
+    Aggregated,
+    /// one binary sensor per bit
+    PerBit,
+    #[default]
+    Both,
+}
+
+/// A summary calculated across every monitored repetition of a repeating-group point.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+/// Limits on writes to a point, on top of what its input accepts.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct WritePolicy {
+    /// the least time, in seconds, between writes to the point
+    pub min_interval: Option<u64>,
+    /// the most writes to the point in any hour
+    pub max_per_hour: Option<u32>,
+    /// the only values that may be written, as they're sent or as the register value they come to
+    pub allowed_values: Option<Vec<String>>,
+    /// the lowest value that may be written
+    pub min: Option<f64>,
+    /// the highest value that may be written
+    pub max: Option<f64>,
+    /// other points of the unit that have to read a certain way for the point to be written
+    pub requires: Option<Vec<WriteDependency>>,
+}
+
+/// A point of the same unit that has to have one of `values` for a write to go ahead, e.g.
+/// `WMaxLim_Ena` being `ENABLED` before `WMaxLimPct` is written.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct WriteDependency {
+    /// the model of the point, if it isn't the one being written
+    pub model: Option<String>,
+    /// the point, by canonical path
+    pub point: String,
+    /// the values, as they're read (a symbol, or a raw value), any of which allows the write
+    pub values: Vec<String>,
+}
+
 #[derive(Deserialize, Clone, Debug, Default)]
 pub struct PointConfig {
     pub point: Option<String>,
@@ -54,6 +456,21 @@ pub struct PointConfig {
     pub value_min: Option<f64>,
     pub value_max: Option<f64>,
     pub check_deviations: Option<u16>,
+    pub integrate: Option<bool>,
+    pub integration_method: Option<IntegrationMethod>,
+    pub integration_max_gap: Option<u64>,
+    pub counter_rollover: Option<CounterRollover>,
+    pub repetitions: Option<Vec<u16>>,
+    pub aggregate: Option<Vec<Aggregation>>,
+    pub retain: Option<bool>,
+    pub change_only: Option<bool>,
+    pub deadband: Option<Deadband>,
+    pub heartbeat: Option<u64>,
+    pub qos: Option<u8>,
+    pub symbol_entities: Option<SymbolEntities>,
+    /// the severity of events raised by each symbol of a bitfield or enum (`info` if not listed)
+    pub severity: Option<HashMap<String, Severity>>,
+    pub write_policy: Option<WritePolicy>,
 }
 impl PointConfig {
     pub fn name(&self) -> String {
@@ -72,10 +489,44 @@ pub struct GatewayConfig {
     pub hass_enabled: Option<bool>,
     pub units: Vec<UnitConfig>,
     pub models: HashMap<String, Vec<PointConfig>>,
+    /// profiles that units can use, besides the built-in ones, by name
+    pub profiles: Option<HashMap<String, ProfileConfig>>,
+    /// YAML files of more profiles, each a map of profiles by name
+    pub profile_files: Option<Vec<String>>,
     pub mqtt_server_addr: String,
     pub mqtt_server_port: Option<u16>,
     pub mqtt_client_id: Option<String>,
     pub mqtt_username: Option<String>,
     pub mqtt_password: Option<String>,
     pub tracing: Option<TracingConfig>,
+    /// the base url Home Assistant users reach this gateway on, e.g. `http://gateway:8080`
+    pub gateway_url: Option<String>,
+    pub events: Option<EventsConfig>,
+    pub http_sink: Option<HttpSinkConfig>,
+    pub sparkplug: Option<SparkplugConfig>,
+    pub modbus_server: Option<ModbusServerConfig>,
+    pub auth: Option<AuthConfig>,
+    pub session: Option<SessionConfig>,
+    pub http_server: Option<HttpServerConfig>,
+    pub schedules: Option<Vec<ScheduleConfig>>,
+    pub export_limit: Option<ExportLimitConfig>,
+    /// queue settings for each sink (`mqtt`, `history`, `http`, `sparkplug`, `export_limit`), by
+    /// name
+    pub sinks: Option<HashMap<String, SinkQueueConfig>>,
+}
+
+impl GatewayConfig {
+    /// The points a slave is polled for, by model.
+    pub fn unit_models(&self, addr: &str, slave: u8) -> &HashMap<String, Vec<PointConfig>> {
+        self.units
+            .iter()
+            .find(|u| u.addr == addr && u.slaves.contains(&slave))
+            .and_then(|u| u.points.as_ref())
+            .unwrap_or(&self.models)
+    }
+
+    /// The points of every unit, by model, starting with the ones every unit is polled for.
+    pub fn all_models(&self) -> impl Iterator<Item = &HashMap<String, Vec<PointConfig>>> {
+        std::iter::once(&self.models).chain(self.units.iter().filter_map(|u| u.points.as_ref()))
+    }
 }
@@ -12,24 +12,108 @@ pub const CHECK_DEVIATIONS_COUNT: u16 = 10_u16;
 // we won't let points get checked faster than every 10 seconds.
 // if we change this, the modbus could get saturated very quickly
 pub const LOWER_LIMIT_INTERVAL: u64 = 10_u64;
+// if two power samples are further apart than this, we don't trust the area between them and
+// restart integration from the newer sample instead.
+pub const DEFAULT_INTEGRATION_MAX_GAP_SECS: u64 = 300_u64;
+pub const INTEGRATED_ENERGY_SUFFIX: &str = "energy";
+pub const REPEATING_GROUP_WILDCARD: &str = "[*]";
 pub const COMMON_MODEL_ID: u16 = 1_u16;
 pub const DEFAULT_DISPLAY_PRECISION: Option<u8> = Some(4_u8);
 
 pub const MQTT_KEEPALIVE_TIME: u64 = 5_u64;
 
 pub const MQTT_THREAD_CHANNEL_CAPACITY: usize = 10_usize;
+/// the identifier of the Home Assistant device representing the gateway itself
+pub const GATEWAY_DEVICE_IDENTIFIER: &str = "sunspec_gateway";
+pub const MQTT_INBOUND_CONTROL_PREFIX: &str = "sunspec_gateway/input";
 pub const MQTT_INBOUND_CONTROL_TOPIC: &str = "sunspec_gateway/input/#";
+/// whether the gateway is refusing writes, `ON` or `OFF`, retained
+pub const MQTT_READ_ONLY_TOPIC: &str = "sunspec_gateway/read_only";
+/// send `ON` or `OFF` here to turn read-only mode on or off
+pub const MQTT_READ_ONLY_SET_TOPIC: &str = "sunspec_gateway/read_only/set";
 
 // poll intervals
+/// how long Home Assistant waits for a state update before marking an entity unavailable
+pub const DEFAULT_EXPIRES_AFTER_SECS: u64 = 300;
 pub const MQTT_POLL_INTERVAL_MILLIS: u64 = 100_u64;
 pub const GENERIC_WAIT_MILLIS: u64 = 250_u64;
 pub const MQTT_PROCESSING_PAD_MILLIS: u64 = 2000_u64;
 pub const API_VER: &str = "/api/v1";
 pub const SCALAR_PATH: &str = "/api/scalar";
 pub const API_PATH: &str = "/api/openapi.json";
+pub const DEFAULT_HTTP_LISTEN: &str = "[::]:8080";
+pub const DEFAULT_HTTPS_LISTEN: &str = "[::]:8443";
+/// how often the https certificate and key are checked for changes
+pub const DEFAULT_TLS_RELOAD_SECS: u64 = 60;
 pub const SESSION_INACTIVITY_LIMIT_HOURS: i64 = 24;
-pub const JWT_SECRET: &str = "secret";
+/// how often expired sessions are deleted from the database
+pub const SESSION_CLEANUP_SECS: u64 = 3600;
+/// PBKDF2-HMAC-SHA256 rounds for new password hashes
+pub const PASSWORD_HASH_ROUNDS: u32 = 600_000;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// where the first admin's API key is written, unless `ADMIN_KEY_FILE_PATH` says otherwise
+pub const DEFAULT_ADMIN_KEY_FILE: &str = "./admin_api_key";
+/// how long an OIDC issuer's keys are used before they're fetched again
+pub const DEFAULT_JWKS_TTL_SECS: u64 = 3600;
+/// the least time between fetches of an issuer's keys prompted by tokens signed with unknown keys
+pub const JWKS_MIN_REFRESH_SECS: u64 = 60;
 pub const HEALTH_PATH: &str = "/api/health";
+pub const SINK_HEALTH_PATH: &str = "/api/health/sinks";
+/// how long to wait for a webhook to accept an event
+pub const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+/// how long a message for a sink with `block` backpressure waits for room before it's dropped
+pub const SINK_BLOCK_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_HTTP_SINK_BATCH_SIZE: usize = 100;
+pub const DEFAULT_HTTP_SINK_FLUSH_SECS: u64 = 10;
+pub const DEFAULT_HTTP_SINK_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_HTTP_SINK_BACKOFF_SECS: u64 = 1;
+pub const DEFAULT_HTTP_SINK_SPOOL_LIMIT: i64 = 10_000;
+/// how many spooled batches are resent on each flush
+pub const HTTP_SINK_SPOOL_DRAIN_BATCHES: i64 = 10;
+pub const SPARKPLUG_NAMESPACE: &str = "spBv1.0";
+/// how often the sparkplug sink sends births, and the metrics that changed since the last tick
+pub const SPARKPLUG_TICK_MILLIS: u64 = 1000;
+pub const DEFAULT_MODBUS_SERVER_LISTEN: &str = "0.0.0.0:502";
+pub const DEFAULT_MODBUS_SERVER_REFRESH_SECS: u64 = 10;
+/// where the SunSpec map starts, as a zero-based modbus address (register 40001)
+pub const SUNSPEC_BASE_ADDRESS: u16 = 40000;
+/// the most registers a single modbus read may ask for
+pub const MODBUS_MAX_READ_REGISTERS: u16 = 125;
+/// how many events the events API returns when no limit is given
+pub const DEFAULT_EVENTS_LIMIT: i64 = 100;
 
 pub const POINTS_TAG: &str = "points";
 pub const POINTS_TAG_DESCRIPTION: &str = "Points";
+pub const DISCOVERY_TAG: &str = "discovery";
+pub const DISCOVERY_TAG_DESCRIPTION: &str = "Home Assistant discovery";
+pub const EVENTS_TAG: &str = "events";
+pub const EVENTS_TAG_DESCRIPTION: &str = "Raised and cleared bitfield and enum symbols";
+pub const AUTH_TAG: &str = "auth";
+pub const AUTH_TAG_DESCRIPTION: &str = "Logging in to and out of the web UI";
+pub const AUDIT_TAG: &str = "audit";
+pub const AUDIT_TAG_DESCRIPTION: &str = "Writes to units, and attempts at them";
+/// how many writes the audit API returns when no limit is given
+pub const DEFAULT_AUDIT_LIMIT: i64 = 100;
+pub const CONTROL_TAG: &str = "control";
+pub const CONTROL_TAG_DESCRIPTION: &str = "Switches that apply to the whole gateway";
+pub const SCHEDULES_TAG: &str = "schedules";
+pub const SCHEDULES_TAG_DESCRIPTION: &str = "Writes made at set times";
+/// how often the scheduler looks for schedules that are due
+pub const SCHEDULER_TICK_SECS: u64 = 15;
+/// how late a run can be and still be made by a schedule that skips missed runs
+pub const SCHEDULE_GRACE_SECS: i64 = 120;
+/// the most occurrences of a schedule looked through for the latest missed one
+pub const SCHEDULE_MAX_MISSED: usize = 100_000;
+/// what the export limiter's writes are recorded as coming from
+pub const EXPORT_LIMIT_SOURCE: &str = "export_limit";
+/// how often the export limiter ramps its limit, and checks for a stale meter and limits due to
+/// be written again
+pub const EXPORT_LIMIT_TICK_SECS: u64 = 1;
+pub const DEFAULT_EXPORT_LIMIT_KP: f64 = 0.5;
+pub const DEFAULT_EXPORT_LIMIT_KI: f64 = 0.05;
+pub const DEFAULT_EXPORT_LIMIT_RAMP: f64 = 10.0;
+pub const DEFAULT_EXPORT_LIMIT_STALE_SECS: u64 = 30;
+/// meter readings that can be missed before the export limiter falls back, by default
+pub const EXPORT_LIMIT_STALE_READINGS: u64 = 3;
+pub const DEFAULT_EXPORT_LIMIT_DEADBAND: f64 = 1.0;
+pub const DEFAULT_EXPORT_LIMIT_REFRESH_SECS: u64 = 60;
@@ -0,0 +1,192 @@
+use crate::config_structs::CounterRollover;
+use crate::monitored_point::MonitoredPoint;
+use crate::payload::{PayloadValueType, StatePayload};
+use crate::state_mgmt::{get_counter_offset, write_counter_offset, CounterOffset};
+use crate::sunspec_unit::SunSpecUnit;
+use sunspec_rs::sunspec_connection::apply_scale_factor;
+use sunspec_rs::sunspec_models::Point;
+
+#[derive(Debug, PartialEq)]
+pub enum CounterChange {
+    /// the counter moved forward (or stayed put)
+    Increase,
+    /// the counter passed its maximum value and started over from zero.  Contains the modulus of
+    /// the counter in engineering units.
+    Wrap(f64),
+    /// the counter went backwards for some other reason (firmware update, device reset, etc.)
+    Reset,
+}
+
+/// Return the width of a counter point, based on the type in its model definition.
+pub fn counter_bits(point_type: &str) -> Option<u32> {
+    match point_type {
+        "acc16" | "uint16" => Some(16),
+        "acc32" | "uint32" => Some(32),
+        "acc64" | "uint64" => Some(64),
+        _ => None,
+    }
+}
+
+/// Decide whether a counter moving from `previous` to `current` was a wrap or a reset.
+///
+/// A drop of more than half of the counter's range is taken to be a wrap, anything smaller is a
+/// reset.  When we don't know the width of the counter every drop is a reset.  `scale_factor` is
+/// everything the raw count was scaled by: the model's scale factor (e.g. `WH_SF`) and the config's.
+pub fn classify_counter_change(
+    previous: f64,
+    current: f64,
+    bits: Option<u32>,
+    scale_factor: Option<i32>,
+) -> CounterChange {
+    if current >= previous {
+        return CounterChange::Increase;
+    }
+    if let Some(bits) = bits {
+        let modulus = apply_scale_factor(2_f64.powi(bits as i32), scale_factor.unwrap_or(0));
+        if previous - current > modulus / 2.0 {
+            return CounterChange::Wrap(modulus);
+        }
+    }
+    CounterChange::Reset
+}
+
+/// Check a `total_increasing` value against the last raw value we saw for it, and apply (or flag)
+/// any wrap or reset according to the point's `counter_rollover` setting.
+pub async fn adjust_counter(
+    unit: &SunSpecUnit,
+    log_prefix: &String,
+    uniqueid: &String,
+    monitored_point: &MonitoredPoint,
+    point_data: Option<&Point>,
+    state_payload: &mut StatePayload,
+) {
+    if monitored_point.counter_rollover == CounterRollover::Ignore {
+        return;
+    }
+    let raw = match state_payload.value {
+        PayloadValueType::Float(f) => f,
+        PayloadValueType::Int(i) => i as f64,
+        _ => return,
+    };
+    let bits = point_data.and_then(|p| counter_bits(p.r#type.as_str()));
+
+    let mut counter = match get_counter_offset(uniqueid).await {
+        Ok(Some(co)) => co,
+        Ok(None) => CounterOffset {
+            uniqueid: uniqueid.clone(),
+            ..CounterOffset::default()
+        },
+        Err(e) => {
+            warn!("{log_prefix}: Couldn't load counter offset, publishing raw value: {e}");
+            return;
+        }
+    };
+
+    if let Some(last_raw) = counter.last_raw {
+        // the model's scale factor is only needed to size a wrap, so it's only read for a drop
+        let scale_factor = if raw < last_raw {
+            let model_sf = match monitored_point.model.parse() {
+                Ok(model) => unit
+                    .scale_factor(model, &monitored_point.path)
+                    .await
+                    .unwrap_or(0),
+                Err(_) => 0,
+            };
+            Some(model_sf + monitored_point.scale_factor.unwrap_or(0))
+        } else {
+            monitored_point.scale_factor
+        };
+        match classify_counter_change(last_raw, raw, bits, scale_factor) {
+            CounterChange::Increase => {}
+            change => {
+                counter.resets += 1;
+                counter.last_reset = Some(state_payload.last_seen.timestamp());
+                let kind = match change {
+                    CounterChange::Wrap(_) => "wrapped",
+                    _ => "reset",
+                };
+                match monitored_point.counter_rollover {
+                    CounterRollover::Offset => {
+                        counter.offset += match change {
+                            CounterChange::Wrap(modulus) => modulus,
+                            _ => last_raw,
+                        };
+                        warn!("{log_prefix}: counter {kind} ({last_raw} -> {raw}), continuing from offset {}", counter.offset);
+                    }
+                    _ => {
+                        warn!("{log_prefix}: counter {kind} ({last_raw} -> {raw})");
+                        state_payload.notes = Some(format!("counter {kind} from {last_raw}"));
+                    }
+                }
+            }
+        }
+    }
+    counter.last_raw = Some(raw);
+    if let Err(e) = write_counter_offset(&counter).await {
+        warn!("{log_prefix}: Couldn't persist counter offset: {e}");
+    }
+
+    if monitored_point.counter_rollover == CounterRollover::Offset && counter.offset != 0.0 {
+        state_payload.value = match state_payload.value {
+            PayloadValueType::Int(i) if counter.offset.fract() == 0.0 => {
+                PayloadValueType::Int(i + counter.offset as i64)
+            }
+            _ => PayloadValueType::Float(raw + counter.offset),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_widths() {
+        assert_eq!(counter_bits("acc16"), Some(16));
+        assert_eq!(counter_bits("uint32"), Some(32));
+        assert_eq!(counter_bits("acc64"), Some(64));
+        assert_eq!(counter_bits("int32"), None);
+        assert_eq!(counter_bits("float32"), None);
+    }
+
+    #[test]
+    fn wraps_and_resets() {
+        assert_eq!(
+            classify_counter_change(10.0, 10.0, Some(16), None),
+            CounterChange::Increase
+        );
+        assert_eq!(
+            classify_counter_change(65_000.0, 100.0, Some(16), None),
+            CounterChange::Wrap(65_536.0)
+        );
+        // a small drop is a reset, whatever the width
+        assert_eq!(
+            classify_counter_change(30_000.0, 29_000.0, Some(16), None),
+            CounterChange::Reset
+        );
+        assert_eq!(
+            classify_counter_change(65_000.0, 100.0, None, None),
+            CounterChange::Reset
+        );
+    }
+
+    #[test]
+    fn wraps_are_scaled() {
+        // a WH_SF of 3 makes an acc32 count kWh, so it wraps at 2^32 kWh worth of Wh
+        let modulus = 2_f64.powi(32) * 1000.0;
+        assert_eq!(
+            classify_counter_change(modulus - 5000.0, 2000.0, Some(32), Some(3)),
+            CounterChange::Wrap(modulus)
+        );
+        // a drop in a counter of Wh too small to be a wrap of the kWh count is a reset
+        assert_eq!(
+            classify_counter_change(1_000_000_000_000.0, 2000.0, Some(32), Some(3)),
+            CounterChange::Reset
+        );
+        // and one of -1 makes the range smaller
+        assert_eq!(
+            classify_counter_change(6_500.0, 10.0, Some(16), Some(-1)),
+            CounterChange::Wrap(6_553.6)
+        );
+    }
+}
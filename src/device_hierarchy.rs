@@ -0,0 +1,160 @@
+use crate::config_structs::{GatewayConfig, UnitConfig};
+use crate::consts::*;
+use crate::ipc::PublishMessage;
+use crate::payload::{
+    DeviceInfo, EntityCategory, HAConfigPayload, Payload, PayloadValueType, StatePayload,
+};
+use rumqttc::QoS;
+
+/// The identifier of the Home Assistant device for a modbus address that units sit behind.
+pub fn bridge_identifier(addr: &str) -> String {
+    let addr: String = addr
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{GATEWAY_DEVICE_IDENTIFIER}_{addr}")
+}
+
+fn configuration_url(config: &GatewayConfig) -> Option<String> {
+    config
+        .gateway_url
+        .as_ref()
+        .map(|url| format!("{}/ui", url.trim_end_matches('/')))
+}
+
+/// The device representing this gateway, at the top of the hierarchy.
+pub fn gateway_device_info(config: &GatewayConfig) -> DeviceInfo {
+    DeviceInfo {
+        identifiers: vec![GATEWAY_DEVICE_IDENTIFIER.to_string()],
+        manufacturer: "sunspec_gateway".to_string(),
+        name: "SunSpec Gateway".to_string(),
+        model: "sunspec_gateway".to_string(),
+        sw_version: env!("CARGO_PKG_VERSION").to_string(),
+        configuration_url: configuration_url(config),
+        ..DeviceInfo::default()
+    }
+}
+
+/// The device representing a modbus address, which the units on it sit behind.
+pub fn bridge_device_info(config: &GatewayConfig, unit: &UnitConfig) -> DeviceInfo {
+    DeviceInfo {
+        identifiers: vec![bridge_identifier(&unit.addr)],
+        manufacturer: "sunspec_gateway".to_string(),
+        name: format!("SunSpec bridge {}", unit.addr),
+        model: "Modbus TCP".to_string(),
+        via_device: Some(GATEWAY_DEVICE_IDENTIFIER.to_string()),
+        configuration_url: configuration_url(config),
+        suggested_area: unit.suggested_area.clone(),
+        ..DeviceInfo::default()
+    }
+}
+
+/// Fill in where a unit sits in the device hierarchy, according to the config for its address.
+pub fn place_unit(device_info: &mut DeviceInfo, config: &GatewayConfig, addr: &String) {
+    let unit = config.units.iter().find(|u| u.addr == *addr);
+    device_info.via_device = Some(match unit.and_then(|u| u.parent.clone()) {
+        Some(parent) => parent,
+        None => bridge_identifier(addr),
+    });
+    device_info.suggested_area = unit.and_then(|u| u.suggested_area.clone());
+    device_info.configuration_url = configuration_url(config);
+}
+
+fn diagnostic_sensor(
+    device: DeviceInfo,
+    object_id: String,
+    name: &str,
+    value: PayloadValueType,
+) -> Vec<PublishMessage> {
+    let state_topic = format!("sunspec_gateway/{object_id}");
+    let config = HAConfigPayload {
+        name: name.to_string(),
+        device,
+        unique_id: object_id.clone(),
+        entity_id: format!("sensor.{object_id}"),
+        state_topic: state_topic.clone(),
+        entity_category: Some(EntityCategory::Diagnostic),
+        value_template: Some("{{ value_json.value }}".to_string()),
+        ..HAConfigPayload::default()
+    };
+    vec![
+        PublishMessage {
+            topic: format!("homeassistant/sensor/{object_id}/config"),
+            payload: Payload::Config(config),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        },
+        PublishMessage {
+            topic: state_topic,
+            payload: Payload::CurrentState(StatePayload {
+                value,
+                ..StatePayload::default()
+            }),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        },
+    ]
+}
+
+/// Discovery and state for the gateway and bridge devices.  Home Assistant only links a unit to
+/// its `via_device` once that device exists, and a device only exists once it has an entity.
+pub fn hierarchy_messages(config: &GatewayConfig) -> Vec<PublishMessage> {
+    let mut messages = diagnostic_sensor(
+        gateway_device_info(config),
+        format!("{GATEWAY_DEVICE_IDENTIFIER}_version"),
+        "Version",
+        PayloadValueType::String(env!("CARGO_PKG_VERSION").to_string()),
+    );
+    for unit in config.units.iter().filter(|u| u.parent.is_none()) {
+        messages.extend(diagnostic_sensor(
+            bridge_device_info(config, unit),
+            format!("{}_units", bridge_identifier(&unit.addr)),
+            "Units",
+            PayloadValueType::Int(unit.slaves.len() as i64),
+        ));
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_sit_behind_their_bridge_or_parent() {
+        let config = GatewayConfig {
+            gateway_url: Some("http://gateway:8080/".to_string()),
+            units: vec![
+                UnitConfig {
+                    addr: "10.0.0.5:502".to_string(),
+                    suggested_area: Some("Garage".to_string()),
+                    ..UnitConfig::default()
+                },
+                UnitConfig {
+                    addr: "10.0.0.6:502".to_string(),
+                    parent: Some("0001ABCD".to_string()),
+                    ..UnitConfig::default()
+                },
+            ],
+            ..GatewayConfig::default()
+        };
+        let mut bridged = DeviceInfo::default();
+        place_unit(&mut bridged, &config, &"10.0.0.5:502".to_string());
+        assert_eq!(
+            bridged.via_device.as_deref(),
+            Some("sunspec_gateway_10_0_0_5_502")
+        );
+        assert_eq!(bridged.suggested_area.as_deref(), Some("Garage"));
+        assert_eq!(
+            bridged.configuration_url.as_deref(),
+            Some("http://gateway:8080/ui")
+        );
+
+        let mut parented = DeviceInfo::default();
+        place_unit(&mut parented, &config, &"10.0.0.6:502".to_string());
+        assert_eq!(parented.via_device.as_deref(), Some("0001ABCD"));
+
+        // only the bridge without a configured parent needs a device of its own
+        assert_eq!(hierarchy_messages(&config).len(), 4);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::ipc::PublishMessage;
+use crate::monitored_point::MonitoredPoint;
+use crate::payload::Payload;
+use crate::state_mgmt::{delete_discovery_topic, get_discovery_topics, record_discovery_topic};
+use lazy_static::lazy_static;
+use rumqttc::QoS;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+
+lazy_static! {
+    /// How many times each serial number's discovery configs have been purged, so that its
+    /// tracker knows to forget what it recorded.
+    static ref PURGES: RwLock<HashMap<String, u64>> = RwLock::new(HashMap::new());
+}
+
+/// The owner recorded for discovery configs produced by a point (including its bitfield and
+/// energy entities).
+pub fn point_owner(point: &MonitoredPoint) -> String {
+    format!("{}/{}", point.model, point.path)
+}
+
+/// The owner recorded for the aggregate sensors of a repeating group.
+pub fn aggregate_owner(point: &MonitoredPoint) -> Option<String> {
+    point
+        .group_pattern
+        .as_ref()
+        .map(|pattern| format!("{}/{pattern}", point.model))
+}
+
+/// The message that removes a discovery config (and its entity) from Home Assistant.
+pub fn clear_message(topic: String) -> PublishMessage {
+    PublishMessage {
+        topic,
+        payload: Payload::Clear,
+        qos: QoS::AtLeastOnce,
+        retain: true,
+    }
+}
+
+/// Forget every discovery topic in `topics`, returning the messages that clear them.
+async fn clear_topics(topics: Vec<String>) -> Vec<PublishMessage> {
+    let mut messages = vec![];
+    for topic in topics {
+        if let Err(e) = delete_discovery_topic(&topic).await {
+            warn!("Couldn't forget discovery topic {topic}: {e}");
+        }
+        messages.push(clear_message(topic));
+    }
+    messages
+}
+
+/// Clear every discovery config we've published for a serial number, e.g. when the unit has been
+/// decommissioned.
+pub async fn purge_discovery(serial_number: &String) -> anyhow::Result<Vec<PublishMessage>> {
+    let topics = get_discovery_topics(serial_number).await?;
+    let messages = clear_topics(topics.into_iter().map(|(topic, _)| topic).collect()).await;
+    *PURGES
+        .write()
+        .await
+        .entry(serial_number.clone())
+        .or_default() += 1;
+    Ok(messages)
+}
+
+async fn purges(serial_number: &String) -> u64 {
+    PURGES.read().await.get(serial_number).copied().unwrap_or(0)
+}
+
+/// Keeps track of which discovery topics a unit has published, so that the ones it no longer
+/// produces can be removed from Home Assistant.
+#[derive(Debug, Default)]
+pub struct DiscoveryTracker {
+    serial_number: String,
+    /// the topics written to the database since starting, or since the last purge
+    recorded: HashSet<String>,
+    /// every topic published since starting
+    produced: HashSet<String>,
+    /// the owners that haven't published yet; once they all have, whatever else is in the
+    /// database is stale
+    pending: HashSet<String>,
+    swept: bool,
+    /// the purges of this unit's discovery that have been seen
+    purges: u64,
+}
+
+impl DiscoveryTracker {
+    /// Start tracking a unit, returning the messages that clear any discovery configs previously
+    /// published for points that are no longer monitored with discovery enabled.
+    pub async fn new(
+        serial_number: &String,
+        points: &[MonitoredPoint],
+    ) -> (Self, Vec<PublishMessage>) {
+        let mut owners: HashSet<String> = HashSet::new();
+        for p in points.iter().filter(|p| p.homeassistant_discovery) {
+            owners.insert(point_owner(p));
+            if !p.aggregate.is_empty() {
+                owners.extend(aggregate_owner(p));
+            }
+        }
+        let stale = match get_discovery_topics(serial_number).await {
+            Ok(topics) => topics
+                .into_iter()
+                .filter(|(_, owner)| !owners.contains(owner))
+                .map(|(topic, _)| topic)
+                .collect(),
+            Err(e) => {
+                warn!("{serial_number}: Couldn't load published discovery topics: {e}");
+                vec![]
+            }
+        };
+        let tracker = DiscoveryTracker {
+            serial_number: serial_number.clone(),
+            pending: owners,
+            purges: purges(serial_number).await,
+            ..Default::default()
+        };
+        (tracker, clear_topics(stale).await)
+    }
+
+    /// Whether the unit's discovery has been purged since this was last checked.  Everything
+    /// recorded was forgotten, so has to be published and recorded again.
+    pub async fn purged(&mut self) -> bool {
+        let purged = purges(&self.serial_number).await;
+        if purged == self.purges {
+            return false;
+        }
+        self.purges = purged;
+        self.recorded.clear();
+        true
+    }
+
+    /// Note that a discovery config was published.  Only the first publish of each topic is
+    /// written to the database.
+    pub async fn published(&mut self, owner: &String, topic: &String) {
+        self.pending.remove(owner);
+        self.produced.insert(topic.clone());
+        self.purged().await;
+        if self.recorded.contains(topic) {
+            return;
+        }
+        match record_discovery_topic(topic, &self.serial_number, owner).await {
+            Ok(_) => {
+                self.recorded.insert(topic.clone());
+            }
+            Err(e) => {
+                warn!(
+                    "{}: Couldn't record discovery topic {topic}: {e}",
+                    self.serial_number
+                );
+            }
+        }
+    }
+
+    /// Note that a point can't publish anything, e.g. because the unit doesn't have its model,
+    /// so the sweep doesn't wait for it.
+    pub fn unavailable(&mut self, point: &MonitoredPoint) {
+        self.pending.remove(&point_owner(point));
+        if let Some(owner) = aggregate_owner(point) {
+            self.pending.remove(&owner);
+        }
+    }
+
+    /// Stop producing everything published for `owner`, returning the messages that clear it.
+    pub async fn remove(&mut self, owner: &String) -> Vec<PublishMessage> {
+        let topics: Vec<String> = match get_discovery_topics(&self.serial_number).await {
+            Ok(topics) => topics
+                .into_iter()
+                .filter(|(_, o)| o == owner)
+                .map(|(topic, _)| topic)
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "{}: Couldn't load published discovery topics: {e}",
+                    self.serial_number
+                );
+                return vec![];
+            }
+        };
+        self.pending.remove(owner);
+        for topic in topics.iter() {
+            self.recorded.remove(topic);
+            self.produced.remove(topic);
+        }
+        clear_topics(topics).await
+    }
+
+    /// Once every point has published its discovery configs, clear whatever else was published
+    /// for the unit before, e.g. an entity of a point that's now published as another kind.  Only
+    /// done the once.
+    pub async fn sweep(&mut self) -> Vec<PublishMessage> {
+        if self.swept || !self.pending.is_empty() {
+            return vec![];
+        }
+        let stale: Vec<String> = match get_discovery_topics(&self.serial_number).await {
+            Ok(topics) => topics
+                .into_iter()
+                .map(|(topic, _)| topic)
+                .filter(|topic| !self.produced.contains(topic))
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "{}: Couldn't load published discovery topics: {e}",
+                    self.serial_number
+                );
+                return vec![];
+            }
+        };
+        self.swept = true;
+        clear_topics(stale).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_structs::PointConfig;
+    use crate::payload::HAConfigPayload;
+    use crate::publish_policy::PublishGate;
+    use crate::state_mgmt::test_db;
+
+    fn topics(messages: Vec<PublishMessage>) -> Vec<String> {
+        messages.into_iter().map(|m| m.topic).collect()
+    }
+
+    #[tokio::test]
+    async fn stale_configs_are_cleared() {
+        test_db().await;
+        let sn = "DISC1".to_string();
+        let sensor = "homeassistant/sensor/DISC1/1_A/config".to_string();
+        let old_kind = "homeassistant/number/DISC1/1_A/config".to_string();
+        let gone = "homeassistant/sensor/DISC1/1_Gone/config".to_string();
+        for (topic, owner) in [(&sensor, "1/A"), (&old_kind, "1/A"), (&gone, "1/Gone")] {
+            record_discovery_topic(topic, &sn, &owner.to_string())
+                .await
+                .unwrap();
+        }
+        let point = MonitoredPoint::new(
+            "1".to_string(),
+            PointConfig {
+                point: Some("A".to_string()),
+                interval: 60,
+                ..Default::default()
+            },
+            Some(true),
+        )
+        .unwrap();
+
+        // points that are no longer polled go straight away
+        let (mut tracker, cleared) = DiscoveryTracker::new(&sn, &[point]).await;
+        assert_eq!(topics(cleared), vec![gone]);
+        // the rest wait for every point to have published
+        assert!(tracker.sweep().await.is_empty());
+        tracker.published(&"1/A".to_string(), &sensor).await;
+        assert_eq!(topics(tracker.sweep().await), vec![old_kind]);
+        assert!(tracker.sweep().await.is_empty());
+        assert_eq!(
+            get_discovery_topics(&sn).await.unwrap(),
+            vec![(sensor.clone(), "1/A".to_string())]
+        );
+
+        // a purge forgets everything, so the next publish is recorded again
+        assert_eq!(
+            topics(purge_discovery(&sn).await.unwrap()),
+            vec![sensor.clone()]
+        );
+        assert!(get_discovery_topics(&sn).await.unwrap().is_empty());
+        tracker.published(&"1/A".to_string(), &sensor).await;
+        assert_eq!(get_discovery_topics(&sn).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn points_the_unit_cant_publish_dont_hold_up_the_sweep() {
+        test_db().await;
+        let sn = "DISC2".to_string();
+        let sensor = "homeassistant/sensor/DISC2/1_A/config".to_string();
+        let old = "homeassistant/sensor/DISC2/1_B/config".to_string();
+        record_discovery_topic(&old, &sn, &"1/A".to_string())
+            .await
+            .unwrap();
+        let point = |model: &str| {
+            MonitoredPoint::new(
+                model.to_string(),
+                PointConfig {
+                    point: Some("A".to_string()),
+                    interval: 60,
+                    ..Default::default()
+                },
+                Some(true),
+            )
+            .unwrap()
+        };
+        let missing = point("2");
+        let (mut tracker, _) = DiscoveryTracker::new(&sn, &[point("1"), missing.clone()]).await;
+        tracker.published(&"1/A".to_string(), &sensor).await;
+        assert!(tracker.sweep().await.is_empty());
+        // the unit doesn't have model 2
+        tracker.unavailable(&missing);
+        assert_eq!(topics(tracker.sweep().await), vec![old]);
+    }
+
+    #[tokio::test]
+    async fn a_purged_unit_republishes_its_configs() {
+        test_db().await;
+        let sn = "DISC3".to_string();
+        let owner = "1/A".to_string();
+        let topic = "homeassistant/sensor/DISC3/1_A/config".to_string();
+        let config = HAConfigPayload::default();
+        let (mut tracker, _) = DiscoveryTracker::new(&sn, &[]).await;
+        let mut gate = PublishGate::default();
+
+        // the first poll publishes the config, the next doesn't need to
+        assert!(!tracker.purged().await);
+        assert!(gate.config(&topic, &config, 1));
+        tracker.published(&owner, &topic).await;
+        assert!(!tracker.purged().await);
+        assert!(!gate.config(&topic, &config, 1));
+
+        // the purge clears it from the broker, so the next poll publishes it again
+        assert_eq!(
+            topics(purge_discovery(&sn).await.unwrap()),
+            vec![topic.clone()]
+        );
+        assert!(tracker.purged().await);
+        gate.forget_configs();
+        assert!(gate.config(&topic, &config, 1));
+        tracker.published(&owner, &topic).await;
+        assert_eq!(
+            get_discovery_topics(&sn).await.unwrap(),
+            vec![(topic, owner)]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use crate::config_structs::IntegrationMethod;
+use crate::consts::*;
+use crate::monitored_point::MonitoredPoint;
+use crate::payload::{CompoundPayload, HAConfigPayload, PayloadValueType, StatePayload};
+use crate::state_mgmt::{get_energy_accumulator, write_energy_accumulator, EnergyAccumulator};
+use crate::sunspec_unit::SunSpecUnit;
+
+const SECONDS_PER_HOUR: f64 = 3600.0;
+const WATTS_PER_KILOWATT: f64 = 1000.0;
+
+/// Calculate the energy (kWh) represented by the area between two power samples (W).
+///
+/// Returns zero when the samples are out of order or further apart than `max_gap` seconds, since
+/// we can't say anything useful about what the device did in that window.  Negative power (e.g.
+/// export on a bidirectional point) is treated as zero so that the result is always suitable for a
+/// `total_increasing` sensor.
+pub fn integrate_step(
+    method: &IntegrationMethod,
+    max_gap: u64,
+    previous: (f64, i64),
+    current: (f64, i64),
+) -> f64 {
+    let (prev_watts, prev_ts) = previous;
+    let (cur_watts, cur_ts) = current;
+    let dt = cur_ts - prev_ts;
+    if dt <= 0 || dt as u64 > max_gap {
+        return 0.0;
+    }
+    let prev_watts = prev_watts.max(0.0);
+    let cur_watts = cur_watts.max(0.0);
+    let watts = match method {
+        IntegrationMethod::Left => prev_watts,
+        IntegrationMethod::Trapezoidal => (prev_watts + cur_watts) / 2.0,
+    };
+    watts * (dt as f64) / SECONDS_PER_HOUR / WATTS_PER_KILOWATT
+}
+
+/// Fold the value in `source_state` into the persisted accumulator for this point and build the
+/// payload for the derived energy sensor.
+pub async fn integrate_payload(
+    unit: &SunSpecUnit,
+    monitored_point: &MonitoredPoint,
+    point_name: &String,
+    source_config: &HAConfigPayload,
+    source_state: &StatePayload,
+) -> Option<CompoundPayload> {
+    let sn = unit.serial_number.clone();
+    let model = monitored_point.model.clone();
+    let log_prefix = format!(
+        "[{}:{} {sn} {model}/{point_name}]",
+        unit.addr, unit.slave_id
+    );
+
+    let mut watts = match source_state.value {
+        PayloadValueType::Float(f) => f,
+        PayloadValueType::Int(i) => i as f64,
+        _ => {
+            warn!("{log_prefix}: integrate is set, but this point isn't numeric.");
+            return None;
+        }
+    };
+    if source_config.native_uom.as_deref() == Some("kW") {
+        watts *= WATTS_PER_KILOWATT;
+    }
+    let timestamp = source_state.last_seen.timestamp();
+
+    let uniqueid = format!("{sn}.{model}.{point_name}_{INTEGRATED_ENERGY_SUFFIX}");
+    let mut accumulator = match get_energy_accumulator(&uniqueid).await {
+        Ok(Some(acc)) => acc,
+        Ok(None) => EnergyAccumulator {
+            uniqueid: uniqueid.clone(),
+            ..EnergyAccumulator::default()
+        },
+        Err(e) => {
+            warn!("{log_prefix}: Couldn't load energy accumulator, skipping integration: {e}");
+            return None;
+        }
+    };
+    if let (Some(last_value), Some(last_timestamp)) =
+        (accumulator.last_value, accumulator.last_timestamp)
+    {
+        accumulator.total_kwh += integrate_step(
+            &monitored_point.integration_method,
+            monitored_point.integration_max_gap,
+            (last_value, last_timestamp),
+            (watts, timestamp),
+        );
+    }
+    accumulator.last_value = Some(watts);
+    accumulator.last_timestamp = Some(timestamp);
+    if let Err(e) = write_energy_accumulator(&accumulator).await {
+        warn!("{log_prefix}: Couldn't persist energy accumulator: {e}");
+    }
+
+    let mut config_payload = source_config.clone();
+    let config_topic =
+        format!("homeassistant/sensor/{sn}/{model}_{point_name}_{INTEGRATED_ENERGY_SUFFIX}/config");
+    let state_topic = format!(
+        "sunspec_gateway/{sn}/{model}/{}_{INTEGRATED_ENERGY_SUFFIX}",
+        monitored_point.path
+    );
+    config_payload.name = format!("{} Energy", source_config.name);
+    config_payload.entity_id = format!("sensor.{}", uniqueid.replace('.', "_"));
+    config_payload.unique_id = uniqueid;
+    config_payload.state_topic = state_topic.clone();
+    config_payload.device_class = Some("energy".to_string());
+    config_payload.state_class = Some("total_increasing".to_string());
+    config_payload.native_uom = Some("kWh".to_string());
+    config_payload.command_topic = None;
+    config_payload.entity_category = None;
+
+    let state_payload = StatePayload {
+        value: PayloadValueType::Float(accumulator.total_kwh),
+        last_seen: source_state.last_seen,
+        ..StatePayload::default()
+    };
+
+    Some(CompoundPayload {
+        config: config_payload,
+        config_topic,
+        state: state_payload,
+        state_topic,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: i64 = 1_700_000_000;
+
+    #[test]
+    fn left_and_trapezoidal() {
+        // 1 kW then 3 kW, half an hour apart
+        let (previous, current) = ((1000.0, T0), (3000.0, T0 + 1800));
+        let left = integrate_step(&IntegrationMethod::Left, 3600, previous, current);
+        assert!((left - 0.5).abs() < 1e-9, "{left}");
+        let trapezoidal = integrate_step(&IntegrationMethod::Trapezoidal, 3600, previous, current);
+        assert!((trapezoidal - 1.0).abs() < 1e-9, "{trapezoidal}");
+    }
+
+    #[test]
+    fn gaps_and_out_of_order_samples_count_for_nothing() {
+        let method = IntegrationMethod::Trapezoidal;
+        let step = |previous, current| integrate_step(&method, 600, previous, current);
+        // up to the gap is fine, past it is a hole in the data
+        assert!(step((1000.0, T0), (1000.0, T0 + 600)) > 0.0);
+        assert_eq!(step((1000.0, T0), (1000.0, T0 + 601)), 0.0);
+        // as is going backwards, or standing still
+        assert_eq!(step((1000.0, T0), (1000.0, T0 - 10)), 0.0);
+        assert_eq!(step((1000.0, T0), (1000.0, T0)), 0.0);
+    }
+
+    #[test]
+    fn negative_power_is_clamped() {
+        let trapezoidal = integrate_step(
+            &IntegrationMethod::Trapezoidal,
+            3600,
+            (-2000.0, T0),
+            (2000.0, T0 + 3600),
+        );
+        assert!((trapezoidal - 1.0).abs() < 1e-9, "{trapezoidal}");
+        let left = integrate_step(
+            &IntegrationMethod::Left,
+            3600,
+            (-2000.0, T0),
+            (2000.0, T0 + 3600),
+        );
+        assert_eq!(left, 0.0);
+        let exporting = integrate_step(
+            &IntegrationMethod::Trapezoidal,
+            3600,
+            (-500.0, T0),
+            (-800.0, T0 + 60),
+        );
+        assert_eq!(exporting, 0.0);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::config_structs::{EventsConfig, Severity};
+use crate::consts::*;
+use crate::ipc::PublishMessage;
+use crate::monitored_point::MonitoredPoint;
+use crate::payload::Payload;
+use crate::state_mgmt::{close_event, list_events, open_event, EventRecord};
+use crate::symbol_points::is_enum;
+use chrono::Utc;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use sunspec_rs::sunspec_models::{Point, ValueType};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transition {
+    Raised,
+    Cleared,
+}
+
+/// An event being raised or cleared, as it is published to MQTT and the webhook.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EventNotification {
+    pub transition: Transition,
+    #[serde(flatten)]
+    pub event: EventRecord,
+}
+
+/// The symbols a reading has active, if the point is a bitfield or an enum.
+pub fn active_symbols(point: &Point, value: &ValueType) -> Option<Vec<String>> {
+    match value {
+        ValueType::Array(symbols) => Some(symbols.clone()),
+        ValueType::String(symbol) if is_enum(Some(point)) => Some(vec![symbol.clone()]),
+        _ => None,
+    }
+}
+
+/// The symbols that were raised, and the ones that were cleared, going from `previous` to
+/// `current`.
+pub fn transitions(previous: &HashSet<String>, current: &[String]) -> (Vec<String>, Vec<String>) {
+    let current: HashSet<&String> = current.iter().collect();
+    let mut raised: Vec<String> = current
+        .iter()
+        .filter(|s| !previous.contains(**s))
+        .map(|s| s.to_string())
+        .collect();
+    let mut cleared: Vec<String> = previous
+        .iter()
+        .filter(|s| !current.contains(s))
+        .cloned()
+        .collect();
+    raised.sort();
+    cleared.sort();
+    (raised, cleared)
+}
+
+/// POST an event to a webhook.
+pub async fn send_webhook(
+    client: &reqwest::Client,
+    url: &str,
+    notification: &EventNotification,
+) -> anyhow::Result<()> {
+    client
+        .post(url)
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+        .json(notification)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Watches the bitfields and enums of a unit for symbols being raised and cleared.
+#[derive(Debug, Default)]
+pub struct EventTracker {
+    serial_number: String,
+    config: EventsConfig,
+    client: reqwest::Client,
+    /// `{model}/{point}` -> active symbol -> id of its open event
+    open: HashMap<String, HashMap<String, i64>>,
+}
+
+impl EventTracker {
+    /// Start tracking a unit, carrying on from the events that were open when we last ran.
+    pub async fn new(serial_number: &String, config: Option<EventsConfig>) -> Self {
+        let mut open: HashMap<String, HashMap<String, i64>> = HashMap::new();
+        match list_events(Some(serial_number), true, i64::MAX).await {
+            Ok(events) => {
+                for e in events {
+                    open.entry(format!("{}/{}", e.model, e.point))
+                        .or_default()
+                        .insert(e.symbol, e.id);
+                }
+            }
+            Err(e) => {
+                warn!("{serial_number}: Couldn't load open events: {e}");
+            }
+        }
+        EventTracker {
+            serial_number: serial_number.clone(),
+            config: config.unwrap_or_default(),
+            client: reqwest::Client::new(),
+            open,
+        }
+    }
+
+    /// Record the symbols a point currently has active, returning the messages for any that
+    /// were raised or cleared since the last reading.
+    pub async fn observe(
+        &mut self,
+        point: &MonitoredPoint,
+        active: &[String],
+    ) -> Vec<PublishMessage> {
+        let key = format!("{}/{}", point.model, point.path);
+        let open = self.open.entry(key.clone()).or_default();
+        let previous: HashSet<String> = open.keys().cloned().collect();
+        let (raised, cleared) = transitions(&previous, active);
+        let now = Utc::now().timestamp();
+
+        let mut notifications: Vec<(Severity, EventNotification)> = vec![];
+        for symbol in raised {
+            let severity = point.severity.get(&symbol).copied().unwrap_or_default();
+            let event = EventRecord {
+                serial_number: self.serial_number.clone(),
+                model: point.model.clone(),
+                point: point.path.to_string(),
+                symbol: symbol.clone(),
+                severity: severity.to_string(),
+                raised_at: now,
+                ..EventRecord::default()
+            };
+            match open_event(&event).await {
+                Ok(event) => {
+                    info!("{}: {key} raised {symbol} ({severity})", self.serial_number);
+                    open.insert(symbol, event.id);
+                    notifications.push((
+                        severity,
+                        EventNotification {
+                            transition: Transition::Raised,
+                            event,
+                        },
+                    ));
+                }
+                Err(e) => {
+                    warn!(
+                        "{}: Couldn't record event {key}/{symbol}: {e}",
+                        self.serial_number
+                    );
+                }
+            }
+        }
+        for symbol in cleared {
+            let Some(id) = open.remove(&symbol) else {
+                continue;
+            };
+            let severity = point.severity.get(&symbol).copied().unwrap_or_default();
+            match close_event(id, now).await {
+                Ok(Some(event)) => {
+                    info!("{}: {key} cleared {symbol}", self.serial_number);
+                    notifications.push((
+                        severity,
+                        EventNotification {
+                            transition: Transition::Cleared,
+                            event,
+                        },
+                    ));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(
+                        "{}: Couldn't clear event {key}/{symbol}: {e}",
+                        self.serial_number
+                    );
+                }
+            }
+        }
+
+        let mut messages = vec![];
+        for (severity, notification) in notifications {
+            self.notify(severity, &notification);
+            messages.push(PublishMessage {
+                topic: format!("sunspec_gateway/{}/events", self.serial_number),
+                payload: Payload::Event(notification),
+                qos: QoS::AtLeastOnce,
+                retain: false,
+            });
+        }
+        messages
+    }
+
+    /// Send an event to the webhook in the background, if it's severe enough.
+    fn notify(&self, severity: Severity, notification: &EventNotification) {
+        let Some(url) = self.config.webhook_url.clone() else {
+            return;
+        };
+        if severity
+            < self
+                .config
+                .webhook_min_severity
+                .unwrap_or(Severity::Warning)
+        {
+            return;
+        }
+        let client = self.client.clone();
+        let notification = notification.clone();
+        let sn = self.serial_number.clone();
+        tokio::spawn(async move {
+            if let Err(e) = send_webhook(&client, &url, &notification).await {
+                warn!(
+                    "{sn}: Couldn't send event {} to webhook: {e}",
+                    notification.event.id
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn transitions_between_readings() {
+        let previous: HashSet<String> = ["GROUND_FAULT", "OVER_TEMP"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let (raised, cleared) = transitions(
+            &previous,
+            &["OVER_TEMP".to_string(), "DC_OVER_VOLT".to_string()],
+        );
+        assert_eq!(raised, vec!["DC_OVER_VOLT".to_string()]);
+        assert_eq!(cleared, vec!["GROUND_FAULT".to_string()]);
+
+        let (raised, cleared) = transitions(&HashSet::new(), &[]);
+        assert!(raised.is_empty() && cleared.is_empty());
+    }
+
+    #[tokio::test]
+    async fn webhook_receives_the_event() {
+        let (tx, mut rx) = mpsc::channel::<EventNotification>(1);
+        let app = Router::new().route(
+            "/hook",
+            post(move |Json(n): Json<EventNotification>| async move {
+                tx.send(n).await.unwrap();
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let notification = EventNotification {
+            transition: Transition::Raised,
+            event: EventRecord {
+                id: 7,
+                serial_number: "0001ABCD".to_string(),
+                model: "103".to_string(),
+                point: "Evt1".to_string(),
+                symbol: "GROUND_FAULT".to_string(),
+                severity: Severity::Critical.to_string(),
+                raised_at: 1_700_000_000,
+                ..EventRecord::default()
+            },
+        };
+        send_webhook(
+            &reqwest::Client::new(),
+            &format!("http://{addr}/hook"),
+            &notification,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rx.recv().await, Some(notification));
+    }
+}
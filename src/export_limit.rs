@@ -0,0 +1,789 @@
+use crate::config_structs::{ExportLimitConfig, GatewayConfig, PointConfig};
+use crate::consts::*;
+use crate::ipc::{IPCMessage, InboundMessage, WriteSource};
+use crate::payload::PayloadValueType;
+use crate::point_path::PointPath;
+use crate::sinks::{MessageKind, Sink, SinkMessage};
+use crate::sunspec_poll::polled_at;
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+/// A PI controller working out an inverter's limit, in percent, from the power a meter reads.
+#[derive(Debug, Clone)]
+pub struct Controller {
+    max_export: f64,
+    rated_power: f64,
+    export_negative: bool,
+    kp: f64,
+    ki: f64,
+    ramp: f64,
+    min: f64,
+    max: f64,
+    fallback: f64,
+    deadband: f64,
+    refresh: i64,
+    integral: f64,
+    /// where the latest reading puts the limit
+    target: f64,
+    /// the limit the controller is at, on its way to `target`
+    limit: f64,
+    /// when the latest reading was taken
+    updated: Option<DateTime<Utc>>,
+    /// when the limit was last ramped
+    stepped: Option<DateTime<Utc>>,
+    /// the limit last written, and when
+    written: Option<(f64, DateTime<Utc>)>,
+    falling_back: bool,
+}
+
+impl Controller {
+    /// A controller at the fallback limit, which it ramps up from once readings come in.
+    pub fn new(config: &ExportLimitConfig) -> Self {
+        let fallback = config.fallback_limit.unwrap_or(0.0);
+        Controller {
+            max_export: config.max_export,
+            rated_power: config.rated_power,
+            export_negative: config.export_negative.unwrap_or(true),
+            kp: config.kp.unwrap_or(DEFAULT_EXPORT_LIMIT_KP),
+            ki: config.ki.unwrap_or(DEFAULT_EXPORT_LIMIT_KI),
+            ramp: config.ramp.unwrap_or(DEFAULT_EXPORT_LIMIT_RAMP),
+            min: config.min_limit.unwrap_or(0.0),
+            max: config.max_limit.unwrap_or(100.0),
+            fallback,
+            deadband: config.deadband.unwrap_or(DEFAULT_EXPORT_LIMIT_DEADBAND),
+            refresh: config
+                .refresh_interval
+                .unwrap_or(DEFAULT_EXPORT_LIMIT_REFRESH_SECS) as i64,
+            integral: fallback,
+            target: fallback,
+            limit: fallback,
+            updated: None,
+            stepped: None,
+            written: None,
+            falling_back: false,
+        }
+    }
+
+    pub fn falling_back(&self) -> bool {
+        self.falling_back
+    }
+
+    /// The limit to write now that the meter reads `power`, if one is due.  The limit only moves
+    /// as far toward the new target as the ramp allows; `step` takes it the rest of the way.
+    pub fn reading(&mut self, power: f64, at: DateTime<Utc>) -> Option<f64> {
+        let export = if self.export_negative { -power } else { power };
+        // how far under the cap export is, in percent of the inverter's rated power
+        let error = (self.max_export - export) / self.rated_power * 100.0;
+        // close enough is left alone, or the limit hunts around the cap
+        let error = if error.abs() < self.deadband {
+            0.0
+        } else {
+            error
+        };
+        let dt = self
+            .updated
+            .map(|u| (at - u).num_milliseconds() as f64 / 1000.0)
+            .unwrap_or(0.0)
+            .max(0.0);
+        self.updated = Some(at);
+        self.falling_back = false;
+
+        // don't let the integral wind up while the ramp holds the limit back
+        if self.limit == self.target {
+            self.integral = (self.integral + self.ki * error * dt).clamp(self.min, self.max);
+        }
+        let target = (self.kp * error + self.integral).clamp(self.min, self.max);
+        self.target = (target * 10.0).round() / 10.0;
+        self.step(at)
+    }
+
+    /// Ramp the limit toward its target, and the limit to write, if one is due: when it's moved,
+    /// or hasn't been written for a while.
+    pub fn step(&mut self, at: DateTime<Utc>) -> Option<f64> {
+        let dt = self
+            .stepped
+            .map(|s| (at - s).num_milliseconds() as f64 / 1000.0)
+            .unwrap_or(0.0)
+            .max(0.0);
+        self.stepped = Some(at);
+        let step = self.ramp * dt;
+        let limit = self.target.clamp(self.limit - step, self.limit + step);
+        self.limit = (limit * 10.0).round() / 10.0;
+        self.due(at, false)
+    }
+
+    /// The limit to write while the meter can't be read, if one is due.
+    pub fn lost(&mut self, at: DateTime<Utc>) -> Option<f64> {
+        let falling_back = !self.falling_back;
+        self.falling_back = true;
+        self.integral = self.fallback;
+        self.target = self.fallback;
+        self.limit = self.fallback;
+        self.updated = Some(at);
+        self.stepped = Some(at);
+        self.due(at, falling_back)
+    }
+
+    fn due(&mut self, at: DateTime<Utc>, force: bool) -> Option<f64> {
+        let due = match self.written {
+            None => true,
+            Some((written, when)) => {
+                (self.limit != written
+                    && (force
+                        || (self.limit - written).abs() >= self.deadband
+                        || self.limit <= self.min
+                        || self.limit >= self.max))
+                    || (at - when).num_seconds() >= self.refresh
+            }
+        };
+        if !due {
+            return None;
+        }
+        self.written = Some((self.limit, at));
+        Some(self.limit)
+    }
+}
+
+/// The points a unit might be polled for, by model: the ones of the slave it's polled at once a
+/// poll loop has read its serial number, and every unit's until then.
+fn unit_points<'a>(
+    gateway: &'a GatewayConfig,
+    serial_number: &str,
+) -> Vec<&'a HashMap<String, Vec<PointConfig>>> {
+    match polled_at(serial_number) {
+        Some((addr, slave)) => vec![gateway.unit_models(&addr, slave)],
+        None => gateway.all_models().collect(),
+    }
+}
+
+fn configured<'a>(
+    models: &[&'a HashMap<String, Vec<PointConfig>>],
+    model: &str,
+    point: &PointPath,
+) -> Option<&'a PointConfig> {
+    models
+        .iter()
+        .filter_map(|models| models.get(model))
+        .flatten()
+        .find(|p| {
+            p.name()
+                .parse::<PointPath>()
+                .is_ok_and(|p| p.matches(point))
+        })
+}
+
+/// Check that the meter's unit is polled for the meter's point and the inverter is polled for the
+/// limit, with an input.  How old a reading can get before it's stale, which depends on how often
+/// the meter is read.
+fn check_points(
+    config: &ExportLimitConfig,
+    gateway: &GatewayConfig,
+    meter_point: &PointPath,
+    limit_point: &PointPath,
+) -> anyhow::Result<u64> {
+    let meter_points = unit_points(gateway, &config.meter.serial_number);
+    let Some(meter) = configured(&meter_points, &config.meter.model, meter_point) else {
+        bail!(
+            "{}'s point {}/{meter_point} isn't configured to be polled",
+            config.meter.serial_number,
+            config.meter.model
+        );
+    };
+    let limit_points = unit_points(gateway, &config.limit.serial_number);
+    if configured(&limit_points, &config.limit.model, limit_point)
+        .is_none_or(|p| p.inputs.is_none())
+    {
+        bail!(
+            "{}'s limit point {}/{limit_point} isn't configured with an input",
+            config.limit.serial_number,
+            config.limit.model
+        );
+    }
+    // a reading that's only as old as the meter's interval isn't late
+    match config.stale_after {
+        Some(s) if s <= meter.interval => bail!(
+            "stale_after has to be more than the meter point's interval of {}s",
+            meter.interval
+        ),
+        Some(s) => Ok(s),
+        None => {
+            Ok((meter.interval * EXPORT_LIMIT_STALE_READINGS).max(DEFAULT_EXPORT_LIMIT_STALE_SECS))
+        }
+    }
+}
+
+fn parse_point(model: &str, point: &str) -> anyhow::Result<PointPath> {
+    if model.parse::<u16>().is_err() {
+        bail!("{model} isn't a model id");
+    }
+    point
+        .parse::<PointPath>()
+        .map_err(|e| anyhow!("{point} isn't a point: {e}"))
+}
+
+/// Drives an inverter's limit from a meter's readings, as they come through the dispatcher.  The
+/// limits go through the same write path as everything else, so they're checked against the
+/// point's write policy and kept in the audit log.
+pub struct ExportLimiter {
+    config: ExportLimitConfig,
+    /// the unique id the meter's readings come under
+    meter_id: String,
+    /// how old, in seconds, the latest reading can get before falling back
+    stale_after: u64,
+    meter_point: PointPath,
+    limit_point: PointPath,
+    enable_point: Option<PointPath>,
+    controller: Controller,
+    tx: Sender<IPCMessage>,
+    /// when the latest meter reading was taken
+    last_reading: Option<DateTime<Utc>>,
+    started: DateTime<Utc>,
+    /// whether `enable` has been written since the inverter came online
+    enabled: bool,
+    gateway: GatewayConfig,
+    /// whether the points have been checked against the meter's and inverter's own slaves
+    units_checked: bool,
+    /// set when they aren't polled there, which leaves the inverter alone
+    misconfigured: bool,
+}
+
+impl ExportLimiter {
+    pub fn new(
+        config: ExportLimitConfig,
+        gateway: &GatewayConfig,
+        tx: Sender<IPCMessage>,
+    ) -> anyhow::Result<Self> {
+        let meter_point = parse_point(&config.meter.model, &config.meter.point)?;
+        let limit_point = parse_point(&config.limit.model, &config.limit.point)?;
+        let enable_point = match &config.enable {
+            Some(enable) => Some(parse_point(&config.limit.model, &enable.point)?),
+            None => None,
+        };
+        if config.rated_power <= 0.0 {
+            bail!("rated_power has to be more than 0");
+        }
+        let controller = Controller::new(&config);
+        if controller.min > controller.max {
+            bail!("min_limit can't be more than max_limit");
+        }
+        if !(controller.min..=controller.max).contains(&controller.fallback) {
+            bail!("fallback_limit has to be between min_limit and max_limit");
+        }
+        if controller.ramp <= 0.0 {
+            bail!("ramp has to be more than 0");
+        }
+        // which slaves the units are isn't known until they're polled, so this is checked against
+        // every unit now, and against theirs once they are
+        let stale_after = check_points(&config, gateway, &meter_point, &limit_point)?;
+        Ok(ExportLimiter {
+            // as the poll loop has it in the point's unique id
+            meter_id: format!(
+                "{}.{}.{}",
+                config.meter.serial_number,
+                config.meter.model,
+                meter_point.slug()
+            ),
+            stale_after,
+            meter_point,
+            limit_point,
+            enable_point,
+            gateway: gateway.clone(),
+            units_checked: false,
+            misconfigured: false,
+            controller,
+            tx,
+            last_reading: None,
+            started: Utc::now(),
+            enabled: false,
+            config,
+        })
+    }
+
+    /// Check the points again once both units are polled and it's known which slaves they are.
+    fn check_units(&mut self) {
+        if self.units_checked
+            || polled_at(&self.config.meter.serial_number).is_none()
+            || polled_at(&self.config.limit.serial_number).is_none()
+        {
+            return;
+        }
+        self.units_checked = true;
+        match check_points(
+            &self.config,
+            &self.gateway,
+            &self.meter_point,
+            &self.limit_point,
+        ) {
+            Ok(stale_after) => self.stale_after = stale_after,
+            Err(e) => {
+                error!("Export limiter: {e}, so export isn't being limited");
+                self.misconfigured = true;
+            }
+        }
+    }
+
+    async fn write_point(&self, point: PointPath, payload: String) -> anyhow::Result<()> {
+        self.tx
+            .send(IPCMessage::Inbound(InboundMessage {
+                serial_number: self.config.limit.serial_number.clone(),
+                model: self.config.limit.model.clone(),
+                point,
+                payload,
+                source: WriteSource::Automation(EXPORT_LIMIT_SOURCE.to_string()),
+                dry_run: self.config.dry_run.unwrap_or(false),
+            }))
+            .await
+            .map_err(|e| anyhow!("Couldn't send a limit to be written: {e}"))
+    }
+
+    async fn write(&mut self, limit: Option<f64>) -> anyhow::Result<()> {
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+        if !self.enabled {
+            if let (Some(point), Some(enable)) = (&self.enable_point, &self.config.enable) {
+                self.write_point(point.clone(), enable.value.clone())
+                    .await?;
+            }
+            self.enabled = true;
+        }
+        debug!("Limiting {} to {limit}%", self.config.limit.serial_number);
+        self.write_point(self.limit_point.clone(), limit.to_string())
+            .await
+    }
+}
+
+#[async_trait]
+impl Sink for ExportLimiter {
+    fn name(&self) -> &'static str {
+        "export_limit"
+    }
+
+    fn kinds(&self) -> &'static [MessageKind] {
+        &[MessageKind::History, MessageKind::Lifecycle]
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(EXPORT_LIMIT_TICK_SECS))
+    }
+
+    async fn send(&mut self, msg: SinkMessage) -> anyhow::Result<()> {
+        self.check_units();
+        if self.misconfigured {
+            return Ok(());
+        }
+        match msg {
+            SinkMessage::History(uniqueid, state) if uniqueid == self.meter_id => {
+                let power = match state.value {
+                    PayloadValueType::Float(f) => f,
+                    PayloadValueType::Int(i) => i as f64,
+                    _ => return Ok(()),
+                };
+                if self.last_reading.is_some_and(|r| state.last_seen <= r) {
+                    return Ok(());
+                }
+                if self.controller.falling_back() {
+                    info!("Export limiter: the meter is being read again");
+                }
+                self.last_reading = Some(state.last_seen);
+                let limit = self.controller.reading(power, state.last_seen);
+                self.write(limit).await
+            }
+            SinkMessage::UnitOffline(sn) => {
+                if sn == self.config.limit.serial_number {
+                    self.enabled = false;
+                }
+                if sn != self.config.meter.serial_number {
+                    return Ok(());
+                }
+                self.last_reading = None;
+                if !self.controller.falling_back() {
+                    warn!(
+                        "Export limiter: meter {sn} is offline, falling back to {}%",
+                        self.controller.fallback
+                    );
+                }
+                let limit = self.controller.lost(Utc::now());
+                self.write(limit).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        self.check_units();
+        if self.misconfigured {
+            return Ok(());
+        }
+        let now = Utc::now();
+        let stale_after = self.stale_after as i64;
+        let stale = (now - self.last_reading.unwrap_or(self.started)).num_seconds() >= stale_after;
+        let limit = if stale {
+            if !self.controller.falling_back() {
+                warn!(
+                    "Export limiter: no reading from meter {} for {stale_after}s, falling back to {}%",
+                    self.config.meter.serial_number, self.controller.fallback
+                );
+            }
+            self.controller.lost(now)
+        } else if self.last_reading.is_some() {
+            self.controller.step(now)
+        } else {
+            None
+        };
+        self.write(limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_structs::{InputType, UnitPoint};
+    use crate::payload::StatePayload;
+    use chrono::TimeDelta;
+
+    fn config() -> ExportLimitConfig {
+        ExportLimitConfig {
+            meter: UnitPoint {
+                serial_number: "M1".to_string(),
+                model: "203".to_string(),
+                point: "W".to_string(),
+            },
+            limit: UnitPoint {
+                serial_number: "INV1".to_string(),
+                model: "123".to_string(),
+                point: "WMaxLimPct".to_string(),
+            },
+            max_export: 2000.0,
+            rated_power: 10000.0,
+            ..Default::default()
+        }
+    }
+
+    /// A site with a load, and panels that could make more than the load and the cap together.
+    /// The inverter follows its limit straight away, and the meter reads import as positive.
+    fn meter(limit: f64, load: f64, available: f64) -> f64 {
+        load - available.min(limit / 100.0 * 10000.0)
+    }
+
+    /// `seconds` of a site whose meter is read every 10 seconds, with the controller stepped in
+    /// between as the limiter's tick does.  The limits written, and when.
+    fn run(
+        controller: &mut Controller,
+        start: DateTime<Utc>,
+        seconds: std::ops::Range<i64>,
+        load: f64,
+        available: f64,
+    ) -> Vec<(i64, f64)> {
+        let mut writes = vec![];
+        for t in seconds {
+            let at = start + TimeDelta::seconds(t);
+            let written = controller.written.map(|(w, _)| w).unwrap_or(0.0);
+            let limit = if t % 10 == 0 {
+                controller.reading(meter(written, load, available), at)
+            } else {
+                controller.step(at)
+            };
+            if let Some(limit) = limit {
+                writes.push((t, limit));
+            }
+        }
+        writes
+    }
+
+    #[test]
+    fn export_is_held_under_the_cap() {
+        let mut controller = Controller::new(&config());
+        let start = Utc::now();
+        let at = |s: i64| start + TimeDelta::seconds(s);
+        let (load, available) = (3000.0, 9000.0);
+
+        // starts from the fallback and ramps up, at most 10% a second even though readings are
+        // 10 seconds apart
+        let writes = run(&mut controller, start, 0..600, load, available);
+        assert_eq!(writes[0], (0, 0.0));
+        assert_eq!(writes[1], (1, 10.0));
+        for w in writes.windows(2) {
+            let ((t0, l0), (t1, l1)) = (w[0], w[1]);
+            assert!((l1 - l0).abs() <= 10.0 * (t1 - t0) as f64, "{writes:?}");
+        }
+        // settles on exporting the cap, and once it's there only writes the limit again every
+        // minute
+        assert!(
+            (controller.limit - 50.0).abs() < 1.0,
+            "{}",
+            controller.limit
+        );
+        assert!(-meter(controller.limit, load, available) <= 2000.0 + 100.0);
+        let settled = writes.iter().position(|(t, _)| *t > 100).unwrap();
+        assert!(
+            writes[settled..].windows(2).all(|w| w[1].0 - w[0].0 == 60),
+            "{writes:?}"
+        );
+
+        // the load drops, so the limit has to come down
+        run(&mut controller, start, 600..1200, 1000.0, available);
+        assert!(
+            (controller.limit - 30.0).abs() < 1.0,
+            "{}",
+            controller.limit
+        );
+
+        // the fallback is written as soon as the meter is lost, then only to refresh it
+        assert_eq!(controller.lost(at(1300)), Some(0.0));
+        assert_eq!(controller.lost(at(1301)), None);
+        assert_eq!(controller.lost(at(1361)), Some(0.0));
+        // and the limit ramps back up from it
+        assert_eq!(
+            controller.reading(meter(0.0, 1000.0, available), at(1362)),
+            Some(10.0)
+        );
+        assert_eq!(controller.step(at(1363)), Some(16.5));
+    }
+
+    #[tokio::test]
+    async fn limits_go_down_the_write_path() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let mut models = HashMap::new();
+        models.insert(
+            "203".to_string(),
+            vec![PointConfig {
+                point: Some("W".to_string()),
+                interval: 15,
+                ..Default::default()
+            }],
+        );
+        models.insert(
+            "123".to_string(),
+            vec![PointConfig {
+                point: Some("WMaxLimPct".to_string()),
+                ..Default::default()
+            }],
+        );
+        let mut config = config();
+        config.enable = Some(crate::config_structs::EnableWrite {
+            point: "WMaxLim_Ena".to_string(),
+            value: "ENABLED".to_string(),
+        });
+        let mut gateway = GatewayConfig {
+            models,
+            ..Default::default()
+        };
+        assert!(ExportLimiter::new(config.clone(), &gateway, tx.clone()).is_err());
+        gateway.models.get_mut("123").unwrap()[0].inputs =
+            Some(InputType::Number(crate::config_structs::Numerable {
+                min: 0.0,
+                max: 100.0,
+                step: None,
+                mode: None,
+            }));
+        // a reading every 15 seconds can't go stale after 15 seconds
+        config.stale_after = Some(15);
+        assert!(ExportLimiter::new(config.clone(), &gateway, tx.clone()).is_err());
+        config.stale_after = None;
+        // points in groups are read under their slug
+        let mut grouped = config.clone();
+        grouped.meter.point = ".meter.W".to_string();
+        gateway.models.get_mut("203").unwrap()[0].point = Some(".meter.W".to_string());
+        let limiter = ExportLimiter::new(grouped, &gateway, tx.clone()).unwrap();
+        assert_eq!(limiter.meter_id, "M1.203._meter_W");
+        gateway.models.get_mut("203").unwrap()[0].point = Some("W".to_string());
+        let mut limiter = ExportLimiter::new(config, &gateway, tx).unwrap();
+        assert_eq!(limiter.stale_after, 45);
+
+        let reading = |w: f64| {
+            SinkMessage::History(
+                "M1.203.W".to_string(),
+                StatePayload {
+                    value: PayloadValueType::Float(w),
+                    ..Default::default()
+                },
+            )
+        };
+        limiter.send(reading(-500.0)).await.unwrap();
+        let mut sent = vec![];
+        while let Ok(IPCMessage::Inbound(msg)) = rx.try_recv() {
+            assert_eq!(msg.serial_number, "INV1");
+            assert_eq!(msg.source.to_string(), "automation:export_limit");
+            sent.push((msg.point.to_string(), msg.payload));
+        }
+        assert_eq!(
+            sent,
+            vec![
+                ("WMaxLim_Ena".to_string(), "ENABLED".to_string()),
+                ("WMaxLimPct".to_string(), "0".to_string()),
+            ]
+        );
+
+        // other points are none of its business, and the meter going offline means the fallback
+        limiter
+            .send(SinkMessage::History(
+                "M1.203.VAR".to_string(),
+                StatePayload::default(),
+            ))
+            .await
+            .unwrap();
+        limiter.send(reading(-500.0)).await.unwrap();
+        limiter
+            .send(SinkMessage::UnitOffline("M1".to_string()))
+            .await
+            .unwrap();
+        assert!(limiter.controller.falling_back());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn points_are_checked_on_their_units() {
+        use crate::config_structs::UnitConfig;
+        use crate::sunspec_poll::Polling;
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let point = |point: &str, interval: u64, input: bool| PointConfig {
+            point: Some(point.to_string()),
+            interval,
+            inputs: input.then_some(InputType::Number(crate::config_structs::Numerable {
+                min: 0.0,
+                max: 100.0,
+                step: None,
+                mode: None,
+            })),
+            ..Default::default()
+        };
+        let unit = |addr: &str, models: Vec<(&str, PointConfig)>| UnitConfig {
+            addr: addr.to_string(),
+            slaves: vec![1],
+            points: Some(
+                models
+                    .into_iter()
+                    .map(|(model, p)| (model.to_string(), vec![p]))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let mut config = config();
+        config.meter.serial_number = "M2".to_string();
+        config.limit.serial_number = "INV2".to_string();
+        let reading = || {
+            SinkMessage::History(
+                "M2.203.W".to_string(),
+                StatePayload {
+                    value: PayloadValueType::Float(-500.0),
+                    ..Default::default()
+                },
+            )
+        };
+
+        // the limit is only configured on the meter, which can't be told until it's polled
+        let gateway = GatewayConfig {
+            units: vec![
+                unit(
+                    "meter:502",
+                    vec![
+                        ("203", point("W", 5, false)),
+                        ("123", point("WMaxLimPct", 60, true)),
+                    ],
+                ),
+                unit("inverter:502", vec![("203", point("W", 60, false))]),
+            ],
+            ..Default::default()
+        };
+        let mut limiter = ExportLimiter::new(config.clone(), &gateway, tx.clone()).unwrap();
+        let _meter = Polling::start("M2", "meter:502", 1);
+        limiter.send(reading()).await.unwrap();
+        // the inverter isn't polled yet
+        assert!(!limiter.misconfigured);
+        assert!(rx.try_recv().is_ok());
+        let _inverter = Polling::start("INV2", "inverter:502", 1);
+        limiter.send(reading()).await.unwrap();
+        assert!(limiter.misconfigured);
+        limiter.tick().await.unwrap();
+        assert!(rx.try_recv().is_err());
+
+        // once they're where they're expected, the meter's own interval is what goes stale
+        let gateway = GatewayConfig {
+            units: vec![
+                unit("meter:502", vec![("203", point("W", 20, false))]),
+                unit("inverter:502", vec![("123", point("WMaxLimPct", 60, true))]),
+            ],
+            ..Default::default()
+        };
+        let mut limiter = ExportLimiter::new(config, &gateway, tx).unwrap();
+        limiter.send(reading()).await.unwrap();
+        assert!(!limiter.misconfigured);
+        assert_eq!(limiter.stale_after, 60);
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn limits_are_written_in_the_units_scale() {
+        use crate::config_structs::{Numerable, UnitConfig};
+        use crate::modbus_server::test_unit;
+        use crate::sunspec_poll::write_inbound;
+        use crate::write_policy::READ_ONLY_LOCK;
+
+        // WMaxLimPct in tenths of a percent
+        let mut controls = vec![0; 24];
+        controls[21] = -1_i16 as u16;
+        let unit = test_unit("LIMIT1", vec![(123, controls)]).await;
+        let limit = PointConfig {
+            point: Some("WMaxLimPct".to_string()),
+            inputs: Some(InputType::Number(Numerable {
+                min: 0.0,
+                max: 100.0,
+                step: None,
+                mode: None,
+            })),
+            ..Default::default()
+        };
+        crate::SETTINGS.write().await.units.push(UnitConfig {
+            addr: unit.addr.clone(),
+            slaves: vec![unit.slave_id],
+            points: Some(HashMap::from([("123".to_string(), vec![limit.clone()])])),
+            ..Default::default()
+        });
+        let gateway = GatewayConfig {
+            models: HashMap::from([
+                (
+                    "203".to_string(),
+                    vec![PointConfig {
+                        point: Some("W".to_string()),
+                        interval: 10,
+                        ..Default::default()
+                    }],
+                ),
+                ("123".to_string(), vec![limit]),
+            ]),
+            ..Default::default()
+        };
+        let mut config = config();
+        config.meter.serial_number = "M3".to_string();
+        config.limit.serial_number = "LIMIT1".to_string();
+        config.fallback_limit = Some(40.0);
+        config.dry_run = Some(true);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let mut limiter = ExportLimiter::new(config, &gateway, tx).unwrap();
+        limiter
+            .send(SinkMessage::History(
+                "M3.203.W".to_string(),
+                StatePayload {
+                    value: PayloadValueType::Float(-500.0),
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        let Ok(IPCMessage::Inbound(write)) = rx.try_recv() else {
+            panic!("no limit was written");
+        };
+        assert_eq!(write.payload, "40");
+
+        let _read_only = READ_ONLY_LOCK.lock().await;
+        let record = write_inbound(&unit, &write).await;
+        assert_eq!(record.error, None);
+        assert_eq!(record.raw_value, Some(400));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::config_structs::{InputType, Numerable, SelectOption, Switchable};
+use crate::payload::{HAConfigPayload, PayloadValueType};
+use sunspec_rs::sunspec_connection::apply_scale_factor;
+use sunspec_rs::sunspec_models::Symbol;
+
+/// Errors from turning a Home Assistant command payload into a register value
+#[derive(Error, Debug, PartialEq)]
+pub enum InputError {
+    #[error("{0} isn't one of the configured options")]
+    UnknownOption(String),
+    #[error("{0} doesn't match a symbol or numeric value for this point")]
+    UnknownSymbol(String),
+    #[error("{0} isn't a number")]
+    NotANumber(String),
+    #[error("{value} is outside of {min}<->{max}")]
+    OutOfRange { value: f64, min: f64, max: f64 },
+}
+
+/// The raw register value for a symbol id (e.g. `ENABLED`) or a plain numeric string.
+pub fn symbol_value(symbols: Option<&[Symbol]>, s: &str) -> Option<i64> {
+    if let Some(symbol) = symbols
+        .unwrap_or_default()
+        .iter()
+        .find(|symbol| symbol.id == s)
+    {
+        return symbol.symbol.parse::<i64>().ok();
+    }
+    s.parse::<i64>().ok()
+}
+
+/// The raw register value behind whatever we read for a point.  Enums are read as their symbol
+/// id, or as `ENUM16_{n}` when the value has no symbol.
+pub fn raw_value(symbols: Option<&[Symbol]>, value: &PayloadValueType) -> Option<i64> {
+    match value {
+        PayloadValueType::Int(i) => Some(*i),
+        PayloadValueType::Float(f) if f.fract() == 0.0 => Some(*f as i64),
+        PayloadValueType::String(s) => symbol_value(symbols, s).or_else(|| {
+            s.strip_prefix("ENUM16_")
+                .or_else(|| s.strip_prefix("ENUM32_"))
+                .and_then(|n| n.parse::<i64>().ok())
+        }),
+        _ => None,
+    }
+}
+
+/// The select option (by label) that a value read from the device corresponds to.
+pub fn select_state(
+    options: &[SelectOption],
+    symbols: Option<&[Symbol]>,
+    value: &PayloadValueType,
+) -> Option<String> {
+    let raw = raw_value(symbols, value)?;
+    options
+        .iter()
+        .find(|o| symbol_value(symbols, o.value()) == Some(raw))
+        .map(|o| o.label().to_string())
+}
+
+/// `on` or `off` (as configured) for a value read from the device.
+pub fn switch_state(
+    switch: &Switchable,
+    symbols: Option<&[Symbol]>,
+    value: &PayloadValueType,
+) -> Option<String> {
+    let raw = raw_value(symbols, value)?;
+    if symbol_value(symbols, &switch.on) == Some(raw) {
+        Some(switch.on.clone())
+    } else if symbol_value(symbols, &switch.off) == Some(raw) {
+        Some(switch.off.clone())
+    } else {
+        None
+    }
+}
+
+/// The step a number entity moves in: the configured step, or one unit of the register.  A
+/// register's unit is its scale factors together: the configured `scale_factor` and the one the
+/// unit applies itself (e.g. `WMaxLimPct_SF`).
+pub fn number_step(number: &Numerable, scale_factor: Option<i32>, model_scale_factor: i32) -> f64 {
+    let sf = scale_factor.unwrap_or(0) + model_scale_factor;
+    match number.step {
+        Some(step) => step,
+        None if sf < 0 => apply_scale_factor(1, sf),
+        None => 1.0,
+    }
+}
+
+/// Fill in the parts of a discovery payload that are specific to the entity's input type.
+pub fn apply_input_config(
+    config: &mut HAConfigPayload,
+    input: &InputType,
+    scale_factor: Option<i32>,
+    model_scale_factor: i32,
+) {
+    match input {
+        InputType::Select(options) => {
+            config.options = Some(options.iter().map(|o| o.label().to_string()).collect());
+        }
+        InputType::Switch(switch) => {
+            config.payload_on = Some(switch.on.clone());
+            config.payload_off = Some(switch.off.clone());
+            config.state_on = Some(switch.on.clone());
+            config.state_off = Some(switch.off.clone());
+        }
+        InputType::Button(button) => {
+            config.payload_press = Some(button.clone());
+        }
+        InputType::Number(number) => {
+            config.min = Some(number.min);
+            config.max = Some(number.max);
+            config.step = Some(number_step(number, scale_factor, model_scale_factor));
+            config.mode = number.mode.clone();
+        }
+    }
+}
+
+/// The state Home Assistant expects for an input entity, when it differs from the value we read.
+pub fn input_state(
+    input: &InputType,
+    symbols: Option<&[Symbol]>,
+    value: &PayloadValueType,
+) -> Option<PayloadValueType> {
+    match input {
+        InputType::Select(options) => {
+            select_state(options, symbols, value).map(PayloadValueType::String)
+        }
+        InputType::Switch(switch) => {
+            switch_state(switch, symbols, value).map(PayloadValueType::String)
+        }
+        InputType::Button(_) | InputType::Number(_) => None,
+    }
+}
+
+/// Turn a command payload from Home Assistant into the raw value to write to the register.  Numbers
+/// are in engineering units, so both the configured `scale_factor` and the unit's own
+/// `model_scale_factor` are undone.
+pub fn command_value(
+    input: &InputType,
+    symbols: Option<&[Symbol]>,
+    scale_factor: Option<i32>,
+    model_scale_factor: i32,
+    payload: &str,
+) -> Result<i64, InputError> {
+    let symbol =
+        |s: &str| symbol_value(symbols, s).ok_or_else(|| InputError::UnknownSymbol(s.to_string()));
+    match input {
+        InputType::Select(options) => {
+            match options
+                .iter()
+                .find(|o| o.label() == payload || o.value() == payload)
+            {
+                Some(option) => symbol(option.value()),
+                None => Err(InputError::UnknownOption(payload.to_string())),
+            }
+        }
+        InputType::Switch(switch) => {
+            if payload == switch.on || payload == switch.off {
+                symbol(payload)
+            } else {
+                Err(InputError::UnknownOption(payload.to_string()))
+            }
+        }
+        InputType::Button(button) => {
+            if payload == button {
+                symbol(payload)
+            } else {
+                Err(InputError::UnknownOption(payload.to_string()))
+            }
+        }
+        InputType::Number(number) => {
+            let value = payload
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| InputError::NotANumber(payload.to_string()))?;
+            if value < number.min || value > number.max {
+                return Err(InputError::OutOfRange {
+                    value,
+                    min: number.min,
+                    max: number.max,
+                });
+            }
+            let sf = scale_factor.unwrap_or(0) + model_scale_factor;
+            Ok(apply_scale_factor(value, -sf).round() as i64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Vec<Symbol> {
+        vec![
+            Symbol {
+                id: "GRID_TIE".to_string(),
+                symbol: "0".to_string(),
+            },
+            Symbol {
+                id: "SELF_SUPPLY".to_string(),
+                symbol: "1".to_string(),
+            },
+            Symbol {
+                id: "DISABLED".to_string(),
+                symbol: "0".to_string(),
+            },
+            Symbol {
+                id: "ENABLED".to_string(),
+                symbol: "1".to_string(),
+            },
+        ]
+    }
+
+    fn select() -> InputType {
+        InputType::Select(vec![
+            SelectOption::Symbol("GRID_TIE".to_string()),
+            SelectOption::Labeled {
+                label: "Self Supply".to_string(),
+                value: "SELF_SUPPLY".to_string(),
+            },
+        ])
+    }
+
+    fn switch() -> InputType {
+        InputType::Switch(Switchable {
+            on: "ENABLED".to_string(),
+            off: "DISABLED".to_string(),
+        })
+    }
+
+    fn number() -> InputType {
+        InputType::Number(Numerable {
+            min: 0.0,
+            max: 100.0,
+            step: None,
+            mode: None,
+        })
+    }
+
+    #[test]
+    fn select_state_is_an_option_label() {
+        let s = symbols();
+        let sym = Some(s.as_slice());
+        let state = |v: PayloadValueType| match input_state(&select(), sym, &v) {
+            Some(PayloadValueType::String(s)) => Some(s),
+            _ => None,
+        };
+        assert_eq!(
+            state(PayloadValueType::String("GRID_TIE".to_string())),
+            Some("GRID_TIE".to_string())
+        );
+        assert_eq!(
+            state(PayloadValueType::String("SELF_SUPPLY".to_string())),
+            Some("Self Supply".to_string())
+        );
+        assert_eq!(
+            state(PayloadValueType::Int(1)),
+            Some("Self Supply".to_string())
+        );
+        assert_eq!(
+            state(PayloadValueType::String("ENUM16_1".to_string())),
+            Some("Self Supply".to_string())
+        );
+        assert_eq!(state(PayloadValueType::Int(7)), None);
+
+        let mut config = HAConfigPayload::default();
+        apply_input_config(&mut config, &select(), None, 0);
+        assert_eq!(
+            config.options,
+            Some(vec!["GRID_TIE".to_string(), "Self Supply".to_string()])
+        );
+    }
+
+    #[test]
+    fn select_commands_map_back_to_symbols() {
+        let s = symbols();
+        let sym = Some(s.as_slice());
+        assert_eq!(command_value(&select(), sym, None, 0, "Self Supply"), Ok(1));
+        assert_eq!(command_value(&select(), sym, None, 0, "SELF_SUPPLY"), Ok(1));
+        assert_eq!(command_value(&select(), sym, None, 0, "GRID_TIE"), Ok(0));
+        assert_eq!(
+            command_value(&select(), sym, None, 0, "SELL"),
+            Err(InputError::UnknownOption("SELL".to_string()))
+        );
+    }
+
+    #[test]
+    fn switch_state_matches_state_on_and_off() {
+        let s = symbols();
+        let sym = Some(s.as_slice());
+        let mut config = HAConfigPayload::default();
+        apply_input_config(&mut config, &switch(), None, 0);
+        let state_on = config.state_on.unwrap();
+        let state_off = config.state_off.unwrap();
+
+        for (read, expected) in [
+            (PayloadValueType::String("ENABLED".to_string()), &state_on),
+            (PayloadValueType::Int(1), &state_on),
+            (PayloadValueType::Float(1.0), &state_on),
+            (PayloadValueType::String("DISABLED".to_string()), &state_off),
+            (PayloadValueType::Int(0), &state_off),
+        ] {
+            match input_state(&switch(), sym, &read) {
+                Some(PayloadValueType::String(s)) => assert_eq!(&s, expected),
+                other => panic!("unexpected switch state {other:?} for {read:?}"),
+            }
+        }
+        assert_eq!(command_value(&switch(), sym, None, 0, &state_on), Ok(1));
+        assert_eq!(command_value(&switch(), sym, None, 0, &state_off), Ok(0));
+        assert!(command_value(&switch(), sym, None, 0, "on").is_err());
+    }
+
+    #[test]
+    fn numeric_switches_without_symbols() {
+        let numeric = InputType::Switch(Switchable {
+            on: "1".to_string(),
+            off: "0".to_string(),
+        });
+        match input_state(&numeric, None, &PayloadValueType::Int(1)) {
+            Some(PayloadValueType::String(s)) => assert_eq!(s, "1"),
+            other => panic!("unexpected switch state {other:?}"),
+        }
+        assert_eq!(command_value(&numeric, None, None, 0, "0"), Ok(0));
+    }
+
+    #[test]
+    fn number_uses_engineering_units() {
+        let mut config = HAConfigPayload::default();
+        apply_input_config(&mut config, &number(), Some(-1), 0);
+        assert_eq!(config.min, Some(0.0));
+        assert_eq!(config.max, Some(100.0));
+        assert_eq!(config.step, Some(0.1));
+        assert!(input_state(&number(), None, &PayloadValueType::Float(42.5)).is_none());
+
+        assert_eq!(command_value(&number(), None, Some(-1), 0, "42.5"), Ok(425));
+        assert_eq!(command_value(&number(), None, None, 0, "42"), Ok(42));
+        assert!(matches!(
+            command_value(&number(), None, None, 0, "101"),
+            Err(InputError::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            command_value(&number(), None, None, 0, "lots"),
+            Err(InputError::NotANumber(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn numbers_undo_the_units_scale_factor() {
+        // immediate controls, with WMaxLimPct in tenths of a percent
+        let mut controls = vec![0; 24];
+        controls[21] = -1_i16 as u16;
+        let unit = crate::modbus_server::test_unit("SCALED1", vec![(123, controls)]).await;
+        let path = "WMaxLimPct".parse().unwrap();
+        let sf = unit.scale_factor(123, &path).await.unwrap();
+        assert_eq!(sf, -1);
+        assert_eq!(
+            unit.scale_factor(123, &"Conn".parse().unwrap()).await,
+            Some(0)
+        );
+
+        assert_eq!(command_value(&number(), None, None, sf, "50"), Ok(500));
+        // on top of a configured scale factor
+        assert_eq!(command_value(&number(), None, Some(-1), sf, "50"), Ok(5000));
+        let mut config = HAConfigPayload::default();
+        apply_input_config(&mut config, &number(), None, sf);
+        assert_eq!(config.step, Some(0.1));
+    }
+
+    #[test]
+    fn button_presses_resolve_to_register_values() {
+        let s = vec![Symbol {
+            id: "CLEAR_ERROR".to_string(),
+            symbol: "1".to_string(),
+        }];
+        let button = InputType::Button("CLEAR_ERROR".to_string());
+        let mut config = HAConfigPayload::default();
+        apply_input_config(&mut config, &button, None, 0);
+        assert_eq!(config.payload_press, Some("CLEAR_ERROR".to_string()));
+        assert_eq!(
+            command_value(&button, Some(s.as_slice()), None, 0, "CLEAR_ERROR"),
+            Ok(1)
+        );
+        assert_eq!(
+            command_value(&InputType::Button("1".to_string()), None, None, 0, "1"),
+            Ok(1)
+        );
+        assert!(command_value(&button, Some(s.as_slice()), None, 0, "PRESS").is_err());
+    }
+}
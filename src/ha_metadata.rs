@@ -0,0 +1,169 @@
+use crate::payload::EntityCategory;
+use sunspec_rs::sunspec_models::Point;
+
+/// Home Assistant metadata that can be worked out from a point's model definition, for when the
+/// config doesn't specify it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HaMetadata {
+    pub device_class: Option<String>,
+    pub state_class: Option<String>,
+    /// the unit of measure, in the form Home Assistant expects it
+    pub uom: Option<String>,
+    pub entity_category: Option<EntityCategory>,
+}
+
+fn is_accumulator(point_type: &str) -> bool {
+    point_type.starts_with("acc")
+}
+
+fn is_numeric(point_type: &str) -> bool {
+    matches!(
+        point_type,
+        "int16"
+            | "uint16"
+            | "int32"
+            | "uint32"
+            | "int64"
+            | "uint64"
+            | "acc16"
+            | "acc32"
+            | "acc64"
+            | "float32"
+            | "float64"
+    )
+}
+
+/// The unit Home Assistant uses for a SunSpec unit, where they're spelled differently.
+pub fn ha_unit(units: &str) -> &str {
+    match units {
+        "C" => "°C",
+        "Pct" => "%",
+        "Secs" => "s",
+        "VAr" => "var",
+        "varh" | "VArh" => "varh",
+        other => other,
+    }
+}
+
+/// The device class (and whether it's an accumulating total) for a Home Assistant unit.
+/// Energy that isn't an accumulator (a battery's capacity, say) is stored energy, which Home
+/// Assistant won't accept as a `total_increasing` energy sensor.
+fn device_class(uom: &str, accumulator: bool) -> Option<&'static str> {
+    match (uom, accumulator) {
+        ("W" | "kW", _) => Some("power"),
+        ("VA", _) => Some("apparent_power"),
+        ("var", _) => Some("reactive_power"),
+        ("Wh" | "kWh", true) => Some("energy"),
+        ("Wh" | "kWh", false) => Some("energy_storage"),
+        ("varh", true) => Some("reactive_energy"),
+        ("V", _) => Some("voltage"),
+        ("A", _) => Some("current"),
+        ("Hz", _) => Some("frequency"),
+        ("°C", _) => Some("temperature"),
+        ("s", _) => Some("duration"),
+        _ => None,
+    }
+}
+
+/// Work out Home Assistant metadata from a point's SunSpec type and units, or from `uom` when
+/// the config gives the point one of its own.
+///
+/// Numeric points with units are measurements, accumulators are `total_increasing`, and enums
+/// and bitfields (status and event points) are diagnostic.
+pub fn from_point(point: &Point, uom: Option<&str>) -> HaMetadata {
+    let point_type = point.r#type.as_str();
+    if point_type.starts_with("enum") || point_type.starts_with("bitfield") {
+        return HaMetadata {
+            entity_category: Some(EntityCategory::Diagnostic),
+            ..HaMetadata::default()
+        };
+    }
+    let uom = uom.or(point.units.as_deref()).map(ha_unit);
+    if !is_numeric(point_type) {
+        return HaMetadata {
+            uom: uom.map(String::from),
+            ..HaMetadata::default()
+        };
+    }
+    let accumulator = is_accumulator(point_type);
+    let device_class = uom.and_then(|u| device_class(u, accumulator));
+    let state_class = if accumulator {
+        Some("total_increasing")
+    } else if uom.is_some() {
+        Some("measurement")
+    } else {
+        None
+    };
+    HaMetadata {
+        device_class: device_class.map(String::from),
+        state_class: state_class.map(String::from),
+        uom: uom.map(String::from),
+        entity_category: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(point_type: &str, units: Option<&str>) -> Point {
+        Point {
+            id: "P".to_string(),
+            offset: 0,
+            r#type: point_type.to_string(),
+            len: None,
+            mandatory: None,
+            access: None,
+            symbol: None,
+            units: units.map(String::from),
+            scale_factor: None,
+            value: None,
+            literal: None,
+            block_id: None,
+        }
+    }
+
+    #[test]
+    fn metadata_follows_units_and_types() {
+        let power = from_point(&point("int16", Some("W")), None);
+        assert_eq!(power.device_class.as_deref(), Some("power"));
+        assert_eq!(power.state_class.as_deref(), Some("measurement"));
+
+        let energy = from_point(&point("acc32", Some("Wh")), None);
+        assert_eq!(energy.device_class.as_deref(), Some("energy"));
+        assert_eq!(energy.state_class.as_deref(), Some("total_increasing"));
+
+        let capacity = from_point(&point("uint16", Some("Wh")), None);
+        assert_eq!(capacity.device_class.as_deref(), Some("energy_storage"));
+        assert_eq!(capacity.state_class.as_deref(), Some("measurement"));
+
+        let temperature = from_point(&point("int16", Some("C")), None);
+        assert_eq!(temperature.device_class.as_deref(), Some("temperature"));
+        assert_eq!(temperature.uom.as_deref(), Some("°C"));
+
+        let percent = from_point(&point("uint16", Some("Pct")), None);
+        assert_eq!(percent.device_class, None);
+        assert_eq!(percent.uom.as_deref(), Some("%"));
+
+        // the config's unit is what the value is published in, so it decides the class
+        let kilowatts = from_point(&point("int16", Some("W")), Some("kW"));
+        assert_eq!(kilowatts.device_class.as_deref(), Some("power"));
+        assert_eq!(kilowatts.uom.as_deref(), Some("kW"));
+        let share = from_point(&point("uint16", Some("Wh")), Some("%"));
+        assert_eq!(share.device_class, None);
+        assert_eq!(share.state_class.as_deref(), Some("measurement"));
+
+        let count = from_point(&point("uint16", None), None);
+        assert_eq!(count, HaMetadata::default());
+    }
+
+    #[test]
+    fn status_and_events_are_diagnostic() {
+        for point_type in ["enum16", "bitfield32"] {
+            let meta = from_point(&point(point_type, None), None);
+            assert_eq!(meta.entity_category, Some(EntityCategory::Diagnostic));
+            assert_eq!(meta.device_class, None);
+            assert_eq!(meta.state_class, None);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::config_structs::{HttpServerConfig, HttpsConfig};
+use crate::consts::*;
+use anyhow::Context;
+use axum::http::header::HOST;
+use axum::http::uri::Authority;
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+/// Starts serving `app` as configured: plain http, or https along with an optional listener that
+/// redirects plain http to it.  Returns the address the app is served on, once everything is bound.
+pub async fn start(app: Router, config: HttpServerConfig) -> anyhow::Result<SocketAddr> {
+    let Some(tls) = config.tls else {
+        let listen = config.listen.unwrap_or(DEFAULT_HTTP_LISTEN.to_string());
+        let listener = TcpListener::bind(&listen)
+            .await
+            .with_context(|| format!("Couldn't listen for http on {listen}"))?;
+        let addr = listener.local_addr()?;
+        info!("Serving http on {addr}");
+        let _ = tokio::task::Builder::new()
+            .name("axum-listener")
+            .spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    error!("Http server stopped: {e}");
+                }
+            });
+        return Ok(addr);
+    };
+
+    let rustls = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Couldn't load certificate {} and key {}",
+                tls.cert_path, tls.key_path
+            )
+        })?;
+    let listen = config.listen.unwrap_or(DEFAULT_HTTPS_LISTEN.to_string());
+    let listener = TcpListener::bind(&listen)
+        .await
+        .with_context(|| format!("Couldn't listen for https on {listen}"))?;
+    let addr = listener.local_addr()?;
+
+    if let Some(redirect_listen) = config.redirect_listen {
+        let redirect_listener = TcpListener::bind(&redirect_listen)
+            .await
+            .with_context(|| format!("Couldn't listen for http on {redirect_listen}"))?;
+        info!("Redirecting http on {redirect_listen} to https");
+        let https_port = addr.port();
+        let redirect = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+            redirect_to_https(&headers, &uri, https_port)
+        });
+        let _ = tokio::task::Builder::new()
+            .name("https-redirect")
+            .spawn(async move {
+                if let Err(e) = axum::serve(redirect_listener, redirect).await {
+                    error!("Https redirect server stopped: {e}");
+                }
+            });
+    }
+
+    let _ = tokio::task::Builder::new()
+        .name("certificate-reload")
+        .spawn(watch_certificate(rustls.clone(), tls));
+
+    info!("Serving https on {addr}");
+    let server = axum_server::from_tcp_rustls(listener.into_std()?, rustls);
+    let _ = tokio::task::Builder::new()
+        .name("axum-listener")
+        .spawn(async move {
+            if let Err(e) = server.serve(app.into_make_service()).await {
+                error!("Https server stopped: {e}");
+            }
+        });
+    Ok(addr)
+}
+
+fn redirect_to_https(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    let host = headers.get(HOST).and_then(|h| h.to_str().ok());
+    match https_location(host, uri, https_port) {
+        Some(location) => Redirect::permanent(&location).into_response(),
+        None => (StatusCode::BAD_REQUEST, "use https").into_response(),
+    }
+}
+
+/// Where a plain http request should go instead: the same host and path, over https.
+fn https_location(host: Option<&str>, uri: &Uri, https_port: u16) -> Option<String> {
+    let authority: Authority = host?.parse().ok()?;
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    match https_port {
+        443 => Some(format!("https://{}{path}", authority.host())),
+        port => Some(format!("https://{}:{port}{path}", authority.host())),
+    }
+}
+
+fn modified(tls: &HttpsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(&tls.cert_path).and_then(|m| m.modified());
+    let key = std::fs::metadata(&tls.key_path).and_then(|m| m.modified());
+    Some((cert.ok()?, key.ok()?))
+}
+
+/// Loads the certificate and key again whenever either file changes.
+async fn watch_certificate(rustls: RustlsConfig, tls: HttpsConfig) {
+    let interval = Duration::from_secs(tls.reload_interval.unwrap_or(DEFAULT_TLS_RELOAD_SECS));
+    let mut loaded = modified(&tls);
+    loop {
+        sleep(interval).await;
+        let current = modified(&tls);
+        if current == loaded {
+            continue;
+        }
+        // a failed reload (say the key hasn't been written yet) keeps the old certificate, and is
+        // tried again next time
+        match rustls
+            .reload_from_pem_file(&tls.cert_path, &tls.key_path)
+            .await
+        {
+            Ok(_) => {
+                info!("Reloaded certificate {}", tls.cert_path);
+                loaded = current;
+            }
+            Err(e) => warn!("Couldn't reload certificate {}: {e}", tls.cert_path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+    use reqwest::tls::TlsInfo;
+
+    /// A self-signed certificate and its key, as PEM, and the certificate as DER.
+    fn certificate(name: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+        (
+            cert.to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+            cert.to_der().unwrap(),
+        )
+    }
+
+    #[test]
+    fn redirects_keep_host_and_path() {
+        let uri: Uri = "/ui/index.html?x=1".parse().unwrap();
+        assert_eq!(
+            https_location(Some("gateway:8080"), &uri, 443).as_deref(),
+            Some("https://gateway/ui/index.html?x=1")
+        );
+        assert_eq!(
+            https_location(Some("[fd00::1]"), &"/".parse().unwrap(), 8443).as_deref(),
+            Some("https://[fd00::1]:8443/")
+        );
+        assert_eq!(https_location(None, &uri, 443), None);
+    }
+
+    #[tokio::test]
+    async fn certificate_is_reloaded_when_it_changes() {
+        let dir = std::env::temp_dir().join(format!("sunspec_gateway_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tls = HttpsConfig {
+            cert_path: dir.join("cert.pem").to_string_lossy().to_string(),
+            key_path: dir.join("key.pem").to_string_lossy().to_string(),
+            reload_interval: Some(1),
+        };
+        let (cert, key, first) = certificate("first");
+        std::fs::write(&tls.cert_path, cert).unwrap();
+        std::fs::write(&tls.key_path, key).unwrap();
+
+        let config = HttpServerConfig {
+            listen: Some("127.0.0.1:0".to_string()),
+            tls: Some(tls.clone()),
+            redirect_listen: None,
+        };
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let addr = start(app, config).await.unwrap();
+
+        let served_certificate = || async {
+            let client = reqwest::Client::builder()
+                .danger_accept_invalid_certs(true)
+                .tls_info(true)
+                .build()
+                .unwrap();
+            let response = client.get(format!("https://{addr}/")).send().await.unwrap();
+            let cert = response
+                .extensions()
+                .get::<TlsInfo>()
+                .and_then(|t| t.peer_certificate())
+                .map(|c| c.to_vec());
+            assert_eq!(response.text().await.unwrap(), "ok");
+            cert
+        };
+        assert_eq!(served_certificate().await, Some(first));
+
+        // make sure the files look modified even on filesystems with coarse timestamps
+        sleep(Duration::from_millis(1100)).await;
+        let (cert, key, second) = certificate("second");
+        std::fs::write(&tls.key_path, key).unwrap();
+        std::fs::write(&tls.cert_path, cert).unwrap();
+        sleep(Duration::from_millis(2500)).await;
+        assert_eq!(served_certificate().await, Some(second));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,17 +1,47 @@
-use crate::payload::Payload;
+use crate::payload::{Payload, StatePayload};
+use crate::point_path::PointPath;
+use rumqttc::QoS;
+
+/// Where a write came from, as it's recorded in the audit log.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WriteSource {
+    /// a command topic, ours or sparkplug's
+    Mqtt(String),
+    /// a user of the REST API, by login
+    Rest(String),
+    /// a Modbus TCP client, by address
+    Modbus(String),
+    /// something the gateway does by itself
+    Automation(String),
+}
+impl std::fmt::Display for WriteSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteSource::Mqtt(topic) => write!(f, "mqtt:{topic}"),
+            WriteSource::Rest(login) => write!(f, "rest:{login}"),
+            WriteSource::Modbus(peer) => write!(f, "modbus:{peer}"),
+            WriteSource::Automation(name) => write!(f, "automation:{name}"),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct InboundMessage {
     pub serial_number: String,
     pub model: String,
-    pub point_name: String,
+    pub point: PointPath,
     pub payload: String,
+    pub source: WriteSource,
+    /// go through every check, but don't write to the unit
+    pub dry_run: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct PublishMessage {
     pub(crate) topic: String,
     pub(crate) payload: Payload,
+    pub(crate) qos: QoS,
+    pub(crate) retain: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -25,7 +55,11 @@ pub struct IPCError {
 pub enum IPCMessage {
     Inbound(InboundMessage),
     Outbound(PublishMessage),
+    /// a reading to keep in the point history, under the point's unique id
+    History(String, StatePayload),
     PleaseReconnect(String, u8),
+    /// a unit, by serial number, has stopped answering and is being reconnected
+    UnitOffline(String),
     Error(IPCError),
     Shutdown,
 }
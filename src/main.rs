@@ -4,42 +4,69 @@ extern crate strum;
 #[macro_use]
 extern crate thiserror;
 
+mod audit;
 mod auth;
 mod cli_args;
 mod config_structs;
 mod consts;
+mod counter_tracking;
 mod date_serializer;
+mod device_hierarchy;
+mod discovery;
+mod energy_integration;
+mod events;
+mod export_limit;
+mod ha_inputs;
+mod ha_metadata;
+mod http_server;
 mod ipc;
+mod modbus_server;
 mod modules;
 mod monitored_point;
 mod mqtt_connection;
 mod mqtt_poll;
 mod payload;
+mod point_path;
+mod profiles;
+mod publish_policy;
+mod repeating_group;
 mod routes;
+mod scheduler;
+mod sinks;
 mod state;
 mod state_mgmt;
 mod sunspec_poll;
 mod sunspec_unit;
+mod symbol_points;
+mod write_policy;
 
-use crate::auth::token_middleware::auth_middleware;
+use crate::auth::session_store::SqliteSessionStore;
 use crate::config_structs::GatewayConfig;
-use crate::routes::USERS_TAG;
-use axum::middleware;
-use std::net::Ipv6Addr;
+use crate::config_structs::SameSiteConfig;
+use crate::state_mgmt::delete_expired_sessions;
 use tokio::net::TcpListener;
 use tower_http::services::ServeDir;
+use tower_sessions::cookie::SameSite;
 use tower_sessions::Expiry;
-use tower_sessions::MemoryStore;
 use tower_sessions::SessionManagerLayer;
 use utoipa_axum::router::OpenApiRouter;
 
 use crate::consts::*;
-use crate::ipc::{IPCMessage, InboundMessage, PublishMessage};
-use crate::modules::points::point_routes;
+use crate::device_hierarchy::hierarchy_messages;
+use crate::export_limit::ExportLimiter;
+use crate::ipc::{IPCMessage, InboundMessage};
+use crate::modbus_server::FACADE;
 use crate::mqtt_connection::MqttConnection;
 use crate::mqtt_poll::mqtt_poll_loop;
+use crate::scheduler::{load_config_schedules, scheduler_loop};
+use crate::sinks::history::HistorySink;
+use crate::sinks::http::HttpSink;
+use crate::sinks::mqtt::MqttSink;
+use crate::sinks::sparkplug::{EdgeNode, SparkplugSink};
+use crate::sinks::{Dispatcher, SinkMessage, SINKS};
 use crate::state_mgmt::prepare_to_database;
 use crate::sunspec_poll::poll_loop;
+use crate::write_policy::load_read_only;
 
 use console_subscriber as tokio_console_subscriber;
 use futures::FutureExt;
@@ -56,20 +83,22 @@ use std::fs;
 use std::process;
 
 use crate::auth::token_extractor::JwksCache;
-use crate::modules::users::user_routes;
-use crate::routes::{openapi, register_routes, ApiDoc};
+use crate::modules::users::bootstrap_admin;
+use crate::routes::{openapi, protected_routes, register_routes, ApiDoc};
 use crate::state::AppState;
 use axum::http::Method;
 use axum::response::Redirect;
 use axum::routing::get;
+use cached::UnboundCache;
 use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use std::sync::Arc;
 use std::time::Duration;
 use sunspec_rs::model_data::ModelData;
 use sunspec_rs::sunspec_connection::TlsConfig;
 use sunspec_unit::SunSpecUnit;
 use tokio::sync::{broadcast, mpsc, OnceCell, RwLock};
 use tokio::task;
-use tokio::time::{sleep, timeout, Instant};
+use tokio::time::sleep;
 use tower_http::cors::{Any, CorsLayer};
 use tower_sessions::cookie::time::Duration as CookieDuration;
 use tracing::Instrument;
@@ -108,11 +137,14 @@ lazy_static! {
             die(&format!("Can't read config file: {e}"));
             String::default()
             });
-        let gc: GatewayConfig = match serde_yaml::from_str(&yaml)  {
+        let mut gc: GatewayConfig = match serde_yaml::from_str(&yaml)  {
             Ok(gc) => gc,
             Err(e) => { die(&format!("Couldn't deserialize GatewayConfig: {e}"));
             GatewayConfig::default()}
         };
+        if let Err(e) = profiles::resolve(&mut gc) {
+            die(&format!("Can't work out the points of units with profiles: {e:#}"));
+        }
         gc
     });
     //endregion
@@ -191,21 +223,51 @@ async fn main() {
     if let Err(e) = prepare_to_database().await {
         die(&format!("Can't database: {e}"))
     }
+    if let Err(e) = bootstrap_admin().await {
+        die(&format!("Can't create the first user: {e}"))
+    }
+    load_read_only().await;
     //endregion
 
-    let user_cache = None;
+    let user_cache = Some(Arc::new(RwLock::new(UnboundCache::new())));
+    let auth_config = config.auth.clone().unwrap_or_default();
     let state = AppState {
-        jwks_cache: JwksCache::new(),
+        jwks_cache: JwksCache::new(Duration::from_secs(
+            auth_config.jwks_ttl.unwrap_or(DEFAULT_JWKS_TTL_SECS),
+        )),
+        auth_config: Arc::new(auth_config),
         user_cache,
+        ipc_tx: tx.clone(),
+        control_tx: from_mqtt_tx.clone(),
     };
 
     //region axum route setup and serve()
-    let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(false)
+    let http_config = config.http_server.clone().unwrap_or_default();
+    let session_config = config.session.clone().unwrap_or_default();
+    let session_layer = SessionManagerLayer::new(SqliteSessionStore)
+        .with_secure(session_config.secure.unwrap_or(http_config.tls.is_some()))
+        .with_same_site(match session_config.same_site.unwrap_or_default() {
+            SameSiteConfig::Strict => SameSite::Strict,
+            SameSiteConfig::Lax => SameSite::Lax,
+            SameSiteConfig::None => SameSite::None,
+        })
         .with_expiry(Expiry::OnInactivity(CookieDuration::hours(
-            SESSION_INACTIVITY_LIMIT_HOURS,
+            session_config
+                .expiry_hours
+                .unwrap_or(SESSION_INACTIVITY_LIMIT_HOURS),
         )));
+    let _ = task::Builder::new()
+        .name("session-cleanup")
+        .spawn(async move {
+            loop {
+                match delete_expired_sessions().await {
+                    Ok(0) => {}
+                    Ok(n) => debug!("Deleted {n} expired sessions"),
+                    Err(e) => error!("Couldn't delete expired sessions: {e}"),
+                }
+                sleep(Duration::from_secs(SESSION_CLEANUP_SECS)).await;
+            }
+        });
 
     let cors_layer = CorsLayer::new()
         .allow_methods([
@@ -218,29 +280,16 @@ async fn main() {
         .allow_origin(Any)
         .allow_headers(Any);
 
-    let auth_layer = middleware::from_fn_with_state(state.clone(), auth_middleware);
-
     let api = ApiDoc::openapi();
 
     let public_routes = OpenApiRouter::new()
         .merge(register_routes(state.clone()))
         .nest_service("/ui", ServeDir::new("ui"))
-        .nest(
-            &format!("{API_VER}/{POINTS_TAG}"),
-            point_routes(state.clone()),
-        )
         .route(API_PATH, get(openapi));
 
-    let protected_routes = OpenApiRouter::<AppState>::new()
-        .nest(
-            &format!("{API_VER}/{USERS_TAG}"),
-            user_routes(state.clone()),
-        )
-        .layer(auth_layer);
-
     let (router, api) = OpenApiRouter::with_openapi(api)
         .merge(public_routes)
-        .merge(protected_routes)
+        .merge(protected_routes(state.clone()))
         .layer(cors_layer)
         .layer(session_layer)
         .with_state(state)
@@ -253,27 +302,35 @@ async fn main() {
         error!("Couldn't store api into document variable: {e}");
     }
 
-    let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, 8080))
-        .await
-        .expect("Failed to bind");
-    let _ = tokio::task::Builder::new()
-        .name("axum-listener")
-        .spawn(async move {
-            let _ = axum::serve(listener, app).await;
-        });
+    if let Err(e) = http_server::start(app, http_config).await {
+        return die(&format!("{e:#}"));
+    }
 
     //endregion
 
     //region create mqtt server connection and spawn mqtt thread
+    let client_id = config
+        .mqtt_client_id
+        .clone()
+        .unwrap_or("sunspec_gateway".to_string());
+    let edge_node = config
+        .sparkplug
+        .as_ref()
+        .map(|s| EdgeNode::new(s, &client_id));
     let mqtt_conn = match MqttConnection::new(
-        config
-            .mqtt_client_id
-            .clone()
-            .unwrap_or("sunspec_gateway".to_string()),
+        client_id,
         config.mqtt_server_addr.clone(),
         config.mqtt_server_port.unwrap_or(1883),
         config.mqtt_username.clone(),
         config.mqtt_password.clone(),
+        edge_node.as_ref().map(|n| n.last_will()),
+        edge_node
+            .as_ref()
+            .map(|n| n.command_topics())
+            .unwrap_or_default()
+            .into_iter()
+            .chain([MQTT_READ_ONLY_SET_TOPIC.to_string()])
+            .collect(),
     )
     .await
     {
@@ -282,6 +339,45 @@ async fn main() {
             return die("Couldn't create mqtt connection object: {e}");
         }
     };
+    //region run the schedules, with writes going the same way as mqtt's
+    if let Err(e) = load_config_schedules(&config.schedules.clone().unwrap_or_default()).await {
+        return die(&format!("Can't load schedules: {e}"));
+    }
+    let schedule_tx = from_mqtt_tx.clone();
+    let _ = tokio::task::Builder::new()
+        .name("scheduler")
+        .spawn(scheduler_loop(schedule_tx));
+    //endregion
+
+    //region serve the units over modbus, with writes going the same way as mqtt's
+    if let Some(modbus_config) = config.modbus_server.clone() {
+        FACADE
+            .write()
+            .await
+            .configure(modbus_config.unit_ids.unwrap_or_default());
+        let listen = modbus_config
+            .listen
+            .unwrap_or(DEFAULT_MODBUS_SERVER_LISTEN.to_string());
+        let listener = match TcpListener::bind(&listen).await {
+            Ok(l) => l,
+            Err(e) => {
+                return die(&format!("Couldn't listen for modbus on {listen}: {e}"));
+            }
+        };
+        info!("Serving modbus on {listen}");
+        let write_tx = from_mqtt_tx.clone();
+        let _ = tokio::task::Builder::new()
+            .name("modbus_server")
+            .spawn(async move {
+                if let Err(e) = modbus_server::serve(listener, write_tx).await {
+                    error!("Modbus server stopped: {e}");
+                }
+            });
+    }
+    //endregion
+
+    // the export limiter's writes go the same way as mqtt's too
+    let export_limit_tx = from_mqtt_tx.clone();
     let bcasttx = broadcast_tx.clone();
     let mqtt_handler = tokio::task::Builder::new()
         .name("mqtt_thread")
@@ -296,6 +392,46 @@ async fn main() {
         })
         .unwrap();
     //endregion
+
+    //region start the sinks that outbound messages fan out to
+    let mut dispatcher = Dispatcher::default();
+    let queues = config.sinks.clone().unwrap_or_default();
+    if let Some(node) = edge_node {
+        dispatcher.add(
+            Box::new(SparkplugSink::new(node, mqtt_tx.clone())),
+            queues.get("sparkplug"),
+            broadcast_tx.subscribe(),
+        );
+    }
+    dispatcher.add(
+        Box::new(MqttSink::new(mqtt_tx)),
+        queues.get("mqtt"),
+        broadcast_tx.subscribe(),
+    );
+    dispatcher.add(
+        Box::new(HistorySink),
+        queues.get("history"),
+        broadcast_tx.subscribe(),
+    );
+    if let Some(sink_config) = config.http_sink.clone() {
+        dispatcher.add(
+            Box::new(HttpSink::new(sink_config)),
+            queues.get("http"),
+            broadcast_tx.subscribe(),
+        );
+    }
+    if let Some(limit_config) = config.export_limit.clone() {
+        match ExportLimiter::new(limit_config, &config, export_limit_tx) {
+            Ok(limiter) => dispatcher.add(
+                Box::new(limiter),
+                queues.get("export_limit"),
+                broadcast_tx.subscribe(),
+            ),
+            Err(e) => return die(&format!("Can't limit export: {e}")),
+        }
+    }
+    let _ = SINKS.set(dispatcher.handles());
+    //endregion
     let mut retry_queue: VecDeque<(String, u8, DateTime<Utc>)> = VecDeque::new();
     //region populate sunspec devices into an array
     let units = config.units.clone();
@@ -357,7 +493,14 @@ async fn main() {
 
     //region watch the mpsc tasks receive loop
 
-    let mut msg_queue: VecDeque<PublishMessage> = VecDeque::new();
+    let mut msg_queue: VecDeque<SinkMessage> = VecDeque::new();
+    if config.hass_enabled.unwrap_or(true) {
+        msg_queue.extend(
+            hierarchy_messages(&config)
+                .into_iter()
+                .map(SinkMessage::Publish),
+        );
+    }
     let mut incoming_control_queue: VecDeque<InboundMessage> = VecDeque::new();
     loop {
         //endregion
@@ -370,7 +513,13 @@ async fn main() {
                             unreachable!();
                         }
                         IPCMessage::Outbound(o) => {
-                            msg_queue.push_front(o);
+                            msg_queue.push_front(SinkMessage::Publish(o));
+                        }
+                        IPCMessage::History(uniqueid, state) => {
+                            msg_queue.push_front(SinkMessage::History(uniqueid, state));
+                        }
+                        IPCMessage::UnitOffline(sn) => {
+                            msg_queue.push_front(SinkMessage::UnitOffline(sn));
                         }
                         IPCMessage::Error(e) => {
                             die(&format!("serial_number={}: {}", e.serial_number, e.msg));
@@ -451,17 +600,17 @@ async fn main() {
                     IPCMessage::Inbound(inmsg) => {
                         info!(
                             "Received payload for {},{},{}:{}",
-                            inmsg.serial_number, inmsg.model, inmsg.point_name, inmsg.payload
+                            inmsg.serial_number, inmsg.model, inmsg.point, inmsg.payload
                         );
                         incoming_control_queue.push_front(inmsg.clone());
                     }
                     IPCMessage::Shutdown => {
                         unreachable!();
                     }
-                    IPCMessage::Outbound(_) => {
+                    IPCMessage::Outbound(_) | IPCMessage::History(_, _) => {
                         unreachable!();
                     }
-                    IPCMessage::PleaseReconnect(_, _) => {
+                    IPCMessage::PleaseReconnect(_, _) | IPCMessage::UnitOffline(_) => {
                         unreachable!();
                     }
                     IPCMessage::Error(_) => {
@@ -480,21 +629,7 @@ async fn main() {
         }
 
         while let Some(msg) = msg_queue.pop_front() {
-            match timeout(
-                Duration::from_secs(10),
-                mqtt_tx.send(IPCMessage::Outbound(msg)),
-            )
-            .await
-            {
-                Ok(future) => {
-                    if let Err(e) = future {
-                        error!("Unable to send mqtt mpsc tx message: {e}");
-                    }
-                }
-                Err(e) => {
-                    debug!("Timeout sending a mpsc transmission?: {e}");
-                }
-            }
+            dispatcher.dispatch(msg);
         }
 
         // check cleanups
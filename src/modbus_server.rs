@@ -0,0 +1,796 @@
+use crate::config_structs::InputType;
+use crate::consts::*;
+use crate::ha_inputs::command_value;
+use crate::ipc::{IPCMessage, InboundMessage, WriteSource};
+use crate::monitored_point::MonitoredPoint;
+use crate::point_path::PointPath;
+use crate::sunspec_unit::SunSpecUnit;
+use anyhow::bail;
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use sunspec_rs::model_data::ModelData;
+use sunspec_rs::sunspec_connection::apply_scale_factor;
+use sunspec_rs::sunspec_models::{Point, Symbol, ValueType};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, RwLock};
+
+lazy_static! {
+    /// What the Modbus server serves, kept up to date by each unit's poll loop.
+    pub static ref FACADE: RwLock<Facade> = RwLock::new(Facade::default());
+}
+
+/// "SunS", which every SunSpec map starts with
+const SUNSPEC_ID: [u16; 2] = [0x5375, 0x6e53];
+const END_MODEL: [u16; 2] = [0xffff, 0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    ServerDeviceFailure = 0x04,
+    /// there's no unit behind the unit id, or it's offline
+    GatewayTargetDevice = 0x0b,
+}
+
+/// A point that can be written through the server: one with inputs configured.
+#[derive(Debug, Clone)]
+pub struct WritablePoint {
+    /// where the point starts, counted from the start of the model's data
+    offset: u16,
+    len: u16,
+    r#type: String,
+    path: PointPath,
+    input: InputType,
+    symbols: Option<Vec<Symbol>>,
+    scale_factor: Option<i32>,
+    /// the scale factor the unit applies, as last read
+    model_scale_factor: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelImage {
+    id: u16,
+    data: Vec<u16>,
+    points: Vec<WritablePoint>,
+}
+
+/// The registers of a unit's models, as last read.
+#[derive(Debug, Clone)]
+pub struct UnitImage {
+    serial_number: String,
+    models: Vec<ModelImage>,
+}
+
+/// The value written to a point, as the payload the write path expects.  Enums and the like are
+/// written as their symbol; numbers have the point's scale factor applied.  The value is checked
+/// against the point's inputs here too, so the client hears about a bad value.
+fn command_payload(point: &WritablePoint, values: &[u16]) -> Result<String, Exception> {
+    let signed = point.r#type.starts_with("int") || point.r#type == "sunssf";
+    let raw: i64 = match values {
+        [v] if signed => *v as i16 as i64,
+        [v] => *v as i64,
+        [hi, lo] => {
+            let bits = ((*hi as u32) << 16) | *lo as u32;
+            if point.r#type == "float32" {
+                let value = f32::from_bits(bits) as f64;
+                return checked(point, value.to_string());
+            }
+            if signed {
+                bits as i32 as i64
+            } else {
+                bits as i64
+            }
+        }
+        _ => return Err(Exception::IllegalDataAddress),
+    };
+    let payload = match &point.input {
+        InputType::Number(_) => apply_scale_factor(
+            raw as f64,
+            point.scale_factor.unwrap_or(0) + point.model_scale_factor,
+        )
+        .to_string(),
+        _ => point
+            .symbols
+            .iter()
+            .flatten()
+            .find(|s| s.symbol == raw.to_string())
+            .map(|s| s.id.clone())
+            .unwrap_or(raw.to_string()),
+    };
+    checked(point, payload)
+}
+
+fn checked(point: &WritablePoint, payload: String) -> Result<String, Exception> {
+    match command_value(
+        &point.input,
+        point.symbols.as_deref(),
+        point.scale_factor,
+        point.model_scale_factor,
+        &payload,
+    ) {
+        Ok(_) => Ok(payload),
+        Err(_) => Err(Exception::IllegalDataValue),
+    }
+}
+
+impl UnitImage {
+    /// The unit's SunSpec map, as served from `SUNSPEC_BASE_ADDRESS`.
+    fn registers(&self) -> Vec<u16> {
+        let mut registers = SUNSPEC_ID.to_vec();
+        for m in self.models.iter() {
+            registers.push(m.id);
+            registers.push(m.data.len() as u16);
+            registers.extend(&m.data);
+        }
+        registers.extend(END_MODEL);
+        registers
+    }
+
+    fn read(&self, address: u16, count: u16) -> Result<Vec<u16>, Exception> {
+        let start = address
+            .checked_sub(SUNSPEC_BASE_ADDRESS)
+            .ok_or(Exception::IllegalDataAddress)? as usize;
+        self.registers()
+            .get(start..start + count as usize)
+            .map(|r| r.to_vec())
+            .ok_or(Exception::IllegalDataAddress)
+    }
+
+    /// The write that putting `values` at `address` asks of the unit.  Only whole points can be
+    /// written, and only the ones with inputs.
+    fn write(&self, address: u16, values: &[u16]) -> Result<InboundMessage, Exception> {
+        let address = address as usize;
+        // the first model's id comes straight after "SunS"
+        let mut model_start = SUNSPEC_BASE_ADDRESS as usize + SUNSPEC_ID.len();
+        for m in self.models.iter() {
+            let data_start = model_start + 2;
+            if (data_start..data_start + m.data.len()).contains(&address) {
+                let offset = (address - data_start) as u16;
+                let point = m
+                    .points
+                    .iter()
+                    .find(|p| p.offset == offset && p.len as usize == values.len())
+                    .ok_or(Exception::IllegalDataAddress)?;
+                return Ok(InboundMessage {
+                    serial_number: self.serial_number.clone(),
+                    model: m.id.to_string(),
+                    point: point.path.clone(),
+                    payload: command_payload(point, values)?,
+                    // the client is filled in by whoever knows it
+                    source: WriteSource::Modbus(String::new()),
+                    dry_run: false,
+                });
+            }
+            model_start = data_start + m.data.len();
+        }
+        Err(Exception::IllegalDataAddress)
+    }
+}
+
+/// Every unit the Modbus server serves, by unit id.
+#[derive(Debug, Default)]
+pub struct Facade {
+    units: HashMap<u8, UnitImage>,
+    /// serial number -> unit id, for every unit that's configured or has been served
+    ids: HashMap<String, u8>,
+}
+
+impl Facade {
+    pub fn configure(&mut self, unit_ids: HashMap<String, u8>) {
+        self.ids.extend(unit_ids);
+    }
+
+    fn unit_id(&mut self, serial_number: &String) -> Option<u8> {
+        if let Some(id) = self.ids.get(serial_number) {
+            return Some(*id);
+        }
+        let used: HashSet<&u8> = self.ids.values().collect();
+        let id = (1..=247).find(|id| !used.contains(id))?;
+        info!("{serial_number}: serving over modbus as unit id {id}");
+        self.ids.insert(serial_number.clone(), id);
+        Some(id)
+    }
+
+    pub fn update(&mut self, image: UnitImage) {
+        match self.unit_id(&image.serial_number) {
+            Some(id) => {
+                self.units.insert(id, image);
+            }
+            None => {
+                warn!(
+                    "{}: no modbus unit ids left to serve it under",
+                    image.serial_number
+                );
+            }
+        }
+    }
+
+    /// Stop serving a unit, e.g. because it stopped answering and what we have is stale.
+    pub fn forget(&mut self, serial_number: &String) {
+        if let Some(id) = self.ids.get(serial_number) {
+            self.units.remove(id);
+        }
+    }
+
+    /// The response to a request PDU for a unit id, and the write it asks for, if any.
+    pub fn reply(&self, unit_id: u8, pdu: &[u8]) -> (Vec<u8>, Option<InboundMessage>) {
+        let function = pdu.first().copied().unwrap_or_default();
+        match self.handle(unit_id, function, pdu.get(1..).unwrap_or_default()) {
+            Ok(reply) => reply,
+            Err(e) => (vec![function | 0x80, e as u8], None),
+        }
+    }
+
+    fn handle(
+        &self,
+        unit_id: u8,
+        function: u8,
+        body: &[u8],
+    ) -> Result<(Vec<u8>, Option<InboundMessage>), Exception> {
+        let word = |i: usize| {
+            body.get(i..i + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .ok_or(Exception::IllegalDataValue)
+        };
+        if !matches!(function, 3 | 4 | 6 | 16) {
+            return Err(Exception::IllegalFunction);
+        }
+        let unit = self
+            .units
+            .get(&unit_id)
+            .ok_or(Exception::GatewayTargetDevice)?;
+        let address = word(0)?;
+        match function {
+            // holding and input registers are the same thing here
+            3 | 4 => {
+                let count = word(2)?;
+                if count == 0 || count > MODBUS_MAX_READ_REGISTERS {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let mut response = vec![function, (count * 2) as u8];
+                for r in unit.read(address, count)? {
+                    response.extend(r.to_be_bytes());
+                }
+                Ok((response, None))
+            }
+            6 => {
+                let write = unit.write(address, &[word(2)?])?;
+                Ok(([&[function], &body[..4]].concat(), Some(write)))
+            }
+            _ => {
+                let count = word(2)? as usize;
+                let values = (0..count)
+                    .map(|i| word(5 + 2 * i))
+                    .collect::<Result<Vec<u16>, Exception>>()?;
+                let write = unit.write(address, &values)?;
+                Ok(([&[function], &body[..4]].concat(), Some(write)))
+            }
+        }
+    }
+}
+
+fn point_len(point: &Point) -> u16 {
+    point.len.unwrap_or(match point.r#type.as_str() {
+        t if t.ends_with("64") => 4,
+        t if t.ends_with("32") => 2,
+        _ => 1,
+    })
+}
+
+/// The points of a model that can be written through the server.  Numbers with a scale factor
+/// that hasn't been read yet can't be, as what a client writes couldn't be worked out.
+fn writable_points(
+    unit: &SunSpecUnit,
+    md: &ModelData,
+    points: &[MonitoredPoint],
+    scale_factors: &HashMap<(u16, String), i16>,
+) -> Vec<WritablePoint> {
+    let data_start = md.address + 2;
+    points
+        .iter()
+        .filter(|p| p.model == md.id.to_string())
+        .filter_map(|p| {
+            let input = p.input_type.clone()?;
+            let (offset, point) = if p.path.is_catalog() {
+                let node = unit.conn.catalog.get(&p.path.to_string())?;
+                (
+                    node.address.checked_sub(data_start)?,
+                    node.point_data.clone(),
+                )
+            } else {
+                let point = md
+                    .model
+                    .model
+                    .block
+                    .first()?
+                    .point
+                    .iter()
+                    .find(|pt| pt.id == p.path.point)?
+                    .clone();
+                (point.offset, point)
+            };
+            let symbols = point.symbol.clone().or_else(|| {
+                unit.data.clone().get_symbols_for_point(
+                    md.id,
+                    p.path.point.clone(),
+                    Some(unit.device_info.manufacturer.clone()),
+                )
+            });
+            let model_scale_factor = match (&input, &point.scale_factor) {
+                (InputType::Number(_), Some(name)) => {
+                    *scale_factors.get(&(md.id, name.clone()))? as i32
+                }
+                _ => 0,
+            };
+            Some(WritablePoint {
+                offset,
+                len: point_len(&point),
+                r#type: point.r#type.clone(),
+                path: p.path.clone(),
+                input,
+                symbols,
+                scale_factor: p.scale_factor,
+                model_scale_factor,
+            })
+        })
+        .collect()
+}
+
+/// SunSpec's "not implemented" value for a point, which is what the server serves until the poll
+/// loop has read the point.
+fn not_implemented(point: &Point) -> Vec<u16> {
+    let len = point_len(point) as usize;
+    let first = match point.r#type.as_str() {
+        "int16" | "int32" | "int64" | "sunssf" => 0x8000,
+        "float32" => 0x7fc0,
+        t if t.starts_with("acc") || t == "string" || t == "pad" => 0,
+        _ => 0xffff,
+    };
+    let rest = if first == 0xffff { 0xffff } else { 0 };
+    std::iter::once(first)
+        .chain(std::iter::repeat(rest))
+        .take(len)
+        .collect()
+}
+
+/// `raw` as `len` registers, most significant first.
+fn words(raw: i64, len: u16) -> Vec<u16> {
+    (0..len as u32)
+        .rev()
+        .map(|i| (raw >> (16 * i)) as u16)
+        .collect()
+}
+
+fn text(value: &str, len: u16) -> Vec<u16> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.resize(len as usize * 2, 0);
+    bytes
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect()
+}
+
+/// The raw value behind a symbol, or behind the placeholder sunspec_rs reads unknown ones as.
+fn symbol_value(point: &Point, id: &str) -> Option<i64> {
+    point
+        .symbol
+        .iter()
+        .flatten()
+        .find(|s| s.id == id)
+        .and_then(|s| s.symbol.parse().ok())
+        .or_else(|| {
+            id.strip_prefix("ENUM16_")
+                .or_else(|| id.strip_prefix("BITFIELD16_"))?
+                .parse()
+                .ok()
+        })
+}
+
+/// The registers of the models a unit is polled for and its common model, as the poll loop last
+/// read them.  Nothing is read from the unit for this, besides the scale factors of points whose
+/// scale factor isn't polled itself, which are read once.
+#[derive(Debug, Default)]
+pub struct UnitRegisters {
+    serial_number: String,
+    /// by the model's address, so they're served in the unit's order
+    models: BTreeMap<u16, (u16, Vec<u16>)>,
+    scale_factors: HashMap<(u16, String), i16>,
+}
+
+impl UnitRegisters {
+    pub fn new(unit: &SunSpecUnit, points: &[MonitoredPoint]) -> UnitRegisters {
+        let ids: BTreeSet<u16> = points
+            .iter()
+            .filter_map(|p| p.model.parse().ok())
+            .chain([COMMON_MODEL_ID])
+            .collect();
+        let mut registers = UnitRegisters {
+            serial_number: unit.serial_number.clone(),
+            ..Default::default()
+        };
+        for md in ids.iter().filter_map(|id| unit.conn.models.get(id)) {
+            let mut data = vec![0; md.len as usize];
+            for point in md.model.model.block.first().iter().flat_map(|b| &b.point) {
+                let value = not_implemented(point);
+                if let Some(r) =
+                    data.get_mut(point.offset as usize..(point.offset as usize + value.len()))
+                {
+                    r.copy_from_slice(&value);
+                }
+            }
+            registers.models.insert(md.address, (md.id, data));
+        }
+        // the common model is what the unit told us about itself when we connected
+        if let Some(md) = unit.conn.models.get(&COMMON_MODEL_ID) {
+            let info = &unit.device_info;
+            for (id, value) in [
+                ("Mn", info.manufacturer.as_str()),
+                ("Md", info.model.as_str()),
+                ("Opt", info.hw_version.as_deref().unwrap_or_default()),
+                ("Vr", info.sw_version.as_str()),
+                ("SN", unit.serial_number.as_str()),
+            ] {
+                registers.set_point(md, id, |p| text(value, point_len(p)));
+            }
+            registers.set_point(md, "DA", |_| vec![unit.slave_id as u16]);
+        }
+        registers
+    }
+
+    fn set(&mut self, md: &ModelData, offset: u16, values: &[u16]) {
+        if let Some((_, data)) = self.models.get_mut(&md.address) {
+            let offset = offset as usize;
+            if let Some(r) = data.get_mut(offset..offset + values.len()) {
+                r.copy_from_slice(values);
+            }
+        }
+    }
+
+    fn set_point(&mut self, md: &ModelData, id: &str, value: impl Fn(&Point) -> Vec<u16>) {
+        let point = md
+            .model
+            .model
+            .block
+            .first()
+            .and_then(|b| b.point.iter().find(|p| p.id == id));
+        if let Some(point) = point {
+            self.set(md, point.offset, &value(point));
+        }
+    }
+
+    /// The value of the scale factor `name`, which is served from here on too.
+    async fn scale_factor(
+        &mut self,
+        unit: &SunSpecUnit,
+        md: &ModelData,
+        name: &str,
+    ) -> Option<i16> {
+        let key = (md.id, name.to_string());
+        if let Some(sf) = self.scale_factors.get(&key) {
+            return Some(*sf);
+        }
+        let sf = md
+            .clone()
+            .get_scale_factor(name, unit.conn.clone(), None, None)
+            .await?;
+        self.scale_factors.insert(key, sf);
+        self.set_point(md, name, |_| vec![sf as u16]);
+        Some(sf)
+    }
+
+    /// Record a point the poll loop read from the unit.
+    pub async fn record(
+        &mut self,
+        unit: &SunSpecUnit,
+        md: &ModelData,
+        monitored_point: &MonitoredPoint,
+        point: &Point,
+    ) {
+        let offset = if monitored_point.path.is_catalog() {
+            match unit
+                .conn
+                .catalog
+                .get(&monitored_point.path.to_string())
+                .and_then(|node| node.address.checked_sub(md.address + 2))
+            {
+                Some(offset) => offset,
+                None => return,
+            }
+        } else {
+            point.offset
+        };
+        let len = point_len(point);
+        let values = match (&point.value, point.r#type.as_str()) {
+            (Some(ValueType::Integer(raw)), t) => {
+                if t == "sunssf" {
+                    self.scale_factors
+                        .insert((md.id, point.id.clone()), *raw as i16);
+                }
+                words(*raw, len)
+            }
+            (Some(ValueType::Float(value)), "float32") => {
+                words((*value as f32).to_bits() as i64, len)
+            }
+            (Some(ValueType::Float(value)), _) => {
+                let Some(name) = point.scale_factor.as_deref() else {
+                    return;
+                };
+                let Some(sf) = self.scale_factor(unit, md, name).await else {
+                    return;
+                };
+                words((value / 10_f64.powi(sf as i32)).round() as i64, len)
+            }
+            (Some(ValueType::String(value)), "string") => text(value, len),
+            (Some(ValueType::String(id)), _) => match symbol_value(point, id) {
+                Some(raw) => words(raw, len),
+                None => return,
+            },
+            (Some(ValueType::Array(ids)), _) => {
+                let bits = ids
+                    .iter()
+                    .filter_map(|id| symbol_value(point, id))
+                    .fold(0_i64, |bits, bit| bits | 1 << bit);
+                words(bits, len)
+            }
+            _ => return,
+        };
+        self.set(md, offset, &values);
+    }
+}
+
+/// Serve what the poll loop has read from a unit, with its writable points as they're configured
+/// now.
+pub async fn refresh(unit: &SunSpecUnit, points: &[MonitoredPoint], registers: &UnitRegisters) {
+    let models = registers
+        .models
+        .values()
+        .filter_map(|(id, data)| {
+            let md = unit.conn.models.get(id)?;
+            Some(ModelImage {
+                id: *id,
+                data: data.clone(),
+                points: writable_points(unit, md, points, &registers.scale_factors),
+            })
+        })
+        .collect();
+    FACADE.write().await.update(UnitImage {
+        serial_number: registers.serial_number.clone(),
+        models,
+    });
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    tx: mpsc::Sender<IPCMessage>,
+) -> anyhow::Result<()> {
+    loop {
+        // transaction id, protocol id, length, unit id
+        let mut header = [0_u8; 7];
+        stream.read_exact(&mut header).await?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if !(2..=254).contains(&length) {
+            bail!("bad MBAP length {length}");
+        }
+        let mut pdu = vec![0_u8; length - 1];
+        stream.read_exact(&mut pdu).await?;
+        let unit_id = header[6];
+
+        let (mut response, write) = FACADE.read().await.reply(unit_id, &pdu);
+        if let Some(mut write) = write {
+            write.source = WriteSource::Modbus(peer.to_string());
+            info!(
+                "Forwarding modbus write for {} {}/{}: {}",
+                write.serial_number, write.model, write.point, write.payload
+            );
+            if tx.send(IPCMessage::Inbound(write)).await.is_err() {
+                response = vec![pdu[0] | 0x80, Exception::ServerDeviceFailure as u8];
+            }
+        }
+        let mut frame = header[..4].to_vec();
+        frame.extend(((response.len() + 1) as u16).to_be_bytes());
+        frame.push(unit_id);
+        frame.extend(response);
+        stream.write_all(&frame).await?;
+    }
+}
+
+/// Serve every unit's SunSpec map over Modbus TCP.  Writes go to the poll loops through `tx`, the
+/// same way writes from MQTT do.
+///
+/// A write is acknowledged once it has been checked against the point's inputs and handed to the
+/// poll loop, not once the unit has taken it: the write policy, read-only mode or the unit itself
+/// can still refuse it.  How it went is in the write audit, and in the registers once the point is
+/// next polled.
+pub async fn serve(listener: TcpListener, tx: mpsc::Sender<IPCMessage>) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!("Modbus client connected from {peer}");
+        let tx = tx.clone();
+        tokio::task::Builder::new()
+            .name(&format!("modbus_client_{peer}"))
+            .spawn(async move {
+                if let Err(e) = handle_client(stream, peer, tx).await {
+                    debug!("Modbus client {peer} went away: {e}");
+                }
+            })?;
+    }
+}
+
+/// A unit with the common model and `models`, served over Modbus TCP and connected to the way
+/// poll loops connect to units.
+#[cfg(test)]
+pub(crate) async fn test_unit(serial_number: &str, models: Vec<(u16, Vec<u16>)>) -> SunSpecUnit {
+    // Mn, Md, Opt, Vr, SN, DA and a pad
+    let common = [
+        text("sunspec_gateway", 16),
+        text("test", 16),
+        text("", 8),
+        text("", 8),
+        text(serial_number, 16),
+        vec![1, 0],
+    ]
+    .concat();
+    let models = std::iter::once((COMMON_MODEL_ID, common))
+        .chain(models)
+        .map(|(id, data)| ModelImage {
+            id,
+            data,
+            points: vec![],
+        })
+        .collect();
+    let mut facade = FACADE.write().await;
+    facade.update(UnitImage {
+        serial_number: serial_number.to_string(),
+        models,
+    });
+    let unit_id = facade.ids[serial_number];
+    drop(facade);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, _) = mpsc::channel(MPSC_BUFFER_SIZE);
+    tokio::spawn(serve(listener, tx));
+    SunSpecUnit::new(addr.to_string(), unit_id.to_string(), None)
+        .await
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_structs::Numerable;
+
+    fn facade() -> Facade {
+        let mut facade = Facade::default();
+        facade.update(UnitImage {
+            serial_number: "SN1".to_string(),
+            models: vec![
+                ModelImage {
+                    id: 1,
+                    data: vec![0x4142; 4],
+                    points: vec![],
+                },
+                ModelImage {
+                    id: 704,
+                    data: vec![0, 1000, 0],
+                    points: vec![WritablePoint {
+                        offset: 1,
+                        len: 1,
+                        r#type: "uint16".to_string(),
+                        path: ".DERCtlAC.WMaxLimPct".parse().unwrap(),
+                        input: InputType::Number(Numerable {
+                            min: 0.0,
+                            max: 100.0,
+                            step: None,
+                            mode: None,
+                        }),
+                        symbols: None,
+                        scale_factor: Some(-1),
+                        model_scale_factor: 0,
+                    }],
+                },
+            ],
+        });
+        facade
+    }
+
+    #[test]
+    fn serves_a_sunspec_map() {
+        let facade = facade();
+        let (response, _) = facade.reply(1, &[3, 0x9c, 0x40, 0, 2]);
+        assert_eq!(response, vec![3, 4, 0x53, 0x75, 0x6e, 0x53]);
+
+        // SunS, 1 + len + 4, 704 + len + 3, end
+        let (response, _) = facade.reply(1, &[4, 0x9c, 0x40, 0, 15]);
+        assert_eq!(response.len(), 2 + 15 * 2);
+        assert_eq!(&response[2 + 13 * 2..], &[0xff, 0xff, 0, 0]);
+
+        assert_eq!(facade.reply(1, &[3, 0x9c, 0x40, 0, 16]).0, vec![0x83, 2]);
+        assert_eq!(facade.reply(9, &[3, 0x9c, 0x40, 0, 1]).0, vec![0x83, 0x0b]);
+        assert_eq!(facade.reply(1, &[1, 0, 0, 0, 1]).0, vec![0x81, 1]);
+    }
+
+    #[tokio::test]
+    async fn serves_what_was_polled() {
+        // immediate controls: a 50.0% power limit, enabled, and a -0.950 power factor
+        let mut controls = vec![0; 24];
+        controls[3] = 500;
+        controls[7] = 1;
+        controls[8] = -950_i16 as u16;
+        controls[21] = -1_i16 as u16;
+        controls[22] = -3_i16 as u16;
+        let unit = test_unit("REGS1", vec![(123, controls.clone())]).await;
+        let points: Vec<MonitoredPoint> = ["WMaxLimPct", "WMaxLim_Ena", "OutPFSet"]
+            .iter()
+            .map(|p| {
+                let config = crate::config_structs::PointConfig {
+                    point: Some(p.to_string()),
+                    interval: 60,
+                    ..Default::default()
+                };
+                MonitoredPoint::new("123".to_string(), config, None).unwrap()
+            })
+            .collect();
+        let md = unit.conn.models.get(&123).unwrap();
+
+        let mut registers = UnitRegisters::new(&unit, &points);
+        let data = |registers: &UnitRegisters| registers.models[&md.address].1.clone();
+        // nothing's been read yet
+        assert_eq!(
+            &data(&registers)[..5],
+            &[0xffff, 0xffff, 0xffff, 0xffff, 0xffff]
+        );
+        assert_eq!(data(&registers)[8], 0x8000);
+
+        for p in points.iter() {
+            let read = unit
+                .conn
+                .clone()
+                .get_point(md.clone(), p.name.clone())
+                .await
+                .unwrap();
+            registers.record(&unit, md, p, &read).await;
+        }
+        let served = data(&registers);
+        for i in [3, 7, 8, 21, 22] {
+            assert_eq!(served[i], controls[i], "register {i}");
+        }
+        // VArPct_SF isn't used by anything that was read
+        assert_eq!(served[23], 0x8000);
+
+        refresh(&unit, &points, &registers).await;
+        let facade = FACADE.read().await;
+        let unit_id = facade.ids["REGS1"];
+        // the common model comes first, and says who the unit is
+        let (response, _) = facade.reply(unit_id, &[3, 0x9c, 0x42, 0, 2]);
+        assert_eq!(response, vec![3, 4, 0, 1, 0, 66]);
+        let sn = facade.units[&unit_id].models[0].data[48..50].to_vec();
+        assert_eq!(sn, text("REGS1", 2));
+    }
+
+    #[test]
+    fn writes_go_through_the_write_path() {
+        let facade = facade();
+        // 40000 + SunS (2) + model 1 (2 + 4) + 704 header (2) + offset 1
+        let address: u16 = 40011;
+        let [hi, lo] = address.to_be_bytes();
+        let (response, write) = facade.reply(1, &[6, hi, lo, 0x01, 0xf9]);
+        assert_eq!(response, vec![6, hi, lo, 0x01, 0xf9]);
+        let write = write.unwrap();
+        assert_eq!(write.serial_number, "SN1");
+        assert_eq!(write.model, "704");
+        assert_eq!(write.point.to_string(), ".DERCtlAC.WMaxLimPct");
+        assert_eq!(write.payload, "50.5");
+
+        // out of the input's range
+        assert_eq!(facade.reply(1, &[6, hi, lo, 0x04, 0x00]).0, vec![0x86, 3]);
+        // a point without inputs
+        let (response, write) = facade.reply(1, &[16, hi, lo - 1, 0, 1, 2, 0, 1]);
+        assert_eq!(response, vec![0x90, 2]);
+        assert!(write.is_none());
+    }
+}
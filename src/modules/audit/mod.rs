@@ -0,0 +1,131 @@
+use crate::audit::WriteResult;
+use crate::consts::*;
+use crate::modules::users::{Role, User};
+use crate::modules::{AppAPIResponse, Authorizable, AuthorizableType, RBAC};
+use crate::state::AppState;
+use crate::state_mgmt::{list_writes, AuditRecord};
+use async_trait::async_trait;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::{debug_handler, Json};
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+pub(crate) fn audit_routes(state: AppState) -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(get_audit))
+        .with_state(state)
+}
+pub struct Audit;
+
+/// Which writes to list
+#[derive(Deserialize, IntoParams, Debug, Default)]
+pub struct AuditQuery {
+    /// only the writes to this unit
+    pub(crate) serial_number: Option<String>,
+    /// only the writes to this model
+    pub(crate) model: Option<String>,
+    /// only the writes to this point, by canonical path
+    pub(crate) point: Option<String>,
+    /// only the writes from sources starting with this, e.g. `rest:` or `mqtt:`
+    pub(crate) source: Option<String>,
+    /// only the writes that went this way
+    pub(crate) result: Option<WriteResult>,
+    /// only the writes at or after this unix timestamp
+    pub(crate) since: Option<i64>,
+    /// only the writes before this unix timestamp
+    pub(crate) until: Option<i64>,
+    /// the most writes to return, newest first
+    pub(crate) limit: Option<i64>,
+}
+
+/// A list of writes, newest first
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default, Clone)]
+pub struct AuditList {
+    writes: Vec<AuditRecord>,
+}
+
+#[async_trait]
+impl Authorizable for Audit {
+    async fn check_authorization<'a>(
+        _id: &'a AuthorizableType,
+        user: &'a User,
+        rbac: &'a RBAC,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        // the people who can write can see what was written
+        Ok(*rbac == RBAC::Read && user.role >= Role::Operator)
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+get,
+path = "/",
+summary = "list writes to units, and attempts at them",
+params(AuditQuery),
+responses(
+(status = OK, description = "successful request", body = AuditList),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = AUDIT_TAG
+)]
+pub async fn get_audit(
+    State(_state): State<AppState>,
+    session: Session,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditList>, (StatusCode, AppAPIResponse)> {
+    let user: User = match session.get("user").await {
+        Ok(session_request) => match session_request {
+            Some(user) => user,
+            None => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppAPIResponse::message("Couldn't get user id from session"),
+                ));
+            }
+        },
+        Err(e) => {
+            error!("Error getting user from session: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Couldn't get user id from session"),
+            ));
+        }
+    };
+    let unit = AuthorizableType::Unit(query.serial_number.clone().unwrap_or_default());
+    match user.is_authorized::<Audit>(&unit, &RBAC::Read).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                AppAPIResponse::message("You are not authorized to this action."),
+            ));
+        }
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Could not check user authorizations"),
+            ));
+        }
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT);
+    if limit < 1 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            AppAPIResponse::message("limit must be at least 1"),
+        ));
+    }
+    match list_writes(&query, limit).await {
+        Ok(writes) => Ok(Json(AuditList { writes })),
+        Err(e) => {
+            error!("Unable to list writes: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message(format!("unable to list writes: {e}")),
+            ))
+        }
+    }
+}
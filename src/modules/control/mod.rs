@@ -0,0 +1,134 @@
+use crate::consts::*;
+use crate::ipc::IPCMessage;
+use crate::modules::users::{Role, User};
+use crate::modules::{AppAPIResponse, Authorizable, AuthorizableType, RBAC};
+use crate::state::AppState;
+use crate::write_policy::{read_only_message, set_read_only, READ_ONLY};
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::{debug_handler, Json};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use tower_sessions::Session;
+use utoipa::ToSchema;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+pub(crate) fn control_routes(state: AppState) -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(get_read_only, put_read_only))
+        .with_state(state)
+}
+pub struct Control;
+
+/// Whether every write to a unit is refused
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default, Clone, PartialEq)]
+pub struct ReadOnlyMode {
+    read_only: bool,
+}
+
+#[async_trait]
+impl Authorizable for Control {
+    async fn check_authorization<'a>(
+        _id: &'a AuthorizableType,
+        user: &'a User,
+        rbac: &'a RBAC,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        // everyone can see the switches, admins flip them
+        Ok(*rbac == RBAC::Read || user.role == Role::Admin)
+    }
+}
+
+async fn authorize(session: &Session, rbac: &RBAC) -> Result<User, (StatusCode, AppAPIResponse)> {
+    let user: User = match session.get("user").await {
+        Ok(session_request) => match session_request {
+            Some(user) => user,
+            None => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppAPIResponse::message("Couldn't get user id from session"),
+                ));
+            }
+        },
+        Err(e) => {
+            error!("Error getting user from session: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Couldn't get user id from session"),
+            ));
+        }
+    };
+    match user
+        .is_authorized::<Control>(&AuthorizableType::Unit(String::new()), rbac)
+        .await
+    {
+        Ok(true) => Ok(user),
+        Ok(false) => Err((
+            StatusCode::FORBIDDEN,
+            AppAPIResponse::message("You are not authorized to this action."),
+        )),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            AppAPIResponse::message("Could not check user authorizations"),
+        )),
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+get,
+path = "/read_only",
+summary = "whether writes to units are being refused",
+responses(
+(status = OK, description = "successful request", body = ReadOnlyMode),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = CONTROL_TAG
+)]
+pub async fn get_read_only(
+    State(_state): State<AppState>,
+    session: Session,
+) -> Result<Json<ReadOnlyMode>, (StatusCode, AppAPIResponse)> {
+    authorize(&session, &RBAC::Read).await?;
+    Ok(Json(ReadOnlyMode {
+        read_only: READ_ONLY.load(Ordering::SeqCst),
+    }))
+}
+
+#[debug_handler]
+#[utoipa::path(
+put,
+path = "/read_only",
+summary = "turn read-only mode on or off; while it's on, every write to a unit is refused",
+request_body(content_type = "application/json", content = ReadOnlyMode),
+responses(
+(status = OK, description = "successful request", body = ReadOnlyMode),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = CONTROL_TAG
+)]
+pub async fn put_read_only(
+    State(state): State<AppState>,
+    session: Session,
+    Json(body): Json<ReadOnlyMode>,
+) -> Result<Json<ReadOnlyMode>, (StatusCode, AppAPIResponse)> {
+    let user = authorize(&session, &RBAC::Admin).await?;
+    info!(
+        "{} is setting read-only mode to {}",
+        user.login, body.read_only
+    );
+    if let Err(e) = set_read_only(body.read_only).await {
+        error!("Unable to set read-only mode: {e}");
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            AppAPIResponse::message("unable to set read-only mode"),
+        ));
+    }
+    if let Err(e) = state
+        .ipc_tx
+        .send(IPCMessage::Outbound(read_only_message()))
+        .await
+    {
+        warn!("Unable to publish read-only mode: {e}");
+    }
+    Ok(Json(body))
+}
@@ -0,0 +1,115 @@
+use crate::consts::*;
+use crate::discovery::purge_discovery;
+use crate::ipc::IPCMessage;
+use crate::modules::users::{Role, User};
+use crate::modules::{AppAPIResponse, Authorizable, AuthorizableType, RBAC};
+use crate::state::AppState;
+use async_trait::async_trait;
+use axum::debug_handler;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use serde_json::json;
+use tower_sessions::Session;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+pub(crate) fn discovery_routes(state: AppState) -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(purge_unit_discovery))
+        .with_state(state)
+}
+pub struct Discovery;
+
+#[async_trait]
+impl Authorizable for Discovery {
+    async fn check_authorization<'a>(
+        _id: &'a AuthorizableType,
+        user: &'a User,
+        _rbac: &'a RBAC,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        // discovery is configuration, so all of it is for admins
+        Ok(user.role == Role::Admin)
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+delete,
+path = "/{:serial_number}",
+summary = "remove every Home Assistant discovery config published for a unit",
+params(
+("serial_number" = String, Path, description = "serial number of the unit, e.g. one that has been decommissioned")
+),
+responses(
+(status = OK, description = "successful request", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = DISCOVERY_TAG
+)]
+pub async fn purge_unit_discovery(
+    State(state): State<AppState>,
+    session: Session,
+    Path(serial_number): Path<String>,
+) -> Result<AppAPIResponse, (StatusCode, AppAPIResponse)> {
+    let user: User = match session.get("user").await {
+        Ok(session_request) => match session_request {
+            Some(user) => user,
+            None => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppAPIResponse::message("Couldn't get user id from session"),
+                ));
+            }
+        },
+        Err(e) => {
+            error!("Error getting user from session: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Couldn't get user id from session"),
+            ));
+        }
+    };
+    match user
+        .is_authorized::<Discovery>(&AuthorizableType::Unit(serial_number.clone()), &RBAC::Admin)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                AppAPIResponse::message("You are not authorized to this action."),
+            ));
+        }
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Could not check user authorizations"),
+            ));
+        }
+    }
+
+    let messages = match purge_discovery(&serial_number).await {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Unable to look up discovery topics for {serial_number}: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message(format!("unable to look up discovery topics: {e}")),
+            ));
+        }
+    };
+    let cleared = messages.len();
+    for msg in messages {
+        if let Err(e) = state.ipc_tx.send(IPCMessage::Outbound(msg)).await {
+            error!("Unable to queue discovery clear for {serial_number}: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to queue discovery clears"),
+            ));
+        }
+    }
+    info!("Cleared {cleared} discovery configs for {serial_number}");
+    Ok(AppAPIResponse::data(
+        format!("cleared discovery for {serial_number}"),
+        json!({ "cleared": cleared }),
+    ))
+}
@@ -0,0 +1,126 @@
+use crate::consts::*;
+use crate::modules::users::{Role, User};
+use crate::modules::{AppAPIResponse, Authorizable, AuthorizableType, RBAC};
+use crate::state::AppState;
+use crate::state_mgmt::{list_events, EventRecord};
+use async_trait::async_trait;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::{debug_handler, Json};
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+pub(crate) fn event_routes(state: AppState) -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(get_events))
+        .with_state(state)
+}
+pub struct Event;
+
+/// Which events to list
+#[derive(Deserialize, IntoParams, Debug, Default)]
+pub struct EventQuery {
+    /// only the events of this unit
+    serial_number: Option<String>,
+    /// only events that haven't cleared yet
+    active: Option<bool>,
+    /// the most events to return, newest first
+    limit: Option<i64>,
+}
+
+/// A list of events, newest first
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default, Clone)]
+pub struct EventList {
+    events: Vec<EventRecord>,
+}
+
+#[async_trait]
+impl Authorizable for Event {
+    async fn check_authorization<'a>(
+        _id: &'a AuthorizableType,
+        user: &'a User,
+        rbac: &'a RBAC,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        // every role can read events
+        Ok(*rbac == RBAC::Read || user.role == Role::Admin)
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+get,
+path = "/",
+summary = "list raised and cleared events",
+params(EventQuery),
+responses(
+(status = OK, description = "successful request", body = EventList),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = EVENTS_TAG
+)]
+pub async fn get_events(
+    State(_state): State<AppState>,
+    session: Session,
+    Query(query): Query<EventQuery>,
+) -> Result<Json<EventList>, (StatusCode, AppAPIResponse)> {
+    let user: User = match session.get("user").await {
+        Ok(session_request) => match session_request {
+            Some(user) => user,
+            None => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppAPIResponse::message("Couldn't get user id from session"),
+                ));
+            }
+        },
+        Err(e) => {
+            error!("Error getting user from session: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Couldn't get user id from session"),
+            ));
+        }
+    };
+    let unit = AuthorizableType::Unit(query.serial_number.clone().unwrap_or_default());
+    match user.is_authorized::<Event>(&unit, &RBAC::Read).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                AppAPIResponse::message("You are not authorized to this action."),
+            ));
+        }
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Could not check user authorizations"),
+            ));
+        }
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_EVENTS_LIMIT);
+    if limit < 1 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            AppAPIResponse::message("limit must be at least 1"),
+        ));
+    }
+    match list_events(
+        query.serial_number.as_ref(),
+        query.active.unwrap_or(false),
+        limit,
+    )
+    .await
+    {
+        Ok(events) => Ok(Json(EventList { events })),
+        Err(e) => {
+            error!("Unable to list events: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message(format!("unable to list events: {e}")),
+            ))
+        }
+    }
+}
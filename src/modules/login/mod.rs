@@ -0,0 +1,173 @@
+use crate::auth::data::{hash_password, verify_password};
+use crate::auth::token_extractor::{validate_jwt, AuthToken};
+use crate::auth::token_middleware::{token_user, SessionLogin, LOGIN_SESSION_KEY};
+use crate::consts::*;
+use crate::modules::users::User;
+use crate::modules::AppAPIResponse;
+use crate::state::AppState;
+use crate::state_mgmt::{get_password_hash, get_user};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::{debug_handler, Json};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+use utoipa::ToSchema;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+pub(crate) fn login_routes(state: AppState) -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(login))
+        .routes(routes!(logout))
+        .with_state(state)
+}
+
+lazy_static! {
+    /// checked against when there's no such user, so that how long a login takes doesn't tell
+    /// whether the user exists
+    static ref DECOY_PASSWORD_HASH: String = hash_password("");
+}
+
+/// Credentials for logging in: a login and password, or an id token from a trusted OIDC issuer
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct LoginRequest {
+    pub(crate) login: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) id_token: Option<String>,
+}
+
+fn refused() -> (StatusCode, AppAPIResponse) {
+    (
+        StatusCode::UNAUTHORIZED,
+        AppAPIResponse::message("Invalid login."),
+    )
+}
+
+async fn password_user(
+    login: &String,
+    password: String,
+) -> Result<User, (StatusCode, AppAPIResponse)> {
+    let (id, hash) = match get_password_hash(login).await {
+        Ok(Some((id, Some(hash)))) => (Some(id), hash),
+        Ok(_) => (None, DECOY_PASSWORD_HASH.clone()),
+        Err(e) => {
+            error!("Error looking up login {login}: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to log in"),
+            ));
+        }
+    };
+    // hashing takes long enough on purpose that it would hold up other requests
+    let verified = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .unwrap_or(false);
+    let Some(id) = id.filter(|_| verified) else {
+        return Err(refused());
+    };
+    match get_user(id).await {
+        Ok(Some(u)) => Ok(u),
+        Ok(None) => Err(refused()),
+        Err(e) => {
+            error!("Error getting user {id}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to log in"),
+            ))
+        }
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+post,
+path = "/login",
+summary = "log in to the web UI, getting a session cookie",
+request_body(content_type = "application/json", content = LoginRequest),
+responses(
+(status = OK, description = "successful request", body = User),
+(status = UNAUTHORIZED, description = "wrong credentials", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = AUTH_TAG
+)]
+pub async fn login(
+    State(state): State<AppState>,
+    session: Session,
+    Json(body): Json<LoginRequest>,
+) -> Result<Json<User>, (StatusCode, AppAPIResponse)> {
+    let (user, role) = match body {
+        LoginRequest {
+            id_token: Some(id_token),
+            ..
+        } => {
+            let jwt = validate_jwt(&id_token, &state.auth_config, &state.jwks_cache)
+                .await
+                .map_err(|_| refused())?;
+            let role = jwt.role;
+            let user = token_user(&state, &AuthToken::Jwt(jwt))
+                .await
+                .map_err(|_| refused())?;
+            (user, role)
+        }
+        LoginRequest {
+            login: Some(login),
+            password: Some(password),
+            ..
+        } => (password_user(&login, password).await?, None),
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                AppAPIResponse::message("a login and password, or an id_token, are required"),
+            ));
+        }
+    };
+
+    // a new session id, so that one planted on the browser before logging in is no use after
+    if let Err(e) = session.cycle_id().await {
+        error!("Couldn't renew session: {e}");
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            AppAPIResponse::message("unable to log in"),
+        ));
+    }
+    let login = SessionLogin {
+        user_id: user.id,
+        role,
+    };
+    if let Err(e) = session.insert(LOGIN_SESSION_KEY, login).await {
+        error!("Couldn't set login in session: {e}");
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            AppAPIResponse::message("unable to log in"),
+        ));
+    }
+    info!("{} logged in", user.login);
+    Ok(Json(user))
+}
+
+#[debug_handler]
+#[utoipa::path(
+post,
+path = "/logout",
+summary = "log out of the web UI, ending the session",
+responses(
+(status = NO_CONTENT, description = "successful request"),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = AUTH_TAG
+)]
+pub async fn logout(
+    State(_state): State<AppState>,
+    session: Session,
+) -> Result<StatusCode, (StatusCode, AppAPIResponse)> {
+    match session.flush().await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(e) => {
+            error!("Couldn't end session: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to log out"),
+            ))
+        }
+    }
+}
@@ -9,12 +9,21 @@ use sqlx::SqlitePool;
 use std::error::Error;
 use utoipa::ToSchema;
 
+pub(crate) mod audit;
+pub(crate) mod control;
+pub(crate) mod discovery;
+pub(crate) mod events;
+pub(crate) mod login;
 pub(crate) mod points;
+pub(crate) mod schedules;
 pub mod users;
 
 #[derive(PartialEq)]
 pub enum AuthorizableType {
     User(User),
+    Unit(String),
+    /// a point, as `{model}/{point}`, of the unit with the serial number, or of any unit
+    Point(Option<String>, String),
 }
 
 #[derive(PartialEq)]
@@ -1,7 +1,10 @@
 use crate::consts::*;
-use crate::modules::users::User;
+use crate::ipc::{IPCMessage, InboundMessage, WriteSource};
+use crate::modules::users::{Role, User};
 use crate::modules::{AppAPIResponse, Authorizable, AuthorizableType, RBAC};
+use crate::point_path::{GroupIndex, GroupSegment, PointPath};
 use crate::state::AppState;
+use crate::state_mgmt;
 use crate::MODEL_HASH;
 use async_trait::async_trait;
 use axum::extract::{Path, State};
@@ -12,7 +15,7 @@ use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, SqlitePool};
 use std::collections::HashMap;
 use std::error::Error;
-use sunspec_rs::json::group::Group;
+use sunspec_rs::json::group::{Group, GroupCount};
 use sunspec_rs::json::misc::JSONModel;
 use sunspec_rs::sunspec_models::ModelSource;
 use tower_sessions::Session;
@@ -24,6 +27,7 @@ pub(crate) fn point_routes(state: AppState) -> OpenApiRouter<AppState> {
     OpenApiRouter::new()
         .routes(routes!(get_point))
         .routes(routes!(get_all_points))
+        .routes(routes!(write_point))
         .with_state(state)
 }
 pub struct Point;
@@ -72,8 +76,24 @@ impl Authorizable for Point {
         user: &'a User,
         rbac: &'a RBAC,
     ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-        // eventually I may want to define access levels but it's not needed right now
-        Ok(true)
+        match rbac {
+            RBAC::Read => Ok(true),
+            RBAC::Write => match user.role {
+                Role::Admin => Ok(true),
+                Role::Operator => {
+                    let AuthorizableType::Point(serial_number, point) = id else {
+                        return Ok(false);
+                    };
+                    let scopes = state_mgmt::get_operator_scopes(user.id).await?;
+                    Ok(scopes.is_empty()
+                        || serial_number
+                            .as_ref()
+                            .is_some_and(|sn| scopes.iter().any(|s| s.allows(sn, point))))
+                }
+                Role::Viewer => Ok(false),
+            },
+            RBAC::Delete | RBAC::Admin => Ok(user.role == Role::Admin),
+        }
     }
 }
 
@@ -91,7 +111,38 @@ pub async fn get_all_points(
     State(state): State<AppState>,
     session: Session,
 ) -> Result<Json<UnitList>, (StatusCode, AppAPIResponse)> {
-    Ok(get_all_points_from_hash().await)
+    let user: User = match session.get("user").await {
+        Ok(session_request) => match session_request {
+            Some(user) => user,
+            None => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppAPIResponse::message("Couldn't get user id from session"),
+                ));
+            }
+        },
+        Err(e) => {
+            error!("Error getting user from session: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Couldn't get user id from session"),
+            ));
+        }
+    };
+    match user
+        .is_authorized::<Point>(&AuthorizableType::Point(None, String::new()), &RBAC::Read)
+        .await
+    {
+        Ok(true) => Ok(get_all_points_from_hash().await),
+        Ok(false) => Err((
+            StatusCode::FORBIDDEN,
+            AppAPIResponse::message("You are not authorized to this action."),
+        )),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            AppAPIResponse::message("Could not check user authorizations"),
+        )),
+    }
 }
 
 #[cached(time = 10)]
@@ -110,7 +161,7 @@ async fn get_all_points_from_hash() -> Json<UnitList> {
                 built_model.name = json.group.name.clone();
                 built_model.description = json.group.desc.unwrap_or_default();
                 let mut catalog = HashMap::new();
-                iter_group(g, &mut catalog, None);
+                iter_group(g, &mut catalog, vec![]);
                 for (k, v) in catalog.iter() {
                     built_model.points.push(PointResponse {
                         model: *model as i32,
@@ -149,7 +200,7 @@ path = "/{:model}/{:point}",
 summary = "retrieve a specific point details",
 params(
 ("model" = i32, Path, description = "Model number for point"),
-("point" = String, Path, description = "Name of point"),
+("point" = String, Path, description = "Canonical path of point, e.g. W or .DERMeasureDC.Prt[2].DCW"),
 ),
 responses(
 (status = NO_CONTENT, description = "successful request"),
@@ -161,6 +212,15 @@ pub async fn get_point(
     session: Session,
     Path((model, point)): Path<(i32, String)>,
 ) -> Result<Json<PointResponse>, (StatusCode, AppAPIResponse)> {
+    let point: PointPath = match point.parse() {
+        Ok(p) => p,
+        Err(e) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                AppAPIResponse::message(e.to_string()),
+            ));
+        }
+    };
     let user: User = match session.get("user").await {
         Ok(session_request) => match session_request {
             Some(user) => user,
@@ -181,11 +241,8 @@ pub async fn get_point(
     };
     if let Ok(authorized) = user
         .is_authorized::<Point>(
-            &AuthorizableType::User(User {
-                id: model,
-                ..User::default()
-            }),
-            &RBAC::Delete,
+            &AuthorizableType::Point(None, format!("{model}/{point}")),
+            &RBAC::Read,
         )
         .await
     {
@@ -197,7 +254,7 @@ pub async fn get_point(
         } else {
             let pr = PointResponse {
                 model,
-                name: point,
+                name: point.to_string(),
                 description: "".to_string(),
             };
             Ok(Json(pr))
@@ -210,21 +267,127 @@ pub async fn get_point(
     }
 }
 
+/// A value to write to a point
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default, Clone)]
+pub struct WritePoint {
+    /// the value, as it would be published to the point's MQTT command topic
+    pub value: String,
+}
+
+#[debug_handler]
+#[utoipa::path(
+put,
+path = "/{:serial_number}/{:model}/{:point}",
+summary = "write a value to a controllable point of a unit",
+params(
+("serial_number" = String, Path, description = "serial number of the unit"),
+("model" = u16, Path, description = "Model number for point"),
+("point" = String, Path, description = "Canonical path of point, e.g. WMaxLimPct"),
+),
+request_body(content_type = "application/json", content = WritePoint),
+responses(
+(status = ACCEPTED, description = "the write was queued; its result is published on the point's result topic", body = AppAPIResponse),
+(status = FORBIDDEN, description = "not allowed to write this point", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = POINTS_TAG
+)]
+pub async fn write_point(
+    State(state): State<AppState>,
+    session: Session,
+    Path((serial_number, model, point)): Path<(String, u16, String)>,
+    Json(body): Json<WritePoint>,
+) -> Result<(StatusCode, AppAPIResponse), (StatusCode, AppAPIResponse)> {
+    let point: PointPath = match point.parse() {
+        Ok(p) => p,
+        Err(e) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                AppAPIResponse::message(e.to_string()),
+            ));
+        }
+    };
+    let user: User = match session.get("user").await {
+        Ok(session_request) => match session_request {
+            Some(user) => user,
+            None => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppAPIResponse::message("Couldn't get user id from session"),
+                ));
+            }
+        },
+        Err(e) => {
+            error!("Error getting user from session: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Couldn't get user id from session"),
+            ));
+        }
+    };
+    let target = AuthorizableType::Point(Some(serial_number.clone()), format!("{model}/{point}"));
+    match user.is_authorized::<Point>(&target, &RBAC::Write).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                AppAPIResponse::message("You are not authorized to this action."),
+            ));
+        }
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Could not check user authorizations"),
+            ));
+        }
+    }
+
+    info!(
+        "{} is writing {serial_number},{model},{point}:{}",
+        user.login, body.value
+    );
+    let msg = InboundMessage {
+        serial_number,
+        model: model.to_string(),
+        point,
+        payload: body.value,
+        source: WriteSource::Rest(user.login.clone()),
+        dry_run: false,
+    };
+    match state.control_tx.send(IPCMessage::Inbound(msg)).await {
+        Ok(_) => Ok((
+            StatusCode::ACCEPTED,
+            AppAPIResponse::message("Write queued."),
+        )),
+        Err(e) => {
+            error!("Unable to queue write: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to queue write"),
+            ))
+        }
+    }
+}
+
+/// Walk a JSON model's groups, adding every point to `catalog` under its canonical path.  Repeating
+/// groups are listed once, with a `[*]` index.
 pub fn iter_group(
     group: Group,
-    mut catalog: &mut HashMap<String, ModelResponse>,
-    prefix: Option<String>,
+    catalog: &mut HashMap<String, ModelResponse>,
+    prefix: Vec<GroupSegment>,
 ) {
-    let mut pointprefix = "".to_string();
-    match prefix {
-        Some(p) => pointprefix = format!("{}.{}", p, group.name.clone()),
-        None => pointprefix = format!(".{}", group.name.clone()),
-    };
+    let mut groups = prefix;
+    groups.push(GroupSegment {
+        name: group.name.clone(),
+        index: match group.count {
+            GroupCount::Integer(1) => None,
+            _ => Some(GroupIndex::Any),
+        },
+    });
 
     for point in group.points {
-        let point_name = format!("{pointprefix}.{}", point.name.clone());
+        let point_path = PointPath::new(groups.clone(), point.name.clone());
         catalog.insert(
-            point_name,
+            point_path.to_string(),
             ModelResponse {
                 model: 0,
                 name: point.name.clone(),
@@ -235,6 +398,6 @@ pub fn iter_group(
     }
 
     for g in group.groups {
-        iter_group(g, &mut catalog, Some(pointprefix.clone()));
+        iter_group(g, catalog, groups.clone());
     }
 }
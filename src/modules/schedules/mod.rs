@@ -0,0 +1,303 @@
+use crate::config_structs::ScheduleConfig;
+use crate::consts::*;
+use crate::modules::users::{Role, User};
+use crate::modules::{AppAPIResponse, Authorizable, AuthorizableType, RBAC};
+use crate::scheduler::{next_run, validate};
+use crate::state::AppState;
+use crate::state_mgmt::{self, Schedule};
+use async_trait::async_trait;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::{debug_handler, Json};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+use utoipa::ToSchema;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+pub(crate) fn schedule_routes(state: AppState) -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(get_schedules))
+        .routes(routes!(get_schedule))
+        .routes(routes!(create_schedule))
+        .routes(routes!(update_schedule))
+        .routes(routes!(delete_schedule))
+        .with_state(state)
+}
+
+/// A schedule, and when it runs next
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default, Clone)]
+pub struct ScheduleStatus {
+    #[serde(flatten)]
+    schedule: Schedule,
+    /// unix timestamp of the next run, if it's enabled
+    next_run: Option<i64>,
+}
+impl From<Schedule> for ScheduleStatus {
+    fn from(schedule: Schedule) -> Self {
+        let next_run = if schedule.enabled {
+            next_run(&schedule, &Local::now())
+        } else {
+            None
+        };
+        ScheduleStatus { schedule, next_run }
+    }
+}
+
+#[async_trait]
+impl Authorizable for Schedule {
+    async fn check_authorization<'a>(
+        _id: &'a AuthorizableType,
+        user: &'a User,
+        rbac: &'a RBAC,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        // schedules write whenever they like, so only admins set them up
+        match rbac {
+            RBAC::Read => Ok(user.role >= Role::Operator),
+            _ => Ok(user.role == Role::Admin),
+        }
+    }
+}
+
+async fn authorize(session: &Session, rbac: &RBAC) -> Result<User, (StatusCode, AppAPIResponse)> {
+    let user: User = match session.get("user").await {
+        Ok(session_request) => match session_request {
+            Some(user) => user,
+            None => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppAPIResponse::message("Couldn't get user id from session"),
+                ));
+            }
+        },
+        Err(e) => {
+            error!("Error getting user from session: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("Couldn't get user id from session"),
+            ));
+        }
+    };
+    match user
+        .is_authorized::<Schedule>(&AuthorizableType::Unit(String::new()), rbac)
+        .await
+    {
+        Ok(true) => Ok(user),
+        Ok(false) => Err((
+            StatusCode::FORBIDDEN,
+            AppAPIResponse::message("You are not authorized to this action."),
+        )),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            AppAPIResponse::message("Could not check user authorizations"),
+        )),
+    }
+}
+
+/// The schedule, as long as it can be changed through the API.
+async fn changeable(name: &str) -> Result<Schedule, (StatusCode, AppAPIResponse)> {
+    match state_mgmt::get_schedule(name).await {
+        Ok(Some(s)) if s.from_config => Err((
+            StatusCode::CONFLICT,
+            AppAPIResponse::message("schedule is set in the config file, change it there"),
+        )),
+        Ok(Some(s)) => Ok(s),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            AppAPIResponse::message("schedule not found"),
+        )),
+        Err(e) => {
+            error!("Error getting schedule {name}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to get schedule"),
+            ))
+        }
+    }
+}
+
+async fn save(body: &ScheduleConfig) -> Result<ScheduleStatus, (StatusCode, AppAPIResponse)> {
+    if let Err(e) = validate(body) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            AppAPIResponse::message(e.to_string()),
+        ));
+    }
+    match state_mgmt::save_schedule(body, false).await {
+        Ok(s) => Ok(s.into()),
+        Err(e) => {
+            error!("Unable to save schedule {}: {e}", body.name);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message(format!("unable to save schedule: {e}")),
+            ))
+        }
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+get,
+path = "/",
+summary = "get all schedules",
+responses(
+(status = OK, description = "successful request", body = Vec<ScheduleStatus>),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = SCHEDULES_TAG
+)]
+pub async fn get_schedules(
+    State(_state): State<AppState>,
+    session: Session,
+) -> Result<Json<Vec<ScheduleStatus>>, (StatusCode, AppAPIResponse)> {
+    authorize(&session, &RBAC::Read).await?;
+    match state_mgmt::get_schedules().await {
+        Ok(schedules) => Ok(Json(schedules.into_iter().map(|s| s.into()).collect())),
+        Err(e) => {
+            error!("Unable to get schedules: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to get schedules"),
+            ))
+        }
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+get,
+path = "/{:name}",
+summary = "get a specific schedule",
+params(
+("name" = String, Path, description = "name of the schedule to retrieve")
+),
+responses(
+(status = OK, description = "successful request", body = ScheduleStatus),
+(status = NOT_FOUND, description = "no such schedule", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = SCHEDULES_TAG
+)]
+pub async fn get_schedule(
+    State(_state): State<AppState>,
+    session: Session,
+    Path(name): Path<String>,
+) -> Result<Json<ScheduleStatus>, (StatusCode, AppAPIResponse)> {
+    authorize(&session, &RBAC::Read).await?;
+    match state_mgmt::get_schedule(&name).await {
+        Ok(Some(s)) => Ok(Json(s.into())),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            AppAPIResponse::message("schedule not found"),
+        )),
+        Err(e) => {
+            error!("Error getting schedule {name}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to get schedule"),
+            ))
+        }
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(path="/",
+post,
+summary = "create a schedule",
+request_body(content_type = "application/json", content = ScheduleConfig),
+responses(
+(status = CREATED, description = "successful request", body = ScheduleStatus),
+(status = CONFLICT, description = "there's already a schedule with the name", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = SCHEDULES_TAG)]
+pub async fn create_schedule(
+    State(_state): State<AppState>,
+    session: Session,
+    Json(body): Json<ScheduleConfig>,
+) -> Result<(StatusCode, Json<ScheduleStatus>), (StatusCode, AppAPIResponse)> {
+    let user = authorize(&session, &RBAC::Write).await?;
+    match state_mgmt::get_schedule(&body.name).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                AppAPIResponse::message("there's already a schedule with that name"),
+            ));
+        }
+        Err(e) => {
+            error!("Error getting schedule {}: {e}", body.name);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to create schedule"),
+            ));
+        }
+    }
+    let schedule = save(&body).await?;
+    info!("{} created schedule {}", user.login, body.name);
+    Ok((StatusCode::CREATED, Json(schedule)))
+}
+
+#[debug_handler]
+#[utoipa::path(
+put,
+path = "/{:name}",
+summary = "change a schedule; ones from the config file can only be changed there",
+params(
+("name" = String, Path, description = "name of the schedule to change")
+),
+request_body(content_type = "application/json", content = ScheduleConfig),
+responses(
+(status = OK, description = "successful request", body = ScheduleStatus),
+(status = NOT_FOUND, description = "no such schedule", body = AppAPIResponse),
+(status = CONFLICT, description = "the schedule is from the config file", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = SCHEDULES_TAG
+)]
+pub async fn update_schedule(
+    State(_state): State<AppState>,
+    session: Session,
+    Path(name): Path<String>,
+    Json(body): Json<ScheduleConfig>,
+) -> Result<Json<ScheduleStatus>, (StatusCode, AppAPIResponse)> {
+    let user = authorize(&session, &RBAC::Write).await?;
+    changeable(&name).await?;
+    let schedule = save(&ScheduleConfig { name, ..body }).await?;
+    info!("{} changed schedule {}", user.login, schedule.schedule.name);
+    Ok(Json(schedule))
+}
+
+#[debug_handler]
+#[utoipa::path(
+delete,
+path = "/{:name}",
+summary = "delete a schedule; ones from the config file can only be removed there",
+params(
+("name" = String, Path, description = "name of the schedule to delete")
+),
+responses(
+(status = NO_CONTENT, description = "successful request"),
+(status = NOT_FOUND, description = "no such schedule", body = AppAPIResponse),
+(status = CONFLICT, description = "the schedule is from the config file", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = SCHEDULES_TAG
+)]
+pub async fn delete_schedule(
+    State(_state): State<AppState>,
+    session: Session,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, AppAPIResponse)> {
+    let user = authorize(&session, &RBAC::Delete).await?;
+    changeable(&name).await?;
+    match state_mgmt::delete_schedule(&name).await {
+        Ok(_) => {
+            info!("{} deleted schedule {name}", user.login);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(e) => {
+            error!("Unable to delete schedule {name}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message(format!("unable to delete schedule: {e}")),
+            ))
+        }
+    }
+}
@@ -1,13 +1,16 @@
-use crate::consts::*;
+use crate::auth::data::{generate_api_key, hash_api_key, hash_password, ApiKey, NewApiKey};
+use crate::consts::{DEFAULT_ADMIN_KEY_FILE, MIN_PASSWORD_LENGTH};
 use crate::modules::{AppAPIResponse, Authorizable, AuthorizableType, RBAC};
 use crate::routes::USERS_TAG;
 use crate::state::AppState;
+use crate::state_mgmt;
 use async_trait::async_trait;
 use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::{debug_handler, Json};
+use cached::Cached;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, SqlitePool};
+use sqlx::FromRow;
 use std::error::Error;
 use tower_sessions::Session;
 use utoipa::ToSchema;
@@ -21,6 +24,12 @@ pub(crate) fn user_routes(state: AppState) -> OpenApiRouter<AppState> {
         .routes(routes!(get_users))
         .routes(routes!(update_user))
         .routes(routes!(delete_user))
+        .routes(routes!(create_api_key))
+        .routes(routes!(get_api_keys))
+        .routes(routes!(delete_api_key))
+        .routes(routes!(get_operator_scopes))
+        .routes(routes!(set_operator_scopes))
+        .routes(routes!(set_password))
         .with_state(state)
 }
 
@@ -33,33 +42,21 @@ impl Authorizable for User {
     ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
         if let AuthorizableType::User(req) = id {
             let id = &req.id;
-            if *id == 0 && *rbac == RBAC::Read {
-                // probably need admin to list all users
+            // everyone may look after their own record and keys, but only admins manage users
+            if *id == user.id && (*rbac == RBAC::Read || *rbac == RBAC::Write) {
                 return Ok(true);
             }
-            if *id == 0 && *rbac == RBAC::Write {
-                // probably need admin to create users
+            if user.role != Role::Admin {
+                return Ok(false);
+            }
+            // id 0 is the team's list of users, which listing and creating are scoped to
+            if *id == 0 {
                 return Ok(true);
             }
-
-            //TODO: Implement user database
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "invalid assertion: did not get proper AuthorizableType::User object, this is a code error"
-            )));
-            // let rs = sqlx::query_as!(
-            //     User,
-            //     "SELECT * FROM users where id = $1 and team_id = $2",
-            //     id,
-            //     user.team_id
-            // )
-            //     .fetch_one(pool)
-            //     .await?;
-            // Ok(if rs.id == *id {
-            //     true
-            // } else {
-            //     false
-            // })
+            Ok(match state_mgmt::get_user(*id).await? {
+                Some(target) => target.team_id == user.team_id,
+                None => false,
+            })
         } else {
             Err(Box::new(std::io::Error::new(
                 std::io::ErrorKind::Other,
@@ -69,21 +66,12 @@ impl Authorizable for User {
     }
 }
 
-//region get
-#[debug_handler]
-#[utoipa::path(
-get,
-path = "/",
-summary = "get all users",
-responses(
-(status = OK, description = "successful request", body = Vec<User>),
-(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
-tag = USERS_TAG
-)]
-pub async fn get_users(
-    State(state): State<AppState>,
-    session: Session,
-) -> Result<Json<Vec<User>>, (StatusCode, AppAPIResponse)> {
+/// The user of the session, if they may act on user `id`.
+async fn authorized_user(
+    session: &Session,
+    id: i32,
+    rbac: &RBAC,
+) -> Result<User, (StatusCode, AppAPIResponse)> {
     let user: User = match session.get("user").await {
         Ok(session_request) => match session_request {
             Some(user) => user,
@@ -103,7 +91,13 @@ pub async fn get_users(
         }
     };
     if let Ok(authorized) = user
-        .is_authorized::<User>(&AuthorizableType::User(User::default()), &RBAC::Read)
+        .is_authorized::<User>(
+            &AuthorizableType::User(User {
+                id,
+                ..User::default()
+            }),
+            rbac,
+        )
         .await
     {
         if !authorized {
@@ -118,24 +112,93 @@ pub async fn get_users(
             AppAPIResponse::message("Could not check user authorizations"),
         ));
     }
-    //TODO: Implement database for auth
-    return Err((
-        StatusCode::NOT_IMPLEMENTED,
-        AppAPIResponse::message("auth not implemented"),
-    ));
+    Ok(user)
+}
+
+/// Users are cached by both login and id, so just drop the lot when one changes.
+async fn forget_cached_users(state: &AppState) {
+    if let Some(cache) = &state.user_cache {
+        cache.write().await.cache_clear();
+    }
+}
+
+/// Write a secret to a file only its owner can read.  Whatever was there before is replaced, not
+/// opened, so an old file's permissions don't carry over.
+fn write_secret(path: &str, secret: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{secret}")
+}
 
-    // let rs = sqlx::query_as!(
-    //     User,
-    //     "SELECT * FROM users where team_id = $1",
-    //     user.team_id
-    // )
-    //     .fetch_all(&state.pool.clone())
-    //     .await;
-    // if let Ok(data) = rs {
-    //     return Ok(Json(data));
-    // } else {
-    //     return Ok(Json(vec![]));
-    // }
+/// With no users at all nobody could log in to create one, so make an `admin` user and write an
+/// API key for it to `ADMIN_KEY_FILE_PATH`.  The key never goes to the logs.
+pub(crate) async fn bootstrap_admin() -> anyhow::Result<()> {
+    if state_mgmt::count_users().await? > 0 {
+        return Ok(());
+    }
+    let path = match std::env::var("ADMIN_KEY_FILE_PATH") {
+        Ok(s) => s,
+        Err(_e) => DEFAULT_ADMIN_KEY_FILE.to_string(),
+    };
+    // the key is written before the user exists, so a failure here is retried on the next start
+    // rather than leaving an admin nobody has the key of
+    let key = generate_api_key();
+    if let Err(e) = write_secret(&path, &key) {
+        anyhow::bail!("couldn't write the admin API key to {path}: {e}");
+    }
+    let admin = state_mgmt::create_user(
+        &"admin".to_string(),
+        0,
+        &CreateUser {
+            role: Some(Role::Admin),
+            ..CreateUser::default()
+        },
+    )
+    .await?;
+    state_mgmt::create_api_key(
+        admin.id,
+        &hash_api_key(&key),
+        &Some("bootstrap".to_string()),
+    )
+    .await?;
+    warn!("Created user 'admin'; its API key is in {path}, delete it once the key is kept safe");
+    Ok(())
+}
+
+//region get
+#[debug_handler]
+#[utoipa::path(
+get,
+path = "/",
+summary = "get all users",
+responses(
+(status = OK, description = "successful request", body = Vec<User>),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = USERS_TAG
+)]
+pub async fn get_users(
+    State(_state): State<AppState>,
+    session: Session,
+) -> Result<Json<Vec<User>>, (StatusCode, AppAPIResponse)> {
+    let user = authorized_user(&session, 0, &RBAC::Read).await?;
+    match state_mgmt::get_users(user.team_id).await {
+        Ok(users) => Ok(Json(users)),
+        Err(e) => {
+            error!("Error listing users: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to list users"),
+            ))
+        }
+    }
 }
 #[debug_handler]
 #[utoipa::path(
@@ -147,73 +210,30 @@ params(
 ),
 responses(
 (status = OK, description = "successful request", body = User),
+(status = NOT_FOUND, description = "no such user", body = AppAPIResponse),
 (status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
 tag = USERS_TAG
 )]
 pub async fn get_user(
-    State(state): State<AppState>,
+    State(_state): State<AppState>,
     session: Session,
     Path(id): Path<i32>,
 ) -> Result<Json<User>, (StatusCode, AppAPIResponse)> {
-    let user: User = match session.get("user").await {
-        Ok(session_request) => match session_request {
-            Some(user) => user,
-            None => {
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    AppAPIResponse::message("Couldn't get user id from session"),
-                ));
-            }
-        },
+    authorized_user(&session, id, &RBAC::Read).await?;
+    match state_mgmt::get_user(id).await {
+        Ok(Some(u)) => Ok(Json(u)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            AppAPIResponse::message("user not found"),
+        )),
         Err(e) => {
-            error!("Error getting user from session: {e}");
-            return Err((
+            error!("Error getting user {id}: {e}");
+            Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
-                AppAPIResponse::message("Couldn't get user id from session"),
-            ));
-        }
-    };
-    if let Ok(authorized) = user
-        .is_authorized::<User>(
-            &AuthorizableType::User(User {
-                id: id,
-                ..User::default()
-            }),
-            &RBAC::Read,
-        )
-        .await
-    {
-        if !authorized {
-            return Err((
-                StatusCode::FORBIDDEN,
-                AppAPIResponse::message("You are not authorized to this action."),
-            ));
+                AppAPIResponse::message("unable to get user"),
+            ))
         }
-    } else {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            AppAPIResponse::message("Could not check user authorizations"),
-        ));
     }
-    //TODO: Implement database for auth
-    return Err((
-        StatusCode::NOT_IMPLEMENTED,
-        AppAPIResponse::message("auth not implemented"),
-    ));
-
-    // let rs = sqlx::query_as!(
-    //     User,
-    //     "SELECT * FROM users where id = $1 and team_id = $2",
-    //     id,
-    //     user.team_id
-    // )
-    //     .fetch_one(&state.pool.clone())
-    //     .await;
-    // if let Ok(data) = rs {
-    //     return Ok(Json(data));
-    // } else {
-    //     return Err((StatusCode::NOT_FOUND, AppAPIResponse::message("user not found")));
-    // }
 }
 //endregion
 
@@ -223,18 +243,54 @@ post,
 summary = "create new user record",
 request_body(content_type = "application/json", content = CreateUser),
 responses(
-(status = CREATED, description = "successful request", body = AppAPIResponse),
+(status = CREATED, description = "successful request", body = User),
 (status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
 tag = USERS_TAG)]
 pub async fn create_user(
     State(_state): State<AppState>,
-    _session: Session,
-    Json(_body): Json<CreateUser>,
-) -> Result<String, (StatusCode, AppAPIResponse)> {
-    Err((
-        StatusCode::NOT_IMPLEMENTED,
-        AppAPIResponse::message("Creating users is not yet implemented in the api"),
-    ))
+    session: Session,
+    Json(body): Json<CreateUser>,
+) -> Result<(StatusCode, Json<User>), (StatusCode, AppAPIResponse)> {
+    let user = authorized_user(&session, 0, &RBAC::Write).await?;
+    let login = match &body.login {
+        Some(l) if !l.trim().is_empty() => match &body.issuer {
+            Some(issuer) => issuer_login(issuer, l.trim()),
+            None => l.trim().to_string(),
+        },
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                AppAPIResponse::message("a login is required"),
+            ));
+        }
+    };
+    match state_mgmt::get_user_by_login(&login).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                AppAPIResponse::message(format!("login {login} is already taken")),
+            ));
+        }
+        Err(e) => {
+            error!("Error looking up login {login}: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to create user"),
+            ));
+        }
+    }
+    // new users join the team of whoever created them
+    match state_mgmt::create_user(&login, user.team_id, &body).await {
+        Ok(u) => Ok((StatusCode::CREATED, Json(u))),
+        Err(e) => {
+            error!("Error creating user {login}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message(format!("unable to create user: {e}")),
+            ))
+        }
+    }
 }
 
 /// A User object
@@ -254,6 +310,10 @@ pub(crate) struct User {
     pub(crate) login: String,
     /// the team the user is assoociated with
     pub(crate) team_id: i32,
+    /// what the user may do
+    pub(crate) role: Role,
+    /// the OIDC issuer the user logs in through; none for the gateway's own users
+    pub(crate) issuer: Option<String>,
 }
 impl User {
     pub async fn is_authorized<T: Authorizable>(
@@ -265,9 +325,53 @@ impl User {
     }
 }
 
+/// What a user may do.  Each role may do everything the ones before it can.
+#[derive(
+    Debug,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    ToSchema,
+    sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    /// read point values, history and events
+    #[default]
+    Viewer,
+    /// also write controllable points, limited to the user's operator scopes if they have any
+    Operator,
+    /// also manage users and configuration
+    Admin,
+}
+
+/// A unit, or a single point of a unit, that an operator may write.  Operators without any scopes
+/// may write every point.
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow, PartialEq, ToSchema)]
+pub(crate) struct OperatorScope {
+    /// serial number of the unit
+    pub(crate) serial_number: String,
+    /// `{model}/{point}`, e.g. `704/WMaxLimPct`; all of the unit's points if left out
+    pub(crate) point: Option<String>,
+}
+impl OperatorScope {
+    pub(crate) fn allows(&self, serial_number: &str, point: &str) -> bool {
+        self.serial_number == serial_number && self.point.as_deref().is_none_or(|p| p == point)
+    }
+}
+
 /// A payload for creating/updating a user
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow, PartialEq, Default, ToSchema)]
 pub struct CreateUser {
+    /// the login for the user; required when creating, ignored when updating
+    pub(crate) login: Option<String>,
     /// Commonly 'first name' in USA
     pub(crate) given_name: Option<String>,
     /// Commonly 'last name' in USA
@@ -276,6 +380,18 @@ pub struct CreateUser {
     pub(crate) additional_name: Option<String>,
     /// User's preferred display name
     pub(crate) preferred_name: Option<String>,
+    /// what the user may do; new users are viewers unless told otherwise, and only admins may
+    /// change it
+    pub(crate) role: Option<Role>,
+    /// the OIDC issuer the user logs in through, with `login` being their login claim there;
+    /// left out for the gateway's own users.  Ignored when updating.
+    pub(crate) issuer: Option<String>,
+}
+
+/// What the login of a user of an OIDC issuer is stored as, so that the same login at another
+/// issuer, or of one of the gateway's own users, is someone else.
+pub(crate) fn issuer_login(issuer: &str, login: &str) -> String {
+    format!("{issuer}:{login}")
 }
 
 //region update
@@ -290,6 +406,7 @@ params(
 request_body(content_type = "application/json", content = CreateUser),
 responses(
 (status = NO_CONTENT, description = "successful request", body = AppAPIResponse),
+(status = NOT_FOUND, description = "no such user", body = AppAPIResponse),
 (status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
 tag = USERS_TAG
 )]
@@ -299,71 +416,24 @@ pub async fn update_user(
     Path(id): Path<i32>,
     Json(payload): Json<CreateUser>,
 ) -> Result<AppAPIResponse, (StatusCode, AppAPIResponse)> {
-    let user: User = match session.get("user").await {
-        Ok(session_request) => match session_request {
-            Some(user) => user,
-            None => {
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    AppAPIResponse::message("Couldn't get user id from session"),
-                ));
-            }
-        },
-        Err(e) => {
-            error!("Error getting user from session: {e}");
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AppAPIResponse::message("Couldn't get user id from session"),
-            ));
-        }
-    };
-    if let Ok(authorized) = user
-        .is_authorized::<User>(
-            &AuthorizableType::User(User {
-                id: id,
-                ..User::default()
-            }),
-            &RBAC::Write,
-        )
-        .await
-    {
-        if !authorized {
-            return Err((
-                StatusCode::FORBIDDEN,
-                AppAPIResponse::message("You are not authorized to this action."),
-            ));
+    authorized_user(&session, id, &RBAC::Write).await?;
+    if payload.role.is_some() {
+        authorized_user(&session, id, &RBAC::Admin).await?;
+    }
+    match state_mgmt::update_user(id, &payload).await {
+        Ok(Some(_)) => {
+            forget_cached_users(&state).await;
+            Ok(AppAPIResponse::message("User updated."))
         }
-    } else {
-        return Err((
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            AppAPIResponse::message("user not found"),
+        )),
+        Err(e) => Err((
             StatusCode::INTERNAL_SERVER_ERROR,
-            AppAPIResponse::message("Could not check user authorizations"),
-        ));
+            AppAPIResponse::message(format!("unable to update User: {e}")),
+        )),
     }
-    // TODO: figure out how the hell to dynamically build the query to update all the user fields
-
-    //TODO: Implement database for auth
-    return Err((
-        StatusCode::NOT_IMPLEMENTED,
-        AppAPIResponse::message("auth not implemented"),
-    ));
-
-    // match sqlx::query!(
-    //     "UPDATE users set given_name = $1, family_name = $2 where id = $3",
-    //     payload.given_name.unwrap_or_default(),
-    //     payload.family_name.unwrap_or_default(),
-    //     user.id
-    // )
-    //     .execute(&state.pool.clone())
-    //     .await
-    // {
-    //     Ok(_) => {
-    //         Ok(AppAPIResponse::message("User updated."))
-    //     }
-    //     Err(e) => Err((
-    //         StatusCode::INTERNAL_SERVER_ERROR,
-    //         AppAPIResponse::message(format!("unable to update User: {e}")),
-    //     )),
-    // }
 }
 //endregion
 
@@ -377,6 +447,7 @@ params(
 ),
 responses(
 (status = NO_CONTENT, description = "successful request"),
+(status = NOT_FOUND, description = "no such user", body = AppAPIResponse),
 (status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
 tag = USERS_TAG
 )]
@@ -385,77 +456,319 @@ pub async fn delete_user(
     session: Session,
     Path(id): Path<i32>,
 ) -> Result<StatusCode, (StatusCode, AppAPIResponse)> {
-    let user: User = match session.get("user").await {
-        Ok(session_request) => match session_request {
-            Some(user) => user,
-            None => {
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    AppAPIResponse::message("Couldn't get user id from session"),
-                ));
-            }
-        },
+    authorized_user(&session, id, &RBAC::Delete).await?;
+    match state_mgmt::delete_user(id).await {
+        Ok(true) => {
+            forget_cached_users(&state).await;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            AppAPIResponse::message("user not found"),
+        )),
         Err(e) => {
-            error!("Error getting user from session: {e}");
-            return Err((
+            error!("Unable to delete {id}: {e}");
+            Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
-                AppAPIResponse::message("Couldn't get user id from session"),
-            ));
+                AppAPIResponse::message(format!("unable to delete user: {e}")),
+            ))
         }
-    };
-    if let Ok(authorized) = user
-        .is_authorized::<User>(
-            &AuthorizableType::User(User {
-                id: id,
-                ..User::default()
-            }),
-            &RBAC::Delete,
-        )
-        .await
-    {
-        if !authorized {
+    }
+}
+
+//region api keys
+/// A payload for creating an API key
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default, ToSchema)]
+pub struct CreateApiKey {
+    /// what the key is for
+    pub(crate) name: Option<String>,
+}
+
+#[debug_handler]
+#[utoipa::path(
+post,
+path = "/{:id}/api_keys",
+summary = "create an API key for a user; the key is only ever returned here",
+params(
+("id" = i32, Path, description = "id for the user the key authenticates as")
+),
+request_body(content_type = "application/json", content = CreateApiKey),
+responses(
+(status = CREATED, description = "successful request", body = NewApiKey),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = USERS_TAG
+)]
+pub async fn create_api_key(
+    State(_state): State<AppState>,
+    session: Session,
+    Path(id): Path<i32>,
+    Json(body): Json<CreateApiKey>,
+) -> Result<(StatusCode, Json<NewApiKey>), (StatusCode, AppAPIResponse)> {
+    authorized_user(&session, id, &RBAC::Write).await?;
+    let key = generate_api_key();
+    match state_mgmt::create_api_key(id, &hash_api_key(&key), &body.name).await {
+        Ok(record) => Ok((StatusCode::CREATED, Json(NewApiKey { id: record.id, key }))),
+        Err(e) => {
+            error!("Error creating api key for user {id}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to create api key"),
+            ))
+        }
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+get,
+path = "/{:id}/api_keys",
+summary = "list a user's API keys",
+params(
+("id" = i32, Path, description = "id for the user whose keys to list")
+),
+responses(
+(status = OK, description = "successful request", body = Vec<ApiKey>),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = USERS_TAG
+)]
+pub async fn get_api_keys(
+    State(_state): State<AppState>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<ApiKey>>, (StatusCode, AppAPIResponse)> {
+    authorized_user(&session, id, &RBAC::Read).await?;
+    match state_mgmt::get_api_keys(id).await {
+        Ok(keys) => Ok(Json(keys)),
+        Err(e) => {
+            error!("Error listing api keys for user {id}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to list api keys"),
+            ))
+        }
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+delete,
+path = "/{:id}/api_keys/{:key_id}",
+summary = "revoke one of a user's API keys",
+params(
+("id" = i32, Path, description = "id for the user the key belongs to"),
+("key_id" = i64, Path, description = "id of the key to revoke")
+),
+responses(
+(status = NO_CONTENT, description = "successful request"),
+(status = NOT_FOUND, description = "no such key", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = USERS_TAG
+)]
+pub async fn delete_api_key(
+    State(_state): State<AppState>,
+    session: Session,
+    Path((id, key_id)): Path<(i32, i64)>,
+) -> Result<StatusCode, (StatusCode, AppAPIResponse)> {
+    authorized_user(&session, id, &RBAC::Write).await?;
+    match state_mgmt::delete_api_key(id, key_id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            AppAPIResponse::message("api key not found"),
+        )),
+        Err(e) => {
+            error!("Unable to delete api key {key_id}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message(format!("unable to delete api key: {e}")),
+            ))
+        }
+    }
+}
+//endregion
+
+//region operator scopes
+#[debug_handler]
+#[utoipa::path(
+get,
+path = "/{:id}/scopes",
+summary = "list the units and points an operator may write",
+params(
+("id" = i32, Path, description = "id for the user whose scopes to list")
+),
+responses(
+(status = OK, description = "successful request", body = Vec<OperatorScope>),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = USERS_TAG
+)]
+pub async fn get_operator_scopes(
+    State(_state): State<AppState>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<OperatorScope>>, (StatusCode, AppAPIResponse)> {
+    authorized_user(&session, id, &RBAC::Read).await?;
+    match state_mgmt::get_operator_scopes(id).await {
+        Ok(scopes) => Ok(Json(scopes)),
+        Err(e) => {
+            error!("Error listing scopes for user {id}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message("unable to list scopes"),
+            ))
+        }
+    }
+}
+
+#[debug_handler]
+#[utoipa::path(
+put,
+path = "/{:id}/scopes",
+summary = "replace the units and points an operator may write; an empty list allows every point",
+params(
+("id" = i32, Path, description = "id for the user whose scopes to set")
+),
+request_body(content_type = "application/json", content = Vec<OperatorScope>),
+responses(
+(status = OK, description = "successful request", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = USERS_TAG
+)]
+pub async fn set_operator_scopes(
+    State(_state): State<AppState>,
+    session: Session,
+    Path(id): Path<i32>,
+    Json(scopes): Json<Vec<OperatorScope>>,
+) -> Result<AppAPIResponse, (StatusCode, AppAPIResponse)> {
+    authorized_user(&session, id, &RBAC::Admin).await?;
+    match state_mgmt::set_operator_scopes(id, &scopes).await {
+        Ok(_) => Ok(AppAPIResponse::message("Scopes updated.")),
+        Err(e) => {
+            error!("Error setting scopes for user {id}: {e}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                AppAPIResponse::message(format!("unable to set scopes: {e}")),
+            ))
+        }
+    }
+}
+//endregion
+
+//region password
+/// A new password for logging in to the web UI
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default, ToSchema)]
+pub struct SetPassword {
+    /// the new password, or nothing to stop the user logging in with one
+    pub(crate) password: Option<String>,
+}
+
+#[debug_handler]
+#[utoipa::path(
+put,
+path = "/{:id}/password",
+summary = "set the password a user logs in to the web UI with",
+params(
+("id" = i32, Path, description = "id for the user whose password to set")
+),
+request_body(content_type = "application/json", content = SetPassword),
+responses(
+(status = OK, description = "successful request", body = AppAPIResponse),
+(status = NOT_FOUND, description = "no such user", body = AppAPIResponse),
+(status = BAD_REQUEST, description = "bad request", body = AppAPIResponse)),
+tag = USERS_TAG
+)]
+pub async fn set_password(
+    State(_state): State<AppState>,
+    session: Session,
+    Path(id): Path<i32>,
+    Json(body): Json<SetPassword>,
+) -> Result<AppAPIResponse, (StatusCode, AppAPIResponse)> {
+    authorized_user(&session, id, &RBAC::Write).await?;
+    let password_hash = match body.password {
+        Some(p) if p.len() < MIN_PASSWORD_LENGTH => {
             return Err((
-                StatusCode::FORBIDDEN,
-                AppAPIResponse::message("You are not authorized to this action."),
+                StatusCode::BAD_REQUEST,
+                AppAPIResponse::message(format!(
+                    "passwords need at least {MIN_PASSWORD_LENGTH} characters"
+                )),
             ));
         }
-    } else {
-        return Err((
+        // hashing takes long enough on purpose that it would hold up other requests
+        Some(p) => Some(
+            tokio::task::spawn_blocking(move || hash_password(&p))
+                .await
+                .map_err(|_| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        AppAPIResponse::message("unable to set password"),
+                    )
+                })?,
+        ),
+        None => None,
+    };
+    match state_mgmt::set_password_hash(id, password_hash).await {
+        Ok(true) => Ok(AppAPIResponse::message("Password set.")),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            AppAPIResponse::message("user not found"),
+        )),
+        Err(e) => Err((
             StatusCode::INTERNAL_SERVER_ERROR,
-            AppAPIResponse::message("Could not check user authorizations"),
-        ));
+            AppAPIResponse::message(format!("unable to set password: {e}")),
+        )),
     }
-    //TODO: Implement database for auth
-    return Err((
-        StatusCode::NOT_IMPLEMENTED,
-        AppAPIResponse::message("auth not implemented"),
-    ));
+}
+//endregion
 
-    // let rs = sqlx::query_as!(
-    //     User,
-    //     "SELECT * FROM users where id = $1 and team_id = $2",
-    //     id,
-    //     user.team_id
-    // )
-    //     .fetch_one(&state.pool.clone())
-    //     .await;
-    // if let Ok(data) =rs {
-    //     if let Err(e) = sqlx::query!("DELETE FROM users where id = $1", id)
-    //         .execute(&state.pool)
-    //         .await
-    //     {
-    //         error!("Unable to delete {0}: {1}", id, e);
-    //         Err((
-    //             StatusCode::INTERNAL_SERVER_ERROR,
-    //             AppAPIResponse::message(format!("unable to delete user: {e}")),
-    //         ))
-    //     } else {
-    //         Ok(StatusCode::NO_CONTENT)
-    //     }
-    // } else {
-    //     Err((
-    //         StatusCode::BAD_REQUEST,
-    //         AppAPIResponse::message("user not found"),
-    //     ))
-    // }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_mgmt::{api_key_user, test_db};
+
+    #[tokio::test]
+    async fn api_key_resolves_to_its_user() {
+        test_db().await;
+        let user = state_mgmt::create_user(&"api-key-test".to_string(), 0, &CreateUser::default())
+            .await
+            .unwrap();
+        let key = generate_api_key();
+        state_mgmt::create_api_key(user.id, &hash_api_key(&key), &None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            api_key_user(&hash_api_key(&key)).await.unwrap(),
+            Some(user.id)
+        );
+        assert_eq!(
+            api_key_user(&hash_api_key(&generate_api_key()))
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert!(state_mgmt::delete_user(user.id).await.unwrap());
+        assert_eq!(api_key_user(&hash_api_key(&key)).await.unwrap(), None);
+    }
+
+    #[test]
+    fn secrets_are_for_the_owner_only() {
+        let path = std::env::temp_dir().join(format!("admin_key_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "old").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        write_secret(path, "s3cret").unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "s3cret\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -1,7 +1,13 @@
-use crate::config_structs::{InputType, PointConfig};
+use crate::config_structs::{
+    Aggregation, CounterRollover, InputType, IntegrationMethod, PointConfig, Severity,
+    SymbolEntities,
+};
 use crate::consts::*;
 use anyhow::bail;
+use std::collections::HashMap;
 
+use crate::point_path::PointPath;
+use crate::publish_policy::PublishPolicy;
 use sunspec_rs::sunspec_models::{Access, PointIdentifier};
 
 #[derive(Debug, Clone)]
@@ -39,6 +45,28 @@ pub struct MonitoredPoint {
     /// how many standard deviations we'll allow before considering value nonsensical
     pub check_deviations: Option<u16>,
     pub this_address: Option<u16>,
+    /// whether to integrate this (power) point over time into an energy sensor
+    pub integrate: bool,
+    /// the riemann sum method used when integrating
+    pub integration_method: IntegrationMethod,
+    /// the longest gap, in seconds, between two samples that will still be integrated
+    pub integration_max_gap: u64,
+    /// how wraps and resets of a total_increasing counter are handled
+    pub counter_rollover: CounterRollover,
+    /// the canonical path of this point
+    pub path: PointPath,
+    /// the wildcard path this point was expanded from, if it is one repetition of a repeating group
+    pub group_pattern: Option<PointPath>,
+    /// which repetitions of a repeating group to monitor (all of them, if None)
+    pub repetitions: Option<Vec<u16>>,
+    /// summaries to publish across all repetitions of a repeating group
+    pub aggregate: Vec<Aggregation>,
+    /// retain, qos and which readings get published
+    pub publish: PublishPolicy,
+    /// whether a bitfield is published as one aggregated sensor, a binary sensor per bit, or both
+    pub symbol_entities: SymbolEntities,
+    /// the severity of events raised by each symbol of a bitfield or enum
+    pub severity: HashMap<String, Severity>,
 }
 
 impl MonitoredPoint {
@@ -77,17 +105,29 @@ impl MonitoredPoint {
             error!(msg);
             bail!(msg);
         }
-        let mut monitored_point_target: PointIdentifier = {
-            if pc.catalog_ref.is_some() {
-                PointIdentifier::Catalog(pc.catalog_ref.clone().unwrap())
-            } else {
-                PointIdentifier::Point(pc.point.clone().unwrap())
+        let path: PointPath = match pc.name().parse() {
+            Ok(p) => p,
+            Err(e) => {
+                let msg = format!(
+                    "Point {} in model {model} has an invalid name: {e}",
+                    pc.name()
+                );
+                error!(msg);
+                bail!(msg);
+            }
+        };
+        let publish = match PublishPolicy::from_config(&pc) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("{e}");
+                bail!(e);
             }
         };
         Ok(MonitoredPoint {
             model,
             display_name: pc.display_name,
-            name: monitored_point_target,
+            name: path.identifier(),
+            path,
             interval: interval_checked,
             device_class: pc.device_class,
             state_class: pc.state_class,
@@ -102,6 +142,18 @@ impl MonitoredPoint {
             value_max: pc.value_max,
             check_deviations: pc.check_deviations,
             this_address: None,
+            integrate: pc.integrate.unwrap_or(false),
+            integration_method: pc.integration_method.unwrap_or_default(),
+            integration_max_gap: pc
+                .integration_max_gap
+                .unwrap_or(DEFAULT_INTEGRATION_MAX_GAP_SECS),
+            counter_rollover: pc.counter_rollover.unwrap_or_default(),
+            group_pattern: None,
+            repetitions: pc.repetitions,
+            aggregate: pc.aggregate.unwrap_or_default(),
+            publish,
+            symbol_entities: pc.symbol_entities.unwrap_or_default(),
+            severity: pc.severity.unwrap_or_default(),
         })
     }
 }
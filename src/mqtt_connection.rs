@@ -1,6 +1,6 @@
 use crate::consts::*;
 use anyhow;
-use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use rumqttc::{AsyncClient, EventLoop, LastWill, MqttOptions, QoS};
 use std::fmt::{Debug, Formatter};
 use std::ops::{Deref, DerefMut};
 use tokio::time::Duration;
@@ -46,12 +46,17 @@ impl MqttConnection {
         port: u16,
         username: Option<String>,
         password: Option<String>,
+        last_will: Option<LastWill>,
+        extra_topics: Vec<String>,
     ) -> anyhow::Result<Self> {
         let mut mqttoptions = MqttOptions::new(&client, &addr, port);
         mqttoptions.set_keep_alive(Duration::from_secs(MQTT_KEEPALIVE_TIME));
         if username.is_some() && password.is_some() {
             mqttoptions.set_credentials(username.clone().unwrap(), password.clone().unwrap());
         }
+        if let Some(will) = last_will {
+            mqttoptions.set_last_will(will);
+        }
         let (mqtt_client, eventloop) = AsyncClient::new(mqttoptions, MQTT_THREAD_CHANNEL_CAPACITY);
 
         match mqtt_client
@@ -63,6 +68,11 @@ impl MqttConnection {
                 error!("Can't subscribe to inbound control topic: {e}");
             }
         }
+        for topic in extra_topics {
+            if let Err(e) = mqtt_client.subscribe(&topic, QoS::AtMostOnce).await {
+                error!("Can't subscribe to {topic}: {e}");
+            }
+        }
         Ok(MqttConnection {
             client_name: client,
             server_addr: addr,
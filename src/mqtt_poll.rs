@@ -1,23 +1,44 @@
-use crate::consts::MQTT_POLL_INTERVAL_MILLIS;
-use crate::ipc::{IPCMessage, InboundMessage, PublishMessage};
+use crate::consts::{MQTT_POLL_INTERVAL_MILLIS, MQTT_READ_ONLY_SET_TOPIC, SPARKPLUG_NAMESPACE};
+use crate::ipc::{IPCMessage, InboundMessage, PublishMessage, WriteSource};
 use crate::mqtt_connection::MqttConnection;
 use crate::payload::Payload;
+use crate::point_path::parse_command_topic;
+use crate::sinks::sparkplug::{parse_command, Command, REBIRTH_REQUESTED};
+use crate::write_policy::{parse_switch, read_only_message, set_read_only};
 use crate::GatewayError;
 use chrono::Utc;
-use rumqttc::{Event, Incoming, Outgoing, QoS};
+use rumqttc::{AsyncClient, Event, Incoming, Outgoing};
 use std::collections::VecDeque;
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
 use tokio::time::{sleep, timeout};
 
+/// Incremented every time we (re)connect to the broker, so that anything that needs to be sent
+/// once per connection (e.g. discovery) knows when to send it again.
+pub static MQTT_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Say whether read-only mode is on, without waiting on the event loop that's calling us.
+fn publish_read_only(client: &AsyncClient) {
+    let msg = read_only_message();
+    let Payload::Raw(payload) = msg.payload else {
+        return;
+    };
+    if let Err(e) = client.try_publish(msg.topic, msg.qos, msg.retain, payload) {
+        warn!("Couldn't publish read-only mode: {e}");
+    }
+}
+
 pub async fn mqtt_poll_loop(
     mqtt: MqttConnection,
     mut incoming_rx: tokio::sync::mpsc::Receiver<IPCMessage>,
     mut bcast_rx: tokio::sync::broadcast::Receiver<IPCMessage>,
     outgoing_tx: mpsc::Sender<IPCMessage>,
 ) -> Result<(), GatewayError> {
+    // for answering read-only switches straight from the event loop
+    let client = mqtt.client.clone();
     let task = tokio::task::Builder::new()
         .name("mqtt_poll_loop")
         .spawn(async move {
@@ -42,6 +63,8 @@ pub async fn mqtt_poll_loop(
                             }
                             Incoming::ConnAck(_ca) => {
                                 info!("MQTT connection established.");
+                                MQTT_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+                                publish_read_only(&client);
                             }
                             Incoming::PubAck(pa) => {
                                 dlq.retain(|x| *x != pa.pkid);
@@ -52,19 +75,52 @@ pub async fn mqtt_poll_loop(
                             Incoming::SubAck(_) => {}
                             Incoming::Publish(pr) => {
                                 info!("Received publish: {:#?} with payload {:#?}", pr, pr.payload);
-                                let mut splitval = pr.topic.splitn(5, "/");
-                                let (_, _, serial_number, model, point_name) = (
-                                    splitval.next().unwrap().to_string(),
-                                    splitval.next().unwrap().to_string(),
-                                    splitval.next().unwrap().to_string(),
-                                    splitval.next().unwrap().to_string(),
-                                    splitval.next().unwrap().to_string(),
-                                );
+                                if pr.topic == MQTT_READ_ONLY_SET_TOPIC {
+                                    let payload = String::from_utf8_lossy(&pr.payload);
+                                    match parse_switch(&payload) {
+                                        Some(on) => {
+                                            if let Err(e) = set_read_only(on).await {
+                                                error!("Couldn't set read-only mode: {e}");
+                                            }
+                                        }
+                                        None => warn!("Ignoring read-only switch to {payload}"),
+                                    }
+                                    publish_read_only(&client);
+                                    continue;
+                                }
+                                if pr.topic.starts_with(SPARKPLUG_NAMESPACE) {
+                                    match parse_command(&pr.topic, &pr.payload) {
+                                        Ok(Command::Rebirth) => {
+                                            info!("Sparkplug rebirth requested");
+                                            REBIRTH_REQUESTED.store(true, Ordering::SeqCst);
+                                        }
+                                        Ok(Command::Write(writes)) => {
+                                            for w in writes {
+                                                let _ =
+                                                    outgoing_tx.send(IPCMessage::Inbound(w)).await;
+                                            }
+                                        }
+                                        Err(e) => {
+                                            warn!("Ignoring sparkplug command: {e}");
+                                        }
+                                    }
+                                    continue;
+                                }
+                                let (serial_number, model, point) =
+                                    match parse_command_topic(&pr.topic) {
+                                        Ok(parsed) => parsed,
+                                        Err(e) => {
+                                            warn!("Ignoring inbound publish: {e}");
+                                            continue;
+                                        }
+                                    };
                                 let ipc = IPCMessage::Inbound(InboundMessage {
                                     serial_number,
                                     model,
-                                    point_name,
+                                    point,
                                     payload: str::from_utf8(&pr.payload).unwrap().to_string(),
+                                    source: WriteSource::Mqtt(pr.topic.clone()),
+                                    dry_run: false,
                                 });
                                 let _ = outgoing_tx.send(ipc).await;
                             }
@@ -107,7 +163,9 @@ pub async fn mqtt_poll_loop(
                 }
                 IPCMessage::Inbound(_) => {}
                 IPCMessage::Outbound(_) => {}
+                IPCMessage::History(_, _) => {}
                 IPCMessage::PleaseReconnect(_, _) => {}
+                IPCMessage::UnitOffline(_) => {}
                 IPCMessage::Error(_) => {}
             },
             Err(_) => {}
@@ -122,10 +180,10 @@ pub async fn mqtt_poll_loop(
                     error!("serial={}: {}", e.serial_number, e.msg);
                     return Err(GatewayError::Unspecified);
                 }
-                IPCMessage::PleaseReconnect(_, _) => {
+                IPCMessage::PleaseReconnect(_, _) | IPCMessage::UnitOffline(_) => {
                     unreachable!();
                 }
-                IPCMessage::Inbound(_) => {
+                IPCMessage::Inbound(_) | IPCMessage::History(_, _) => {
                     unreachable!();
                 }
                 IPCMessage::Shutdown => {
@@ -147,18 +205,21 @@ pub async fn mqtt_poll_loop(
                 let _enter = span.enter();
 
                 while let Some(msg) = outbound.pop_front() {
-                    let payload = match serde_json::to_vec(&msg.payload) {
-                        Ok(p) => p,
-                        Err(e) => {
-                            error!("Payload couldn't be serialized to vec: {e}");
-                            continue;
-                        }
+                    let payload = match msg.payload {
+                        Payload::Clear => vec![],
+                        Payload::Raw(ref bytes) => bytes.clone(),
+                        _ => match serde_json::to_vec(&msg.payload) {
+                            Ok(p) => p,
+                            Err(e) => {
+                                error!("Payload couldn't be serialized to vec: {e}");
+                                continue;
+                            }
+                        },
                     };
 
                     match timeout(
                         Duration::from_secs(3),
-                        mqtt.client
-                            .publish(msg.topic, QoS::AtLeastOnce, false, payload),
+                        mqtt.client.publish(msg.topic, msg.qos, msg.retain, payload),
                     )
                     .await
                     {
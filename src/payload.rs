@@ -1,10 +1,17 @@
-use crate::config_structs::InputType;
+use crate::config_structs::{InputType, SymbolEntities};
 use crate::consts::*;
+use crate::counter_tracking::adjust_counter;
+use crate::energy_integration::integrate_payload;
+use crate::events::EventNotification;
+use crate::ha_inputs::{apply_input_config, input_state};
+use crate::ha_metadata;
 use crate::monitored_point::MonitoredPoint;
+use crate::point_path::command_topic;
 use crate::state_mgmt::{
-    check_needs_adjust, get_bitfield_history, get_history, write_bitfield_history,
+    check_needs_adjust, get_bitfield_history, get_history, write_bitfield_history, AuditRecord,
 };
 use crate::sunspec_unit::SunSpecUnit;
+use crate::symbol_points::{apply_literals, bitfield_raw, enum_options, enum_raw, is_enum};
 use chrono::{DateTime, Utc};
 use num_traits::pow::Pow;
 use serde::{Deserialize, Serialize};
@@ -19,15 +26,27 @@ pub struct DeviceInfo {
     pub name: String,
     pub model: String,
     pub sw_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hw_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via_device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub configuration_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_area: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum PayloadValueType {
     Float(f64),
     Int(i64),
     String(String),
     Boolean(bool),
+    /// the active symbols of a bitfield
+    Array(Vec<String>),
     #[default]
     None,
 }
@@ -37,11 +56,18 @@ pub enum PayloadValueType {
 pub enum Payload {
     Config(HAConfigPayload),
     CurrentState(StatePayload),
+    Event(EventNotification),
+    Audit(AuditRecord),
+    /// an empty retained message, which removes whatever is retained on the topic
+    Clear,
+    /// an already encoded payload, e.g. sparkplug protobuf
+    #[serde(skip)]
+    Raw(Vec<u8>),
     #[default]
     None,
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum EntityCategory {
     Config,
@@ -97,18 +123,27 @@ pub struct HAConfigPayload {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub payload_press: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub min: Option<i32>,
+    pub min: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub max: Option<i32>,
+    pub max: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub step: Option<i32>,
+    pub step: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_on: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_off: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StatePayload {
     pub value: PayloadValueType,
+    /// the raw register value of an enum or bitfield
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -123,6 +158,7 @@ impl Default for StatePayload {
     fn default() -> Self {
         StatePayload {
             value: PayloadValueType::None,
+            raw: None,
             last_seen: Utc::now(),
             description: None,
             label: None,
@@ -139,6 +175,42 @@ pub struct CompoundPayload {
     pub(crate) state_topic: String,
 }
 
+/// A single sensor for a bitfield, with the active symbols as its state and the raw value and
+/// model literals alongside them as attributes.
+fn aggregated_bitfield(
+    sn: &String,
+    config: &HAConfigPayload,
+    state: &StatePayload,
+    point_data: Option<&Point>,
+    monitored_point: &MonitoredPoint,
+    active: &[String],
+) -> CompoundPayload {
+    let model = &monitored_point.model;
+    let point_name = monitored_point.path.slug();
+    let state_topic = format!("sunspec_gateway/{sn}/{model}/{}", monitored_point.path);
+
+    let mut config = config.clone();
+    config.state_topic = state_topic.clone();
+    config.json_attributes_topic = Some(state_topic.clone());
+    config.value_template = Some("{{ value_json.value | join(', ') or 'none' }}".to_string());
+
+    let mut state = state.clone();
+    state.value = PayloadValueType::Array(active.to_vec());
+    state.raw = bitfield_raw(point_data.and_then(|p| p.symbol.as_deref()), active);
+    apply_literals(
+        &mut state,
+        point_data,
+        monitored_point.display_name.as_ref(),
+    );
+
+    CompoundPayload {
+        config,
+        config_topic: format!("homeassistant/sensor/{sn}/{model}_{point_name}/config"),
+        state,
+        state_topic,
+    }
+}
+
 pub async fn generate_payloads(
     unit: &SunSpecUnit,
     point_data: Option<&Point>,
@@ -147,11 +219,9 @@ pub async fn generate_payloads(
 ) -> Vec<CompoundPayload> {
     let sn = unit.serial_number.clone();
     let model = monitored_point.model.clone();
-    let point_identifier = monitored_point.name.clone().to_string();
-    let point_name = point_identifier
-        .replace("[", "")
-        .replace("]", "")
-        .replace(".", "_");
+    // point_name goes into HA object ids and unique ids, topic_name into our own topics
+    let point_name = monitored_point.path.slug();
+    let topic_name = monitored_point.path.to_string();
 
     let log_prefix = format!(
         "[{}:{} {sn} {model}/{point_name}]",
@@ -164,9 +234,19 @@ pub async fn generate_payloads(
     } else {
         config_payload.name = format!("{model}-{point_name}");
     }
-    config_payload.device_class = monitored_point.device_class.clone();
-    config_payload.state_class = monitored_point.state_class.clone();
-    config_payload.expires_after = 300;
+    // anything the config doesn't say is worked out from the model definition
+    let inferred = point_data
+        .map(|p| ha_metadata::from_point(p, monitored_point.uom.as_deref()))
+        .unwrap_or_default();
+    config_payload.device_class = monitored_point
+        .device_class
+        .clone()
+        .or(inferred.device_class);
+    config_payload.state_class = monitored_point.state_class.clone().or(inferred.state_class);
+    config_payload.entity_category = inferred.entity_category;
+    config_payload.expires_after = monitored_point
+        .publish
+        .expires_after(monitored_point.interval);
     config_payload.value_template = Some("{{ value_json.value }}".to_string());
     config_payload.unique_id = format!("{sn}.{model}.{point_name}");
     config_payload.entity_id = format!("sensor.{sn}_{model}_{point_name}");
@@ -179,12 +259,8 @@ pub async fn generate_payloads(
             }
             ValueType::Integer(int) => {
                 debug!("Response for {model}/{point_name}: {int}");
-                if monitored_point.uom.is_some() {
-                    // we are overriding the default uom from config
-                    config_payload.native_uom = monitored_point.uom.clone();
-                } else {
-                    config_payload.native_uom = point_data.unwrap().units.clone();
-                }
+                // the uom from config overrides the one in the model
+                config_payload.native_uom = monitored_point.uom.clone().or(inferred.uom.clone());
 
                 // if we are employing a scale factor on an int, it becomes a float
                 if let Some(scale) = monitored_point.scale_factor {
@@ -207,7 +283,7 @@ pub async fn generate_payloads(
                             return vec![];
                         }
                     }
-                    match get_history(format!("{sn}.{model}.{point_name}")).await {
+                    match get_history(config_payload.unique_id.clone()).await {
                         Ok(ag) => {
                             let mut deviations: u16 = CHECK_DEVIATIONS_COUNT;
                             if monitored_point.check_deviations.is_some() {
@@ -246,12 +322,8 @@ pub async fn generate_payloads(
                 } else {
                     config_payload.suggested_display_precision = DEFAULT_DISPLAY_PRECISION;
                 };
-                if monitored_point.uom.is_some() {
-                    // we are overriding the default uom from config
-                    config_payload.native_uom = monitored_point.uom.clone();
-                } else {
-                    config_payload.native_uom = point_data.unwrap().units.clone();
-                }
+                // the uom from config overrides the one in the model
+                config_payload.native_uom = monitored_point.uom.clone().or(inferred.uom.clone());
                 let scaled_value: f64;
                 if let Some(scale) = monitored_point.scale_factor {
                     scaled_value = apply_scale_factor(*float, scale);
@@ -270,7 +342,7 @@ pub async fn generate_payloads(
                         return vec![];
                     }
                 }
-                match get_history(format!("{sn}.{model}.{point_name}")).await {
+                match get_history(config_payload.unique_id.clone()).await {
                     Ok(ag) => {
                         let mut deviations: u16 = CHECK_DEVIATIONS_COUNT;
                         if monitored_point.check_deviations.is_some() {
@@ -326,11 +398,25 @@ pub async fn generate_payloads(
                 state_payload.value = PayloadValueType::String(boolean.to_string());
             }
             ValueType::Array(vec) => {
+                let mut payloads: Vec<CompoundPayload> = vec![];
+                if monitored_point.symbol_entities != SymbolEntities::PerBit {
+                    payloads.push(aggregated_bitfield(
+                        &sn,
+                        &config_payload,
+                        &state_payload,
+                        point_data,
+                        monitored_point,
+                        vec,
+                    ));
+                }
+                if monitored_point.symbol_entities == SymbolEntities::Aggregated {
+                    return payloads;
+                }
+
                 // we have an array of strings, we need to make binary sensors.
                 let string_on: String = String::from("on");
                 let string_off: String = String::from("off");
 
-                let mut payloads: Vec<CompoundPayload> = vec![];
                 let mut updated_uniques: Vec<String> = vec![];
 
                 let state_obj_id = format!("{sn}.{model}.{point_name}");
@@ -347,24 +433,11 @@ pub async fn generate_payloads(
                     let mut config_payload = config_payload.clone();
                     let mut state_payload = state_payload.clone();
                     // configure this point's state addresses
-                    let config_topic: String;
-                    let state_topic: String;
-                    if let Some(group_address) = monitored_point.this_address {
-                        config_topic = format!(
-                            "homeassistant/binary_sensor/{sn}/{model}_{point_name}_{state}_{group_address}/config"
-                        );
-                        state_topic = format!(
-                            "sunspec_gateway/{sn}/{model}/{group_address}/{point_name}_{state}"
-                        );
-                        config_payload.unique_id =
-                            format!("{sn}.{model}.{point_name}.{state}.{group_address}");
-                    } else {
-                        config_topic = format!(
-                            "homeassistant/binary_sensor/{sn}/{model}_{point_name}_{state}/config"
-                        );
-                        state_topic = format!("sunspec_gateway/{sn}/{model}/{point_name}_{state}");
-                        config_payload.unique_id = format!("{sn}.{model}.{point_name}.{state}");
-                    }
+                    let config_topic = format!(
+                        "homeassistant/binary_sensor/{sn}/{model}_{point_name}_{state}/config"
+                    );
+                    let state_topic = format!("sunspec_gateway/{sn}/{model}/{topic_name}_{state}");
+                    config_payload.unique_id = format!("{sn}.{model}.{point_name}.{state}");
                     config_payload.entity_id =
                         format!("binary_sensor.{model}_{point_name}_{state}");
                     config_payload.name = format!("{model}/{point_name}: {state}");
@@ -387,24 +460,11 @@ pub async fn generate_payloads(
                     let mut config_payload = config_payload.clone();
                     let mut state_payload = state_payload.clone();
                     // configure this point's state addresses
-                    let config_topic: String;
-                    let state_topic: String;
-                    if let Some(group_address) = monitored_point.this_address {
-                        config_topic = format!(
-                            "homeassistant/binary_sensor/{sn}/{model}_{point_name}_{state}_{group_address}/config"
-                        );
-                        state_topic = format!(
-                            "sunspec_gateway/{sn}/{model}/{group_address}/{point_name}_{state}"
-                        );
-                        config_payload.unique_id =
-                            format!("{sn}.{model}.{point_name}.{state}.{group_address}");
-                    } else {
-                        config_topic = format!(
-                            "homeassistant/binary_sensor/{sn}/{model}_{point_name}_{state}/config"
-                        );
-                        state_topic = format!("sunspec_gateway/{sn}/{model}/{point_name}_{state}");
-                        config_payload.unique_id = format!("{sn}.{model}.{point_name}.{state}");
-                    }
+                    let config_topic = format!(
+                        "homeassistant/binary_sensor/{sn}/{model}_{point_name}_{state}/config"
+                    );
+                    let state_topic = format!("sunspec_gateway/{sn}/{model}/{topic_name}_{state}");
+                    config_payload.unique_id = format!("{sn}.{model}.{point_name}.{state}");
                     config_payload.entity_id =
                         format!("binary_sensor.{model}_{point_name}_{state}");
                     config_payload.name = format!("{model}/{point_name}: {state}");
@@ -430,44 +490,23 @@ pub async fn generate_payloads(
                             );
                         }
                         for stale_unique in stale {
-                            let mut splitval = stale_unique.splitn(4, ".");
-                            let (sn, model, point_name, state) = (
-                                splitval.next().unwrap().to_string(),
-                                splitval.next().unwrap().to_string(),
-                                splitval.next().unwrap().to_string(),
-                                splitval.next().unwrap().to_string(),
-                            );
+                            // uniques are {sn}.{model}.{point_name}.{state}, and only the state varies
+                            let Some(state) = stale_unique.splitn(4, ".").nth(3) else {
+                                continue;
+                            };
                             // clone preexisiting objects
                             let mut config_payload = config_payload.clone();
                             let mut state_payload = state_payload.clone();
-                            let config_topic: String;
-                            let state_topic: String;
                             // configure this point's state addresses
-                            if let Some(group_address) = monitored_point.this_address {
-                                config_topic = format!(
-                                    "homeassistant/binary_sensor/{sn}/{model}_{point_name}_{state}_{group_address}/config"
-                                );
-                                state_topic =
-                                    format!("sunspec_gateway/{sn}/{model}/{group_address}/{point_name}_{state}");
-                                config_payload.unique_id =
-                                    format!("{sn}.{model}.{point_name}.{state}.{group_address}");
-                                config_payload.entity_id = format!(
-                                    "binary_sensor.{model}_{point_name}_{state}.{group_address}"
-                                );
-                                config_payload.name =
-                                    format!("{model}/{point_name}.{group_address}: {state}");
-                            } else {
-                                config_topic = format!(
+                            let config_topic = format!(
                                 "homeassistant/binary_sensor/{sn}/{model}_{point_name}_{state}/config"
                             );
-                                state_topic =
-                                    format!("sunspec_gateway/{sn}/{model}/{point_name}_{state}");
-                                config_payload.unique_id =
-                                    format!("{sn}.{model}.{point_name}.{state}");
-                                config_payload.entity_id =
-                                    format!("binary_sensor.{model}_{point_name}_{state}");
-                                config_payload.name = format!("{model}/{point_name}: {state}");
-                            }
+                            let state_topic =
+                                format!("sunspec_gateway/{sn}/{model}/{topic_name}_{state}");
+                            config_payload.unique_id = format!("{sn}.{model}.{point_name}.{state}");
+                            config_payload.entity_id =
+                                format!("binary_sensor.{model}_{point_name}_{state}");
+                            config_payload.name = format!("{model}/{point_name}: {state}");
                             config_payload.state_topic = state_topic.clone();
                             config_payload.payload_on = Some(string_on.clone());
                             config_payload.payload_off = Some(string_off.clone());
@@ -493,61 +532,98 @@ pub async fn generate_payloads(
             ValueType::Pad => {}
         }
     }
-    let mut config_topic: String;
-    if let Some(group_address) = monitored_point.this_address {
-        config_topic =
-            format!("homeassistant/sensor/{sn}/{model}_{point_name}_{group_address}/config");
-    } else {
-        config_topic = format!("homeassistant/sensor/{sn}/{model}_{point_name}/config");
-    };
+    let mut config_topic = format!("homeassistant/sensor/{sn}/{model}_{point_name}/config");
+    let state_topic = format!("sunspec_gateway/{sn}/{model}/{topic_name}");
+    let has_inputs = matches!(monitored_point.write_mode, Access::ReadWrite)
+        && monitored_point.input_type.is_some();
+    if let (Some(ValueType::String(symbol)), true) = (val, is_enum(point_data)) {
+        let symbols = point_data.and_then(|p| p.symbol.as_deref());
+        state_payload.raw = enum_raw(symbols, symbol);
+        apply_literals(
+            &mut state_payload,
+            point_data,
+            monitored_point.display_name.as_ref(),
+        );
+        // enums with inputs are published as a select, switch, etc. instead
+        if !has_inputs && monitored_point.device_class.is_none() {
+            config_payload.device_class = Some("enum".to_string());
+            config_payload.options = Some(enum_options(symbols, symbol));
+            config_payload.json_attributes_topic = Some(state_topic.clone());
+        }
+    }
     if matches!(monitored_point.write_mode, Access::ReadWrite) {
-        config_payload.command_topic =
-            Some(format!("sunspec_gateway/input/{sn}/{model}/{point_name}"));
+        config_payload.command_topic = Some(command_topic(&sn, &model, &monitored_point.path));
         match &monitored_point.input_type {
-            Some(input) => match input {
-                InputType::Select(options) => {
-                    config_payload.options = Some(options.to_vec());
-                    config_payload.entity_category = Some(EntityCategory::Config);
-                    config_payload.entity_id = format!("select.{model}_{point_name}");
-                    config_topic = format!("homeassistant/select/{sn}/{model}_{point_name}/config");
-                }
-                InputType::Switch(switch) => {
-                    let switch = switch.clone();
-                    config_payload.payload_on = Some(switch.on);
-                    config_payload.payload_off = Some(switch.off);
-                    config_payload.entity_category = Some(EntityCategory::Config);
-                    config_payload.entity_id = format!("switch.{model}_{point_name}");
-                    config_topic = format!("homeassistant/switch/{sn}/{model}_{point_name}/config");
-                }
-                InputType::Button(button) => {
-                    config_payload.payload_press = Some(button.to_string());
-                    config_payload.entity_category = Some(EntityCategory::Config);
-                    config_payload.entity_id = format!("button.{model}_{point_name}");
-                    config_topic = format!("homeassistant/button/{sn}/{model}_{point_name}/config");
-                }
-                InputType::Number(num) => {
-                    config_payload.min = Some(num.min);
-                    config_payload.max = Some(num.max);
-                    config_payload.step = num.step;
-                    config_payload.mode = num.mode.clone();
-                    config_payload.entity_category = Some(EntityCategory::Config);
-                    config_payload.entity_id = format!("number.{model}_{point_name}");
-                    config_topic = format!("homeassistant/number/{sn}/{model}_{point_name}/config");
+            Some(input) => {
+                let component = match input {
+                    InputType::Select(_) => "select",
+                    InputType::Switch(_) => "switch",
+                    InputType::Button(_) => "button",
+                    InputType::Number(_) => "number",
+                };
+                // the slider's step is one unit of the register, scale factors and all
+                let model_scale_factor = match (input, monitored_point.model.parse()) {
+                    (InputType::Number(_), Ok(model)) => unit
+                        .scale_factor(model, &monitored_point.path)
+                        .await
+                        .unwrap_or(0),
+                    _ => 0,
+                };
+                apply_input_config(
+                    &mut config_payload,
+                    input,
+                    monitored_point.scale_factor,
+                    model_scale_factor,
+                );
+                if val.is_some() {
+                    let symbols = point_data.and_then(|p| p.symbol.as_deref());
+                    match input_state(input, symbols, &state_payload.value) {
+                        Some(state) => state_payload.value = state,
+                        None => {
+                            if matches!(input, InputType::Select(_) | InputType::Switch(_)) {
+                                warn!("{log_prefix}: {:?} doesn't match any of the configured {component} options.", state_payload.value);
+                            }
+                        }
+                    }
                 }
-            },
+                config_payload.entity_category = Some(EntityCategory::Config);
+                config_payload.entity_id = format!("{component}.{model}_{point_name}");
+                config_topic =
+                    format!("homeassistant/{component}/{sn}/{model}_{point_name}/config");
+            }
             None => {
                 error!("{log_prefix}: readwrite set in config, but no inputs specified.");
             }
         };
     }
-    let state_topic: String;
-    if let Some(group_address) = monitored_point.this_address {
-        state_topic = format!("sunspec_gateway/{sn}/{model}/{group_address}/{point_name}");
-    } else {
-        state_topic = format!("sunspec_gateway/{sn}/{model}/{point_name}");
+    config_payload.state_topic = state_topic.clone();
+
+    if val.is_some() && config_payload.state_class.as_deref() == Some("total_increasing") {
+        adjust_counter(
+            unit,
+            &log_prefix,
+            &config_payload.unique_id,
+            monitored_point,
+            point_data,
+            &mut state_payload,
+        )
+        .await;
     }
 
-    config_payload.state_topic = state_topic.clone();
+    let mut payloads: Vec<CompoundPayload> = vec![];
+    if monitored_point.integrate && val.is_some() {
+        if let Some(energy) = integrate_payload(
+            unit,
+            monitored_point,
+            &point_name,
+            &config_payload,
+            &state_payload,
+        )
+        .await
+        {
+            payloads.push(energy);
+        }
+    }
 
     let resp = CompoundPayload {
         config: config_payload,
@@ -555,5 +631,6 @@ pub async fn generate_payloads(
         config_topic,
         state_topic,
     };
-    vec![resp]
+    payloads.insert(0, resp);
+    payloads
 }
@@ -0,0 +1,289 @@
+use crate::consts::*;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use sunspec_rs::sunspec_models::PointIdentifier;
+
+/// Errors from parsing a point path
+#[derive(Error, Debug, PartialEq)]
+pub enum PointPathError {
+    #[error("point path is empty")]
+    Empty,
+    #[error("point path {0} has an empty segment")]
+    EmptySegment(String),
+    #[error("point path {0} has an invalid repetition index")]
+    InvalidIndex(String),
+    #[error("point path {0} has a repetition index on the point itself")]
+    IndexedPoint(String),
+    #[error("{0} is not a command topic")]
+    InvalidTopic(String),
+}
+
+/// Which repetition(s) of a repeating group a path segment refers to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GroupIndex {
+    /// a single repetition, 1-based to match the SunSpec catalog
+    Repetition(u16),
+    /// every repetition, written `[*]`
+    Any,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GroupSegment {
+    pub name: String,
+    pub index: Option<GroupIndex>,
+}
+
+/// The canonical name of a point.
+///
+/// Points in fixed-layout models are just their id (`W`, `PhVphA`).  Points inside JSON model
+/// groups use the same syntax as the sunspec_rs point catalog: a leading `.`, then each group name
+/// separated by `.`, with a 1-based repetition index in brackets for repeating groups, then the
+/// point name, e.g. `.DERMeasureDC.Prt[2].DCW`.  In config, `[*]` selects every repetition.
+///
+/// This string is what the points API lists, what `catalog_ref` accepts, and what is used in the
+/// state and command topics, so a command topic can always be turned back into the point it
+/// belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct PointPath {
+    pub groups: Vec<GroupSegment>,
+    pub point: String,
+}
+
+impl PointPath {
+    pub fn new(groups: Vec<GroupSegment>, point: String) -> Self {
+        PointPath { groups, point }
+    }
+
+    /// whether this path names a point in a JSON model group (i.e. a catalog entry)
+    pub fn is_catalog(&self) -> bool {
+        !self.groups.is_empty()
+    }
+
+    /// whether any group in this path is a `[*]` wildcard
+    pub fn is_wildcard(&self) -> bool {
+        self.groups.iter().any(|g| g.index == Some(GroupIndex::Any))
+    }
+
+    pub fn identifier(&self) -> PointIdentifier {
+        if self.is_catalog() {
+            PointIdentifier::Catalog(self.to_string())
+        } else {
+            PointIdentifier::Point(self.point.clone())
+        }
+    }
+
+    /// Whether a concrete path is selected by this (possibly wildcarded) path.  A group the
+    /// catalog lists without an index is only repeated once, so it matches `[*]` and `[1]`.
+    pub fn matches(&self, other: &PointPath) -> bool {
+        if self.point != other.point || self.groups.len() != other.groups.len() {
+            return false;
+        }
+        self.groups.iter().zip(other.groups.iter()).all(|(a, b)| {
+            a.name == b.name
+                && match (&a.index, &b.index) {
+                    (Some(GroupIndex::Any), _) => true,
+                    (None, None) => true,
+                    (Some(GroupIndex::Repetition(1)), None) => true,
+                    (None, Some(GroupIndex::Repetition(1))) => true,
+                    (Some(x), Some(y)) => x == y,
+                    _ => false,
+                }
+        })
+    }
+
+    /// The repetition a concrete path has in the position of this path's `[*]`.
+    pub fn wildcard_repetition(&self, concrete: &PointPath) -> Option<u16> {
+        let pos = self
+            .groups
+            .iter()
+            .position(|g| g.index == Some(GroupIndex::Any))?;
+        match concrete.groups.get(pos)?.index {
+            Some(GroupIndex::Repetition(i)) => Some(i),
+            None => Some(1),
+            Some(GroupIndex::Any) => None,
+        }
+    }
+
+    /// A version of this path usable in Home Assistant object ids and our unique ids, which only
+    /// allow `[a-zA-Z0-9_-]`.  This isn't reversible; use the path itself where that matters.
+    pub fn slug(&self) -> String {
+        let mut slug = String::new();
+        for g in self.groups.iter() {
+            slug.push('_');
+            slug.push_str(&g.name);
+            if let Some(GroupIndex::Repetition(i)) = g.index {
+                slug.push_str(&format!("_{i}"));
+            }
+        }
+        if self.is_catalog() {
+            slug.push('_');
+        }
+        slug.push_str(&self.point);
+        slug
+    }
+}
+
+impl Display for PointPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for g in self.groups.iter() {
+            write!(f, ".{}", g.name)?;
+            match g.index {
+                Some(GroupIndex::Repetition(i)) => write!(f, "[{i}]")?,
+                Some(GroupIndex::Any) => write!(f, "{REPEATING_GROUP_WILDCARD}")?,
+                None => {}
+            }
+        }
+        if self.is_catalog() {
+            write!(f, ".")?;
+        }
+        write!(f, "{}", self.point)
+    }
+}
+
+impl FromStr for PointPath {
+    type Err = PointPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(PointPathError::Empty);
+        }
+        let Some(rest) = s.strip_prefix('.') else {
+            if s.contains('[') || s.contains(']') {
+                return Err(PointPathError::IndexedPoint(s.to_string()));
+            }
+            return Ok(PointPath::new(vec![], s.to_string()));
+        };
+        let mut segments: Vec<&str> = rest.split('.').collect();
+        let point = segments.pop().unwrap_or_default();
+        if point.is_empty() || segments.is_empty() {
+            return Err(PointPathError::EmptySegment(s.to_string()));
+        }
+        if point.contains('[') || point.contains(']') {
+            return Err(PointPathError::IndexedPoint(s.to_string()));
+        }
+        let mut groups: Vec<GroupSegment> = vec![];
+        for segment in segments {
+            let (name, index) = match segment.split_once('[') {
+                Some((name, idx)) => {
+                    let idx = idx
+                        .strip_suffix(']')
+                        .ok_or(PointPathError::InvalidIndex(s.to_string()))?;
+                    let index = if idx == "*" {
+                        GroupIndex::Any
+                    } else {
+                        match idx.parse::<u16>() {
+                            Ok(i) if i > 0 => GroupIndex::Repetition(i),
+                            _ => return Err(PointPathError::InvalidIndex(s.to_string())),
+                        }
+                    };
+                    (name, Some(index))
+                }
+                None => (segment, None),
+            };
+            if name.is_empty() || name.contains(']') {
+                return Err(PointPathError::EmptySegment(s.to_string()));
+            }
+            groups.push(GroupSegment {
+                name: name.to_string(),
+                index,
+            });
+        }
+        Ok(PointPath::new(groups, point.to_string()))
+    }
+}
+
+/// The topic Home Assistant (or anyone else) publishes to in order to write a point.
+pub fn command_topic(sn: &str, model: &str, path: &PointPath) -> String {
+    format!("{MQTT_INBOUND_CONTROL_PREFIX}/{sn}/{model}/{path}")
+}
+
+/// Split a command topic back into its serial number, model and point path.
+pub fn parse_command_topic(topic: &str) -> Result<(String, String, PointPath), PointPathError> {
+    let rest = topic
+        .strip_prefix(MQTT_INBOUND_CONTROL_PREFIX)
+        .and_then(|r| r.strip_prefix('/'))
+        .ok_or(PointPathError::InvalidTopic(topic.to_string()))?;
+    let mut splitval = rest.splitn(3, '/');
+    match (splitval.next(), splitval.next(), splitval.next()) {
+        (Some(sn), Some(model), Some(point)) if !sn.is_empty() && !model.is_empty() => {
+            Ok((sn.to_string(), model.to_string(), point.parse()?))
+        }
+        _ => Err(PointPathError::InvalidTopic(topic.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_canonical_paths() {
+        for path in [
+            "W",
+            ".DERMeasureAC.W",
+            ".DERMeasureDC.Prt[2].DCW",
+            ".DERMeasureDC.Prt[*].DCW",
+            ".a[1].b[12].c",
+        ] {
+            let parsed: PointPath = path.parse().unwrap();
+            assert_eq!(parsed.to_string(), path);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        assert_eq!("".parse::<PointPath>(), Err(PointPathError::Empty));
+        assert!(".W".parse::<PointPath>().is_err());
+        assert!(".a..W".parse::<PointPath>().is_err());
+        assert!(".a[0].W".parse::<PointPath>().is_err());
+        assert!(".a[x].W".parse::<PointPath>().is_err());
+        assert!(".a[1.W".parse::<PointPath>().is_err());
+        assert!(".a.W[1]".parse::<PointPath>().is_err());
+        assert!("W[1]".parse::<PointPath>().is_err());
+    }
+
+    #[test]
+    fn identifiers_match_catalog_keys() {
+        let plain: PointPath = "W".parse().unwrap();
+        assert_eq!(plain.identifier(), PointIdentifier::Point("W".to_string()));
+        let grouped: PointPath = ".DERMeasureDC.Prt[2].DCW".parse().unwrap();
+        assert_eq!(
+            grouped.identifier(),
+            PointIdentifier::Catalog(".DERMeasureDC.Prt[2].DCW".to_string())
+        );
+    }
+
+    #[test]
+    fn wildcards_select_repetitions() {
+        let pattern: PointPath = ".DERMeasureDC.Prt[*].DCW".parse().unwrap();
+        let second: PointPath = ".DERMeasureDC.Prt[2].DCW".parse().unwrap();
+        let only: PointPath = ".DERMeasureDC.Prt.DCW".parse().unwrap();
+        let other: PointPath = ".DERMeasureDC.Prt[2].DCA".parse().unwrap();
+        assert!(pattern.matches(&second));
+        assert!(pattern.matches(&only));
+        assert!(!pattern.matches(&other));
+        assert_eq!(pattern.wildcard_repetition(&second), Some(2));
+        assert_eq!(pattern.wildcard_repetition(&only), Some(1));
+    }
+
+    #[test]
+    fn command_topics_round_trip() {
+        for path in ["W", ".DERMeasureDC.Prt[2].DCW"] {
+            let parsed: PointPath = path.parse().unwrap();
+            let topic = command_topic("SN123", "714", &parsed);
+            let (sn, model, recovered) = parse_command_topic(&topic).unwrap();
+            assert_eq!(sn, "SN123");
+            assert_eq!(model, "714");
+            assert_eq!(recovered, parsed);
+        }
+    }
+
+    #[test]
+    fn slugs_are_safe_for_home_assistant() {
+        let grouped: PointPath = ".DERMeasureDC.Prt[2].DCW".parse().unwrap();
+        assert_eq!(grouped.slug(), "_DERMeasureDC_Prt_2_DCW");
+        let plain: PointPath = "W".parse().unwrap();
+        assert_eq!(plain.slug(), "W");
+    }
+}
@@ -0,0 +1,257 @@
+use crate::config_structs::{GatewayConfig, PointConfig, ProfileConfig};
+use anyhow::{anyhow, bail, Context};
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+
+/// The profiles every gateway has, e.g. `inverter_3ph_basic` and `meter_wye_full`.
+const BUILTIN_PROFILES: &str = include_str!("../profiles/builtin.yaml");
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_string())
+}
+
+/// A point's settings, with those of any `<<` merged in underneath them.
+fn mapping(point: &Value) -> anyhow::Result<Mapping> {
+    let Value::Mapping(point) = point else {
+        bail!("a point has to be a map of its settings");
+    };
+    let mut merged = match point.get(&key("<<")) {
+        Some(base) => mapping(base)?,
+        None => Mapping::new(),
+    };
+    for (k, v) in point.iter().filter(|(k, _)| **k != key("<<")) {
+        merged.insert(k.clone(), v.clone());
+    }
+    Ok(merged)
+}
+
+/// The name a point goes by, the same as `PointConfig::name`.
+fn point_name(point: &Mapping) -> Option<String> {
+    point
+        .get(&key("catalog_ref"))
+        .or(point.get(&key("point")))
+        .and_then(|n| n.as_str())
+        .map(|n| n.to_string())
+}
+
+/// A point for each of its `phases`, or the point by itself.
+fn fan_out(point: &Value) -> anyhow::Result<Vec<Mapping>> {
+    let mut point = mapping(point)?;
+    let Some(phases) = point.remove(&key("phases")) else {
+        return Ok(vec![point]);
+    };
+    let phases: Vec<String> =
+        serde_yaml::from_value(phases).context("phases has to be a list of phases")?;
+    Ok(phases
+        .iter()
+        .map(|phase| {
+            let mut phased = point.clone();
+            for field in ["point", "catalog_ref", "display_name"] {
+                if let Some(Value::String(s)) = phased.get_mut(&key(field)) {
+                    *s = s.replace("{phase}", phase);
+                }
+            }
+            phased
+        })
+        .collect())
+}
+
+/// Merge `points` over `into`, setting by setting, for points of the same name.
+fn merge(into: &mut Vec<(String, Mapping)>, points: &[Value]) -> anyhow::Result<()> {
+    for point in points {
+        for point in fan_out(point)? {
+            let Some(name) = point_name(&point) else {
+                bail!("a point needs a point name or catalog ref");
+            };
+            match into.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, existing)) => {
+                    for (k, v) in point {
+                        existing.insert(k, v);
+                    }
+                }
+                None => into.push((name, point)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The built-in profiles, then those in `profile_files`, then those in the config file itself.
+/// Later profiles replace earlier ones of the same name.
+pub fn load_profiles(config: &GatewayConfig) -> anyhow::Result<HashMap<String, ProfileConfig>> {
+    let mut profiles: HashMap<String, ProfileConfig> =
+        serde_yaml::from_str(BUILTIN_PROFILES).context("built-in profiles")?;
+    for path in config.profile_files.iter().flatten() {
+        let yaml = std::fs::read_to_string(path)
+            .with_context(|| format!("Can't read profile file {path}"))?;
+        let file: HashMap<String, ProfileConfig> = serde_yaml::from_str(&yaml)
+            .with_context(|| format!("Couldn't read profiles from {path}"))?;
+        profiles.extend(file);
+    }
+    profiles.extend(config.profiles.clone().unwrap_or_default());
+    Ok(profiles)
+}
+
+/// The points of a profile, or of a unit, by model.
+pub fn points(
+    layers: &[&HashMap<String, Vec<Value>>],
+) -> anyhow::Result<HashMap<String, Vec<PointConfig>>> {
+    let mut merged: HashMap<String, Vec<(String, Mapping)>> = HashMap::new();
+    for layer in layers {
+        for (model, points) in layer.iter() {
+            merge(merged.entry(model.clone()).or_default(), points)
+                .with_context(|| format!("model {model}"))?;
+        }
+    }
+    let mut models = HashMap::new();
+    for (model, points) in merged {
+        let mut configs = vec![];
+        for (name, point) in points {
+            let config: PointConfig = serde_yaml::from_value(Value::Mapping(point))
+                .map_err(|e| anyhow!("point {name} of model {model}: {e}"))?;
+            configs.push(config);
+        }
+        models.insert(model, configs);
+    }
+    Ok(models)
+}
+
+/// Work out the points of each unit that uses profiles or has points of its own.  Its points
+/// replace those of the same name in `models`.
+pub fn resolve(config: &mut GatewayConfig) -> anyhow::Result<()> {
+    if config
+        .units
+        .iter()
+        .all(|u| u.profiles.is_none() && u.models.is_none())
+    {
+        return Ok(());
+    }
+    let profiles = load_profiles(config)?;
+    for unit in config.units.iter_mut() {
+        let mut layers = vec![];
+        for name in unit.profiles.iter().flatten() {
+            let Some(profile) = profiles.get(name) else {
+                bail!(
+                    "unit {} uses profile {name}, which doesn't exist",
+                    unit.addr
+                );
+            };
+            layers.push(&profile.models);
+        }
+        if let Some(own) = &unit.models {
+            layers.push(own);
+        }
+        if layers.is_empty() {
+            continue;
+        }
+        let own = points(&layers).with_context(|| format!("unit {}", unit.addr))?;
+        let mut models = config.models.clone();
+        for (model, points) in own {
+            let shared = models.entry(model).or_default();
+            shared.retain(|p| !points.iter().any(|o| o.name() == p.name()));
+            shared.extend(points);
+        }
+        unit.points = Some(models);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_structs::UnitConfig;
+
+    fn names(points: &[PointConfig]) -> Vec<String> {
+        points.iter().map(|p| p.name()).collect()
+    }
+
+    #[test]
+    fn builtin_profiles_fan_out() {
+        let profiles = load_profiles(&GatewayConfig::default()).unwrap();
+        for (name, profile) in profiles.iter() {
+            points(&[&profile.models]).unwrap_or_else(|e| panic!("{name}: {e:#}"));
+        }
+        let wye = points(&[&profiles["meter_wye_full"].models]).unwrap();
+        let wye = &wye["203"];
+        let phase_b = wye.iter().find(|p| p.name() == "PhVphB").unwrap();
+        assert_eq!(phase_b.display_name.as_deref(), Some("Phase B voltage"));
+        assert_eq!(phase_b.device_class.as_deref(), Some("voltage"));
+        for point in ["AphC", "WphA", "TotWhImpPhC", "PhVphCA", "TotWhExp"] {
+            assert!(names(wye).contains(&point.to_string()), "{point}");
+        }
+        // delta meters have no neutral to measure phase voltages against
+        for profile in ["meter_delta_basic", "meter_delta_full"] {
+            let delta = points(&[&profiles[profile].models]).unwrap();
+            let delta = names(&delta["204"]);
+            for point in ["PhV", "PhVphA", "PhVphB", "PhVphC"] {
+                assert!(!delta.contains(&point.to_string()), "{profile}: {point}");
+            }
+            assert!(delta.contains(&"PPV".to_string()), "{profile}");
+        }
+        let delta = points(&[&profiles["meter_delta_full"].models]).unwrap();
+        for point in ["PhVphAB", "PhVphBC", "PhVphCA", "WphC"] {
+            assert!(names(&delta["204"]).contains(&point.to_string()), "{point}");
+        }
+        let split = points(&[&profiles["meter_split_full"].models]).unwrap();
+        assert!(names(&split["202"]).contains(&"WphB".to_string()));
+        assert!(!names(&split["202"]).contains(&"WphC".to_string()));
+    }
+
+    #[test]
+    fn units_override_their_profiles() {
+        let overrides: HashMap<String, Vec<Value>> = serde_yaml::from_str(
+            r#"
+"203":
+  - point: "W"
+    interval: 1
+  - point: "Wph{phase}"
+    phases: ["A", "B", "C"]
+    homeassistant: false
+  - point: "Evt"
+    interval: 60
+"#,
+        )
+        .unwrap();
+        let mut config = GatewayConfig {
+            units: vec![
+                UnitConfig {
+                    addr: "meter:502".to_string(),
+                    slaves: vec![1],
+                    profiles: Some(vec!["meter_wye_full".to_string()]),
+                    models: Some(overrides),
+                    ..Default::default()
+                },
+                UnitConfig {
+                    addr: "inverter:502".to_string(),
+                    slaves: vec![1],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        config.models.insert(
+            "203".to_string(),
+            vec![PointConfig {
+                point: Some("W".to_string()),
+                interval: 30,
+                ..Default::default()
+            }],
+        );
+        resolve(&mut config).unwrap();
+
+        let meter = &config.unit_models("meter:502", 1)["203"];
+        let w: Vec<&PointConfig> = meter.iter().filter(|p| p.name() == "W").collect();
+        assert_eq!(w.len(), 1);
+        assert_eq!(w[0].interval, 1);
+        assert_eq!(w[0].device_class.as_deref(), Some("power"));
+        let wph_c = meter.iter().find(|p| p.name() == "WphC").unwrap();
+        assert_eq!(wph_c.homeassistant, Some(false));
+        assert_eq!(wph_c.interval, 15);
+        assert!(meter.iter().any(|p| p.name() == "Evt"));
+        // units without profiles are polled for `models`, as ever
+        assert_eq!(config.unit_models("inverter:502", 1)["203"].len(), 1);
+
+        config.units[0].profiles = Some(vec!["no_such_profile".to_string()]);
+        assert!(resolve(&mut config).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use crate::config_structs::{Deadband, PointConfig};
+use crate::consts::*;
+use crate::payload::{HAConfigPayload, PayloadValueType};
+use anyhow::bail;
+use rumqttc::QoS;
+use std::collections::HashMap;
+
+/// How the readings of a point are published.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishPolicy {
+    /// whether state messages are retained by the broker
+    pub retain: bool,
+    pub qos: QoS,
+    /// only publish when the value changes
+    pub change_only: bool,
+    /// only publish when the value moves outside of this band around the last published value
+    pub deadband: Option<Deadband>,
+    /// the longest time, in seconds, to go without publishing when readings are held back
+    pub heartbeat: Option<u64>,
+}
+
+impl Default for PublishPolicy {
+    fn default() -> Self {
+        PublishPolicy {
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            change_only: false,
+            deadband: None,
+            heartbeat: None,
+        }
+    }
+}
+
+impl PublishPolicy {
+    pub fn from_config(pc: &PointConfig) -> anyhow::Result<Self> {
+        let qos = match pc.qos {
+            None => QoS::AtLeastOnce,
+            Some(q) => match rumqttc::qos(q) {
+                Ok(qos) => qos,
+                Err(e) => {
+                    bail!("Point {} has an invalid qos: {e}", pc.name());
+                }
+            },
+        };
+        Ok(PublishPolicy {
+            retain: pc.retain.unwrap_or(false),
+            qos,
+            change_only: pc.change_only.unwrap_or(false),
+            deadband: pc.deadband.clone(),
+            heartbeat: pc.heartbeat,
+        })
+    }
+
+    /// whether readings can be held back, rather than published on every poll
+    pub fn is_gated(&self) -> bool {
+        self.change_only || self.deadband.is_some()
+    }
+
+    /// The `expires_after` to advertise in discovery.  A point that only publishes on change
+    /// could be quiet for a long time, so it only expires when it misses its heartbeat.
+    pub fn expires_after(&self, interval: u64) -> u64 {
+        if !self.is_gated() {
+            return DEFAULT_EXPIRES_AFTER_SECS;
+        }
+        match self.heartbeat {
+            Some(heartbeat) => heartbeat.max(interval) * 2,
+            None => 0,
+        }
+    }
+}
+
+fn as_f64(value: &PayloadValueType) -> Option<f64> {
+    match value {
+        PayloadValueType::Float(f) => Some(*f),
+        PayloadValueType::Int(i) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+/// Whether `current` is different enough from the last published value to be published.
+/// Deadbands only apply to numeric values; anything else is published when it changes.
+pub fn value_changed(
+    deadband: Option<&Deadband>,
+    last: &PayloadValueType,
+    current: &PayloadValueType,
+) -> bool {
+    match (as_f64(last), as_f64(current), deadband) {
+        (Some(l), Some(c), Some(Deadband::Absolute(band))) => (c - l).abs() > *band,
+        (Some(l), Some(c), Some(Deadband::Percent(pct))) => {
+            if l == 0.0 {
+                c != l
+            } else {
+                (c - l).abs() > l.abs() * pct / 100.0
+            }
+        }
+        _ => last != current,
+    }
+}
+
+/// Remembers what a unit has published, to decide what needs publishing next.
+#[derive(Debug, Default)]
+pub struct PublishGate {
+    /// state topic -> last published value and when it was published
+    states: HashMap<String, (PayloadValueType, i64)>,
+    /// config topic -> the connection it was last sent on, and what was sent
+    configs: HashMap<String, (u64, String)>,
+}
+
+impl PublishGate {
+    /// Whether a reading should be published under `policy`.  Published readings are remembered
+    /// so that the next one can be compared against them.
+    pub fn state(
+        &mut self,
+        topic: &String,
+        policy: &PublishPolicy,
+        value: &PayloadValueType,
+        now: i64,
+    ) -> bool {
+        let publish = match (self.states.get(topic), policy.is_gated()) {
+            (Some((last, at)), true) => {
+                let changed = value_changed(policy.deadband.as_ref(), last, value);
+                let heartbeat_due = policy
+                    .heartbeat
+                    .is_some_and(|heartbeat| now - at >= heartbeat as i64);
+                changed || heartbeat_due
+            }
+            _ => true,
+        };
+        if publish {
+            self.states.insert(topic.clone(), (value.clone(), now));
+        }
+        publish
+    }
+
+    /// Whether a discovery config needs to be sent: once per MQTT connection, or again if it
+    /// has changed since it was sent.
+    pub fn config(&mut self, topic: &String, config: &HAConfigPayload, connection: u64) -> bool {
+        let serialized = serde_json::to_string(config).unwrap_or_default();
+        if let Some((sent_on, sent)) = self.configs.get(topic) {
+            if *sent_on == connection && *sent == serialized {
+                return false;
+            }
+        }
+        self.configs.insert(topic.clone(), (connection, serialized));
+        true
+    }
+
+    /// Forget which discovery configs were sent, e.g. because they were cleared from the broker,
+    /// so that they're all sent again.
+    pub fn forget_configs(&mut self) {
+        self.configs.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gated(deadband: Option<Deadband>, heartbeat: Option<u64>) -> PublishPolicy {
+        PublishPolicy {
+            change_only: true,
+            deadband,
+            heartbeat,
+            ..PublishPolicy::default()
+        }
+    }
+
+    #[test]
+    fn ungated_points_always_publish() {
+        let mut gate = PublishGate::default();
+        let topic = "t".to_string();
+        let policy = PublishPolicy::default();
+        assert!(gate.state(&topic, &policy, &PayloadValueType::Int(1), 0));
+        assert!(gate.state(&topic, &policy, &PayloadValueType::Int(1), 1));
+    }
+
+    #[test]
+    fn change_only_and_heartbeat() {
+        let mut gate = PublishGate::default();
+        let topic = "t".to_string();
+        let policy = gated(None, Some(60));
+        let on = PayloadValueType::String("ENABLED".to_string());
+        assert!(gate.state(&topic, &policy, &on, 0));
+        assert!(!gate.state(&topic, &policy, &on, 30));
+        assert!(gate.state(&topic, &policy, &on, 60));
+        assert!(gate.state(
+            &topic,
+            &policy,
+            &PayloadValueType::String("DISABLED".to_string()),
+            61
+        ));
+    }
+
+    #[test]
+    fn deadbands() {
+        let absolute = Some(&Deadband::Absolute(5.0));
+        let percent = Some(&Deadband::Percent(10.0));
+        let hundred = PayloadValueType::Float(100.0);
+        assert!(!value_changed(
+            absolute,
+            &hundred,
+            &PayloadValueType::Float(104.0)
+        ));
+        assert!(value_changed(
+            absolute,
+            &hundred,
+            &PayloadValueType::Int(106)
+        ));
+        assert!(!value_changed(
+            percent,
+            &hundred,
+            &PayloadValueType::Float(91.0)
+        ));
+        assert!(value_changed(
+            percent,
+            &hundred,
+            &PayloadValueType::Float(89.0)
+        ));
+        assert!(value_changed(
+            percent,
+            &PayloadValueType::Float(0.0),
+            &PayloadValueType::Float(0.1)
+        ));
+    }
+
+    #[test]
+    fn configs_are_sent_once_per_connection() {
+        let mut gate = PublishGate::default();
+        let topic = "c".to_string();
+        let mut config = HAConfigPayload::default();
+        assert!(gate.config(&topic, &config, 1));
+        assert!(!gate.config(&topic, &config, 1));
+        assert!(gate.config(&topic, &config, 2));
+        config.name = "renamed".to_string();
+        assert!(gate.config(&topic, &config, 2));
+        assert!(!gate.config(&topic, &config, 2));
+    }
+
+    #[test]
+    fn expiry_follows_the_heartbeat() {
+        assert_eq!(
+            PublishPolicy::default().expires_after(60),
+            DEFAULT_EXPIRES_AFTER_SECS
+        );
+        assert_eq!(gated(None, Some(600)).expires_after(60), 1200);
+        assert_eq!(gated(None, None).expires_after(60), 0);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::config_structs::Aggregation;
+use crate::monitored_point::MonitoredPoint;
+use crate::payload::{CompoundPayload, PayloadValueType, StatePayload};
+use crate::point_path::{GroupIndex, PointPath};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use sunspec_rs::sunspec_connection::PointNode;
+
+/// Find the catalog entries selected by a wildcarded path, along with their repetition index.
+pub fn matching_repetitions(
+    pattern: &PointPath,
+    catalog: &HashMap<String, PointNode>,
+) -> Vec<(u16, PointPath)> {
+    let mut matches: Vec<(u16, PointPath)> = catalog
+        .keys()
+        .filter_map(|key| key.parse::<PointPath>().ok())
+        .filter(|concrete| pattern.matches(concrete))
+        .filter_map(|concrete| Some((pattern.wildcard_repetition(&concrete)?, concrete)))
+        .collect();
+    matches.sort_unstable_by_key(|(idx, _)| *idx);
+    matches
+}
+
+/// Turn a monitored point with a `[*]` path into one monitored point per repetition.
+/// Points without a wildcard are returned untouched.
+pub fn expand_repeating_point(
+    point: MonitoredPoint,
+    catalog: &HashMap<String, PointNode>,
+) -> Vec<MonitoredPoint> {
+    if !point.path.is_wildcard() {
+        return vec![point];
+    }
+    let pattern = point.path.clone();
+    if pattern
+        .groups
+        .iter()
+        .filter(|g| g.index == Some(GroupIndex::Any))
+        .count()
+        > 1
+    {
+        warn!("{pattern}: only one repeating group wildcard is supported per point.");
+        return vec![];
+    }
+
+    matching_repetitions(&pattern, catalog)
+        .into_iter()
+        .filter(|(idx, _)| match &point.repetitions {
+            Some(wanted) => wanted.contains(idx),
+            None => true,
+        })
+        .map(|(idx, concrete)| {
+            let mut p = point.clone();
+            p.name = concrete.identifier();
+            p.path = concrete;
+            p.display_name = point.display_name.as_ref().map(|d| format!("{d} {idx}"));
+            p.this_address = Some(idx);
+            p.group_pattern = Some(pattern.clone());
+            p
+        })
+        .collect()
+}
+
+/// Calculate a single aggregation across a set of values.
+pub fn aggregate_values(agg: &Aggregation, values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(match agg {
+        Aggregation::Sum => values.iter().sum(),
+        Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+        Aggregation::Min => values.iter().cloned().fold(f64::INFINITY, f64::min),
+        Aggregation::Max => values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+    })
+}
+
+#[derive(Debug, Default)]
+struct GroupValues {
+    /// the repetitions that are polled
+    members: HashSet<u16>,
+    values: BTreeMap<u16, f64>,
+    fresh: HashSet<u16>,
+}
+
+/// Keeps the latest value of every repetition of each aggregated group, so that we can publish
+/// the aggregates once every member has been refreshed.
+#[derive(Debug, Default)]
+pub struct RepeatingGroupTracker {
+    groups: HashMap<String, GroupValues>,
+}
+
+impl RepeatingGroupTracker {
+    pub fn new(points: &[MonitoredPoint]) -> Self {
+        let mut groups: HashMap<String, GroupValues> = HashMap::new();
+        for p in points.iter().filter(|p| !p.aggregate.is_empty()) {
+            if let (Some(pattern), Some(idx)) = (&p.group_pattern, p.this_address) {
+                groups
+                    .entry(format!("{}/{pattern}", p.model))
+                    .or_default()
+                    .members
+                    .insert(idx);
+            }
+        }
+        RepeatingGroupTracker { groups }
+    }
+
+    /// Stop waiting on a repetition that's no longer polled, e.g. because the unit doesn't have it.
+    pub fn remove(&mut self, point: &MonitoredPoint) {
+        let (Some(pattern), Some(idx)) = (&point.group_pattern, point.this_address) else {
+            return;
+        };
+        if let Some(group) = self.groups.get_mut(&format!("{}/{pattern}", point.model)) {
+            group.members.remove(&idx);
+            group.values.remove(&idx);
+            group.fresh.remove(&idx);
+        }
+    }
+
+    /// Record a reading for one repetition.  Returns the aggregates when every repetition has been
+    /// read since the last time they were returned.
+    pub fn record(
+        &mut self,
+        point: &MonitoredPoint,
+        value: f64,
+    ) -> Option<Vec<(Aggregation, f64)>> {
+        let key = format!("{}/{}", point.model, point.group_pattern.as_ref()?);
+        let idx = point.this_address?;
+        let group = self.groups.get_mut(&key)?;
+        group.values.insert(idx, value);
+        group.fresh.insert(idx);
+        if group.fresh.len() < group.members.len() {
+            return None;
+        }
+        group.fresh.clear();
+        let values: Vec<f64> = group.values.values().cloned().collect();
+        Some(
+            point
+                .aggregate
+                .iter()
+                .filter_map(|agg| aggregate_values(agg, &values).map(|v| (agg.clone(), v)))
+                .collect(),
+        )
+    }
+}
+
+/// Build the sensor payloads for a group's aggregates, based on the payload of one repetition.
+pub fn aggregate_payloads(
+    sn: &String,
+    point: &MonitoredPoint,
+    point_name: &String,
+    member: &CompoundPayload,
+    aggregates: Vec<(Aggregation, f64)>,
+) -> Vec<CompoundPayload> {
+    let model = point.model.clone();
+    aggregates
+        .into_iter()
+        .map(|(agg, value)| {
+            let agg_name = format!("{agg:?}").to_lowercase();
+            let state_topic = format!("sunspec_gateway/{sn}/{model}/{point_name}_{agg_name}");
+            let mut config = member.config.clone();
+            let base_name = match (&point.display_name, point.this_address) {
+                (Some(d), Some(idx)) => d.strip_suffix(&format!(" {idx}")).unwrap_or(d).to_string(),
+                _ => format!("{model}-{point_name}"),
+            };
+            config.name = format!("{base_name} ({agg_name})");
+            config.unique_id = format!("{sn}.{model}.{point_name}_{agg_name}");
+            config.entity_id = format!("sensor.{sn}_{model}_{point_name}_{agg_name}");
+            config.state_topic = state_topic.clone();
+            config.command_topic = None;
+            CompoundPayload {
+                config,
+                config_topic: format!(
+                    "homeassistant/sensor/{sn}/{model}_{point_name}_{agg_name}/config"
+                ),
+                state: StatePayload {
+                    value: PayloadValueType::Float(value),
+                    last_seen: member.state.last_seen,
+                    ..StatePayload::default()
+                },
+                state_topic,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_structs::PointConfig;
+    use sunspec_rs::sunspec_models::{Point, ValueType};
+
+    fn catalog(keys: &[&str]) -> HashMap<String, PointNode> {
+        keys.iter()
+            .map(|key| {
+                let node = PointNode {
+                    value: ValueType::Integer(0),
+                    address: 0,
+                    point_data: Point {
+                        id: "DCW".to_string(),
+                        offset: 0,
+                        r#type: "int16".to_string(),
+                        len: None,
+                        mandatory: None,
+                        access: None,
+                        symbol: None,
+                        units: None,
+                        scale_factor: None,
+                        value: None,
+                        literal: None,
+                        block_id: None,
+                    },
+                };
+                (key.to_string(), node)
+            })
+            .collect()
+    }
+
+    fn modules(repetitions: Option<Vec<u16>>) -> Vec<MonitoredPoint> {
+        let point = MonitoredPoint::new(
+            "160".to_string(),
+            PointConfig {
+                point: Some(".module[*].DCW".to_string()),
+                interval: 60,
+                display_name: Some("Module power".to_string()),
+                repetitions,
+                aggregate: Some(vec![Aggregation::Sum, Aggregation::Max]),
+                ..Default::default()
+            },
+            Some(true),
+        )
+        .unwrap();
+        let catalog = catalog(&[
+            ".module[2].DCW",
+            ".module[1].DCW",
+            ".module[3].DCW",
+            ".module[1].DCV",
+            "DCW",
+        ]);
+        expand_repeating_point(point, &catalog)
+    }
+
+    #[test]
+    fn wildcards_expand_to_each_repetition() {
+        let points = modules(None);
+        let paths: Vec<String> = points.iter().map(|p| p.path.to_string()).collect();
+        assert_eq!(
+            paths,
+            vec![".module[1].DCW", ".module[2].DCW", ".module[3].DCW"]
+        );
+        assert_eq!(points[1].this_address, Some(2));
+        assert_eq!(points[1].display_name.as_deref(), Some("Module power 2"));
+        assert_eq!(
+            points[1].group_pattern.as_ref().map(|p| p.to_string()),
+            Some(".module[*].DCW".to_string())
+        );
+
+        let some = modules(Some(vec![1, 3]));
+        assert_eq!(
+            some.iter().map(|p| p.this_address).collect::<Vec<_>>(),
+            vec![Some(1), Some(3)]
+        );
+    }
+
+    #[test]
+    fn aggregations() {
+        let values = [3.0, -1.0, 4.0];
+        assert_eq!(aggregate_values(&Aggregation::Sum, &values), Some(6.0));
+        assert_eq!(aggregate_values(&Aggregation::Avg, &values), Some(2.0));
+        assert_eq!(aggregate_values(&Aggregation::Min, &values), Some(-1.0));
+        assert_eq!(aggregate_values(&Aggregation::Max, &values), Some(4.0));
+        assert_eq!(aggregate_values(&Aggregation::Sum, &[]), None);
+    }
+
+    #[test]
+    fn aggregates_wait_for_every_member() {
+        let points = modules(None);
+        let mut tracker = RepeatingGroupTracker::new(&points);
+        assert_eq!(tracker.record(&points[0], 100.0), None);
+        assert_eq!(tracker.record(&points[1], 200.0), None);
+        // a repeat reading doesn't count twice
+        assert_eq!(tracker.record(&points[1], 250.0), None);
+        assert_eq!(
+            tracker.record(&points[2], 50.0),
+            Some(vec![(Aggregation::Sum, 400.0), (Aggregation::Max, 250.0)])
+        );
+        assert_eq!(tracker.record(&points[0], 100.0), None);
+
+        // a member that's gone no longer holds the rest up, or counts towards them
+        tracker.remove(&points[2]);
+        assert_eq!(
+            tracker.record(&points[1], 200.0),
+            Some(vec![(Aggregation::Sum, 300.0), (Aggregation::Max, 200.0)])
+        );
+    }
+}
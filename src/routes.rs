@@ -1,9 +1,19 @@
+use crate::auth::token_middleware::auth_middleware;
 use crate::consts::*;
+use crate::modules::audit::audit_routes;
+use crate::modules::control::control_routes;
+use crate::modules::discovery::discovery_routes;
+use crate::modules::events::event_routes;
+use crate::modules::login::login_routes;
+use crate::modules::points::point_routes;
+use crate::modules::schedules::schedule_routes;
+use crate::modules::users::user_routes;
 use crate::modules::AppAPIResponse;
+use crate::sinks::{SinkStatus, SINKS};
 use crate::state::AppState;
 use crate::API_DOC;
 use axum::http::StatusCode;
-use axum::Json;
+use axum::{middleware, Json};
 use utoipa::OpenApi;
 use utoipa_axum::router::OpenApiRouter;
 use utoipa_axum::routes;
@@ -19,13 +29,59 @@ pub const ROUTE_TAG_DESCRIPTION: &str = "Operations about routes";
     tags(
     (name = USERS_TAG, description = USERS_TAG_DESCRIPTION ),
     (name = POINTS_TAG, description = POINTS_TAG_DESCRIPTION ),
+    (name = DISCOVERY_TAG, description = DISCOVERY_TAG_DESCRIPTION ),
+    (name = EVENTS_TAG, description = EVENTS_TAG_DESCRIPTION ),
+    (name = AUTH_TAG, description = AUTH_TAG_DESCRIPTION ),
+    (name = AUDIT_TAG, description = AUDIT_TAG_DESCRIPTION ),
+    (name = CONTROL_TAG, description = CONTROL_TAG_DESCRIPTION ),
+    (name = SCHEDULES_TAG, description = SCHEDULES_TAG_DESCRIPTION ),
     )
 )]
 pub struct ApiDoc;
 
+/// Every route that needs a user, behind the auth middleware.
+pub fn protected_routes(state: AppState) -> OpenApiRouter<AppState> {
+    let auth_layer = middleware::from_fn_with_state(state.clone(), auth_middleware);
+    OpenApiRouter::new()
+        .nest(
+            &format!("{API_VER}/{USERS_TAG}"),
+            user_routes(state.clone()),
+        )
+        .nest(
+            &format!("{API_VER}/{POINTS_TAG}"),
+            point_routes(state.clone()),
+        )
+        .nest(
+            &format!("{API_VER}/{DISCOVERY_TAG}"),
+            discovery_routes(state.clone()),
+        )
+        .nest(
+            &format!("{API_VER}/{EVENTS_TAG}"),
+            event_routes(state.clone()),
+        )
+        .nest(
+            &format!("{API_VER}/{AUDIT_TAG}"),
+            audit_routes(state.clone()),
+        )
+        .nest(
+            &format!("{API_VER}/{CONTROL_TAG}"),
+            control_routes(state.clone()),
+        )
+        .nest(
+            &format!("{API_VER}/{SCHEDULES_TAG}"),
+            schedule_routes(state.clone()),
+        )
+        .layer(auth_layer)
+}
+
 pub fn register_routes(state: AppState) -> OpenApiRouter<AppState> {
     OpenApiRouter::new()
         .routes(routes!(health))
+        .routes(routes!(sink_health))
+        .nest(
+            &format!("{API_VER}/{AUTH_TAG}"),
+            login_routes(state.clone()),
+        )
         .with_state(state)
 }
 
@@ -40,6 +96,23 @@ pub fn register_routes(state: AppState) -> OpenApiRouter<AppState> {
 pub async fn health() -> &'static str {
     "ok"
 }
+
+#[utoipa::path(
+    get,
+    path = SINK_HEALTH_PATH,
+    tag = ROUTE_TAG,
+    responses(
+        (status = OK, description = "the queue and health of each output sink", body = Vec<SinkStatus>)
+    )
+)]
+pub async fn sink_health() -> Json<Vec<SinkStatus>> {
+    Json(
+        SINKS
+            .get()
+            .map(|sinks| sinks.iter().map(|s| s.status()).collect())
+            .unwrap_or_default(),
+    )
+}
 /// Return JSON version of an OpenAPI schema
 #[utoipa::path(
     get,
@@ -59,3 +132,416 @@ pub async fn openapi() -> Result<Json<utoipa::openapi::OpenApi>, (StatusCode, Ap
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::data::{generate_api_key, hash_api_key, hash_password_rounds};
+    use crate::auth::session_store::SqliteSessionStore;
+    use crate::auth::token_extractor::JwksCache;
+    use crate::config_structs::AuthConfig;
+    use crate::ipc::IPCMessage;
+    use crate::modules::users::{CreateUser, OperatorScope, Role};
+    use crate::state_mgmt::{self, test_db};
+    use crate::write_policy::READ_ONLY_LOCK;
+    use axum::body::Body;
+    use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE, SET_COOKIE};
+    use axum::http::{Method, Request};
+    use axum::Router;
+    use cached::UnboundCache;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{self, Receiver};
+    use tokio::sync::RwLock;
+    use tower::ServiceExt;
+    use tower_sessions::SessionManagerLayer;
+
+    /// The API, and the receivers that have to stay open for handlers to send on.
+    fn app() -> (Router, Receiver<IPCMessage>, Receiver<IPCMessage>) {
+        let (ipc_tx, ipc_rx) = mpsc::channel(MPSC_BUFFER_SIZE);
+        let (control_tx, control_rx) = mpsc::channel(MPSC_BUFFER_SIZE);
+        let state = AppState {
+            jwks_cache: JwksCache::new(Duration::from_secs(DEFAULT_JWKS_TTL_SECS)),
+            auth_config: Arc::new(AuthConfig::default()),
+            user_cache: Some(Arc::new(RwLock::new(UnboundCache::new()))),
+            ipc_tx,
+            control_tx,
+        };
+        let (router, _) = register_routes(state.clone())
+            .merge(protected_routes(state.clone()))
+            .layer(SessionManagerLayer::new(SqliteSessionStore))
+            .with_state(state)
+            .split_for_parts();
+        (router, ipc_rx, control_rx)
+    }
+
+    /// A new user with the role, and an API key for them.
+    async fn user(login: &str, role: Role) -> (i32, String) {
+        let user = state_mgmt::create_user(
+            &login.to_string(),
+            0,
+            &CreateUser {
+                role: Some(role),
+                ..CreateUser::default()
+            },
+        )
+        .await
+        .unwrap();
+        let key = generate_api_key();
+        state_mgmt::create_api_key(user.id, &hash_api_key(&key), &None)
+            .await
+            .unwrap();
+        (user.id, key)
+    }
+
+    async fn status(
+        app: &Router,
+        key: &str,
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> StatusCode {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("ApiKey {key}"));
+        let request = match body {
+            Some(b) => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(b.to_string())),
+            None => request.body(Body::empty()),
+        };
+        app.clone()
+            .oneshot(request.unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn each_route_for_each_role() {
+        test_db().await;
+        // read-only mode is switched off along the way
+        let _read_only = READ_ONLY_LOCK.lock().await;
+        let (app, _ipc_rx, _control_rx) = app();
+        let users = "/api/v1/users";
+        let (target, _) = user("rbac-target", Role::Viewer).await;
+
+        for role in [Role::Viewer, Role::Operator, Role::Admin] {
+            let name = format!("{role:?}").to_lowercase();
+            let (me, key) = user(&format!("rbac-{name}"), role).await;
+            let (victim, _) = user(&format!("rbac-victim-{name}"), Role::Viewer).await;
+            let target_key = state_mgmt::create_api_key(target, &generate_api_key(), &None)
+                .await
+                .unwrap();
+            let admin_only = if role == Role::Admin {
+                |ok: StatusCode| ok
+            } else {
+                |_| StatusCode::FORBIDDEN
+            };
+
+            let cases: Vec<(Method, String, Option<Value>, StatusCode)> = vec![
+                // points: everyone reads, operators and admins write
+                (Method::GET, "/api/v1/points".into(), None, StatusCode::OK),
+                (
+                    Method::GET,
+                    "/api/v1/points/1/Mn".into(),
+                    None,
+                    StatusCode::OK,
+                ),
+                (
+                    Method::PUT,
+                    "/api/v1/points/SN1/704/WMaxLimPct".into(),
+                    Some(json!({"value": "50"})),
+                    if role == Role::Viewer {
+                        StatusCode::FORBIDDEN
+                    } else {
+                        StatusCode::ACCEPTED
+                    },
+                ),
+                // events: everyone reads
+                (Method::GET, "/api/v1/events".into(), None, StatusCode::OK),
+                // the audit log: whoever can write
+                (
+                    Method::GET,
+                    "/api/v1/audit".into(),
+                    None,
+                    if role == Role::Viewer {
+                        StatusCode::FORBIDDEN
+                    } else {
+                        StatusCode::OK
+                    },
+                ),
+                // everyone sees the kill switch, admins flip it
+                (
+                    Method::GET,
+                    "/api/v1/control/read_only".into(),
+                    None,
+                    StatusCode::OK,
+                ),
+                (
+                    Method::PUT,
+                    "/api/v1/control/read_only".into(),
+                    Some(json!({"read_only": false})),
+                    admin_only(StatusCode::OK),
+                ),
+                // schedules: operators look, admins set them up
+                (
+                    Method::GET,
+                    "/api/v1/schedules".into(),
+                    None,
+                    if role == Role::Viewer {
+                        StatusCode::FORBIDDEN
+                    } else {
+                        StatusCode::OK
+                    },
+                ),
+                (
+                    Method::DELETE,
+                    "/api/v1/schedules/missing".into(),
+                    None,
+                    admin_only(StatusCode::NOT_FOUND),
+                ),
+                // discovery is configuration
+                (
+                    Method::DELETE,
+                    "/api/v1/discovery/SN1".into(),
+                    None,
+                    admin_only(StatusCode::OK),
+                ),
+                // users: admins manage everyone
+                (Method::GET, users.into(), None, admin_only(StatusCode::OK)),
+                (
+                    Method::POST,
+                    users.into(),
+                    Some(json!({"login": format!("rbac-new-{name}")})),
+                    admin_only(StatusCode::CREATED),
+                ),
+                (
+                    Method::GET,
+                    format!("{users}/{target}"),
+                    None,
+                    admin_only(StatusCode::OK),
+                ),
+                (
+                    Method::PUT,
+                    format!("{users}/{target}"),
+                    Some(json!({"given_name": name})),
+                    admin_only(StatusCode::OK),
+                ),
+                (
+                    Method::POST,
+                    format!("{users}/{target}/api_keys"),
+                    Some(json!({})),
+                    admin_only(StatusCode::CREATED),
+                ),
+                (
+                    Method::GET,
+                    format!("{users}/{target}/api_keys"),
+                    None,
+                    admin_only(StatusCode::OK),
+                ),
+                (
+                    Method::DELETE,
+                    format!("{users}/{target}/api_keys/{}", target_key.id),
+                    None,
+                    admin_only(StatusCode::NO_CONTENT),
+                ),
+                (
+                    Method::GET,
+                    format!("{users}/{target}/scopes"),
+                    None,
+                    admin_only(StatusCode::OK),
+                ),
+                (
+                    Method::PUT,
+                    format!("{users}/{target}/scopes"),
+                    Some(json!([])),
+                    admin_only(StatusCode::OK),
+                ),
+                (
+                    Method::DELETE,
+                    format!("{users}/{victim}"),
+                    None,
+                    admin_only(StatusCode::NO_CONTENT),
+                ),
+                // ...but everyone looks after themselves, short of their role
+                (Method::GET, format!("{users}/{me}"), None, StatusCode::OK),
+                (
+                    Method::PUT,
+                    format!("{users}/{me}"),
+                    Some(json!({"preferred_name": name})),
+                    StatusCode::OK,
+                ),
+                (
+                    Method::PUT,
+                    format!("{users}/{me}"),
+                    Some(json!({"role": "admin"})),
+                    admin_only(StatusCode::OK),
+                ),
+                (
+                    Method::POST,
+                    format!("{users}/{me}/api_keys"),
+                    Some(json!({"name": "mine"})),
+                    StatusCode::CREATED,
+                ),
+                (
+                    Method::GET,
+                    format!("{users}/{me}/scopes"),
+                    None,
+                    StatusCode::OK,
+                ),
+                (
+                    Method::PUT,
+                    format!("{users}/{me}/scopes"),
+                    Some(json!([])),
+                    admin_only(StatusCode::OK),
+                ),
+            ];
+            for (method, uri, body, expected) in cases {
+                assert_eq!(
+                    status(&app, &key, method.clone(), &uri, body).await,
+                    expected,
+                    "{name} {method} {uri}"
+                );
+            }
+        }
+
+        assert_eq!(
+            status(
+                &app,
+                &generate_api_key(),
+                Method::GET,
+                "/api/v1/events",
+                None
+            )
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn operator_scopes_limit_writes() {
+        test_db().await;
+        let (app, _ipc_rx, mut control_rx) = app();
+        let (id, key) = user("scoped-operator", Role::Operator).await;
+        state_mgmt::set_operator_scopes(
+            id,
+            &vec![
+                OperatorScope {
+                    serial_number: "SN1".into(),
+                    point: Some("704/WMaxLimPct".into()),
+                },
+                OperatorScope {
+                    serial_number: "SN2".into(),
+                    point: None,
+                },
+            ],
+        )
+        .await
+        .unwrap();
+
+        let value = Some(json!({"value": "50"}));
+        for (uri, expected) in [
+            ("/api/v1/points/SN1/704/WMaxLimPct", StatusCode::ACCEPTED),
+            ("/api/v1/points/SN1/704/WMaxLim_Ena", StatusCode::FORBIDDEN),
+            ("/api/v1/points/SN2/124/StorCtl_Mod", StatusCode::ACCEPTED),
+            ("/api/v1/points/SN3/704/WMaxLimPct", StatusCode::FORBIDDEN),
+        ] {
+            assert_eq!(
+                status(&app, &key, Method::PUT, uri, value.clone()).await,
+                expected,
+                "{uri}"
+            );
+        }
+
+        let Some(IPCMessage::Inbound(write)) = control_rx.recv().await else {
+            panic!("expected the write to be queued");
+        };
+        assert_eq!(write.serial_number, "SN1");
+        assert_eq!(write.model, "704");
+        assert_eq!(write.point.to_string(), "WMaxLimPct");
+        assert_eq!(write.payload, "50");
+    }
+
+    #[tokio::test]
+    async fn web_ui_login_and_logout() {
+        test_db().await;
+        let (app, _ipc_rx, _control_rx) = app();
+        let (id, key) = user("web-user", Role::Viewer).await;
+        state_mgmt::set_password_hash(id, Some(hash_password_rounds("hunter2hunter2", 1000)))
+            .await
+            .unwrap();
+
+        let login = |password: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/api/v1/auth/login")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({"login": "web-user", "password": password}).to_string(),
+                ))
+                .unwrap()
+        };
+        let with_cookie = |method: Method, uri: String, cookie: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(login("hunter3")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().get(SET_COOKIE).is_none());
+
+        let response = app.clone().oneshot(login("hunter2hunter2")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+
+        // the session stands in for a token, with the user's own role
+        let me = format!("/api/v1/users/{id}");
+        let response = app
+            .clone()
+            .oneshot(with_cookie(Method::GET, me.clone(), &cookie))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(with_cookie(Method::GET, "/api/v1/users".into(), &cookie))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app
+            .clone()
+            .oneshot(with_cookie(
+                Method::POST,
+                "/api/v1/auth/logout".into(),
+                &cookie,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app
+            .clone()
+            .oneshot(with_cookie(Method::GET, me.clone(), &cookie))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // requests with tokens don't start sessions
+        let request = Request::builder()
+            .uri(me)
+            .header(AUTHORIZATION, format!("ApiKey {key}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(SET_COOKIE).is_none());
+    }
+}
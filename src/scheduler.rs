@@ -0,0 +1,287 @@
+use crate::config_structs::{MissedRuns, ScheduleConfig};
+use crate::consts::*;
+use crate::ipc::{IPCMessage, InboundMessage, WriteSource};
+use crate::point_path::PointPath;
+use crate::state_mgmt::{
+    delete_schedule, get_schedules, save_schedule, set_schedule_progress, Schedule,
+};
+use crate::sunspec_poll::polling;
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Local, TimeZone};
+use croner::Cron;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{sleep, Duration};
+
+pub fn parse_cron(expr: &str) -> anyhow::Result<Cron> {
+    Cron::new(expr)
+        .with_seconds_optional()
+        .parse()
+        .map_err(|e| anyhow!("{expr} isn't a cron expression: {e}"))
+}
+
+/// Whether a schedule makes sense, before it's saved.
+pub fn validate(config: &ScheduleConfig) -> anyhow::Result<()> {
+    if config.name.trim().is_empty() {
+        bail!("a schedule needs a name");
+    }
+    parse_cron(&config.cron)?;
+    if config.model.parse::<u16>().is_err() {
+        bail!("{} isn't a model id", config.model);
+    }
+    if let Err(e) = config.point.parse::<PointPath>() {
+        bail!("{} isn't a point: {e}", config.point);
+    }
+    if config.value.is_empty() {
+        bail!("a schedule needs a value to write");
+    }
+    Ok(())
+}
+
+/// Save the schedules in the config file, and drop the ones that have been taken out of it.
+pub async fn load_config_schedules(configs: &[ScheduleConfig]) -> anyhow::Result<()> {
+    for config in configs {
+        if let Err(e) = validate(config) {
+            bail!("schedule {}: {e}", config.name);
+        }
+        save_schedule(config, true).await?;
+    }
+    for schedule in get_schedules().await? {
+        if schedule.from_config && !configs.iter().any(|c| c.name == schedule.name) {
+            info!(
+                "Schedule {} is no longer configured, removing it",
+                schedule.name
+            );
+            delete_schedule(&schedule.name).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The latest time in `(after, now]` that the cron expression matches, if any.
+pub fn latest_run<Tz: TimeZone>(
+    cron: &Cron,
+    after: &DateTime<Tz>,
+    now: &DateTime<Tz>,
+) -> Option<DateTime<Tz>> {
+    let mut latest = None;
+    let mut from = after.clone();
+    for _ in 0..SCHEDULE_MAX_MISSED {
+        match cron.find_next_occurrence(&from, false) {
+            Ok(next) if next <= *now => {
+                from = next.clone();
+                latest = Some(next);
+            }
+            _ => break,
+        }
+    }
+    latest
+}
+
+/// When a schedule runs next, after `now`.
+pub fn next_run(schedule: &Schedule, now: &DateTime<Local>) -> Option<i64> {
+    let cron = parse_cron(&schedule.cron).ok()?;
+    cron.find_next_occurrence(now, false)
+        .ok()
+        .map(|t| t.timestamp())
+}
+
+/// Make the run of a schedule that's due, if any, and note how far it's got.
+async fn run_schedule(
+    tx: &Sender<IPCMessage>,
+    schedule: &Schedule,
+    now: DateTime<Local>,
+) -> anyhow::Result<()> {
+    let mut ran = None;
+    // new and disabled schedules start from now, rather than catching up
+    if let (true, Some(last_checked)) = (schedule.enabled, schedule.last_checked) {
+        let cron = parse_cron(&schedule.cron)?;
+        let after = Local.timestamp_opt(last_checked, 0).single().unwrap_or(now);
+        if let Some(run) = latest_run(&cron, &after, &now) {
+            // the write would go nowhere, so the run waits for the unit, to be made or skipped
+            // according to `missed` once it's back
+            if !polling(&schedule.serial_number) {
+                debug!(
+                    "Schedule {} is waiting for {} to be polled",
+                    schedule.name, schedule.serial_number
+                );
+                return Ok(());
+            }
+            let late = now.timestamp() - run.timestamp();
+            if late <= SCHEDULE_GRACE_SECS || schedule.missed == MissedRuns::CatchUp {
+                info!("Schedule {} writing {}", schedule.name, schedule.value);
+                let msg = InboundMessage {
+                    serial_number: schedule.serial_number.clone(),
+                    model: schedule.model.clone(),
+                    point: schedule.point.parse()?,
+                    payload: schedule.value.clone(),
+                    source: WriteSource::Automation(format!("schedule:{}", schedule.name)),
+                    dry_run: schedule.dry_run,
+                };
+                tx.send(IPCMessage::Inbound(msg)).await?;
+                ran = Some(run.timestamp());
+            } else {
+                warn!(
+                    "Schedule {} missed its run at {run} by {late}s, skipping it",
+                    schedule.name
+                );
+            }
+        }
+    }
+    set_schedule_progress(&schedule.name, now.timestamp(), ran).await
+}
+
+/// Make whatever run each schedule is due, sending its write down `tx` like any other.
+pub async fn tick(tx: &Sender<IPCMessage>, now: DateTime<Local>) -> anyhow::Result<()> {
+    for schedule in get_schedules().await? {
+        if let Err(e) = run_schedule(tx, &schedule, now).await {
+            error!("Couldn't run schedule {}: {e}", schedule.name);
+        }
+    }
+    Ok(())
+}
+
+pub async fn scheduler_loop(tx: Sender<IPCMessage>) {
+    loop {
+        if let Err(e) = tick(&tx, Local::now()).await {
+            error!("Couldn't run schedules: {e}");
+        }
+        sleep(Duration::from_secs(SCHEDULER_TICK_SECS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_mgmt::{get_schedule, test_db};
+    use crate::sunspec_poll::Polling;
+    use chrono::{Datelike, Timelike, Utc};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn latest_of_the_missed_runs() {
+        // weekdays at 16:00; 2026-10-16 was a Friday
+        let cron = parse_cron("0 16 * * MON-FRI").unwrap();
+        let at = |d: u32, h: u32, m: u32| Utc.with_ymd_and_hms(2026, 10, d, h, m, 0).unwrap();
+        assert_eq!(
+            latest_run(&cron, &at(16, 15, 59), &at(16, 16, 1)),
+            Some(at(16, 16, 0))
+        );
+        assert_eq!(latest_run(&cron, &at(16, 16, 0), &at(16, 16, 1)), None);
+        // down over the weekend and through monday's run
+        assert_eq!(
+            latest_run(&cron, &at(16, 17, 0), &at(19, 18, 0)),
+            Some(at(19, 16, 0))
+        );
+        assert_eq!(latest_run(&cron, &at(17, 0, 0), &at(18, 23, 0)), None);
+        assert!(parse_cron("at four").is_err());
+    }
+
+    #[tokio::test]
+    async fn due_runs_go_down_the_write_path() {
+        test_db().await;
+        let now = Local::now().with_nanosecond(0).unwrap();
+        let cron_at =
+            |t: DateTime<Local>| format!("{} {} {} {} *", t.minute(), t.hour(), t.day(), t.month());
+        // a run a minute ago is made; one two days ago only if missed runs are caught up
+        let recent = ScheduleConfig {
+            name: "sched-recent".to_string(),
+            cron: cron_at(now - chrono::Duration::minutes(1)),
+            serial_number: "SCHED1".to_string(),
+            model: "124".to_string(),
+            point: "StorCtl_Mod".to_string(),
+            value: "2".to_string(),
+            dry_run: Some(true),
+            ..ScheduleConfig::default()
+        };
+        let stale = |name: &str, missed| ScheduleConfig {
+            name: name.to_string(),
+            cron: cron_at(now - chrono::Duration::days(2)),
+            missed: Some(missed),
+            dry_run: None,
+            ..recent.clone()
+        };
+        for (config, checked) in [
+            (recent.clone(), Some(now - chrono::Duration::minutes(2))),
+            (
+                stale("sched-skip", MissedRuns::Skip),
+                Some(now - chrono::Duration::days(3)),
+            ),
+            (
+                stale("sched-catch-up", MissedRuns::CatchUp),
+                Some(now - chrono::Duration::days(3)),
+            ),
+            // never checked, so it starts from now
+            (stale("sched-new", MissedRuns::CatchUp), None),
+        ] {
+            validate(&config).unwrap();
+            save_schedule(&config, false).await.unwrap();
+            if let Some(checked) = checked {
+                set_schedule_progress(&config.name, checked.timestamp(), None)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // one that can't be run doesn't hold up the rest
+        let broken = ScheduleConfig {
+            name: "sched-broken".to_string(),
+            cron: "at four".to_string(),
+            ..recent.clone()
+        };
+        save_schedule(&broken, false).await.unwrap();
+        set_schedule_progress(&broken.name, now.timestamp() - 60, None)
+            .await
+            .unwrap();
+
+        // nothing is made while the unit isn't polled, and the runs wait for it
+        let (tx, mut rx) = mpsc::channel(10);
+        tick(&tx, now).await.unwrap();
+        assert!(rx.try_recv().is_err());
+        let waiting = get_schedule("sched-recent").await.unwrap().unwrap();
+        assert_eq!(
+            waiting.last_checked,
+            Some((now - chrono::Duration::minutes(2)).timestamp())
+        );
+
+        let _polling = Polling::start("SCHED1", "sched:502", 1);
+        tick(&tx, now).await.unwrap();
+        let mut runs = vec![];
+        while let Ok(IPCMessage::Inbound(msg)) = rx.try_recv() {
+            runs.push((msg.source.to_string(), msg.dry_run));
+        }
+        runs.sort();
+        assert_eq!(
+            runs,
+            vec![
+                ("automation:schedule:sched-catch-up".to_string(), false),
+                ("automation:schedule:sched-recent".to_string(), true)
+            ]
+        );
+        let caught_up = get_schedule("sched-catch-up").await.unwrap().unwrap();
+        assert_eq!(
+            caught_up.last_run,
+            Some(
+                (now - chrono::Duration::days(2))
+                    .with_second(0)
+                    .unwrap()
+                    .timestamp()
+            )
+        );
+        let new = get_schedule("sched-new").await.unwrap().unwrap();
+        assert_eq!(new.last_checked, Some(now.timestamp()));
+        assert_eq!(new.last_run, None);
+
+        // and nothing runs twice
+        tick(&tx, now).await.unwrap();
+        assert!(rx.try_recv().is_err());
+        for name in [
+            "sched-recent",
+            "sched-skip",
+            "sched-catch-up",
+            "sched-new",
+            "sched-broken",
+        ] {
+            delete_schedule(name).await.unwrap();
+        }
+    }
+}
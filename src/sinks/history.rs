@@ -0,0 +1,35 @@
+use crate::consts::*;
+use crate::sinks::{Backpressure, MessageKind, Sink, SinkMessage};
+use crate::state_mgmt::{cull_records_to, write_payload_history};
+use async_trait::async_trait;
+
+/// Keeps the point history that deviation checks and bitfield states are worked out from.
+#[derive(Default)]
+pub struct HistorySink;
+
+#[async_trait]
+impl Sink for HistorySink {
+    fn name(&self) -> &'static str {
+        "history"
+    }
+
+    fn kinds(&self) -> &'static [MessageKind] {
+        &[MessageKind::History]
+    }
+
+    /// deviation checks and bitfield states go wrong with gaps in the history, so it waits
+    /// rather than dropping readings
+    fn default_backpressure(&self) -> Backpressure {
+        Backpressure::Block
+    }
+
+    async fn send(&mut self, msg: SinkMessage) -> anyhow::Result<()> {
+        let SinkMessage::History(uniqueid, state) = msg else {
+            return Ok(());
+        };
+        if let Err(e) = cull_records_to(uniqueid.clone(), CULL_HISTORY_ROWS).await {
+            warn!("{uniqueid}: Couldn't cull history for this point: {e}");
+        }
+        write_payload_history(uniqueid, state).await
+    }
+}
@@ -0,0 +1,310 @@
+use crate::config_structs::HttpSinkConfig;
+use crate::consts::*;
+use crate::ipc::PublishMessage;
+use crate::payload::{Payload, StatePayload};
+use crate::sinks::{MessageKind, Sink, SinkMessage};
+use crate::state_mgmt::{delete_spooled_batch, get_spooled_batches, spool_batch, trim_spool};
+use anyhow::bail;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// A reading, as it is sent to the http sink.
+#[derive(Serialize, Debug, Clone)]
+pub struct Reading {
+    pub topic: String,
+    #[serde(flatten)]
+    pub state: StatePayload,
+}
+
+#[derive(Serialize, Debug)]
+struct Batch<'a> {
+    readings: &'a [Reading],
+}
+
+/// Batches readings and POSTs them to an HTTP endpoint, spooling them to the database while the
+/// endpoint can't be reached.
+pub struct HttpSink {
+    config: HttpSinkConfig,
+    client: reqwest::Client,
+    buffer: Vec<Reading>,
+    /// whether there may be batches in the spool; new batches queue up behind them
+    spooled: bool,
+}
+
+impl HttpSink {
+    pub fn new(config: HttpSinkConfig) -> Self {
+        HttpSink {
+            config,
+            client: reqwest::Client::new(),
+            buffer: vec![],
+            spooled: true,
+        }
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.config
+            .batch_size
+            .unwrap_or(DEFAULT_HTTP_SINK_BATCH_SIZE)
+            .max(1)
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.config
+                .flush_interval
+                .unwrap_or(DEFAULT_HTTP_SINK_FLUSH_SECS)
+                .max(1),
+        )
+    }
+
+    /// How long to wait before retry number `attempt` (counting from 0).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let base = self
+            .config
+            .retry_backoff
+            .unwrap_or(DEFAULT_HTTP_SINK_BACKOFF_SECS);
+        Duration::from_secs(base.saturating_mul(2_u64.saturating_pow(attempt)))
+    }
+
+    /// Add a message to the current batch, if it's a reading.  Returns whether the batch is full.
+    pub fn push(&mut self, msg: PublishMessage) -> bool {
+        if let Payload::CurrentState(state) = msg.payload {
+            self.buffer.push(Reading {
+                topic: msg.topic,
+                state,
+            });
+        }
+        self.buffer.len() >= self.batch_size()
+    }
+
+    fn take_batch(&mut self) -> Option<String> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let readings = std::mem::take(&mut self.buffer);
+        match serde_json::to_string(&Batch {
+            readings: &readings,
+        }) {
+            Ok(body) => Some(body),
+            Err(e) => {
+                error!(
+                    "Couldn't serialize a batch of {} readings: {e}",
+                    readings.len()
+                );
+                None
+            }
+        }
+    }
+
+    pub async fn post(&self, body: &str) -> anyhow::Result<()> {
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        for (name, value) in self.config.headers.iter().flatten() {
+            request = request.header(name, value);
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    async fn post_with_retries(&self, body: &str) -> anyhow::Result<()> {
+        let retries = self
+            .config
+            .max_retries
+            .unwrap_or(DEFAULT_HTTP_SINK_MAX_RETRIES);
+        let mut attempt = 0;
+        loop {
+            match self.post(body).await {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < retries => {
+                    let wait = self.backoff(attempt);
+                    debug!("http sink: request failed, retrying in {wait:?}: {e}");
+                    sleep(wait).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn spool(&mut self, body: &str) {
+        self.spooled = true;
+        if let Err(e) = spool_batch(body).await {
+            error!("http sink: couldn't spool a batch, it is lost: {e}");
+            return;
+        }
+        let limit = self
+            .config
+            .spool_limit
+            .unwrap_or(DEFAULT_HTTP_SINK_SPOOL_LIMIT);
+        match trim_spool(limit).await {
+            Ok(0) => {}
+            Ok(n) => warn!("http sink: spool is full, dropped the {n} oldest batches"),
+            Err(e) => warn!("http sink: couldn't trim the spool: {e}"),
+        }
+    }
+
+    /// Resend spooled batches, oldest first, until one fails.
+    async fn drain_spool(&mut self) -> anyhow::Result<()> {
+        let batches = get_spooled_batches(HTTP_SINK_SPOOL_DRAIN_BATCHES).await?;
+        let complete = (batches.len() as i64) < HTTP_SINK_SPOOL_DRAIN_BATCHES;
+        for (id, body) in batches {
+            if let Err(e) = self.post(&body).await {
+                debug!("http sink: endpoint still unavailable, keeping spool: {e}");
+                bail!(e);
+            }
+            delete_spooled_batch(id).await?;
+        }
+        if complete {
+            self.spooled = false;
+        }
+        Ok(())
+    }
+
+    /// Send the current batch (or spool it, if older batches are waiting or it can't be sent),
+    /// then try to send whatever is spooled.
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(body) = self.take_batch() {
+            if self.spooled {
+                self.spool(&body).await;
+            } else if let Err(e) = self.post_with_retries(&body).await {
+                warn!("http sink: couldn't send batch, spooling it: {e}");
+                self.spool(&body).await;
+                bail!(e);
+            }
+        }
+        if self.spooled {
+            self.drain_spool().await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for HttpSink {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn kinds(&self) -> &'static [MessageKind] {
+        &[MessageKind::Publish]
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.flush_interval())
+    }
+
+    async fn send(&mut self, msg: SinkMessage) -> anyhow::Result<()> {
+        let SinkMessage::Publish(msg) = msg else {
+            return Ok(());
+        };
+        if self.push(msg) {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        self.flush().await
+    }
+
+    /// there's no time to wait on the endpoint, so whatever is batched goes to the spool
+    async fn shutdown(&mut self) {
+        if let Some(body) = self.take_batch() {
+            self.spool(&body).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::PayloadValueType;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use axum::Router;
+    use rumqttc::QoS;
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    fn reading(topic: &str, value: f64) -> PublishMessage {
+        PublishMessage {
+            topic: topic.to_string(),
+            payload: Payload::CurrentState(StatePayload {
+                value: PayloadValueType::Float(value),
+                ..StatePayload::default()
+            }),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        }
+    }
+
+    #[test]
+    fn batches_and_backoff() {
+        let mut sink = HttpSink::new(HttpSinkConfig {
+            batch_size: Some(2),
+            retry_backoff: Some(2),
+            ..HttpSinkConfig::default()
+        });
+        assert!(!sink.push(reading("a", 1.0)));
+        // only readings are batched
+        assert!(!sink.push(PublishMessage {
+            payload: Payload::Clear,
+            ..reading("b", 0.0)
+        }));
+        assert!(sink.push(reading("c", 2.0)));
+        let body: serde_json::Value = serde_json::from_str(&sink.take_batch().unwrap()).unwrap();
+        assert_eq!(body["readings"][1]["topic"], "c");
+        assert_eq!(body["readings"][1]["value"], 2.0);
+        assert!(sink.take_batch().is_none());
+
+        assert_eq!(sink.backoff(0), Duration::from_secs(2));
+        assert_eq!(sink.backoff(3), Duration::from_secs(16));
+    }
+
+    #[tokio::test]
+    async fn posts_with_configured_headers() {
+        let (tx, mut rx) = mpsc::channel::<(Option<String>, String)>(1);
+        let app = Router::new().route(
+            "/ingest",
+            post(move |headers: HeaderMap, body: String| async move {
+                let auth = headers
+                    .get("authorization")
+                    .and_then(|h| h.to_str().ok())
+                    .map(String::from);
+                tx.send((auth, body)).await.unwrap();
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let sink = HttpSink::new(HttpSinkConfig {
+            url: format!("http://{addr}/ingest"),
+            headers: Some(HashMap::from([(
+                "Authorization".to_string(),
+                "Bearer abc".to_string(),
+            )])),
+            ..HttpSinkConfig::default()
+        });
+        sink.post("{\"readings\":[]}").await.unwrap();
+        assert_eq!(
+            rx.recv().await,
+            Some((
+                Some("Bearer abc".to_string()),
+                "{\"readings\":[]}".to_string()
+            ))
+        );
+
+        let unreachable = HttpSink::new(HttpSinkConfig {
+            url: format!("http://{addr}/missing"),
+            ..HttpSinkConfig::default()
+        });
+        assert!(unreachable.post("{}").await.is_err());
+    }
+}
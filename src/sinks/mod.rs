@@ -0,0 +1,419 @@
+use crate::config_structs::{Backpressure, SinkQueueConfig};
+use crate::consts::*;
+use crate::ipc::{IPCMessage, PublishMessage};
+use crate::payload::StatePayload;
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, OnceCell};
+use tokio::time::{interval, timeout};
+use utoipa::ToSchema;
+
+pub(crate) mod history;
+pub(crate) mod http;
+pub(crate) mod mqtt;
+pub(crate) mod sparkplug;
+
+/// Every sink the dispatcher feeds, for reporting their health.
+pub static SINKS: OnceCell<Vec<SinkHandle>> = OnceCell::const_new();
+
+/// What the dispatcher hands to sinks.
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum SinkMessage {
+    /// a message to publish
+    Publish(PublishMessage),
+    /// a reading to keep in the point history, under the point's unique id
+    History(String, StatePayload),
+    /// a unit, by serial number, has stopped answering
+    UnitOffline(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageKind {
+    Publish,
+    History,
+    Lifecycle,
+}
+
+impl SinkMessage {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            SinkMessage::Publish(_) => MessageKind::Publish,
+            SinkMessage::History(_, _) => MessageKind::History,
+            SinkMessage::UnitOffline(_) => MessageKind::Lifecycle,
+        }
+    }
+}
+
+/// Somewhere readings go.  Each sink runs on its own task, behind its own queue.
+#[async_trait]
+pub trait Sink: Send {
+    /// the name the sink is configured and reported by
+    fn name(&self) -> &'static str;
+    /// the kinds of message the sink wants
+    fn kinds(&self) -> &'static [MessageKind];
+    /// what to do when the sink's queue is full, unless configured otherwise
+    fn default_backpressure(&self) -> Backpressure {
+        Backpressure::Drop
+    }
+    /// how often `tick` is called, if at all
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+    async fn send(&mut self, msg: SinkMessage) -> anyhow::Result<()>;
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// called once, when the gateway is shutting down
+    async fn shutdown(&mut self) {}
+}
+
+/// Counters for how a sink is getting on.
+#[derive(Debug, Default)]
+pub struct SinkHealth {
+    healthy: AtomicBool,
+    sent: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    last_success: AtomicI64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl SinkHealth {
+    fn dropped(&self, name: &str) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        // don't flood the log while a sink is stuck
+        if dropped.is_power_of_two() {
+            warn!("{name} sink isn't keeping up, {dropped} messages dropped so far");
+        }
+    }
+
+    fn record(&self, result: anyhow::Result<()>) {
+        match result {
+            Ok(_) => {
+                self.healthy.store(true, Ordering::Relaxed);
+                self.sent.fetch_add(1, Ordering::Relaxed);
+                self.last_success
+                    .store(Utc::now().timestamp(), Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.healthy.store(false, Ordering::Relaxed);
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                if let Ok(mut last_error) = self.last_error.lock() {
+                    *last_error = Some(e.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// The health of a sink, as reported by the API
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct SinkStatus {
+    pub name: String,
+    /// whether the last thing the sink did succeeded
+    pub healthy: bool,
+    /// messages waiting in the sink's queue
+    pub queued: usize,
+    pub capacity: usize,
+    pub sent: u64,
+    pub failed: u64,
+    /// messages dropped because the queue was full
+    pub dropped: u64,
+    /// unix timestamp of the last success
+    pub last_success: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+/// The dispatcher's end of a sink.
+#[derive(Clone, Debug)]
+pub struct SinkHandle {
+    name: &'static str,
+    kinds: &'static [MessageKind],
+    tx: mpsc::Sender<SinkMessage>,
+    /// where messages for a `block` sink wait for room in its queue, so that it's the sink's
+    /// forwarding task that waits rather than the dispatcher
+    inbox: Option<mpsc::Sender<SinkMessage>>,
+    health: Arc<SinkHealth>,
+}
+
+impl SinkHandle {
+    pub fn status(&self) -> SinkStatus {
+        let last_success = self.health.last_success.load(Ordering::Relaxed);
+        let (queued, capacity) = std::iter::once(&self.tx).chain(self.inbox.as_ref()).fold(
+            (0, 0),
+            |(queued, capacity), tx| {
+                (
+                    queued + tx.max_capacity() - tx.capacity(),
+                    capacity + tx.max_capacity(),
+                )
+            },
+        );
+        SinkStatus {
+            name: self.name.to_string(),
+            healthy: self.health.healthy.load(Ordering::Relaxed),
+            queued,
+            capacity,
+            sent: self.health.sent.load(Ordering::Relaxed),
+            failed: self.health.failed.load(Ordering::Relaxed),
+            dropped: self.health.dropped.load(Ordering::Relaxed),
+            last_success: (last_success > 0).then_some(last_success),
+            last_error: self.health.last_error.lock().ok().and_then(|e| e.clone()),
+        }
+    }
+
+    fn offer(&self, msg: SinkMessage) {
+        if self
+            .inbox
+            .as_ref()
+            .unwrap_or(&self.tx)
+            .try_send(msg)
+            .is_err()
+        {
+            self.health.dropped(self.name);
+        }
+    }
+}
+
+/// Move a `block` sink's messages into its queue, waiting (for a while) for room.
+async fn forward(
+    name: &'static str,
+    mut inbox: mpsc::Receiver<SinkMessage>,
+    tx: mpsc::Sender<SinkMessage>,
+    health: Arc<SinkHealth>,
+) {
+    while let Some(msg) = inbox.recv().await {
+        match timeout(Duration::from_secs(SINK_BLOCK_TIMEOUT_SECS), tx.send(msg)).await {
+            Ok(Ok(_)) => {}
+            // the sink has stopped
+            Ok(Err(_)) => return,
+            Err(_) => health.dropped(name),
+        }
+    }
+}
+
+async fn run_sink(
+    mut sink: Box<dyn Sink>,
+    mut rx: mpsc::Receiver<SinkMessage>,
+    health: Arc<SinkHealth>,
+    mut bcast_rx: broadcast::Receiver<IPCMessage>,
+) {
+    let mut ticker = sink.tick_interval().map(interval);
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => health.record(sink.send(msg).await),
+                None => {
+                    sink.shutdown().await;
+                    return;
+                }
+            },
+            _ = async {
+                match ticker.as_mut() {
+                    Some(t) => {
+                        t.tick().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            } => {
+                if let Err(e) = sink.tick().await {
+                    health.record(Err(e));
+                }
+            },
+            bcast = bcast_rx.recv() => {
+                if let Ok(IPCMessage::Shutdown) = bcast {
+                    sink.shutdown().await;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Fans messages out to every sink, each behind its own bounded queue, so that one slow sink
+/// doesn't hold up the others (or polling).
+#[derive(Default)]
+pub struct Dispatcher {
+    sinks: Vec<SinkHandle>,
+}
+
+impl Dispatcher {
+    /// Start a sink on its own task.
+    pub fn add(
+        &mut self,
+        sink: Box<dyn Sink>,
+        queue: Option<&SinkQueueConfig>,
+        bcast_rx: broadcast::Receiver<IPCMessage>,
+    ) {
+        let name = sink.name();
+        let capacity = queue
+            .and_then(|q| q.capacity)
+            .unwrap_or(MPSC_BUFFER_SIZE)
+            .max(1);
+        let backpressure = queue
+            .and_then(|q| q.backpressure)
+            .unwrap_or(sink.default_backpressure());
+        let (tx, rx) = mpsc::channel(capacity);
+        let health = Arc::new(SinkHealth::default());
+        let inbox = match backpressure {
+            Backpressure::Drop => None,
+            Backpressure::Block => {
+                let (inbox_tx, inbox_rx) = mpsc::channel(capacity);
+                tokio::task::Builder::new()
+                    .name(&format!("{name}_sink_forward"))
+                    .spawn(forward(name, inbox_rx, tx.clone(), health.clone()))
+                    .unwrap();
+                Some(inbox_tx)
+            }
+        };
+        self.sinks.push(SinkHandle {
+            name,
+            kinds: sink.kinds(),
+            tx,
+            inbox,
+            health: health.clone(),
+        });
+        info!("Starting {name} sink (queue of {capacity}, {backpressure:?} when full)");
+        tokio::task::Builder::new()
+            .name(&format!("{name}_sink"))
+            .spawn(run_sink(sink, rx, health, bcast_rx))
+            .unwrap();
+    }
+
+    /// Hand a message to every sink that wants it.  This never waits on a sink: a `block` sink
+    /// waits for room on its own forwarding task.
+    pub fn dispatch(&self, msg: SinkMessage) {
+        let kind = msg.kind();
+        for sink in self.sinks.iter().filter(|s| s.kinds.contains(&kind)) {
+            sink.offer(msg.clone());
+        }
+    }
+
+    pub fn handles(&self) -> Vec<SinkHandle> {
+        self.sinks.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::Payload;
+    use rumqttc::QoS;
+    use tokio::sync::Notify;
+
+    /// a sink that takes the first message, then waits until it's released
+    struct StuckSink(Arc<Notify>, mpsc::Sender<String>);
+
+    #[async_trait]
+    impl Sink for StuckSink {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+        fn kinds(&self) -> &'static [MessageKind] {
+            &[MessageKind::Publish]
+        }
+        async fn send(&mut self, msg: SinkMessage) -> anyhow::Result<()> {
+            self.0.notified().await;
+            if let SinkMessage::Publish(m) = msg {
+                let _ = self.1.send(m.topic).await;
+            }
+            Ok(())
+        }
+    }
+
+    /// a sink that passes on every message straight away
+    struct RecordingSink(mpsc::Sender<String>);
+
+    #[async_trait]
+    impl Sink for RecordingSink {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+        fn kinds(&self) -> &'static [MessageKind] {
+            &[MessageKind::Publish]
+        }
+        async fn send(&mut self, msg: SinkMessage) -> anyhow::Result<()> {
+            if let SinkMessage::Publish(m) = msg {
+                let _ = self.0.send(m.topic).await;
+            }
+            Ok(())
+        }
+    }
+
+    fn publish(topic: &str) -> SinkMessage {
+        SinkMessage::Publish(PublishMessage {
+            topic: topic.to_string(),
+            payload: Payload::Clear,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        })
+    }
+
+    #[tokio::test]
+    async fn a_stuck_sink_drops_instead_of_blocking() {
+        let (_bcast_tx, bcast_rx) = broadcast::channel(1);
+        let release = Arc::new(Notify::new());
+        let (seen_tx, mut seen_rx) = mpsc::channel(8);
+        let mut dispatcher = Dispatcher::default();
+        dispatcher.add(
+            Box::new(StuckSink(release.clone(), seen_tx)),
+            Some(&SinkQueueConfig {
+                capacity: Some(1),
+                backpressure: None,
+            }),
+            bcast_rx,
+        );
+        // one message is taken by the stuck sink, one waits in the queue, the rest are dropped
+        for n in 0..4 {
+            dispatcher.dispatch(publish(&n.to_string()));
+            tokio::task::yield_now().await;
+        }
+        // history isn't something this sink wants, so it's not dropped either
+        dispatcher.dispatch(SinkMessage::History(
+            "u".to_string(),
+            StatePayload::default(),
+        ));
+        let status = dispatcher.handles()[0].status();
+        assert_eq!(status.dropped, 2);
+        assert_eq!(status.queued, 1);
+
+        release.notify_one();
+        assert_eq!(seen_rx.recv().await, Some("0".to_string()));
+        release.notify_one();
+        assert_eq!(seen_rx.recv().await, Some("1".to_string()));
+        assert_eq!(dispatcher.handles()[0].status().sent, 2);
+    }
+
+    #[tokio::test]
+    async fn a_blocked_sink_doesnt_hold_up_the_others() {
+        let (bcast_tx, _) = broadcast::channel(1);
+        let release = Arc::new(Notify::new());
+        let (stuck_tx, _stuck_rx) = mpsc::channel(8);
+        let (seen_tx, mut seen_rx) = mpsc::channel(8);
+        let mut dispatcher = Dispatcher::default();
+        dispatcher.add(
+            Box::new(StuckSink(release.clone(), stuck_tx)),
+            Some(&SinkQueueConfig {
+                capacity: Some(1),
+                backpressure: Some(Backpressure::Block),
+            }),
+            bcast_tx.subscribe(),
+        );
+        dispatcher.add(Box::new(RecordingSink(seen_tx)), None, bcast_tx.subscribe());
+
+        // the stuck sink's queue fills up, but the recording sink gets every message promptly
+        for n in 0..4 {
+            dispatcher.dispatch(publish(&n.to_string()));
+            tokio::task::yield_now().await;
+        }
+        for n in 0..4 {
+            let seen = timeout(Duration::from_secs(1), seen_rx.recv()).await;
+            assert_eq!(seen, Ok(Some(n.to_string())));
+        }
+        assert_eq!(dispatcher.handles()[0].status().dropped, 0);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::config_structs::Backpressure;
+use crate::consts::*;
+use crate::ipc::IPCMessage;
+use crate::sinks::{MessageKind, Sink, SinkMessage};
+use anyhow::bail;
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// Hands messages to the MQTT thread.
+pub struct MqttSink {
+    tx: mpsc::Sender<IPCMessage>,
+}
+
+impl MqttSink {
+    pub fn new(tx: mpsc::Sender<IPCMessage>) -> Self {
+        MqttSink { tx }
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn kinds(&self) -> &'static [MessageKind] {
+        &[MessageKind::Publish]
+    }
+
+    /// discovery configs have to arrive, so bursts of them wait rather than being dropped
+    fn default_backpressure(&self) -> Backpressure {
+        Backpressure::Block
+    }
+
+    async fn send(&mut self, msg: SinkMessage) -> anyhow::Result<()> {
+        let SinkMessage::Publish(msg) = msg else {
+            return Ok(());
+        };
+        match timeout(
+            Duration::from_secs(SINK_BLOCK_TIMEOUT_SECS),
+            self.tx.send(IPCMessage::Outbound(msg)),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => {
+                bail!("Unable to send mqtt mpsc tx message: {e}");
+            }
+            Err(e) => {
+                bail!("Timeout sending to the mqtt thread: {e}");
+            }
+        }
+    }
+}
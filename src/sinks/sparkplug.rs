@@ -0,0 +1,551 @@
+use crate::config_structs::SparkplugConfig;
+use crate::consts::*;
+use crate::ipc::{IPCMessage, InboundMessage, PublishMessage, WriteSource};
+use crate::mqtt_poll::MQTT_CONNECTIONS;
+use crate::payload::{Payload, PayloadValueType};
+use crate::point_path::PointPath;
+use crate::sinks::{MessageKind, Sink, SinkMessage};
+use anyhow::bail;
+use async_trait::async_trait;
+use chrono::Utc;
+use prost::Message;
+use proto::metric::Value;
+use rumqttc::{LastWill, QoS};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// Set when a host application asks for a rebirth, so that births are sent again on the next
+/// tick.
+pub static REBIRTH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+const BD_SEQ: &str = "bdSeq";
+const REBIRTH: &str = "Node Control/Rebirth";
+
+/// The parts of the Sparkplug B schema (`sparkplug_b.proto`) the gateway uses.
+pub mod proto {
+    pub const INT8: u32 = 1;
+    pub const INT16: u32 = 2;
+    pub const INT32: u32 = 3;
+    pub const INT64: u32 = 4;
+    pub const DOUBLE: u32 = 10;
+    pub const BOOLEAN: u32 = 11;
+    pub const STRING: u32 = 12;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Payload {
+        #[prost(uint64, optional, tag = "1")]
+        pub timestamp: Option<u64>,
+        #[prost(message, repeated, tag = "2")]
+        pub metrics: Vec<Metric>,
+        #[prost(uint64, optional, tag = "3")]
+        pub seq: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Metric {
+        #[prost(string, optional, tag = "1")]
+        pub name: Option<String>,
+        #[prost(uint64, optional, tag = "3")]
+        pub timestamp: Option<u64>,
+        #[prost(uint32, optional, tag = "4")]
+        pub datatype: Option<u32>,
+        #[prost(bool, optional, tag = "7")]
+        pub is_null: Option<bool>,
+        #[prost(oneof = "metric::Value", tags = "10, 11, 12, 13, 14, 15")]
+        pub value: Option<metric::Value>,
+    }
+
+    pub mod metric {
+        /// named after the fields of the `value` oneof in the schema
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        #[allow(clippy::enum_variant_names)]
+        pub enum Value {
+            #[prost(uint32, tag = "10")]
+            IntValue(u32),
+            #[prost(uint64, tag = "11")]
+            LongValue(u64),
+            #[prost(float, tag = "12")]
+            FloatValue(f32),
+            #[prost(double, tag = "13")]
+            DoubleValue(f64),
+            #[prost(bool, tag = "14")]
+            BooleanValue(bool),
+            #[prost(string, tag = "15")]
+            StringValue(String),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+/// A metric carrying a reading, with its datatype following the value read.
+fn metric(name: &str, value: &PayloadValueType) -> proto::Metric {
+    let (datatype, value) = match value {
+        PayloadValueType::Int(i) => (proto::INT64, Some(Value::LongValue(*i as u64))),
+        PayloadValueType::Float(f) => (proto::DOUBLE, Some(Value::DoubleValue(*f))),
+        PayloadValueType::Boolean(b) => (proto::BOOLEAN, Some(Value::BooleanValue(*b))),
+        PayloadValueType::String(s) => (proto::STRING, Some(Value::StringValue(s.clone()))),
+        PayloadValueType::Array(symbols) => {
+            (proto::STRING, Some(Value::StringValue(symbols.join(","))))
+        }
+        PayloadValueType::None => (proto::STRING, None),
+    };
+    proto::Metric {
+        name: Some(name.to_string()),
+        timestamp: Some(now_millis()),
+        datatype: Some(datatype),
+        is_null: value.is_none().then_some(true),
+        value,
+    }
+}
+
+/// A command value, as the payload the write path expects.
+fn command_payload(metric: &proto::Metric) -> anyhow::Result<String> {
+    let signed = matches!(
+        metric.datatype,
+        Some(proto::INT8 | proto::INT16 | proto::INT32 | proto::INT64)
+    );
+    Ok(match &metric.value {
+        Some(Value::IntValue(i)) if signed => (*i as i32).to_string(),
+        Some(Value::IntValue(i)) => i.to_string(),
+        Some(Value::LongValue(l)) if signed => (*l as i64).to_string(),
+        Some(Value::LongValue(l)) => l.to_string(),
+        Some(Value::FloatValue(f)) => f.to_string(),
+        Some(Value::DoubleValue(d)) => d.to_string(),
+        Some(Value::BooleanValue(b)) => b.to_string(),
+        Some(Value::StringValue(s)) => s.clone(),
+        None => bail!("metric has no value"),
+    })
+}
+
+/// Where a state topic (`sunspec_gateway/{sn}/{model}/{point}`) goes in Sparkplug: the device,
+/// and the metric name (`{model}/{point}`).
+fn device_metric(topic: &str) -> Option<(String, String)> {
+    let mut parts = topic.strip_prefix("sunspec_gateway/")?.splitn(3, '/');
+    let (sn, model, point) = (parts.next()?, parts.next()?, parts.next()?);
+    model.parse::<u16>().ok()?;
+    Some((sn.to_string(), format!("{model}/{point}")))
+}
+
+/// What a host application asked for.
+#[derive(Clone)]
+pub enum Command {
+    Rebirth,
+    Write(Vec<InboundMessage>),
+}
+
+/// Work out a command from an NCMD or DCMD.  DCMD metrics are named like the ones we publish,
+/// `{model}/{point}`, and are written through the same path as our own command topics.
+pub fn parse_command(topic: &str, payload: &[u8]) -> anyhow::Result<Command> {
+    let parts: Vec<&str> = topic.split('/').collect();
+    let payload = proto::Payload::decode(payload)?;
+    match parts.as_slice() {
+        [SPARKPLUG_NAMESPACE, _, "NCMD", _] => {
+            if payload.metrics.iter().any(|m| {
+                m.name.as_deref() == Some(REBIRTH) && m.value == Some(Value::BooleanValue(true))
+            }) {
+                Ok(Command::Rebirth)
+            } else {
+                bail!("unsupported node command on {topic}");
+            }
+        }
+        [SPARKPLUG_NAMESPACE, _, "DCMD", _, device] => {
+            let mut writes = vec![];
+            for m in payload.metrics.iter() {
+                let name = m.name.clone().unwrap_or_default();
+                let Some((model, point)) = name.split_once('/') else {
+                    bail!("metric {name} isn't a {{model}}/{{point}}");
+                };
+                writes.push(InboundMessage {
+                    serial_number: device.to_string(),
+                    model: model.to_string(),
+                    point: point.parse::<PointPath>()?,
+                    payload: command_payload(m)?,
+                    source: WriteSource::Mqtt(topic.to_string()),
+                    dry_run: false,
+                });
+            }
+            Ok(Command::Write(writes))
+        }
+        _ => bail!("{topic} isn't a sparkplug command"),
+    }
+}
+
+/// The gateway, as a Sparkplug B edge node.
+#[derive(Clone, Debug)]
+pub struct EdgeNode {
+    group_id: String,
+    edge_node_id: String,
+    /// birth/death sequence number, tying each NBIRTH to the NDEATH left as our last will.
+    /// The will is set once per process, so this is too.
+    bd_seq: u64,
+}
+
+impl EdgeNode {
+    pub fn new(config: &SparkplugConfig, client_id: &str) -> Self {
+        EdgeNode {
+            group_id: config.group_id.clone(),
+            edge_node_id: config.edge_node_id.clone().unwrap_or(client_id.to_string()),
+            bd_seq: Utc::now().timestamp() as u64 % 256,
+        }
+    }
+
+    fn topic(&self, message_type: &str, device: Option<&str>) -> String {
+        let topic = format!(
+            "{SPARKPLUG_NAMESPACE}/{}/{message_type}/{}",
+            self.group_id, self.edge_node_id
+        );
+        match device {
+            Some(device) => format!("{topic}/{device}"),
+            None => topic,
+        }
+    }
+
+    fn bd_seq_metric(&self) -> proto::Metric {
+        metric(BD_SEQ, &PayloadValueType::Int(self.bd_seq as i64))
+    }
+
+    fn death_certificate(&self) -> Vec<u8> {
+        proto::Payload {
+            timestamp: Some(now_millis()),
+            metrics: vec![self.bd_seq_metric()],
+            seq: None,
+        }
+        .encode_to_vec()
+    }
+
+    /// The NDEATH the broker publishes for us if we drop off.
+    pub fn last_will(&self) -> LastWill {
+        LastWill::new(
+            self.topic("NDEATH", None),
+            self.death_certificate(),
+            QoS::AtLeastOnce,
+            false,
+        )
+    }
+
+    /// The topics host applications send us commands on.
+    pub fn command_topics(&self) -> Vec<String> {
+        vec![self.topic("NCMD", None), self.topic("DCMD", Some("+"))]
+    }
+}
+
+/// What we've told host applications about a unit.
+#[derive(Debug, Default)]
+struct Device {
+    metrics: BTreeMap<String, PayloadValueType>,
+    /// metrics that changed since the last DBIRTH or DDATA
+    changed: BTreeSet<String>,
+    born: bool,
+}
+
+/// Publishes readings as a Sparkplug B edge node, with each unit as a device.
+///
+/// Births carry every metric we know of, so a new metric turning up means a new DBIRTH for its
+/// device; otherwise what changed between ticks goes out as DDATA.
+pub struct SparkplugSink {
+    node: EdgeNode,
+    tx: mpsc::Sender<IPCMessage>,
+    /// the broker connection births were last sent on
+    connection: u64,
+    node_born: bool,
+    seq: u64,
+    devices: HashMap<String, Device>,
+}
+
+impl SparkplugSink {
+    pub fn new(node: EdgeNode, tx: mpsc::Sender<IPCMessage>) -> Self {
+        SparkplugSink {
+            node,
+            tx,
+            connection: 0,
+            node_born: false,
+            seq: 0,
+            devices: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, topic: &str, value: PayloadValueType) {
+        let Some((sn, name)) = device_metric(topic) else {
+            return;
+        };
+        let device = self.devices.entry(sn).or_default();
+        match device.metrics.get(&name) {
+            Some(v) if *v == value => {}
+            Some(_) => {
+                device.metrics.insert(name.clone(), value);
+                device.changed.insert(name);
+            }
+            None => {
+                device.metrics.insert(name, value);
+                device.born = false;
+            }
+        }
+    }
+
+    fn message(&mut self, topic: String, metrics: Vec<proto::Metric>) -> PublishMessage {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        PublishMessage {
+            topic,
+            payload: Payload::Raw(
+                proto::Payload {
+                    timestamp: Some(now_millis()),
+                    metrics,
+                    seq: Some(seq),
+                }
+                .encode_to_vec(),
+            ),
+            qos: QoS::AtMostOnce,
+            retain: false,
+        }
+    }
+
+    /// The births and data due, given the broker connection we're on and whether a rebirth was
+    /// asked for.
+    fn pending(&mut self, connection: u64, rebirth: bool) -> Vec<PublishMessage> {
+        if connection == 0 {
+            return vec![];
+        }
+        if connection != self.connection || rebirth {
+            self.connection = connection;
+            self.node_born = false;
+        }
+        let mut messages = vec![];
+        if !self.node_born {
+            self.seq = 0;
+            self.devices.values_mut().for_each(|d| d.born = false);
+            let metrics = vec![
+                self.node.bd_seq_metric(),
+                metric(REBIRTH, &PayloadValueType::Boolean(false)),
+            ];
+            messages.push(self.message(self.node.topic("NBIRTH", None), metrics));
+            self.node_born = true;
+        }
+
+        let mut due = vec![];
+        for (sn, device) in self.devices.iter_mut() {
+            if !device.born {
+                device.born = true;
+                device.changed.clear();
+                due.push((
+                    self.node.topic("DBIRTH", Some(sn)),
+                    device
+                        .metrics
+                        .iter()
+                        .map(|(name, value)| metric(name, value))
+                        .collect(),
+                ));
+            } else if !device.changed.is_empty() {
+                due.push((
+                    self.node.topic("DDATA", Some(sn)),
+                    device
+                        .changed
+                        .iter()
+                        .map(|name| metric(name, &device.metrics[name]))
+                        .collect(),
+                ));
+                device.changed.clear();
+            }
+        }
+        for (topic, metrics) in due {
+            messages.push(self.message(topic, metrics));
+        }
+        messages
+    }
+
+    /// The DDEATH for a unit that stopped answering, if it had been born.  What we knew of it is
+    /// forgotten, so it's born again from fresh readings.
+    fn death(&mut self, sn: &str) -> Option<PublishMessage> {
+        let device = self.devices.remove(sn)?;
+        if !(self.node_born && device.born) {
+            return None;
+        }
+        Some(self.message(self.node.topic("DDEATH", Some(sn)), vec![]))
+    }
+
+    async fn publish(&self, msg: PublishMessage) -> anyhow::Result<()> {
+        match timeout(
+            Duration::from_secs(SINK_BLOCK_TIMEOUT_SECS),
+            self.tx.send(IPCMessage::Outbound(msg)),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => {
+                bail!("Unable to send mqtt mpsc tx message: {e}");
+            }
+            Err(e) => {
+                bail!("Timeout sending to the mqtt thread: {e}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for SparkplugSink {
+    fn name(&self) -> &'static str {
+        "sparkplug"
+    }
+
+    fn kinds(&self) -> &'static [MessageKind] {
+        &[MessageKind::Publish, MessageKind::Lifecycle]
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(SPARKPLUG_TICK_MILLIS))
+    }
+
+    async fn send(&mut self, msg: SinkMessage) -> anyhow::Result<()> {
+        match msg {
+            SinkMessage::Publish(PublishMessage {
+                topic,
+                payload: Payload::CurrentState(state),
+                ..
+            }) => {
+                self.record(&topic, state.value);
+                Ok(())
+            }
+            SinkMessage::UnitOffline(sn) => match self.death(&sn) {
+                Some(msg) => {
+                    info!("{sn}: sending sparkplug DDEATH");
+                    self.publish(msg).await
+                }
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        let rebirth = REBIRTH_REQUESTED.swap(false, Ordering::SeqCst);
+        for msg in self.pending(MQTT_CONNECTIONS.load(Ordering::SeqCst), rebirth) {
+            self.publish(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// the broker only sends our will if we drop off, so say goodbye ourselves
+    async fn shutdown(&mut self) {
+        if !self.node_born {
+            return;
+        }
+        let _ = self
+            .publish(PublishMessage {
+                topic: self.node.topic("NDEATH", None),
+                payload: Payload::Raw(self.node.death_certificate()),
+                qos: QoS::AtLeastOnce,
+                retain: false,
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(msg: &PublishMessage) -> proto::Payload {
+        let Payload::Raw(bytes) = &msg.payload else {
+            panic!("{} isn't protobuf", msg.topic);
+        };
+        proto::Payload::decode(bytes.as_slice()).unwrap()
+    }
+
+    fn names(payload: &proto::Payload) -> Vec<String> {
+        payload
+            .metrics
+            .iter()
+            .map(|m| m.name.clone().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn births_then_data_on_change() {
+        let (tx, _rx) = mpsc::channel(1);
+        let node = EdgeNode::new(
+            &SparkplugConfig {
+                group_id: "solar".to_string(),
+                edge_node_id: None,
+            },
+            "gw",
+        );
+        let mut sink = SparkplugSink::new(node, tx);
+        sink.record("sunspec_gateway/SN1/103/W", PayloadValueType::Int(1500));
+        sink.record("sunspec_gateway/SN1/events", PayloadValueType::None);
+        assert!(sink.pending(0, false).is_empty());
+
+        let births = sink.pending(1, false);
+        assert_eq!(births[0].topic, "spBv1.0/solar/NBIRTH/gw");
+        assert_eq!(names(&decode(&births[0])), vec![BD_SEQ, REBIRTH]);
+        assert_eq!(births[1].topic, "spBv1.0/solar/DBIRTH/gw/SN1");
+        let dbirth = decode(&births[1]);
+        assert_eq!(dbirth.seq, Some(1));
+        assert_eq!(dbirth.metrics[0].datatype, Some(proto::INT64));
+
+        // unchanged readings aren't sent again
+        sink.record("sunspec_gateway/SN1/103/W", PayloadValueType::Int(1500));
+        assert!(sink.pending(1, false).is_empty());
+        sink.record("sunspec_gateway/SN1/103/W", PayloadValueType::Int(1600));
+        let data = sink.pending(1, false);
+        assert_eq!(data[0].topic, "spBv1.0/solar/DDATA/gw/SN1");
+        assert_eq!(
+            decode(&data[0]).metrics[0].value,
+            Some(Value::LongValue(1600))
+        );
+
+        // a metric the host hasn't been told about means a new DBIRTH
+        sink.record(
+            "sunspec_gateway/SN1/103/St",
+            PayloadValueType::String("MPPT".into()),
+        );
+        let rebirth = sink.pending(1, false);
+        assert_eq!(rebirth[0].topic, "spBv1.0/solar/DBIRTH/gw/SN1");
+        assert_eq!(names(&decode(&rebirth[0])), vec!["103/St", "103/W"]);
+
+        let death = sink.death("SN1").unwrap();
+        assert_eq!(death.topic, "spBv1.0/solar/DDEATH/gw/SN1");
+        assert!(sink.death("SN1").is_none());
+
+        // a new broker connection starts everything over
+        assert_eq!(decode(&sink.pending(2, false)[0]).seq, Some(0));
+    }
+
+    #[test]
+    fn commands_map_onto_writes() {
+        let write = proto::Payload {
+            timestamp: None,
+            metrics: vec![proto::Metric {
+                name: Some("704/.DERCtlAC.WMaxLimPct".to_string()),
+                datatype: Some(proto::INT32),
+                value: Some(Value::IntValue(-5_i32 as u32)),
+                ..proto::Metric::default()
+            }],
+            seq: Some(0),
+        };
+        let Command::Write(writes) =
+            parse_command("spBv1.0/solar/DCMD/gw/SN1", &write.encode_to_vec()).unwrap()
+        else {
+            panic!("not a write");
+        };
+        assert_eq!(writes[0].serial_number, "SN1");
+        assert_eq!(writes[0].model, "704");
+        assert_eq!(writes[0].point.to_string(), ".DERCtlAC.WMaxLimPct");
+        assert_eq!(writes[0].payload, "-5");
+
+        let rebirth = proto::Payload {
+            metrics: vec![metric(REBIRTH, &PayloadValueType::Boolean(true))],
+            ..proto::Payload::default()
+        };
+        assert!(matches!(
+            parse_command("spBv1.0/solar/NCMD/gw", &rebirth.encode_to_vec()),
+            Ok(Command::Rebirth)
+        ));
+        assert!(parse_command("spBv1.0/solar/DDATA/gw/SN1", &[]).is_err());
+    }
+}
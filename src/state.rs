@@ -1,9 +1,12 @@
 use sqlx::sqlite::SqlitePool;
 
 use crate::auth::token_extractor::JwksCache;
+use crate::config_structs::AuthConfig;
+use crate::ipc::IPCMessage;
 use crate::modules::users::User;
 use cached::UnboundCache;
 use std::sync::Arc;
+use tokio::sync::mpsc::Sender;
 use tokio::sync::RwLock;
 
 #[derive(Clone)]
@@ -11,5 +14,10 @@ pub(crate) struct AppState {
     // we use the static ref DB_POOL for the sqlitepool object
     //pub(crate) pool: Option<SqlitePool>,
     pub(crate) jwks_cache: JwksCache,
+    pub(crate) auth_config: Arc<AuthConfig>,
     pub(crate) user_cache: Option<Arc<RwLock<UnboundCache<String, User>>>>,
+    /// for handlers that need to publish to MQTT (via the main loop)
+    pub(crate) ipc_tx: Sender<IPCMessage>,
+    /// for handlers that write to units (via the same queue as MQTT commands)
+    pub(crate) control_tx: Sender<IPCMessage>,
 }
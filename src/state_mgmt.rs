@@ -1,6 +1,12 @@
-use crate::payload::{HAConfigPayload, StatePayload};
+use crate::auth::data::ApiKey;
+use crate::config_structs::{MissedRuns, ScheduleConfig};
+use crate::modules::audit::AuditQuery;
+use crate::modules::users::{CreateUser, OperatorScope, User};
+use crate::payload::StatePayload;
 use anyhow::{bail, Result};
+use chrono::Utc;
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 
 use sqlx::pool::PoolConnection;
 use sqlx::sqlite::SqliteConnectOptions;
@@ -10,6 +16,7 @@ use std::str::FromStr;
 use tokio::sync::OnceCell;
 
 use url::Url;
+use utoipa::ToSchema;
 
 #[derive(Debug, Clone, FromRow)]
 #[allow(non_camel_case_types)]
@@ -26,6 +33,83 @@ pub struct BitfieldHistory {
     pub field_name: String,
 }
 
+#[derive(Default, Debug, Clone, FromRow)]
+pub struct EnergyAccumulator {
+    pub uniqueid: String,
+    pub total_kwh: f64,
+    pub last_value: Option<f64>,
+    pub last_timestamp: Option<i64>,
+}
+
+#[derive(Default, Debug, Clone, FromRow)]
+pub struct CounterOffset {
+    pub uniqueid: String,
+    pub offset: f64,
+    pub last_raw: Option<f64>,
+    pub resets: i64,
+    pub last_reset: Option<i64>,
+}
+
+/// A symbol of a bitfield or enum being active, from when it was raised until it was cleared.
+#[derive(Default, Debug, Clone, PartialEq, FromRow, Serialize, Deserialize, ToSchema)]
+pub struct EventRecord {
+    pub id: i64,
+    pub serial_number: String,
+    pub model: String,
+    /// canonical path of the point
+    pub point: String,
+    pub symbol: String,
+    pub severity: String,
+    /// unix timestamp the symbol became active
+    pub raised_at: i64,
+    /// unix timestamp the symbol stopped being active, if it has
+    pub cleared_at: Option<i64>,
+    pub duration_secs: Option<i64>,
+}
+
+/// A write to a unit, or an attempt at one.
+#[derive(Default, Debug, Clone, PartialEq, FromRow, Serialize, Deserialize, ToSchema)]
+pub struct AuditRecord {
+    pub id: i64,
+    /// unix timestamp of the attempt
+    pub timestamp: i64,
+    /// where the write came from, e.g. `mqtt:{topic}` or `rest:{login}`
+    pub source: String,
+    pub serial_number: String,
+    pub model: String,
+    /// canonical path of the point
+    pub point: String,
+    /// the value as it was asked for
+    pub requested_value: String,
+    /// the register value it came to, if it got that far
+    pub raw_value: Option<i64>,
+    /// the point's value before the write, if it could be read
+    pub previous_value: Option<String>,
+    /// `written`, `rejected`, `failed` or `dry_run`
+    pub result: String,
+    pub error: Option<String>,
+}
+
+/// A schedule, and how far it has got.
+#[derive(Default, Debug, Clone, PartialEq, FromRow, Serialize, Deserialize, ToSchema)]
+pub struct Schedule {
+    pub name: String,
+    pub cron: String,
+    pub serial_number: String,
+    pub model: String,
+    pub point: String,
+    pub value: String,
+    pub missed: MissedRuns,
+    pub dry_run: bool,
+    pub enabled: bool,
+    /// whether it's from the config file, which it's reset to on every start
+    pub from_config: bool,
+    /// unix timestamp up to which runs have been made or skipped
+    pub last_checked: Option<i64>,
+    /// unix timestamp of the run last made
+    pub last_run: Option<i64>,
+}
+
 #[derive(Default, Debug, Clone, FromRow)]
 pub struct AggregatedMeasurements {
     pub min: f64,
@@ -241,28 +325,90 @@ pub async fn write_bitfield_history(uniqueid: &String, field_name: &String) -> a
     }
 }
 
-pub async fn write_payload_history(
-    config: HAConfigPayload,
-    state: StatePayload,
-) -> anyhow::Result<()> {
-    let timestamp = state.last_seen;
-    let uniqueid = config.unique_id;
-    let value_json = match serde_json::to_string(&state.value) {
-        Ok(s) => s,
+pub async fn get_energy_accumulator(
+    uniqueid: &String,
+) -> anyhow::Result<Option<EnergyAccumulator>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    SELECT uniqueid, total_kwh, last_value, last_timestamp from energy_accumulators
+    WHERE uniqueid = $1
+    "#,
+    )
+    .bind(uniqueid)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(acc) => Ok(acc),
         Err(e) => {
             bail!(e);
         }
-    };
+    }
+}
+
+pub async fn write_energy_accumulator(acc: &EnergyAccumulator) -> anyhow::Result<()> {
     let pool = DB_POOL.get().unwrap();
     match sqlx::query(
         r#"
-    INSERT INTO point_history (timestamp, uniqueid, value)
-    VALUES (?,?,?)
+    INSERT INTO energy_accumulators (uniqueid, total_kwh, last_value, last_timestamp)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT(uniqueid) DO UPDATE SET
+    total_kwh = excluded.total_kwh,
+    last_value = excluded.last_value,
+    last_timestamp = excluded.last_timestamp
+    "#,
+    )
+    .bind(&acc.uniqueid)
+    .bind(acc.total_kwh)
+    .bind(acc.last_value)
+    .bind(acc.last_timestamp)
+    .execute(pool)
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_counter_offset(uniqueid: &String) -> anyhow::Result<Option<CounterOffset>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    SELECT uniqueid, offset, last_raw, resets, last_reset from counter_offsets
+    WHERE uniqueid = $1
     "#,
     )
-    .bind(timestamp.timestamp())
     .bind(uniqueid)
-    .bind(value_json)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(co) => Ok(co),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn write_counter_offset(co: &CounterOffset) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        r#"
+    INSERT INTO counter_offsets (uniqueid, offset, last_raw, resets, last_reset)
+    VALUES ($1, $2, $3, $4, $5)
+    ON CONFLICT(uniqueid) DO UPDATE SET
+    offset = excluded.offset,
+    last_raw = excluded.last_raw,
+    resets = excluded.resets,
+    last_reset = excluded.last_reset
+    "#,
+    )
+    .bind(&co.uniqueid)
+    .bind(co.offset)
+    .bind(co.last_raw)
+    .bind(co.resets)
+    .bind(co.last_reset)
     .execute(pool)
     .await
     {
@@ -273,49 +419,961 @@ pub async fn write_payload_history(
     }
 }
 
-pub async fn get_history(uniqueid: String) -> anyhow::Result<AggregatedMeasurements> {
+/// Remember that we published a discovery config, so that it can be cleared if the point that
+/// produced it goes away.  `owner` identifies that point within the unit (`{model}/{path}`).
+pub async fn record_discovery_topic(
+    topic: &String,
+    serial_number: &String,
+    owner: &String,
+) -> anyhow::Result<()> {
     let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        r#"
+    INSERT INTO discovery_topics (topic, serial_number, owner, last_published)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT(topic) DO UPDATE SET
+    serial_number = excluded.serial_number,
+    owner = excluded.owner,
+    last_published = excluded.last_published
+    "#,
+    )
+    .bind(topic)
+    .bind(serial_number)
+    .bind(owner)
+    .bind(Utc::now().timestamp())
+    .execute(pool)
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
 
-    // let mut ts: Vec<f64> = vec![];
-    // let tsq= sqlx::query(
-    //     r#"
-    // SELECT CAST(value as real) as value
-    // FROM point_history
-    // WHERE uniqueid = $1
-    // ORDER BY timestamp desc
-    // "#
-    // )
-    //     .bind(uniqueid.clone())
-    //     .fetch_all(pool)
-    //     .await.unwrap();
-    //
-    // let _ = tsq.iter().map(|x| ts.push(x.get::<f64, &str>("value")));
-    // let mut resl: Vec<f64> = vec![];
-    // res_l(ts.into_iter(), resl.as_mut_slice());
-    // info!("Reservoir Samples: {:#?}", resl);
+/// All discovery topics published for a serial number, with the owner that produced each.
+pub async fn get_discovery_topics(serial_number: &String) -> anyhow::Result<Vec<(String, String)>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    SELECT topic, owner FROM discovery_topics
+    WHERE serial_number = $1
+    "#,
+    )
+    .bind(serial_number)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(rows) => Ok(rows),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
 
-    let values: AggregatedMeasurements = match sqlx::query_as(
+pub async fn delete_discovery_topic(topic: &String) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("DELETE FROM discovery_topics WHERE topic = $1")
+        .bind(topic)
+        .execute(pool)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Record a newly raised event, returning it with its id.
+pub async fn open_event(event: &EventRecord) -> anyhow::Result<EventRecord> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
         r#"
-    SELECT
-    COUNT(value) as count,
-    MIN(CAST(value as real)) as min,
-    MAX(CAST(value as real)) as max,
-    PERCENTILE(CAST(value as real),50) as median,
-    AVG(CAST(value as real)) as avg,
-    STDDEV(CAST(value as real)) as stdev
-    from point_history
-    WHERE uniqueid = $1
+    INSERT INTO events (serial_number, model, point, symbol, severity, raised_at)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    RETURNING *
     "#,
     )
-    .bind(uniqueid)
+    .bind(&event.serial_number)
+    .bind(&event.model)
+    .bind(&event.point)
+    .bind(&event.symbol)
+    .bind(&event.severity)
+    .bind(event.raised_at)
     .fetch_one(pool)
     .await
     {
-        Ok(s) => s,
+        Ok(e) => Ok(e),
         Err(e) => {
-            bail!(e)
+            bail!(e);
         }
-    };
+    }
+}
 
-    Ok(values)
+/// Mark an event as cleared, returning it (if it was still open).
+pub async fn close_event(id: i64, cleared_at: i64) -> anyhow::Result<Option<EventRecord>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    UPDATE events SET cleared_at = $2, duration_secs = $2 - raised_at
+    WHERE id = $1 AND cleared_at IS NULL
+    RETURNING *
+    "#,
+    )
+    .bind(id)
+    .bind(cleared_at)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(e) => Ok(e),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Events, newest first.  Only the events of `serial_number` if given, and only the ones that
+/// haven't cleared if `active_only`.
+pub async fn list_events(
+    serial_number: Option<&String>,
+    active_only: bool,
+    limit: i64,
+) -> anyhow::Result<Vec<EventRecord>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    SELECT * FROM events
+    WHERE ($1 IS NULL OR serial_number = $1)
+    AND ($2 = 0 OR cleared_at IS NULL)
+    ORDER BY raised_at DESC, id DESC
+    LIMIT $3
+    "#,
+    )
+    .bind(serial_number)
+    .bind(active_only)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(events) => Ok(events),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Add a write to the audit log, returning it with its id.
+pub async fn record_write(record: &AuditRecord) -> anyhow::Result<AuditRecord> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    INSERT INTO audit_log (timestamp, source, serial_number, model, point, requested_value,
+        raw_value, previous_value, result, error)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+    RETURNING *
+    "#,
+    )
+    .bind(record.timestamp)
+    .bind(&record.source)
+    .bind(&record.serial_number)
+    .bind(&record.model)
+    .bind(&record.point)
+    .bind(&record.requested_value)
+    .bind(record.raw_value)
+    .bind(&record.previous_value)
+    .bind(&record.result)
+    .bind(&record.error)
+    .fetch_one(pool)
+    .await
+    {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// The audit log, newest first, narrowed down by whichever filters are given.
+pub async fn list_writes(query: &AuditQuery, limit: i64) -> anyhow::Result<Vec<AuditRecord>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    SELECT * FROM audit_log
+    WHERE ($1 IS NULL OR serial_number = $1)
+    AND ($2 IS NULL OR model = $2)
+    AND ($3 IS NULL OR point = $3)
+    AND ($4 IS NULL OR substr(source, 1, length($4)) = $4)
+    AND ($5 IS NULL OR result = $5)
+    AND ($6 IS NULL OR timestamp >= $6)
+    AND ($7 IS NULL OR timestamp < $7)
+    ORDER BY timestamp DESC, id DESC
+    LIMIT $8
+    "#,
+    )
+    .bind(&query.serial_number)
+    .bind(&query.model)
+    .bind(&query.point)
+    .bind(&query.source)
+    .bind(query.result.map(|r| r.to_string()))
+    .bind(query.since)
+    .bind(query.until)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(records) => Ok(records),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// When a point was sent writes (whether or not the unit took them) since `since`, oldest first.
+pub async fn recent_writes(
+    serial_number: &str,
+    model: &str,
+    point: &str,
+    since: i64,
+) -> anyhow::Result<Vec<i64>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_scalar(
+        r#"
+    SELECT timestamp FROM audit_log
+    WHERE serial_number = $1 AND model = $2 AND point = $3
+    AND result IN ('written', 'failed') AND timestamp >= $4
+    ORDER BY timestamp
+    "#,
+    )
+    .bind(serial_number)
+    .bind(model)
+    .bind(point)
+    .bind(since)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(timestamps) => Ok(timestamps),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_schedules() -> anyhow::Result<Vec<Schedule>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as("SELECT * FROM schedules ORDER BY name")
+        .fetch_all(pool)
+        .await
+    {
+        Ok(schedules) => Ok(schedules),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_schedule(name: &str) -> anyhow::Result<Option<Schedule>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as("SELECT * FROM schedules WHERE name = $1")
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(schedule) => Ok(schedule),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Add a schedule, or change the one with the same name, keeping how far it has got.
+pub async fn save_schedule(config: &ScheduleConfig, from_config: bool) -> anyhow::Result<Schedule> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    INSERT INTO schedules (name, cron, serial_number, model, point, value, missed, dry_run,
+        enabled, from_config)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+    ON CONFLICT (name) DO UPDATE SET cron = excluded.cron,
+        serial_number = excluded.serial_number, model = excluded.model, point = excluded.point,
+        value = excluded.value, missed = excluded.missed, dry_run = excluded.dry_run,
+        enabled = excluded.enabled, from_config = excluded.from_config
+    RETURNING *
+    "#,
+    )
+    .bind(&config.name)
+    .bind(&config.cron)
+    .bind(&config.serial_number)
+    .bind(&config.model)
+    .bind(&config.point)
+    .bind(&config.value)
+    .bind(config.missed.unwrap_or_default())
+    .bind(config.dry_run.unwrap_or(false))
+    .bind(config.enabled.unwrap_or(true))
+    .bind(from_config)
+    .fetch_one(pool)
+    .await
+    {
+        Ok(schedule) => Ok(schedule),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn delete_schedule(name: &str) -> anyhow::Result<bool> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("DELETE FROM schedules WHERE name = $1")
+        .bind(name)
+        .execute(pool)
+        .await
+    {
+        Ok(r) => Ok(r.rows_affected() > 0),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Record that a schedule has made or skipped its runs up to `last_checked`, and when it last
+/// made one, if it just did.
+pub async fn set_schedule_progress(
+    name: &str,
+    last_checked: i64,
+    last_run: Option<i64>,
+) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        "UPDATE schedules SET last_checked = $2, last_run = COALESCE($3, last_run) WHERE name = $1",
+    )
+    .bind(name)
+    .bind(last_checked)
+    .bind(last_run)
+    .execute(pool)
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_setting(name: &str) -> anyhow::Result<Option<String>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_scalar("SELECT value FROM gateway_settings WHERE name = $1")
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn set_setting(name: &str, value: &str) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        r#"
+    INSERT INTO gateway_settings (name, value) VALUES ($1, $2)
+    ON CONFLICT (name) DO UPDATE SET value = excluded.value
+    "#,
+    )
+    .bind(name)
+    .bind(value)
+    .execute(pool)
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Keep a batch that couldn't be sent to the http sink, to send later.
+pub async fn spool_batch(body: &str) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("INSERT INTO http_spool (body, created_at) VALUES ($1, $2)")
+        .bind(body)
+        .bind(Utc::now().timestamp())
+        .execute(pool)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// The oldest spooled batches, with their ids.
+pub async fn get_spooled_batches(limit: i64) -> anyhow::Result<Vec<(i64, String)>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as("SELECT id, body FROM http_spool ORDER BY id LIMIT $1")
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    {
+        Ok(rows) => Ok(rows),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn delete_spooled_batch(id: i64) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("DELETE FROM http_spool WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Drop the oldest spooled batches beyond `limit`, returning how many were dropped.
+pub async fn trim_spool(limit: i64) -> anyhow::Result<u64> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        r#"
+    DELETE FROM http_spool WHERE id NOT IN
+    (SELECT id FROM http_spool ORDER BY id DESC LIMIT $1)
+    "#,
+    )
+    .bind(limit)
+    .execute(pool)
+    .await
+    {
+        Ok(r) => Ok(r.rows_affected()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn write_payload_history(uniqueid: String, state: StatePayload) -> anyhow::Result<()> {
+    let timestamp = state.last_seen;
+    let value_json = match serde_json::to_string(&state.value) {
+        Ok(s) => s,
+        Err(e) => {
+            bail!(e);
+        }
+    };
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        r#"
+    INSERT INTO point_history (timestamp, uniqueid, value)
+    VALUES (?,?,?)
+    "#,
+    )
+    .bind(timestamp.timestamp())
+    .bind(uniqueid)
+    .bind(value_json)
+    .execute(pool)
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_history(uniqueid: String) -> anyhow::Result<AggregatedMeasurements> {
+    let pool = DB_POOL.get().unwrap();
+
+    // let mut ts: Vec<f64> = vec![];
+    // let tsq= sqlx::query(
+    //     r#"
+    // SELECT CAST(value as real) as value
+    // FROM point_history
+    // WHERE uniqueid = $1
+    // ORDER BY timestamp desc
+    // "#
+    // )
+    //     .bind(uniqueid.clone())
+    //     .fetch_all(pool)
+    //     .await.unwrap();
+    //
+    // let _ = tsq.iter().map(|x| ts.push(x.get::<f64, &str>("value")));
+    // let mut resl: Vec<f64> = vec![];
+    // res_l(ts.into_iter(), resl.as_mut_slice());
+    // info!("Reservoir Samples: {:#?}", resl);
+
+    let values: AggregatedMeasurements = match sqlx::query_as(
+        r#"
+    SELECT
+    COUNT(value) as count,
+    MIN(CAST(value as real)) as min,
+    MAX(CAST(value as real)) as max,
+    PERCENTILE(CAST(value as real),50) as median,
+    AVG(CAST(value as real)) as avg,
+    STDDEV(CAST(value as real)) as stdev
+    from point_history
+    WHERE uniqueid = $1
+    "#,
+    )
+    .bind(uniqueid)
+    .fetch_one(pool)
+    .await
+    {
+        Ok(s) => s,
+        Err(e) => {
+            bail!(e)
+        }
+    };
+
+    Ok(values)
+}
+
+pub async fn get_user(id: i32) -> anyhow::Result<Option<User>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as("SELECT * FROM users WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(u) => Ok(u),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_user_by_login(login: &String) -> anyhow::Result<Option<User>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as("SELECT * FROM users WHERE login = $1")
+        .bind(login)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(u) => Ok(u),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// The user a token names: one of the gateway's own users when there's no issuer, or else a user of
+/// that issuer.
+pub async fn get_token_user(issuer: Option<&str>, login: &str) -> anyhow::Result<Option<User>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as("SELECT * FROM users WHERE login = $1 AND issuer IS $2")
+        .bind(login)
+        .bind(issuer)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(u) => Ok(u),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_users(team_id: i32) -> anyhow::Result<Vec<User>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as("SELECT * FROM users WHERE team_id = $1 ORDER BY id")
+        .bind(team_id)
+        .fetch_all(pool)
+        .await
+    {
+        Ok(u) => Ok(u),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn count_users() -> anyhow::Result<i64> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("SELECT COUNT(*) AS count FROM users")
+        .fetch_one(pool)
+        .await
+    {
+        Ok(r) => Ok(r.get("count")),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Create a user, returning it with its id.  Fails if the login is taken.
+pub async fn create_user(login: &String, team_id: i32, user: &CreateUser) -> anyhow::Result<User> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    INSERT INTO users (login, given_name, family_name, additional_name, preferred_name, team_id, role, issuer)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+    RETURNING *
+    "#,
+    )
+    .bind(login)
+    .bind(&user.given_name)
+    .bind(&user.family_name)
+    .bind(&user.additional_name)
+    .bind(&user.preferred_name)
+    .bind(team_id)
+    .bind(user.role.unwrap_or_default())
+    .bind(&user.issuer)
+    .fetch_one(pool)
+    .await
+    {
+        Ok(u) => Ok(u),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Update the names of a user; fields left out of `user` keep their value.  Returns the updated
+/// user, if it exists.
+pub async fn update_user(id: i32, user: &CreateUser) -> anyhow::Result<Option<User>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    UPDATE users SET
+    given_name = COALESCE($1, given_name),
+    family_name = COALESCE($2, family_name),
+    additional_name = COALESCE($3, additional_name),
+    preferred_name = COALESCE($4, preferred_name),
+    role = COALESCE($5, role)
+    WHERE id = $6
+    RETURNING *
+    "#,
+    )
+    .bind(&user.given_name)
+    .bind(&user.family_name)
+    .bind(&user.additional_name)
+    .bind(&user.preferred_name)
+    .bind(user.role)
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(u) => Ok(u),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Delete a user with their API keys and scopes, returning whether the user existed.
+pub async fn delete_user(id: i32) -> anyhow::Result<bool> {
+    let pool = DB_POOL.get().unwrap();
+    // sqlite only honours ON DELETE CASCADE with foreign keys turned on, so don't rely on it
+    for table in ["api_keys", "operator_scopes"] {
+        if let Err(e) = sqlx::query(&format!("DELETE FROM {table} WHERE user_id = $1"))
+            .bind(id)
+            .execute(pool)
+            .await
+        {
+            bail!(e);
+        }
+    }
+    match sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await
+    {
+        Ok(r) => Ok(r.rows_affected() > 0),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Store the hash of a new API key for a user, returning the key's record.
+pub async fn create_api_key(
+    user_id: i32,
+    key_hash: &String,
+    name: &Option<String>,
+) -> anyhow::Result<ApiKey> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    INSERT INTO api_keys (user_id, key_hash, name, created_at)
+    VALUES ($1, $2, $3, $4)
+    RETURNING id, user_id, name, created_at, last_used
+    "#,
+    )
+    .bind(user_id)
+    .bind(key_hash)
+    .bind(name)
+    .bind(Utc::now().timestamp())
+    .fetch_one(pool)
+    .await
+    {
+        Ok(k) => Ok(k),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_api_keys(user_id: i32) -> anyhow::Result<Vec<ApiKey>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    SELECT id, user_id, name, created_at, last_used FROM api_keys
+    WHERE user_id = $1
+    ORDER BY id
+    "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(k) => Ok(k),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Delete one of a user's API keys, returning whether it existed.
+pub async fn delete_api_key(user_id: i32, id: i64) -> anyhow::Result<bool> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("DELETE FROM api_keys WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+    {
+        Ok(r) => Ok(r.rows_affected() > 0),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// The user an API key (by hash) belongs to, marking the key as used.
+pub async fn api_key_user(key_hash: &String) -> anyhow::Result<Option<i32>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        r#"
+    UPDATE api_keys SET last_used = $1
+    WHERE key_hash = $2
+    RETURNING user_id
+    "#,
+    )
+    .bind(Utc::now().timestamp())
+    .bind(key_hash)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(r) => Ok(r.map(|r| r.get("user_id"))),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn get_operator_scopes(user_id: i32) -> anyhow::Result<Vec<OperatorScope>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as(
+        r#"
+    SELECT serial_number, point FROM operator_scopes
+    WHERE user_id = $1
+    ORDER BY id
+    "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(s) => Ok(s),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Replace all of a user's operator scopes.
+pub async fn set_operator_scopes(user_id: i32, scopes: &Vec<OperatorScope>) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    let mut tx = pool.begin().await?;
+    if let Err(e) = sqlx::query("DELETE FROM operator_scopes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+    {
+        bail!(e);
+    }
+    for scope in scopes {
+        if let Err(e) = sqlx::query(
+            r#"
+    INSERT INTO operator_scopes (user_id, serial_number, point)
+    VALUES ($1, $2, $3)
+    "#,
+        )
+        .bind(user_id)
+        .bind(&scope.serial_number)
+        .bind(&scope.point)
+        .execute(&mut *tx)
+        .await
+        {
+            bail!(e);
+        }
+    }
+    match tx.commit().await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Set (or with `None`, remove) a user's password hash, returning whether the user exists.
+pub async fn set_password_hash(id: i32, password_hash: Option<String>) -> anyhow::Result<bool> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+        .bind(password_hash)
+        .bind(id)
+        .execute(pool)
+        .await
+    {
+        Ok(r) => Ok(r.rows_affected() > 0),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// The id and password hash of the user with the login, if there's such a user.
+pub async fn get_password_hash(login: &String) -> anyhow::Result<Option<(i32, Option<String>)>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query_as("SELECT id, password_hash FROM users WHERE login = $1")
+        .bind(login)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Store a new session, returning false if its id is already taken.
+pub async fn create_session(id: &String, data: &String, expiry_date: i64) -> anyhow::Result<bool> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        r#"
+    INSERT INTO sessions (id, data, expiry_date)
+    VALUES ($1, $2, $3)
+    ON CONFLICT(id) DO NOTHING
+    "#,
+    )
+    .bind(id)
+    .bind(data)
+    .bind(expiry_date)
+    .execute(pool)
+    .await
+    {
+        Ok(r) => Ok(r.rows_affected() > 0),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn save_session(id: &String, data: &String, expiry_date: i64) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query(
+        r#"
+    INSERT INTO sessions (id, data, expiry_date)
+    VALUES ($1, $2, $3)
+    ON CONFLICT(id) DO UPDATE SET
+    data = excluded.data,
+    expiry_date = excluded.expiry_date
+    "#,
+    )
+    .bind(id)
+    .bind(data)
+    .bind(expiry_date)
+    .execute(pool)
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// A session's data, unless it has expired.
+pub async fn load_session(id: &String) -> anyhow::Result<Option<String>> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("SELECT data FROM sessions WHERE id = $1 AND expiry_date > $2")
+        .bind(id)
+        .bind(Utc::now().timestamp())
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(r) => Ok(r.map(|r| r.get("data"))),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+pub async fn delete_session(id: &String) -> anyhow::Result<()> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("DELETE FROM sessions WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// Delete every expired session, returning how many there were.
+pub async fn delete_expired_sessions() -> anyhow::Result<u64> {
+    let pool = DB_POOL.get().unwrap();
+    match sqlx::query("DELETE FROM sessions WHERE expiry_date <= $1")
+        .bind(Utc::now().timestamp())
+        .execute(pool)
+        .await
+    {
+        Ok(r) => Ok(r.rows_affected()),
+        Err(e) => {
+            bail!(e);
+        }
+    }
+}
+
+/// An empty, migrated database for tests that need one.  It's a file rather than `:memory:`,
+/// because each test has its own runtime, and an in-memory database goes away with the connection
+/// when the runtime that last used it shuts down.
+#[cfg(test)]
+pub(crate) async fn test_db() {
+    DB_POOL
+        .get_or_init(|| async {
+            let path = std::env::temp_dir()
+                .join(format!("sunspec_gateway_test_{}.db", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let options = SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(true);
+            let pool = sqlx::sqlite::SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect_with(options)
+                .await
+                .unwrap();
+            sqlx::migrate!().run(&pool).await.unwrap();
+            pool
+        })
+        .await;
 }
@@ -1,15 +1,29 @@
+use crate::audit::{audit_value, audit_write, WriteResult};
 use crate::config_structs::{InputType, PointConfig};
 use crate::consts::*;
-use crate::ipc::{IPCMessage, PublishMessage};
+use crate::discovery::{aggregate_owner, point_owner, DiscoveryTracker};
+use crate::events::{active_symbols, EventTracker};
+use crate::ha_inputs::command_value;
+use crate::ipc::{IPCMessage, InboundMessage, PublishMessage};
+use crate::modbus_server::{self, UnitRegisters, FACADE};
 use crate::monitored_point::MonitoredPoint;
+use crate::mqtt_poll::MQTT_CONNECTIONS;
 use crate::payload::generate_payloads;
-use crate::payload::Payload;
-use crate::state_mgmt::{cull_records_to, write_payload_history};
+use crate::payload::{CompoundPayload, Payload, PayloadValueType};
+use crate::point_path::PointPath;
+use crate::publish_policy::PublishGate;
+use crate::repeating_group::{aggregate_payloads, expand_repeating_point, RepeatingGroupTracker};
+use crate::state_mgmt::AuditRecord;
 use crate::sunspec_unit::SunSpecUnit;
+use crate::write_policy::{check_policy, READ_ONLY};
 use crate::{GatewayError, SETTINGS};
 use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
 use rand::{thread_rng, Rng};
+use rumqttc::QoS;
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
 
 use sunspec_rs::sunspec_connection::SunSpecPointError;
 use sunspec_rs::sunspec_models::{PointIdentifier, ValueType};
@@ -20,7 +34,240 @@ use tokio::time::{sleep, Duration};
 use tracing::Instrument;
 use tracing::Level;
 
+/// Check a write against the input configured for its point, and write it to the unit, returning
+/// how it went for the audit log.
+pub(crate) async fn write_inbound(unit: &SunSpecUnit, inmsg: &InboundMessage) -> AuditRecord {
+    let mut record = AuditRecord {
+        timestamp: Utc::now().timestamp(),
+        source: inmsg.source.to_string(),
+        serial_number: inmsg.serial_number.clone(),
+        model: inmsg.model.clone(),
+        point: inmsg.point.to_string(),
+        requested_value: inmsg.payload.clone(),
+        ..AuditRecord::default()
+    };
+    match try_write(unit, inmsg, &mut record).await {
+        Ok(result) => record.result = result.to_string(),
+        Err((result, e)) => {
+            record.result = result.to_string();
+            record.error = Some(e);
+        }
+    }
+    record
+}
+
+async fn try_write(
+    unit: &SunSpecUnit,
+    inmsg: &InboundMessage,
+    record: &mut AuditRecord,
+) -> Result<WriteResult, (WriteResult, String)> {
+    let log_prefix = format!(
+        "[{}:{} {} {}/{}]",
+        unit.addr, unit.slave_id, inmsg.serial_number, inmsg.model, inmsg.point
+    );
+    let rejected = |msg: String| {
+        warn!("{log_prefix}: {msg}");
+        (WriteResult::Rejected, msg)
+    };
+    if READ_ONLY.load(Ordering::SeqCst) {
+        return Err(rejected(
+            "The gateway is in read-only mode, refusing writes.".to_string(),
+        ));
+    }
+    let Some((mid, md)) = inmsg
+        .model
+        .parse::<u16>()
+        .ok()
+        .and_then(|mid| Some((mid, unit.conn.models.get(&mid)?)))
+    else {
+        return Err(rejected(
+            "Inbound message is for a model this unit doesn't have.".to_string(),
+        ));
+    };
+    let point_config: Option<PointConfig> = {
+        let config = SETTINGS.read().await;
+        config
+            .unit_models(&unit.addr, unit.slave_id)
+            .get(&inmsg.model)
+            .and_then(|points| {
+                points
+                    .iter()
+                    .find(|p| {
+                        p.name()
+                            .parse::<PointPath>()
+                            .is_ok_and(|p| p.matches(&inmsg.point))
+                    })
+                    .cloned()
+            })
+    };
+    let Some((input, scale_factor, policy)) =
+        point_config.and_then(|pc| Some((pc.inputs?, pc.scale_factor, pc.write_policy)))
+    else {
+        return Err(rejected(format!(
+            "No inputs are configured for this point, ignoring {}",
+            inmsg.payload
+        )));
+    };
+
+    let symbols = if inmsg.point.is_catalog() {
+        unit.conn
+            .catalog
+            .get(&inmsg.point.to_string())
+            .and_then(|pn| pn.point_data.symbol.clone())
+    } else {
+        unit.data.clone().get_symbols_for_point(
+            mid,
+            inmsg.point.point.clone(),
+            Some(unit.device_info.manufacturer.clone()),
+        )
+    };
+    // numbers come in engineering units, so the unit's own scale factor has to be undone too
+    let model_scale_factor = match input {
+        InputType::Number(_) => match unit.scale_factor(mid, &inmsg.point).await {
+            Some(sf) => sf,
+            None => {
+                return Err(rejected(format!(
+                    "Couldn't read the point's scale factor, ignoring {}",
+                    inmsg.payload
+                )));
+            }
+        },
+        _ => 0,
+    };
+    let raw = match command_value(
+        &input,
+        symbols.as_deref(),
+        scale_factor,
+        model_scale_factor,
+        &inmsg.payload,
+    ) {
+        Ok(raw) => raw,
+        Err(e) => {
+            return Err(rejected(format!("Rejecting inbound payload: {e}")));
+        }
+    };
+    record.raw_value = Some(raw);
+    if let Some(policy) = policy {
+        if let Err(e) = check_policy(
+            unit,
+            &inmsg.model,
+            &inmsg.point,
+            &policy,
+            &inmsg.payload,
+            raw,
+            record.timestamp,
+        )
+        .await
+        {
+            return Err(rejected(format!(
+                "Write policy refuses {}: {e}",
+                inmsg.payload
+            )));
+        }
+    }
+
+    match unit
+        .conn
+        .clone()
+        .get_point(md.clone(), inmsg.point.identifier())
+        .await
+    {
+        Ok(point) => record.previous_value = point.value.as_ref().and_then(audit_value),
+        Err(e) => warn!("{log_prefix}: Couldn't read the value before writing: {e}"),
+    }
+    if inmsg.dry_run {
+        info!("{log_prefix}: Dry run, not sending {}:{raw}", inmsg.payload);
+        return Ok(WriteResult::DryRun);
+    }
+    match unit
+        .conn
+        .clone()
+        .set_point(
+            md.clone(),
+            inmsg.point.identifier(),
+            ValueType::Integer(raw),
+        )
+        .await
+    {
+        Ok(_) => {
+            // TODO maybe I can immediately reschedule a check of the point to get it refreshed?
+            info!(
+                "{log_prefix}: Value successfully sent {}:{raw}",
+                inmsg.payload
+            );
+            Ok(WriteResult::Written)
+        }
+        Err(e) => {
+            error!("{log_prefix}: Couldn't set point: {e}");
+            Err((WriteResult::Failed, e.to_string()))
+        }
+    }
+}
+
+/// Discovery configs are retained, so that Home Assistant picks them up whenever it restarts
+/// without us having to send them on every poll.
+fn config_message(payload: &CompoundPayload) -> PublishMessage {
+    PublishMessage {
+        topic: payload.config_topic.clone(),
+        payload: Payload::Config(payload.config.clone()),
+        qos: QoS::AtLeastOnce,
+        retain: true,
+    }
+}
+
+lazy_static! {
+    /// The slaves poll loops are running for, by serial number.  Writes for a unit with none go
+    /// nowhere.
+    static ref POLLING: Mutex<HashMap<String, Vec<(String, u8)>>> = Mutex::new(HashMap::new());
+}
+
+/// Whether a unit is being polled, and so will take writes.
+pub fn polling(serial_number: &str) -> bool {
+    POLLING.lock().unwrap().contains_key(serial_number)
+}
+
+/// The address and slave id a unit is being polled at.  Which slave has which serial number is
+/// only known once it's been read.
+pub fn polled_at(serial_number: &str) -> Option<(String, u8)> {
+    POLLING
+        .lock()
+        .unwrap()
+        .get(serial_number)
+        .and_then(|slaves| slaves.first().cloned())
+}
+
+/// Counts a poll loop as running for a unit, for as long as it's held.
+pub(crate) struct Polling(String, (String, u8));
+
+impl Polling {
+    pub(crate) fn start(serial_number: &str, addr: &str, slave_id: u8) -> Self {
+        let slave = (addr.to_string(), slave_id);
+        POLLING
+            .lock()
+            .unwrap()
+            .entry(serial_number.to_string())
+            .or_default()
+            .push(slave.clone());
+        Polling(serial_number.to_string(), slave)
+    }
+}
+
+impl Drop for Polling {
+    fn drop(&mut self) {
+        let mut polling = POLLING.lock().unwrap();
+        if let Some(slaves) = polling.get_mut(&self.0) {
+            if let Some(i) = slaves.iter().position(|s| *s == self.1) {
+                slaves.remove(i);
+            }
+            if slaves.is_empty() {
+                polling.remove(&self.0);
+            }
+        }
+    }
+}
+
 struct PollLoopGuard {
+    _polling: Polling,
     serial_number: String,
     addr: String,
     slave_id: u8,
@@ -42,6 +289,33 @@ impl Drop for PollLoopGuard {
     }
 }
 
+/// The points a unit is polled for, one per configured point, or one per repetition of a point with
+/// a `[*]` in its path.
+fn monitored_points(
+    unit: &SunSpecUnit,
+    models: &HashMap<String, Vec<PointConfig>>,
+    hass_enabled: Option<bool>,
+) -> Vec<MonitoredPoint> {
+    let sn = &unit.serial_number;
+    let mut points: Vec<MonitoredPoint> = vec![];
+    for (model, config_points) in models.iter() {
+        for point in config_points {
+            if point.point.is_none() && point.catalog_ref.is_none() {
+                error!("There is a defined point in model {model} that has neither point name nor catalog ref.  Skipping.");
+                continue;
+            }
+            match MonitoredPoint::new(model.clone(), point.clone(), hass_enabled) {
+                Ok(p) => points.extend(expand_repeating_point(p, &unit.conn.catalog)),
+                Err(e) => {
+                    warn!(%sn, "unable to create MonitoredPoint for {model}/{}: {e}", point.name());
+                    continue;
+                }
+            };
+        }
+    }
+    points
+}
+
 #[instrument(skip_all)]
 pub async fn poll_loop(
     unit: &SunSpecUnit,
@@ -52,44 +326,41 @@ pub async fn poll_loop(
     let sn = &unit.serial_number;
     let addr = &unit.addr;
     let config = SETTINGS.read().await;
-    let mut points: Vec<MonitoredPoint> = vec![];
     let mut last_report: HashMap<String, DateTime<Utc>> = HashMap::new();
 
     let _guard = PollLoopGuard {
+        _polling: Polling::start(sn, addr, unit.slave_id),
         serial_number: unit.serial_number.clone(),
         addr: unit.addr.clone(),
         slave_id: unit.slave_id,
         start_time: Utc::now(),
     };
 
-    for (id, _) in unit.conn.models.iter() {
-        for (model, config_points) in config.models.iter() {
-            for point in config_points {
-                if point.point.is_none() && point.catalog_ref.is_none() {
-                    error!("There is a defined point in model {id} that has neither point name nor catalog ref.  Skipping.");
-                    continue;
-                }
-                let mut monitored_point_target: PointIdentifier = {
-                    if point.catalog_ref.is_some() {
-                        PointIdentifier::Catalog(point.catalog_ref.clone().unwrap())
-                    } else {
-                        PointIdentifier::Point(point.point.clone().unwrap())
-                    }
-                };
-
-                match MonitoredPoint::new(model.clone(), point.clone(), config.hass_enabled) {
-                    Ok(p) => points.push(p),
-                    Err(e) => {
-                        warn!(%sn, "unable to create MonitoredPoint for {id}/{}: {e}", monitored_point_target);
-                        continue;
-                    }
-                };
-            }
-        }
-    } // at this point, `points` should contain all points we've been asked to check.
+    let mut points = monitored_points(
+        unit,
+        config.unit_models(addr, unit.slave_id),
+        config.hass_enabled,
+    );
 
+    let events_config = config.events.clone();
+    let modbus_refresh = config.modbus_server.as_ref().map(|m| {
+        m.refresh_interval
+            .unwrap_or(DEFAULT_MODBUS_SERVER_REFRESH_SECS) as i64
+    });
     // since we're done reading config file variables, lets drop the RwLock.
     drop(config);
+    let mut group_tracker = RepeatingGroupTracker::new(&points);
+    let mut gate = PublishGate::default();
+    let (mut discovery, stale_discovery) = DiscoveryTracker::new(sn, &points).await;
+    if !stale_discovery.is_empty() {
+        info!(%sn, "Clearing {} discovery configs that are no longer published", stale_discovery.len());
+    }
+    for msg in stale_discovery {
+        let _ = tx.send(IPCMessage::Outbound(msg)).await;
+    }
+    let mut events = EventTracker::new(sn, events_config).await;
+    let mut modbus_refreshed: Option<DateTime<Utc>> = None;
+    let mut modbus_registers = modbus_refresh.map(|_| UnitRegisters::new(unit, &points));
 
     loop {
         let timestamp = Utc::now().timestamp();
@@ -101,6 +372,10 @@ pub async fn poll_loop(
         );
         let _enter_single = single_point_span.enter();
 
+        if discovery.purged().await {
+            gate.forget_configs();
+        }
+
         let mut remove_points: Vec<PointIdentifier> = vec![];
         let point_count = points.len();
 
@@ -109,7 +384,7 @@ pub async fn poll_loop(
             let interval = requested_point_to_check.interval as i64;
             let time_pad = thread_rng().gen_range(0..(interval / 3)) as i64;
             let model = requested_point_to_check.model.clone();
-            let point_name = requested_point_to_check.name.clone();
+            let point_name = requested_point_to_check.path.to_string();
             let mut uniqueid = format!("{sn}.{model}.{point_name}");
             let log_prefix = format!(
                 "[{}:{} {sn} {model}/{point_name} {idx}/{point_count}]",
@@ -129,220 +404,11 @@ pub async fn poll_loop(
                         IPCMessage::Inbound(inmsg) => {
                             if inmsg.serial_number == *sn {
                                 info!("{log_prefix}: message was destined for me");
-                                let mn = unit.device_info.manufacturer.clone();
-                                let ssd = unit.data.clone();
-                                let mid = inmsg.model.parse::<u16>().unwrap();
-
-                                if let Some(symbols) = ssd.get_symbols_for_point(
-                                    mid,
-                                    inmsg.point_name.clone(),
-                                    Some(mn),
-                                ) {
-                                    for symbol in symbols {
-                                        if symbol.id == inmsg.payload {
-                                            info!("Found symbol {}->{}", symbol.id, symbol.symbol);
-                                            let md = unit.conn.models.get(&mid).unwrap();
-                                            match unit
-                                                .conn
-                                                .clone()
-                                                .set_point(
-                                                    md.clone(),
-                                                    PointIdentifier::Point(
-                                                        inmsg.point_name.clone(),
-                                                    ),
-                                                    ValueType::Integer(
-                                                        symbol.symbol.parse::<i64>().unwrap(),
-                                                    ),
-                                                )
-                                                .instrument(span!(Level::INFO, "modbus_write"))
-                                                .await
-                                            {
-                                                Ok(_) => {
-                                                    // TODO maybe I can immediately reschedule a check of the point to get it refreshed?
-                                                    info!(
-                                                        "Value successfully sent {}:{}",
-                                                        inmsg.point_name, symbol.id
-                                                    );
-                                                }
-                                                Err(e) => {
-                                                    error!("Couldn't set point: {e}");
-                                                }
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    debug!("This inbound message has no symbol.  Need to check for number");
-
-                                    let points: Vec<PointConfig> = {
-                                        let config = SETTINGS.read().await;
-                                        config.models.get(&inmsg.model).unwrap().clone()
-                                    };
-                                    for point in points {
-                                        if point.clone().name() == inmsg.point_name {
-                                            debug!("Found point config, now validating number");
-                                            if let Some(input) = point.inputs {
-                                                match input {
-                                                    InputType::Select(_) => {
-                                                        warn!("{log_prefix}: We can't find a symbol for this selection.  Received {}", inmsg.payload);
-                                                    }
-                                                    InputType::Switch(sw) => {
-                                                        if let Ok(on_val) = sw.on.parse::<u32>() {
-                                                            if let Ok(off_val) =
-                                                                sw.off.parse::<u32>()
-                                                            {
-                                                                if let Ok(payload_val) =
-                                                                    inmsg.payload.parse::<u32>()
-                                                                {
-                                                                    // phew, we have all the ducks in a row
-                                                                    if payload_val == on_val
-                                                                        || payload_val == off_val
-                                                                    {
-                                                                        let md = unit
-                                                                            .conn
-                                                                            .models
-                                                                            .get(&mid)
-                                                                            .unwrap();
-                                                                        match unit
-                                                                            .conn
-                                                                            .clone()
-                                                                            .set_point(
-                                                                                md.clone(),
-                                                                                PointIdentifier::Point(inmsg.point_name.clone()),
-                                                                                ValueType::Integer(
-                                                                                    payload_val
-                                                                                        .try_into()
-                                                                                        .unwrap(),
-                                                                                ),
-                                                                            )
-                                                                            .instrument(span!(
-                                                                                Level::INFO,
-                                                                                "modbus_write"
-                                                                            ))
-                                                                            .await
-                                                                        {
-                                                                            Ok(_) => {
-                                                                                // TODO maybe I can immediately reschedule a check of the point to get it refreshed?
-                                                                                info!(
-                                                        "Value successfully sent {}:{}",
-                                                        inmsg.point_name, payload_val
-                                                    );
-                                                                            }
-                                                                            Err(e) => {
-                                                                                error!("Couldn't set point: {e}");
-                                                                            }
-                                                                        }
-                                                                    }
-                                                                }
-                                                            }
-                                                        }
-                                                        //warn!("{log_prefix}: We can't find a symbol for this switch value.  Received {}", inmsg.payload);
-                                                    }
-                                                    InputType::Button(val) => {
-                                                        if let Ok(off_val) = val.parse::<u32>() {
-                                                            if let Ok(payload_val) =
-                                                                inmsg.payload.parse::<u32>()
-                                                            {
-                                                                // phew, we have all the ducks in a row
-                                                                if payload_val == off_val {
-                                                                    let md = unit
-                                                                        .conn
-                                                                        .models
-                                                                        .get(&mid)
-                                                                        .unwrap();
-                                                                    match unit
-                                                                        .conn
-                                                                        .clone()
-                                                                        .set_point(
-                                                                            md.clone(),
-                                                                            PointIdentifier::Point(
-                                                                                inmsg
-                                                                                    .point_name
-                                                                                    .clone(),
-                                                                            ),
-                                                                            ValueType::Integer(
-                                                                                payload_val
-                                                                                    .try_into()
-                                                                                    .unwrap(),
-                                                                            ),
-                                                                        )
-                                                                        .instrument(span!(
-                                                                            Level::INFO,
-                                                                            "modbus_write"
-                                                                        ))
-                                                                        .await
-                                                                    {
-                                                                        Ok(_) => {
-                                                                            // TODO maybe I can immediately reschedule a check of the point to get it refreshed?
-                                                                            info!(
-                                                        "Value successfully sent {}:{}",
-                                                        inmsg.point_name, payload_val
-                                                    );
-                                                                        }
-                                                                        Err(e) => {
-                                                                            error!("Couldn't set point: {e}");
-                                                                        }
-                                                                    }
-                                                                }
-                                                            }
-                                                        }
-                                                        //warn!("{log_prefix}: We can't find a symbol for this button payload.  Received {}", inmsg.payload);
-                                                    }
-                                                    InputType::Number(val) => {
-                                                        debug!("Inbound payload is a number!");
-                                                        match inmsg.payload.parse::<i64>() {
-                                                            Ok(parsed) => {
-                                                                if parsed >= val.min as i64
-                                                                    && parsed <= val.max as i64
-                                                                {
-                                                                    let md = unit
-                                                                        .conn
-                                                                        .models
-                                                                        .get(&mid)
-                                                                        .unwrap();
-                                                                    match unit
-                                                                        .conn
-                                                                        .clone()
-                                                                        .set_point(
-                                                                            md.clone(),
-                                                                            PointIdentifier::Point(
-                                                                                inmsg
-                                                                                    .point_name
-                                                                                    .clone(),
-                                                                            ),
-                                                                            ValueType::Integer(
-                                                                                parsed,
-                                                                            ),
-                                                                        )
-                                                                        .instrument(span!(
-                                                                            Level::INFO,
-                                                                            "modbus_write"
-                                                                        ))
-                                                                        .await
-                                                                    {
-                                                                        Ok(_) => {
-                                                                            // TODO maybe I can immediately reschedule a check of the point to get it refreshed?
-                                                                            info!(
-                                                        "Value successfully sent {}:{}",
-                                                        inmsg.point_name, parsed
-                                                    );
-                                                                        }
-                                                                        Err(e) => {
-                                                                            error!("Couldn't set point: {e}");
-                                                                        }
-                                                                    }
-                                                                } else {
-                                                                    error!("{log_prefix}: Sanity check failed: {parsed} is outside of {}<->{}",val.min, val.max);
-                                                                }
-                                                            }
-                                                            Err(e) => {
-                                                                error!("{log_prefix}: Inbound number was unparseable: {e}");
-                                                            }
-                                                        }
-                                                    }
-                                                };
-                                            }
-                                        }
-                                    }
+                                let record = write_inbound(unit, &inmsg)
+                                    .instrument(span!(Level::INFO, "modbus_write"))
+                                    .await;
+                                for msg in audit_write(record).await {
+                                    let _ = tx.send(IPCMessage::Outbound(msg)).await;
                                 }
                             }
                         }
@@ -350,6 +416,12 @@ pub async fn poll_loop(
                             error!("{log_prefix}: Received outbound message, but we're not expecting any: {o:#?}");
                             continue;
                         }
+                        IPCMessage::History(_, _) | IPCMessage::UnitOffline(_) => {
+                            error!(
+                                "{log_prefix}: Received sink message, but we're not expecting any"
+                            );
+                            continue;
+                        }
                         IPCMessage::PleaseReconnect(addr, slave) => {
                             error!("Received a pleasereconnect but its unhandled");
                             return Err(GatewayError::ExitingThread);
@@ -381,6 +453,7 @@ pub async fn poll_loop(
                 Some(model) => Some(model),
                 None => {
                     // the unit we're watching doesn't have this point in its system table
+                    discovery.unavailable(requested_point_to_check);
                     continue;
                 }
             };
@@ -418,12 +491,21 @@ pub async fn poll_loop(
                     .await;
 
                 last_report.insert(uniqueid, Utc::now());
-                if requested_point_to_check.homeassistant_discovery {
+                if requested_point_to_check.homeassistant_discovery
+                    && gate.config(
+                        &payloads[0].config_topic,
+                        &payloads[0].config,
+                        MQTT_CONNECTIONS.load(Ordering::SeqCst),
+                    )
+                {
+                    discovery
+                        .published(
+                            &point_owner(requested_point_to_check),
+                            &payloads[0].config_topic,
+                        )
+                        .await;
                     let _ = tx
-                        .send(IPCMessage::Outbound(PublishMessage {
-                            topic: payloads[0].config_topic.clone(),
-                            payload: Payload::Config(payloads[0].config.clone()),
-                        }))
+                        .send(IPCMessage::Outbound(config_message(&payloads[0])))
                         .instrument(span!(Level::INFO, "outbound_config_send"))
                         .await;
                 }
@@ -453,6 +535,8 @@ pub async fn poll_loop(
                                 continue;
                             }
                             SunSpecPointError::CommError(e) => {
+                                FACADE.write().await.forget(sn);
+                                let _ = tx.send(IPCMessage::UnitOffline(sn.clone())).await;
                                 let _ = tx
                                     .send(IPCMessage::PleaseReconnect(
                                         unit.addr.clone(),
@@ -474,7 +558,17 @@ pub async fn poll_loop(
                         }
                         Some(val) => {
                             let _v = recvd_point.clone();
-                            let payloads = generate_payloads(
+                            if let Some(registers) = modbus_registers.as_mut() {
+                                registers
+                                    .record(
+                                        unit,
+                                        md.unwrap(),
+                                        requested_point_to_check,
+                                        &recvd_point,
+                                    )
+                                    .await;
+                            }
+                            let mut payloads = generate_payloads(
                                 unit,
                                 Some(&recvd_point),
                                 &requested_point_to_check,
@@ -484,41 +578,87 @@ pub async fn poll_loop(
                             .await;
 
                             last_report.insert(uniqueid.clone(), Utc::now());
-                            for payload in payloads {
-                                if requested_point_to_check.homeassistant_discovery {
+                            if let Some(active) = active_symbols(&recvd_point, &val) {
+                                for msg in events
+                                    .observe(requested_point_to_check, &active)
+                                    .instrument(span!(Level::INFO, "observe_events"))
+                                    .await
+                                {
+                                    let _ = tx.send(IPCMessage::Outbound(msg)).await;
+                                }
+                            }
+                            // everything from here on in `payloads` is an aggregate of the group
+                            let aggregates_start = payloads.len();
+                            if !requested_point_to_check.aggregate.is_empty() {
+                                let member_value = match payloads.first().map(|p| &p.state.value) {
+                                    Some(PayloadValueType::Float(f)) => Some(*f),
+                                    Some(PayloadValueType::Int(i)) => Some(*i as f64),
+                                    _ => None,
+                                };
+                                if let Some(aggregates) = member_value
+                                    .and_then(|v| group_tracker.record(requested_point_to_check, v))
+                                {
+                                    let aggs = aggregate_payloads(
+                                        sn,
+                                        requested_point_to_check,
+                                        &requested_point_to_check
+                                            .group_pattern
+                                            .as_ref()
+                                            .unwrap_or(&requested_point_to_check.path)
+                                            .slug(),
+                                        &payloads[0],
+                                        aggregates,
+                                    );
+                                    payloads.extend(aggs);
+                                }
+                            }
+                            let connection = MQTT_CONNECTIONS.load(Ordering::SeqCst);
+                            let policy = &requested_point_to_check.publish;
+                            for (n, payload) in payloads.into_iter().enumerate() {
+                                if requested_point_to_check.homeassistant_discovery
+                                    && gate.config(
+                                        &payload.config_topic,
+                                        &payload.config,
+                                        connection,
+                                    )
+                                {
+                                    let owner = if n < aggregates_start {
+                                        Some(point_owner(requested_point_to_check))
+                                    } else {
+                                        aggregate_owner(requested_point_to_check)
+                                    };
+                                    if let Some(owner) = owner {
+                                        discovery.published(&owner, &payload.config_topic).await;
+                                    }
                                     let _ = tx
-                                        .send(IPCMessage::Outbound(PublishMessage {
-                                            topic: payload.config_topic,
-                                            payload: Payload::Config(payload.config.clone()),
-                                        }))
+                                        .send(IPCMessage::Outbound(config_message(&payload)))
                                         .instrument(span!(Level::INFO, "outbound_config_send"))
                                         .await;
                                 }
 
+                                if gate.state(
+                                    &payload.state_topic,
+                                    policy,
+                                    &payload.state.value,
+                                    Utc::now().timestamp(),
+                                ) {
+                                    let _ = tx
+                                        .send(IPCMessage::Outbound(PublishMessage {
+                                            topic: payload.state_topic.clone(),
+                                            payload: Payload::CurrentState(payload.state.clone()),
+                                            qos: policy.qos,
+                                            retain: policy.retain,
+                                        }))
+                                        .instrument(span!(Level::INFO, "outbound_state_send"))
+                                        .await;
+                                }
                                 let _ = tx
-                                    .send(IPCMessage::Outbound(PublishMessage {
-                                        topic: payload.state_topic,
-                                        payload: Payload::CurrentState(payload.state.clone()),
-                                    }))
-                                    .instrument(span!(Level::INFO, "outbound_state_send"))
+                                    .send(IPCMessage::History(
+                                        payload.config.unique_id,
+                                        payload.state,
+                                    ))
+                                    .instrument(span!(Level::INFO, "outbound_history_send"))
                                     .await;
-                                if let Err(e) = cull_records_to(
-                                    payload.config.clone().unique_id,
-                                    CULL_HISTORY_ROWS,
-                                )
-                                .instrument(span!(Level::INFO, "cull_records_in_sql"))
-                                .await
-                                {
-                                    warn!(
-                                        "{log_prefix}: Couldn't cull history for this point: {e}"
-                                    );
-                                };
-                                if let Err(e) = write_payload_history(payload.config, payload.state)
-                                    .instrument(span!(Level::INFO, "write_payload_history"))
-                                    .await
-                                {
-                                    warn!("{log_prefix}: Unable to store value in db: {e}");
-                                }
                             }
                         }
                     },
@@ -532,13 +672,108 @@ pub async fn poll_loop(
         // and catch any new deletes on follow-on loops.
         if remove_points.len() > 0 {
             for rp in remove_points {
+                for p in points.iter().filter(|p| p.name == rp) {
+                    group_tracker.remove(p);
+                    for msg in discovery.remove(&point_owner(p)).await {
+                        let _ = tx.send(IPCMessage::Outbound(msg)).await;
+                    }
+                }
                 points = points.into_iter().filter(|p| p.name != rp).collect();
             }
         }
 
+        for msg in discovery.sweep().await {
+            let _ = tx.send(IPCMessage::Outbound(msg)).await;
+        }
+
+        if let (Some(refresh), Some(registers)) = (modbus_refresh, modbus_registers.as_ref()) {
+            if modbus_refreshed.is_none_or(|r| (Utc::now() - r).num_seconds() >= refresh) {
+                modbus_refreshed = Some(Utc::now());
+                modbus_server::refresh(unit, &points, registers)
+                    .instrument(span!(Level::INFO, "modbus_refresh"))
+                    .await;
+            }
+        }
+
         debug!(%addr, %sn, "Device tick");
         let _ = sleep(Duration::from_secs(MINIMUM_QUERY_INTERVAL_SECS.into()))
             .instrument(span!(Level::INFO, "main-loop-sleep"))
             .await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::WriteSource;
+    use crate::modbus_server::test_unit;
+    use crate::write_policy::READ_ONLY_LOCK;
+
+    #[tokio::test]
+    async fn groups_aggregate_on_units_with_many_models() {
+        // two ports of 25 registers after the fixed part, next to immediate controls
+        let mut ports = vec![0; 18 + 2 * 25];
+        ports[2] = 2;
+        let unit = test_unit("GROUPS1", vec![(123, vec![0; 24]), (714, ports)]).await;
+        assert!(unit.conn.models.len() > 2);
+        let mut models = HashMap::new();
+        models.insert(
+            "714".to_string(),
+            vec![PointConfig {
+                point: Some(".DERMeasureDC.Prt[*].DCW".to_string()),
+                interval: 60,
+                aggregate: Some(vec![crate::config_structs::Aggregation::Sum]),
+                ..Default::default()
+            }],
+        );
+        models.insert(
+            "123".to_string(),
+            vec![PointConfig {
+                point: Some("WMaxLimPct".to_string()),
+                interval: 60,
+                ..Default::default()
+            }],
+        );
+
+        let points = monitored_points(&unit, &models, Some(true));
+        let ports: Vec<&MonitoredPoint> = points.iter().filter(|p| p.model == "714").collect();
+        assert_eq!(ports.len(), 2, "{points:?}");
+        assert_eq!(points.len(), 3);
+
+        let mut tracker = RepeatingGroupTracker::new(&points);
+        assert!(tracker.record(ports[0], 100.0).is_none());
+        let aggregates = tracker.record(ports[1], 150.0).unwrap();
+        assert_eq!(aggregates[0].1, 250.0);
+    }
+
+    #[tokio::test]
+    async fn read_only_refuses_every_write() {
+        let unit = test_unit("READONLY1", vec![(123, vec![0; 24])]).await;
+        let write = |model: &str| InboundMessage {
+            serial_number: "READONLY1".to_string(),
+            model: model.to_string(),
+            point: "WMaxLimPct".parse().unwrap(),
+            payload: "50".to_string(),
+            source: WriteSource::Rest("admin".to_string()),
+            dry_run: true,
+        };
+
+        let _read_only = READ_ONLY_LOCK.lock().await;
+        READ_ONLY.store(true, Ordering::SeqCst);
+        let record = write_inbound(&unit, &write("123")).await;
+        READ_ONLY.store(false, Ordering::SeqCst);
+        assert_eq!(record.result, WriteResult::Rejected.to_string());
+        assert_eq!(
+            record.error.as_deref(),
+            Some("The gateway is in read-only mode, refusing writes.")
+        );
+        assert_eq!(record.requested_value, "50");
+
+        // otherwise the write gets as far as the unit's models
+        let record = write_inbound(&unit, &write("704")).await;
+        assert_eq!(
+            record.error.as_deref(),
+            Some("Inbound message is for a model this unit doesn't have.")
+        );
+    }
+}
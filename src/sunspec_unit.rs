@@ -1,14 +1,16 @@
 use crate::consts::*;
+use crate::device_hierarchy::place_unit;
 use crate::monitored_point::MonitoredPoint;
 use crate::payload::DeviceInfo;
-use crate::{GatewayError, MODEL_HASH, SHUTDOWN};
+use crate::point_path::PointPath;
+use crate::{GatewayError, MODEL_HASH, SETTINGS};
 use anyhow::bail;
 
 use std::time::Duration;
 
 use sunspec_rs::sunspec_connection::{SunSpecConnection, TlsConfig};
 use sunspec_rs::sunspec_data::SunSpecData;
-use sunspec_rs::sunspec_models::{PointIdentifier, ValueType};
+use sunspec_rs::sunspec_models::{Point, PointIdentifier, ValueType};
 use tokio::task;
 use tokio::time::sleep;
 
@@ -166,6 +168,20 @@ impl SunSpecUnit {
         device_info.manufacturer = manufacturer.clone();
         device_info.name = format!("{serial_number}: {physical_model}");
         device_info.identifiers = vec![serial_number.clone()];
+        device_info.serial_number = Some(serial_number.clone());
+        // the common model has no hardware version, but Opt is where vendors put build options
+        if let Ok(options) = conn
+            .clone()
+            .get_point(common.clone(), PointIdentifier::Point("Opt".to_string()))
+            .await
+        {
+            if let Some(ValueType::String(opt)) = options.value {
+                if !opt.trim().is_empty() {
+                    device_info.hw_version = Some(opt.trim().to_string());
+                }
+            }
+        }
+        place_unit(&mut device_info, &*SETTINGS.read().await, &addr);
 
         info!("Initialized {manufacturer}/{physical_model} with SN {serial_number}");
         debug!("Models: {:#?}", conn.models.keys());
@@ -181,3 +197,41 @@ impl SunSpecUnit {
         })
     }
 }
+
+impl SunSpecUnit {
+    /// The definition of a point of one of the unit's models.
+    pub fn point_data(&self, model: u16, path: &PointPath) -> Option<Point> {
+        if path.is_catalog() {
+            return self
+                .conn
+                .catalog
+                .get(&path.to_string())
+                .map(|node| node.point_data.clone());
+        }
+        self.conn
+            .models
+            .get(&model)?
+            .model
+            .model
+            .block
+            .iter()
+            .flat_map(|b| b.point.iter())
+            .find(|p| p.id == path.point)
+            .cloned()
+    }
+
+    /// The scale factor the unit applies to a point, e.g. `WMaxLimPct_SF` for `WMaxLimPct`, as
+    /// read from the unit: 0 for points without one, or `None` if it can't be read.
+    pub async fn scale_factor(&self, model: u16, path: &PointPath) -> Option<i32> {
+        let Some(sf_name) = self.point_data(model, path)?.scale_factor else {
+            return Some(0);
+        };
+        self.conn
+            .models
+            .get(&model)?
+            .clone()
+            .get_scale_factor(&sf_name, self.conn.clone(), None, None)
+            .await
+            .map(i32::from)
+    }
+}
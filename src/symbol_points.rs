@@ -0,0 +1,99 @@
+use crate::ha_inputs::raw_value;
+use crate::payload::{PayloadValueType, StatePayload};
+use sunspec_rs::sunspec_models::{Point, Symbol};
+
+/// Whether a point is an enum, according to its model definition.
+pub fn is_enum(point: Option<&Point>) -> bool {
+    point.is_some_and(|p| p.r#type.starts_with("enum"))
+}
+
+/// The raw register value of a bitfield, from the symbols that are set.  `None` if one of them
+/// isn't a symbol of the point.
+pub fn bitfield_raw(symbols: Option<&[Symbol]>, active: &[String]) -> Option<i64> {
+    let symbols = symbols?;
+    active.iter().try_fold(0_i64, |raw, id| {
+        let bit = symbols
+            .iter()
+            .find(|s| s.id == *id)?
+            .symbol
+            .parse::<u32>()
+            .ok()?;
+        Some(raw | 1_i64.checked_shl(bit)?)
+    })
+}
+
+/// The raw register value of an enum, from the symbol (or `ENUM16_{n}`) that was read.
+pub fn enum_raw(symbols: Option<&[Symbol]>, value: &str) -> Option<i64> {
+    raw_value(symbols, &PayloadValueType::String(value.to_string()))
+}
+
+/// The options for an enum sensor: every symbol of the point, plus the value read if it isn't
+/// one of them, since Home Assistant rejects states that aren't in the options.
+pub fn enum_options(symbols: Option<&[Symbol]>, value: &str) -> Vec<String> {
+    let mut options: Vec<String> = symbols
+        .unwrap_or_default()
+        .iter()
+        .map(|s| s.id.clone())
+        .collect();
+    if !options.iter().any(|o| o == value) {
+        options.push(value.to_string());
+    }
+    options
+}
+
+/// Copy the label, description and notes of a point from its model literal.  A display name
+/// from config takes the place of the literal's label.
+pub fn apply_literals(
+    state: &mut StatePayload,
+    point: Option<&Point>,
+    display_name: Option<&String>,
+) {
+    let literal = point.and_then(|p| p.literal.as_ref());
+    state.label = display_name
+        .cloned()
+        .or_else(|| literal.and_then(|l| l.label.clone()));
+    state.description = literal.and_then(|l| l.description.clone());
+    state.notes = literal.and_then(|l| l.notes.clone());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Vec<Symbol> {
+        ["GROUND_FAULT:0", "DC_OVER_VOLT:1", "OVER_TEMP:7"]
+            .iter()
+            .map(|s| {
+                let (id, symbol) = s.split_once(':').unwrap();
+                Symbol {
+                    id: id.to_string(),
+                    symbol: symbol.to_string(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn raw_values_come_from_symbols() {
+        let s = symbols();
+        let sym = Some(s.as_slice());
+        assert_eq!(bitfield_raw(sym, &[]), Some(0));
+        assert_eq!(
+            bitfield_raw(sym, &["GROUND_FAULT".to_string(), "OVER_TEMP".to_string()]),
+            Some(129)
+        );
+        assert_eq!(bitfield_raw(sym, &["UNKNOWN".to_string()]), None);
+        assert_eq!(enum_raw(sym, "DC_OVER_VOLT"), Some(1));
+        assert_eq!(enum_raw(sym, "ENUM16_9"), Some(9));
+    }
+
+    #[test]
+    fn enum_options_include_unknown_values() {
+        let s = symbols();
+        assert_eq!(enum_options(Some(s.as_slice()), "OVER_TEMP").len(), 3);
+        assert_eq!(
+            enum_options(Some(s.as_slice()), "ENUM16_9").last(),
+            Some(&"ENUM16_9".to_string())
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use crate::audit::audit_value;
+use crate::config_structs::{WriteDependency, WritePolicy};
+use crate::consts::*;
+use crate::ipc::PublishMessage;
+use crate::payload::Payload;
+use crate::point_path::PointPath;
+use crate::state_mgmt::{get_setting, recent_writes, set_setting};
+use crate::sunspec_unit::SunSpecUnit;
+use rumqttc::QoS;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether every write to a unit is refused, whatever it's from.
+pub static READ_ONLY: AtomicBool = AtomicBool::new(false);
+/// Held by tests that need read-only mode to stay the way they set it.
+#[cfg(test)]
+pub(crate) static READ_ONLY_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+const READ_ONLY_SETTING: &str = "read_only";
+
+/// Carry on in read-only mode if that's how we were left.
+pub async fn load_read_only() {
+    match get_setting(READ_ONLY_SETTING).await {
+        Ok(Some(value)) => {
+            let on = value == "true";
+            if on {
+                warn!("Starting in read-only mode; writes to units will be refused");
+            }
+            READ_ONLY.store(on, Ordering::SeqCst);
+        }
+        Ok(None) => {}
+        Err(e) => error!("Couldn't load read-only mode: {e}"),
+    }
+}
+
+pub async fn set_read_only(on: bool) -> anyhow::Result<()> {
+    set_setting(READ_ONLY_SETTING, &on.to_string()).await?;
+    READ_ONLY.store(on, Ordering::SeqCst);
+    warn!("Read-only mode turned {}", if on { "on" } else { "off" });
+    Ok(())
+}
+
+/// `ON` or `OFF`, the way Home Assistant switches say it.
+pub fn parse_switch(payload: &str) -> Option<bool> {
+    match payload.trim().to_lowercase().as_str() {
+        "on" | "true" | "1" => Some(true),
+        "off" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// The retained message saying whether read-only mode is on.
+pub fn read_only_message() -> PublishMessage {
+    let on = READ_ONLY.load(Ordering::SeqCst);
+    PublishMessage {
+        topic: MQTT_READ_ONLY_TOPIC.to_string(),
+        payload: Payload::Raw(if on { "ON" } else { "OFF" }.into()),
+        qos: QoS::AtLeastOnce,
+        retain: true,
+    }
+}
+
+/// Whether a value may be written at all: it has to be one of the allowed ones, and in range.
+pub fn check_value(policy: &WritePolicy, requested: &str, raw: i64) -> Result<(), String> {
+    if let Some(allowed) = &policy.allowed_values {
+        if !allowed
+            .iter()
+            .any(|a| a == requested || *a == raw.to_string())
+        {
+            return Err(format!("{requested} isn't one of {}", allowed.join(", ")));
+        }
+    }
+    if policy.min.is_some() || policy.max.is_some() {
+        let Ok(value) = requested.trim().parse::<f64>() else {
+            return Err(format!("{requested} isn't a number"));
+        };
+        if policy.min.is_some_and(|min| value < min) || policy.max.is_some_and(|max| value > max) {
+            return Err(format!(
+                "{requested} is outside {} to {}",
+                policy.min.map(|m| m.to_string()).unwrap_or("-inf".into()),
+                policy.max.map(|m| m.to_string()).unwrap_or("inf".into())
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a point can be written again at `now`, given when it was last written.
+pub fn check_rate(policy: &WritePolicy, writes: &[i64], now: i64) -> Result<(), String> {
+    if let (Some(min_interval), Some(last)) = (policy.min_interval, writes.iter().max()) {
+        let next = last + min_interval as i64;
+        if now < next {
+            return Err(format!(
+                "written {}s ago, and may only be written every {min_interval}s",
+                now - last
+            ));
+        }
+    }
+    if let Some(max) = policy.max_per_hour {
+        let in_last_hour = writes.iter().filter(|t| **t > now - 3600).count();
+        if in_last_hour >= max as usize {
+            return Err(format!(
+                "already written {in_last_hour} times in the last hour, of at most {max}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check a write to `{model}/{point}` against its policy, reading the points it depends on from
+/// the unit.
+pub async fn check_policy(
+    unit: &SunSpecUnit,
+    model: &str,
+    point: &PointPath,
+    policy: &WritePolicy,
+    requested: &str,
+    raw: i64,
+    now: i64,
+) -> Result<(), String> {
+    check_value(policy, requested, raw)?;
+
+    if policy.min_interval.is_some() || policy.max_per_hour.is_some() {
+        let window = policy.min_interval.unwrap_or(0).max(3600) as i64;
+        let writes = recent_writes(&unit.serial_number, model, &point.to_string(), now - window)
+            .await
+            .map_err(|e| format!("couldn't check recent writes: {e}"))?;
+        check_rate(policy, &writes, now)?;
+    }
+
+    for dependency in policy.requires.iter().flatten() {
+        check_dependency(unit, model, dependency).await?;
+    }
+    Ok(())
+}
+
+async fn check_dependency(
+    unit: &SunSpecUnit,
+    model: &str,
+    dependency: &WriteDependency,
+) -> Result<(), String> {
+    let model = dependency.model.as_deref().unwrap_or(model);
+    let name = format!("{model}/{}", dependency.point);
+    let path: PointPath = dependency
+        .point
+        .parse()
+        .map_err(|e| format!("{name} isn't a point: {e}"))?;
+    let md = model
+        .parse::<u16>()
+        .ok()
+        .and_then(|mid| unit.conn.models.get(&mid))
+        .ok_or(format!(
+            "this unit has no model {model}, which {name} is in"
+        ))?;
+    let value = unit
+        .conn
+        .clone()
+        .get_point(md.clone(), path.identifier())
+        .await
+        .map_err(|e| format!("couldn't read {name}: {e}"))?
+        .value
+        .as_ref()
+        .and_then(audit_value);
+    match value {
+        Some(v) if dependency.values.contains(&v) => Ok(()),
+        v => Err(format!(
+            "{name} is {}, not {}",
+            v.unwrap_or("unset".to_string()),
+            dependency.values.join(" or ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus_server::test_unit;
+
+    #[tokio::test]
+    async fn read_only_is_published_as_a_switch_state() {
+        let _read_only = READ_ONLY_LOCK.lock().await;
+        let was = READ_ONLY.load(Ordering::SeqCst);
+        for (on, expected) in [(true, "ON"), (false, "OFF")] {
+            READ_ONLY.store(on, Ordering::SeqCst);
+            let msg = read_only_message();
+            assert!(msg.retain);
+            assert!(matches!(msg.payload, Payload::Raw(p) if p == expected.as_bytes()));
+        }
+        READ_ONLY.store(was, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn values_and_rates() {
+        let policy: WritePolicy = serde_yaml::from_str(
+            r#"
+            min_interval: 60
+            max_per_hour: 3
+            allowed_values: ["MAINTAIN", "2"]
+            requires:
+              - point: WMaxLim_Ena
+                values: [ENABLED]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            policy.requires.as_ref().unwrap()[0].point,
+            "WMaxLim_Ena".to_string()
+        );
+        assert!(check_value(&policy, "MAINTAIN", 1).is_ok());
+        assert!(check_value(&policy, "DISCHARGE", 2).is_ok());
+        assert!(check_value(&policy, "CHARGE", 3).is_err());
+
+        let ranged = WritePolicy {
+            min: Some(0.0),
+            max: Some(80.0),
+            ..WritePolicy::default()
+        };
+        assert!(check_value(&ranged, "80", 800).is_ok());
+        assert!(check_value(&ranged, "80.5", 805).is_err());
+        assert!(check_value(&ranged, "lots", 0).is_err());
+
+        let now = 1_700_010_000;
+        assert!(check_rate(&policy, &[], now).is_ok());
+        assert!(check_rate(&policy, &[now - 30], now).is_err());
+        assert!(check_rate(&policy, &[now - 60], now).is_ok());
+        assert!(check_rate(&policy, &[now - 3000, now - 2000, now - 1000], now).is_err());
+        assert!(check_rate(&policy, &[now - 3600, now - 2000, now - 1000], now).is_ok());
+    }
+
+    #[tokio::test]
+    async fn dependencies_are_read_from_the_unit() {
+        // immediate controls, with the power limit set but not enabled
+        let mut controls = vec![0; 24];
+        controls[3] = 50;
+        let unit = test_unit("POLICY1", vec![(123, controls)]).await;
+        let requires = |model: Option<&str>, point: &str, values: &[&str]| WriteDependency {
+            model: model.map(String::from),
+            point: point.to_string(),
+            values: values.iter().map(|v| v.to_string()).collect(),
+        };
+
+        let err = check_dependency(&unit, "123", &requires(None, "WMaxLim_Ena", &["ENABLED"]))
+            .await
+            .unwrap_err();
+        assert_eq!(err, "123/WMaxLim_Ena is DISABLED, not ENABLED");
+        assert!(check_dependency(
+            &unit,
+            "123",
+            &requires(None, "WMaxLim_Ena", &["ENABLED", "DISABLED"])
+        )
+        .await
+        .is_ok());
+        // numbers are compared as they read
+        assert!(
+            check_dependency(&unit, "1", &requires(Some("123"), "WMaxLimPct", &["50"]))
+                .await
+                .is_ok()
+        );
+
+        let err = check_dependency(&unit, "123", &requires(Some("704"), "WMaxLimPct", &["50"]))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "this unit has no model 704, which 704/WMaxLimPct is in"
+        );
+        assert!(
+            check_dependency(&unit, "123", &requires(None, "NoSuchPoint", &["1"]))
+                .await
+                .is_err()
+        );
+    }
+}